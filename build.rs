@@ -54,7 +54,7 @@ fn main() {
         PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR environment variable not defined"));
 
     // generate bash completions for both executables
-    generate_completion(&mut cli::MdevctlCommands::command(), &outdir);
+    generate_completion(&mut cli::Mdevctl::command(), &outdir);
     generate_completion(&mut cli::LsmdevOptions::command(), &outdir);
 
     // generate manpage
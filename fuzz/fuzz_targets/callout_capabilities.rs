@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mdevctl_fuzz::callouts::CalloutScriptCache;
+
+// the capabilities document printed by callout scripts
+fuzz_target!(|data: &[u8]| {
+    let _ = CalloutScriptCache::parse_script_capabilities(data);
+});
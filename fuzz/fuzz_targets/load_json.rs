@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mdevctl_fuzz::mdev::MDev;
+use mdevctl_fuzz::FuzzEnvironment;
+use std::rc::Rc;
+
+// device configuration files as written by users and vendor tools
+fuzz_target!(|data: &[u8]| {
+    if let Ok(json) = serde_json::from_slice::<serde_json::Value>(data) {
+        let mut dev = MDev::new(Rc::new(FuzzEnvironment), uuid::Uuid::nil());
+        if dev.load_from_json("parent".to_string(), &json).is_ok() {
+            let _ = dev.to_json(false);
+        }
+    }
+});
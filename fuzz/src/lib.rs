@@ -0,0 +1,27 @@
+//! Support for the fuzz targets, which fuzz the parsers of the mdevctl library
+
+use std::path::Path;
+use std::rc::Rc;
+
+pub use mdevctl::{callouts, environment, mdev};
+
+/// An environment without any devices or callout scripts
+#[derive(Debug)]
+pub struct FuzzEnvironment;
+
+impl environment::Environment for FuzzEnvironment {
+    fn root(&self) -> &Path {
+        Path::new("/nonexistent")
+    }
+
+    fn find_script(
+        &self,
+        _dev: &mdev::MDev,
+    ) -> anyhow::Result<Option<callouts::CalloutScriptInfo>> {
+        Ok(None)
+    }
+
+    fn as_env(self: Rc<Self>) -> Rc<dyn environment::Environment> {
+        self
+    }
+}
@@ -0,0 +1,134 @@
+//! Records of device changes in the Linux audit subsystem
+//!
+//! Some compliance regimes require every change of the devices assigned on a host to be audited.
+//! With `audit` set in the policy, defining, undefining, starting, stopping and modifying a
+//! device, including its attributes while it is active, is sent to the kernel as an
+//! `AUDIT_USER_DEVICE` message over an audit netlink socket. The kernel adds the credentials of
+//! mdevctl to the record, i.e. its pid, uid, login uid, session and security context, so that the
+//! record identifies the user that made the change.
+
+use crate::callouts::{Action, ActionResult};
+use crate::mdev::MDev;
+use anyhow::{Context, Result};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+/// user space hotplug device changes, see `linux/audit.h`
+const AUDIT_USER_DEVICE: u16 = 1137;
+
+/// Whether `action` changes the devices of the host and is audited
+pub fn audited(action: Action) -> bool {
+    matches!(
+        action,
+        Action::Define | Action::Undefine | Action::Start | Action::Stop | Action::Modify
+    )
+}
+
+/// Format an untrusted value of a field like the audit userspace does: quoted, or hex encoded if
+/// it contains characters that cannot be quoted
+fn field(value: &str) -> String {
+    match value.bytes().any(|b| b == b'"' || b <= b' ' || b >= 0x7f) {
+        true => value.bytes().map(|b| format!("{:02X}", b)).collect(),
+        false => format!("\"{}\"", value),
+    }
+}
+
+/// The message recorded for `action` on `dev`, e.g.
+/// `op=start uuid="..." parent="0000:00:02.0" type="i915-GVTg_V5_4" exe="/usr/sbin/mdevctl"
+/// res=success`
+pub fn message(dev: &MDev, action: Action, result: ActionResult) -> String {
+    let exe = std::env::current_exe()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!(
+        "op={} uuid={} parent={} type={} exe={} res={}",
+        action,
+        field(&dev.uuid.hyphenated().to_string()),
+        field(dev.parent.as_deref().unwrap_or("?")),
+        field(dev.mdev_type.as_deref().unwrap_or("?")),
+        field(&exe),
+        // the results of the audit userspace
+        match result {
+            ActionResult::Success => "success",
+            ActionResult::Failure => "failed",
+        }
+    )
+}
+
+/// Send `message` to the audit subsystem and check that the kernel accepted it. This requires
+/// the `CAP_AUDIT_WRITE` capability.
+pub fn send(message: &str) -> Result<()> {
+    // SAFETY: plain system call, the returned descriptor is owned below
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_AUDIT,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Unable to open audit socket");
+    }
+    // SAFETY: the descriptor was just opened and is not used elsewhere
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let hdrlen = mem::size_of::<libc::nlmsghdr>();
+    let len = hdrlen + message.len() + 1;
+    let hdr = libc::nlmsghdr {
+        nlmsg_len: len as u32,
+        nlmsg_type: AUDIT_USER_DEVICE,
+        nlmsg_flags: (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16,
+        nlmsg_seq: 1,
+        nlmsg_pid: 0,
+    };
+    let mut buf = vec![0u8; (len + 3) & !3];
+    // SAFETY: the buffer is large enough for the header, which is plain old data
+    unsafe { std::ptr::write_unaligned(buf.as_mut_ptr() as *mut libc::nlmsghdr, hdr) };
+    buf[hdrlen..hdrlen + message.len()].copy_from_slice(message.as_bytes());
+
+    // SAFETY: an all-zero address is valid, and the kernel is addressed by pid 0
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    // SAFETY: the buffer and the address are valid for the given lengths
+    let sent = unsafe {
+        libc::sendto(
+            fd.as_raw_fd(),
+            buf.as_ptr() as *const libc::c_void,
+            buf.len(),
+            0,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error()).context("Unable to send audit message");
+    }
+
+    // the kernel handles the message while it is sent, so the acknowledgement is already queued
+    let mut reply = [0u8; 1024];
+    // SAFETY: the buffer is valid for its length
+    let received = unsafe {
+        libc::recv(
+            fd.as_raw_fd(),
+            reply.as_mut_ptr() as *mut libc::c_void,
+            reply.len(),
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if received < (hdrlen + mem::size_of::<libc::c_int>()) as isize {
+        // no acknowledgement to check
+        return Ok(());
+    }
+    // SAFETY: the reply is long enough for the header and the error code that follows it
+    let (reply_hdr, error) = unsafe {
+        (
+            std::ptr::read_unaligned(reply.as_ptr() as *const libc::nlmsghdr),
+            std::ptr::read_unaligned(reply[hdrlen..].as_ptr() as *const libc::c_int),
+        )
+    };
+    if reply_hdr.nlmsg_type == libc::NLMSG_ERROR as u16 && error != 0 {
+        return Err(io::Error::from_raw_os_error(-error)).context("Audit message was rejected");
+    }
+    Ok(())
+}
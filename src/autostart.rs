@@ -0,0 +1,575 @@
+//! Automatic start of the devices of a parent device, for `start-parent-mdevs`
+//!
+//! The udev rules start the devices of a parent once its driver registers it. Other init systems,
+//! e.g. of an appliance without udev and systemd, start them with [`start_parent_mdevs`] instead of
+//! the command and get the result of every device, rather than having to parse the log. The same
+//! result is written as JSON by `start-parent-mdevs --dumpjson`. Systems without rules for every
+//! parent start the devices of all present parents with [`start_all_parents`] instead, which is
+//! also how `start --all-defined --auto-only` starts them.
+//!
+//! Devices are started in the order of [`start_order`], so that a device is only started after
+//! the devices it depends on. Devices on other parents that depend on a started device are started
+//! along with the remaining devices of their parent, as their parent may have been registered
+//! before the dependency could be started.
+
+use crate::bulk::{Bulk, OnFailure, Outcome};
+use crate::callouts::{callout, Action};
+use crate::environment::{Environment, WorkerEnvironment};
+use crate::error::ErrorCategory;
+use crate::mdev::{MDev, StartFailure};
+use anyhow::{anyhow, ensure, Result};
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The result of starting a single device automatically
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+pub enum DeviceResult {
+    Started,
+    Failed {
+        failure: StartFailure,
+        /// the error with all its causes
+        error: String,
+        category: ErrorCategory,
+    },
+    /// the device was not started because an earlier device failed with `--fail-fast`
+    Cancelled,
+}
+
+impl fmt::Display for DeviceResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceResult::Started => write!(f, "started"),
+            DeviceResult::Failed { error, .. } => write!(f, "failed: {}", error),
+            DeviceResult::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceAutostart {
+    pub uuid: String,
+    #[serde(flatten)]
+    pub result: DeviceResult,
+}
+
+/// The results of starting the devices of a parent automatically, in the order they were
+/// started. Devices that are not set to start automatically or that are already active are left
+/// out.
+#[derive(Debug, Serialize)]
+pub struct ParentAutostart {
+    pub parent: String,
+    pub devices: Vec<DeviceAutostart>,
+    /// the results of the other parents whose devices depend on a device that was started
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dependents: Vec<ParentAutostart>,
+}
+
+impl ParentAutostart {
+    fn count(&self, pred: impl Fn(&DeviceResult) -> bool) -> usize {
+        self.devices.iter().filter(|d| pred(&d.result)).count()
+    }
+
+    pub fn started(&self) -> usize {
+        self.count(|r| *r == DeviceResult::Started)
+    }
+
+    pub fn cancelled(&self) -> usize {
+        self.count(|r| *r == DeviceResult::Cancelled)
+    }
+
+    /// The number of failed devices by the reason of the failure
+    pub fn failures(&self) -> BTreeMap<String, usize> {
+        let mut failures = BTreeMap::new();
+        for d in self.devices.iter() {
+            if let DeviceResult::Failed { failure, .. } = d.result {
+                *failures.entry(failure.to_string()).or_default() += 1;
+            }
+        }
+        failures
+    }
+
+    /// The devices of the parent followed by those of the dependent parents
+    fn all_devices(&self) -> Vec<(&str, &DeviceAutostart)> {
+        self.devices
+            .iter()
+            .map(|d| (self.parent.as_str(), d))
+            .chain(self.dependents.iter().flat_map(|d| d.all_devices()))
+            .collect()
+    }
+
+    /// The results of the devices of the parent and of the dependent parents, one line per
+    /// device
+    pub fn to_text(&self) -> String {
+        self.all_devices()
+            .iter()
+            .map(|(parent, d)| format!("{} {}: {}\n", d.uuid, parent, d.result))
+            .collect()
+    }
+
+    /// A summary for the log, e.g. `Started 1 of 2 devices on parent 0000:00:03.0, 1 timeout`,
+    /// if the parent has any devices that are started automatically, followed by the summaries
+    /// of the dependent parents on lines of their own
+    pub fn summary(&self) -> Option<String> {
+        let lines: Vec<_> = self
+            .parent_summary()
+            .into_iter()
+            .chain(self.dependents.iter().filter_map(|d| d.summary()))
+            .collect();
+        match lines.is_empty() {
+            true => None,
+            false => Some(lines.join("\n")),
+        }
+    }
+
+    fn parent_summary(&self) -> Option<String> {
+        if self.devices.is_empty() {
+            return None;
+        }
+        let mut summary = format!(
+            "Started {} of {} devices on parent {}",
+            self.started(),
+            self.devices.len(),
+            self.parent
+        );
+        for (failure, count) in self.failures() {
+            let _ = write!(summary, ", {} {}", count, failure);
+        }
+        if self.cancelled() > 0 {
+            let _ = write!(summary, ", {} cancelled", self.cancelled());
+        }
+        Some(summary)
+    }
+}
+
+/// What starting the devices of a parent would do with a device, see [`preview_parent_mdevs`]
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum DevicePreview {
+    Start,
+    /// the device is not set to start automatically
+    NotAuto,
+    /// the device is already active with the defined parent and type
+    Active,
+    /// a call-out script rejected the pre event of the start
+    CalloutVeto {
+        /// the error with all its causes
+        error: String,
+    },
+}
+
+impl fmt::Display for DevicePreview {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DevicePreview::Start => write!(f, "start"),
+            DevicePreview::NotAuto => write!(f, "skip, not set to start automatically"),
+            DevicePreview::Active => write!(f, "skip, already active"),
+            DevicePreview::CalloutVeto { error } => write!(f, "skip, callout veto: {}", error),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceAutostartPreview {
+    pub uuid: String,
+    #[serde(flatten)]
+    pub preview: DevicePreview,
+}
+
+/// The devices of a parent that would be started automatically, in their start order, followed
+/// by the devices that would be skipped
+#[derive(Debug, Serialize)]
+pub struct ParentAutostartPreview {
+    pub parent: String,
+    pub devices: Vec<DeviceAutostartPreview>,
+}
+
+impl ParentAutostartPreview {
+    /// A line per device, e.g. `976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9 skip, already active`, and
+    /// the number of devices that would be started
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for d in self.devices.iter() {
+            let _ = writeln!(text, "{} {}", d.uuid, d.preview);
+        }
+        let start = self
+            .devices
+            .iter()
+            .filter(|d| d.preview == DevicePreview::Start)
+            .count();
+        let _ = writeln!(
+            text,
+            "Would start {} of {} devices on parent {}",
+            start,
+            self.devices.len(),
+            self.parent
+        );
+        text
+    }
+}
+
+/// Order devices for starting them: a device follows the devices it depends on, and otherwise
+/// devices with a higher priority come first. Devices of equal priority keep their order. The
+/// devices of a dependency cycle are left in their order at the end, and fail to start unless
+/// their dependencies are already active.
+pub fn start_order(devs: Vec<&mut MDev>) -> Vec<&mut MDev> {
+    let mut remaining: Vec<Option<&mut MDev>> = devs.into_iter().map(Some).collect();
+    let mut ordered = Vec::new();
+    while ordered.len() < remaining.len() {
+        let pending: BTreeSet<_> = remaining.iter().flatten().map(|d| d.uuid).collect();
+        let next = remaining
+            .iter()
+            .enumerate()
+            .filter_map(|(i, d)| d.as_ref().map(|d| (i, d)))
+            .filter(|(_, d)| d.depends_on.iter().all(|u| !pending.contains(u)))
+            // the first of the devices with the highest priority
+            .min_by_key(|(i, d)| (-i64::from(d.priority), *i))
+            .map(|(i, _)| i);
+        match next {
+            Some(i) => ordered.push(remaining[i].take().unwrap()),
+            None => {
+                warn!(
+                    "Devices {} depend on each other",
+                    pending
+                        .iter()
+                        .map(|u| u.hyphenated().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                ordered.extend(remaining.iter_mut().filter_map(|d| d.take()));
+            }
+        }
+    }
+    ordered
+}
+
+/// Start `child`, abandoning the attempt after `timeout` seconds unless it is 0
+fn start_device(child: &mut MDev, timeout: u64) -> Result<()> {
+    child.deadline = match timeout {
+        0 => None,
+        t => Some(Instant::now() + Duration::from_secs(t)),
+    };
+    debug!("Autostarting {:?}", child.uuid);
+    callout(child)?.invoke(Action::Start, false, |c| c.dev.start(false))
+}
+
+fn started(child: &MDev) -> DeviceResult {
+    child.clear_autostart_failure();
+    DeviceResult::Started
+}
+
+fn failed(child: &MDev, e: &anyhow::Error) -> DeviceResult {
+    let failure = StartFailure::classify(e);
+    warn!(
+        "Failed to start device {} automatically ({})",
+        child.uuid.hyphenated(),
+        failure
+    );
+    for x in e.chain() {
+        warn!("{}", x);
+    }
+    if let Err(e) = child.record_autostart_failure(failure, e) {
+        warn!("{:#}", e);
+    }
+    DeviceResult::Failed {
+        failure,
+        error: format!("{:#}", e),
+        category: ErrorCategory::of(e),
+    }
+}
+
+/// The result of a device from the outcome of starting it
+fn device_result(child: &MDev, outcome: &Outcome) -> DeviceResult {
+    match outcome {
+        Outcome::Failed(e) => failed(child, e),
+        Outcome::Cancelled => DeviceResult::Cancelled,
+        Outcome::Done(_) | Outcome::Skipped(_) => started(child),
+    }
+}
+
+/// Start `children` in up to `jobs` threads at a time. Neither environments nor devices are
+/// shared between threads, so every thread creates an environment with `worker` and loads its
+/// device from the definition. A device is only started once the devices it depends on and all
+/// devices of a different priority before it in the start order completed, so that the order of
+/// [`start_order`] is kept where it matters.
+fn start_concurrently(
+    worker: WorkerEnvironment,
+    bulk: &mut Bulk,
+    children: &[&mut MDev],
+    timeout: u64,
+    jobs: usize,
+) -> Vec<DeviceResult> {
+    let items = children
+        .iter()
+        .map(|c| (c.uuid.hyphenated().to_string(), (c.uuid, c.parent.clone())))
+        .collect();
+    let waits = |i: usize, j: usize| {
+        children[i].priority != children[j].priority
+            || children[i].depends_on.contains(&children[j].uuid)
+    };
+    let outcomes = bulk.run_concurrently(items, jobs, waits, |(uuid, parent)| {
+        let mut child = worker().get_defined_device(uuid, parent.as_ref())?;
+        let begin = Instant::now();
+        let res = start_device(&mut child, timeout);
+        debug!(
+            "Autostart of {} took {:.3}s",
+            uuid,
+            begin.elapsed().as_secs_f64()
+        );
+        res.map(|_| "started")
+    });
+    children
+        .iter()
+        .zip(outcomes)
+        .map(|(child, (_, outcome))| device_result(child, outcome))
+        .collect()
+}
+
+/// Start the devices of `parent` that are set to start automatically, abandoning each attempt
+/// after `timeout` seconds unless it is 0. Devices that are already active with the defined
+/// parent and type are skipped, as udev may trigger this more than once for a parent. With
+/// `jobs` greater than 1, as many devices are started concurrently. Failing to start a device is
+/// part of the result, errors are only returned if the defined devices cannot be determined.
+pub fn start_parent_mdevs(
+    env: Rc<dyn Environment>,
+    parent: &str,
+    timeout: u64,
+    on_failure: OnFailure,
+    jobs: usize,
+) -> Result<ParentAutostart> {
+    let parent = parent.to_string();
+    let mut devs = env.clone().get_defined_devices(None, Some(&parent))?;
+    let mut autostart = ParentAutostart {
+        parent,
+        devices: Vec::new(),
+        dependents: Vec::new(),
+    };
+    if devs.is_empty() {
+        // nothing to do
+        return Ok(autostart);
+    }
+
+    ensure!(devs.len() == 1, "More than one parent found");
+
+    let autostart_begin = Instant::now();
+    let children = devs
+        .values_mut()
+        .flatten()
+        .filter(|c| c.autostart)
+        // only matches an active device with the same parent and type, a different device with
+        // the same UUID still fails to start
+        .filter_map(|c| {
+            if c.load_from_sysfs().is_ok() && c.active {
+                debug!("Device {:?} is already active, skipping", c.uuid);
+                return None;
+            }
+            Some(c)
+        })
+        .collect();
+    let children = start_order(children);
+    let uuids: Vec<_> = children.iter().map(|c| c.uuid).collect();
+    let mut bulk = Bulk::new(on_failure);
+    let results = match env.worker_environment().filter(|_| jobs > 1) {
+        Some(worker) => start_concurrently(worker, &mut bulk, &children, timeout, jobs),
+        None => {
+            let mut results = Vec::new();
+            for child in children {
+                let device_begin = Instant::now();
+                let outcome = bulk.run(child.uuid.hyphenated().to_string(), || {
+                    start_device(child, timeout)?;
+                    Ok("started")
+                });
+                results.push(device_result(child, outcome));
+                debug!(
+                    "Autostart of {} took {:.3}s",
+                    child.uuid,
+                    device_begin.elapsed().as_secs_f64()
+                );
+            }
+            results
+        }
+    };
+    let started: Vec<_> = uuids
+        .iter()
+        .zip(results.iter())
+        .filter(|(_, r)| **r == DeviceResult::Started)
+        .map(|(u, _)| *u)
+        .collect();
+    autostart.devices = uuids
+        .iter()
+        .zip(results)
+        .map(|(u, result)| DeviceAutostart {
+            uuid: u.hyphenated().to_string(),
+            result,
+        })
+        .collect();
+
+    debug!(
+        "Autostart of {} devices on parent {} took {:.3}s",
+        autostart.devices.len(),
+        autostart.parent,
+        autostart_begin.elapsed().as_secs_f64()
+    );
+    if !started.is_empty() {
+        autostart.dependents =
+            start_dependents(env, &autostart.parent, &started, timeout, on_failure, jobs)?;
+    }
+    Ok(autostart)
+}
+
+/// Start the devices of the other parents that are present and have devices waiting for one of
+/// the `started` devices of `parent`
+fn start_dependents(
+    env: Rc<dyn Environment>,
+    parent: &str,
+    started: &[Uuid],
+    timeout: u64,
+    on_failure: OnFailure,
+    jobs: usize,
+) -> Result<Vec<ParentAutostart>> {
+    let devs = env.clone().get_defined_devices(None, None)?;
+    let mut dependents = Vec::new();
+    for (other, children) in devs.iter() {
+        let waiting = children
+            .iter()
+            .any(|c| c.autostart && !c.active && c.depends_on.iter().any(|u| started.contains(u)));
+        if other == parent || !waiting || !env.parent_base().join(other).exists() {
+            continue;
+        }
+        debug!(
+            "Starting the devices of parent {} that depend on devices of {}",
+            other, parent
+        );
+        dependents.push(start_parent_mdevs(
+            env.clone(),
+            other,
+            timeout,
+            on_failure,
+            jobs,
+        )?);
+    }
+    Ok(dependents)
+}
+
+/// Start the devices of every present parent that has devices set to start automatically, as if
+/// each parent was registered, e.g. from a single service at boot where rules for every parent
+/// are impractical. Parents whose devices were already started as dependents of another parent
+/// are not started again.
+pub fn start_all_parents(
+    env: Rc<dyn Environment>,
+    timeout: u64,
+    on_failure: OnFailure,
+    jobs: usize,
+) -> Result<Vec<ParentAutostart>> {
+    let parents: Vec<_> = env
+        .clone()
+        .get_defined_devices(None, None)?
+        .into_iter()
+        .filter(|(_, devs)| devs.iter().any(|d| d.autostart))
+        .map(|(parent, _)| parent)
+        .collect();
+    let mut handled = BTreeSet::new();
+    let mut results = Vec::new();
+    for parent in parents {
+        if handled.contains(&parent) {
+            continue;
+        }
+        if !env.parent_base().join(&parent).exists() {
+            debug!("Parent {} is not present, skipping", parent);
+            continue;
+        }
+        let autostart = start_parent_mdevs(env.clone(), &parent, timeout, on_failure, jobs)?;
+        add_parents(&autostart, &mut handled);
+        results.push(autostart);
+    }
+    Ok(results)
+}
+
+/// Fail if any device of `autostarts` could not be started, with the first failure as the cause,
+/// e.g. `Failed to start 1 of 3 devices`
+pub fn finish(autostarts: &[ParentAutostart]) -> Result<()> {
+    let devices: Vec<_> = autostarts.iter().flat_map(|a| a.all_devices()).collect();
+    let mut errors = devices.iter().filter_map(|(_, d)| match &d.result {
+        DeviceResult::Failed { error, .. } => Some(error),
+        _ => None,
+    });
+    let first = match errors.next() {
+        Some(e) => e,
+        None => return Ok(()),
+    };
+    let cancelled = devices
+        .iter()
+        .filter(|(_, d)| d.result == DeviceResult::Cancelled)
+        .count();
+    let mut summary = format!(
+        "Failed to start {} of {} devices",
+        errors.count() + 1,
+        devices.len()
+    );
+    if cancelled > 0 {
+        let _ = write!(summary, ", {} cancelled", cancelled);
+    }
+    Err(anyhow!("{}", first).context(summary))
+}
+
+fn add_parents(autostart: &ParentAutostart, parents: &mut BTreeSet<String>) {
+    parents.insert(autostart.parent.clone());
+    for d in autostart.dependents.iter() {
+        add_parents(d, parents);
+    }
+}
+
+/// Determine which devices of `parent` [`start_parent_mdevs`] would start, without writing to
+/// sysfs. The call-out scripts are only invoked for the pre event of the start, see
+/// [`crate::callouts::Callout::invoke_dry_run`]. Devices on other parents that depend on the
+/// started devices are not included, as starting them depends on the outcome.
+pub fn preview_parent_mdevs(
+    env: Rc<dyn Environment>,
+    parent: &str,
+) -> Result<ParentAutostartPreview> {
+    let parent = parent.to_string();
+    let mut devs = env.get_defined_devices(None, Some(&parent))?;
+    let mut preview = ParentAutostartPreview {
+        parent,
+        devices: Vec::new(),
+    };
+    let mut skipped = Vec::new();
+    let mut children = Vec::new();
+    for child in devs.values_mut().flatten() {
+        let uuid = child.uuid.hyphenated().to_string();
+        if !child.autostart {
+            skipped.push(DeviceAutostartPreview {
+                uuid,
+                preview: DevicePreview::NotAuto,
+            });
+        } else if child.load_from_sysfs().is_ok() && child.active {
+            skipped.push(DeviceAutostartPreview {
+                uuid,
+                preview: DevicePreview::Active,
+            });
+        } else {
+            children.push(child);
+        }
+    }
+    for child in start_order(children) {
+        let uuid = child.uuid.hyphenated().to_string();
+        let res =
+            callout(child).and_then(|mut c| c.invoke_dry_run(Action::Start, false, |_| Ok(())));
+        preview.devices.push(DeviceAutostartPreview {
+            uuid,
+            preview: match res {
+                Ok(_) => DevicePreview::Start,
+                Err(e) => DevicePreview::CalloutVeto {
+                    error: format!("{:#}", e),
+                },
+            },
+        });
+    }
+    preview.devices.extend(skipped);
+    Ok(preview)
+}
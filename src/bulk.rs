@@ -0,0 +1,212 @@
+//! Acting on a set of devices in the bulk commands
+//!
+//! The bulk commands, `start --all-defined`, `start-parent-mdevs` and `import`, act on their
+//! devices one after another in a fixed order. Once acting on a device fails, the remaining
+//! devices are either still acted on, with `--keep-going`, or cancelled, with `--fail-fast`. The
+//! results are collected in the order of the devices, including the cancelled ones, so that the
+//! outcome of a command only depends on which devices failed, not on how far it got.
+//!
+//! With `--jobs`, `start-parent-mdevs` acts on several devices at a time in threads of their own
+//! with [`Bulk::run_concurrently`], with the same policy and results as one after another.
+
+use anyhow::{anyhow, Error, Result};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread;
+
+/// What a bulk command does with the remaining devices once acting on one of them failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnFailure {
+    /// cancel the remaining devices
+    Cancel,
+    /// act on the remaining devices
+    Continue,
+}
+
+impl OnFailure {
+    /// The policy selected by the `--fail-fast` and `--keep-going` options, which conflict, or
+    /// the default of the command if neither is given
+    pub fn from_options(fail_fast: bool, keep_going: bool, default: OnFailure) -> OnFailure {
+        match (fail_fast, keep_going) {
+            (true, _) => OnFailure::Cancel,
+            (_, true) => OnFailure::Continue,
+            _ => default,
+        }
+    }
+}
+
+/// The result of acting on a single device
+#[derive(Debug)]
+pub enum Outcome {
+    /// the device was acted on, e.g. `started`
+    Done(&'static str),
+    /// nothing needed to be done for the device, e.g. `already active`
+    Skipped(&'static str),
+    Failed(Error),
+    /// the device was not acted on because an earlier device failed
+    Cancelled,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Done(s) | Outcome::Skipped(s) => write!(f, "{}", s),
+            Outcome::Failed(e) => write!(f, "failed: {:#}", e),
+            Outcome::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// The devices a bulk command acts on, and their results
+#[derive(Debug)]
+pub struct Bulk {
+    on_failure: OnFailure,
+    cancelled: bool,
+    results: Vec<(String, Outcome)>,
+}
+
+impl Bulk {
+    pub fn new(on_failure: OnFailure) -> Bulk {
+        Bulk {
+            on_failure,
+            cancelled: false,
+            results: Vec::new(),
+        }
+    }
+
+    /// Act on the device `name` with `f`, unless the remaining devices were cancelled because an
+    /// earlier device failed
+    pub fn run<F>(&mut self, name: String, f: F) -> &Outcome
+    where
+        F: FnOnce() -> Result<&'static str>,
+    {
+        let outcome = match self.cancelled {
+            true => Outcome::Cancelled,
+            false => self.outcome(f()),
+        };
+        self.results.push((name, outcome));
+        &self.results.last().unwrap().1
+    }
+
+    /// The outcome of acting on a device, cancelling the remaining devices if it failed and the
+    /// policy says so
+    fn outcome(&mut self, res: Result<&'static str>) -> Outcome {
+        match res {
+            Ok(s) => Outcome::Done(s),
+            Err(e) => {
+                self.cancelled = self.on_failure == OnFailure::Cancel;
+                Outcome::Failed(e)
+            }
+        }
+    }
+
+    /// Act on the devices `items` with `f` like [`Bulk::run`], but in up to `jobs` threads at a
+    /// time. The device at index `i` is only acted on once all devices `j` before it for which
+    /// `waits(i, j)` is true are done, and no further devices are acted on once the remaining
+    /// devices were cancelled. The outcomes are recorded in the order of `items` and returned.
+    pub fn run_concurrently<T, F, W>(
+        &mut self,
+        items: Vec<(String, T)>,
+        jobs: usize,
+        waits: W,
+        f: F,
+    ) -> &[(String, Outcome)]
+    where
+        T: Send,
+        F: Fn(T) -> Result<&'static str> + Sync,
+        W: Fn(usize, usize) -> bool,
+    {
+        let (names, items): (Vec<_>, Vec<_>) = items.into_iter().unzip();
+        let mut pending: Vec<_> = items.into_iter().map(Some).collect();
+        let mut outcomes: Vec<Option<Outcome>> = names.iter().map(|_| None).collect();
+        let f = &f;
+        thread::scope(|scope| {
+            let (tx, rx) = mpsc::channel();
+            let mut running = 0;
+            let mut next = 0;
+            loop {
+                while !self.cancelled
+                    && next < pending.len()
+                    && running < jobs.max(1)
+                    && !(0..next).any(|j| outcomes[j].is_none() && waits(next, j))
+                {
+                    let item = pending[next].take().unwrap();
+                    let (tx, i) = (tx.clone(), next);
+                    scope.spawn(move || {
+                        // a panic is a failure of the device, rather than leaving it running
+                        let res = panic::catch_unwind(AssertUnwindSafe(|| f(item)))
+                            .unwrap_or_else(|_| Err(anyhow!("Worker thread panicked")));
+                        let _ = tx.send((i, res));
+                    });
+                    running += 1;
+                    next += 1;
+                }
+                if running == 0 {
+                    break;
+                }
+                // every running thread sends its result, so this does not fail
+                let (i, res) = rx.recv().unwrap();
+                running -= 1;
+                outcomes[i] = Some(self.outcome(res));
+            }
+        });
+        let first = self.results.len();
+        self.results.extend(
+            names
+                .into_iter()
+                .zip(outcomes)
+                .map(|(name, o)| (name, o.unwrap_or(Outcome::Cancelled))),
+        );
+        &self.results[first..]
+    }
+
+    /// Record that nothing needed to be done for the device `name`
+    pub fn skip(&mut self, name: String, reason: &'static str) {
+        self.results.push((name, Outcome::Skipped(reason)));
+    }
+
+    fn count(&self, pred: impl Fn(&Outcome) -> bool) -> usize {
+        self.results.iter().filter(|(_, o)| pred(o)).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Failed(_)))
+    }
+
+    pub fn cancelled(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Cancelled))
+    }
+
+    /// The number of devices that were to be acted on, i.e. that were not skipped
+    pub fn total(&self) -> usize {
+        self.count(|o| !matches!(o, Outcome::Skipped(_)))
+    }
+
+    /// The results of all devices in order, one line per device
+    pub fn to_text(&self) -> String {
+        self.results
+            .iter()
+            .map(|(name, outcome)| format!("{}: {}\n", name, outcome))
+            .collect()
+    }
+
+    /// Fail if acting on any device failed, with the first failure as the cause, e.g.
+    /// `Failed to start 1 of 3 devices`
+    pub fn finish(self, verb: &str) -> Result<()> {
+        let (failed, cancelled, total) = (self.failed(), self.cancelled(), self.total());
+        let first = self.results.into_iter().find_map(|(_, o)| match o {
+            Outcome::Failed(e) => Some(e),
+            _ => None,
+        });
+        let first = match first {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+        let mut summary = format!("Failed to {} {} of {} devices", verb, failed, total);
+        if cancelled > 0 {
+            summary.push_str(&format!(", {} cancelled", cancelled));
+        }
+        Err(first.context(summary))
+    }
+}
@@ -0,0 +1,263 @@
+//! Support bundles collecting information for bug reports
+
+use crate::environment::Environment;
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::debug;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+/// number of journal lines included in a support bundle
+const JOURNAL_LINES: &str = "1000";
+
+/// files of a mediated device type that are included in the sysfs snapshot
+const TYPE_FILES: &[&str] = &["available_instances", "device_api", "name", "description"];
+
+/// Replaces host identifying information in the collected data
+struct Redactor {
+    secrets: Vec<(String, &'static str)>,
+}
+
+impl Redactor {
+    fn new(env: &dyn Environment) -> Redactor {
+        let mut secrets = Vec::new();
+        for (path, replacement) in [
+            ("proc/sys/kernel/hostname", "<hostname>"),
+            ("etc/machine-id", "<machine-id>"),
+        ] {
+            if let Ok(val) = fs::read_to_string(env.root().join(path)) {
+                let val = val.trim();
+                if !val.is_empty() {
+                    secrets.push((val.to_string(), replacement));
+                }
+            }
+        }
+        Redactor { secrets }
+    }
+
+    fn redact(&self, data: &[u8]) -> Vec<u8> {
+        let mut text = String::from_utf8_lossy(data).into_owned();
+        for (secret, replacement) in self.secrets.iter() {
+            text = replace_words(&text, secret, replacement);
+        }
+        text.into_bytes()
+    }
+}
+
+/// Replace the occurrences of `word` in `text` that are not part of a longer word, e.g. a short
+/// hostname like `db` in `mdb_dump`, where words consist of alphanumeric characters and `_`
+fn replace_words(text: &str, word: &str, replacement: &str) -> String {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find(word) {
+        let (before, after) = (&rest[..pos], &rest[pos + word.len()..]);
+        let bounded = !before.ends_with(is_word_char) && !after.starts_with(is_word_char);
+        result.push_str(before);
+        result.push_str(if bounded { replacement } else { word });
+        rest = after;
+    }
+    result.push_str(rest);
+    result
+}
+
+/// A gzip compressed tar archive of redacted text files
+pub struct SupportBundle<W: Write> {
+    archive: tar::Builder<GzEncoder<W>>,
+    redactor: Redactor,
+    mtime: u64,
+}
+
+impl<W: Write> SupportBundle<W> {
+    pub fn new(env: &dyn Environment, output: W) -> SupportBundle<W> {
+        SupportBundle {
+            archive: tar::Builder::new(GzEncoder::new(output, Compression::default())),
+            redactor: Redactor::new(env),
+            mtime: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// add a file with the given contents to the bundle
+    pub fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        debug!("Adding {} to support bundle", name);
+        let data = self.redactor.redact(data);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(self.mtime);
+        header.set_cksum();
+        self.archive
+            .append_data(&mut header, name, data.as_slice())
+            .with_context(|| format!("Failed to add {} to support bundle", name))
+    }
+
+    /// Add the output of a command to the bundle. Errors are recorded in the bundle instead.
+    pub fn add_result(&mut self, name: &str, res: Result<Vec<u8>>) -> Result<()> {
+        match res {
+            Ok(data) => self.add(name, &data),
+            Err(e) => self.add(name, format!("Error: {:#}\n", e).as_bytes()),
+        }
+    }
+
+    /// add all regular files below `dir`, keeping their relative paths below `prefix`
+    pub fn add_dir(&mut self, prefix: &str, dir: &Path) -> Result<()> {
+        let mut files = Vec::new();
+        collect_files(dir, &mut files);
+        files.sort();
+        for file in files {
+            let relpath = file.strip_prefix(dir).unwrap_or(&file);
+            let name = format!("{}/{}", prefix, relpath.display());
+            let res = fs::read(&file).with_context(|| format!("Unable to read {:?}", file));
+            self.add_result(&name, res)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.archive
+            .into_inner()
+            .and_then(|gz| gz.finish())
+            .map(|_| ())
+            .with_context(|| "Failed to write support bundle")
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    if let Ok(entries) = dir.read_dir() {
+        for path in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+            if path.is_dir() {
+                collect_files(&path, files);
+            } else if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+}
+
+/// List the installed callout and notification scripts with their size and permissions
+pub fn script_inventory(env: &dyn Environment) -> Vec<u8> {
+    let mut text = String::new();
+    for dir in env
+        .callout_dirs()
+        .into_iter()
+        .chain(env.notification_dirs())
+    {
+        let _ = writeln!(text, "{}", dir.display());
+        let mut entries = match dir.read_dir() {
+            Ok(rd) => rd.filter_map(|e| e.ok()).collect::<Vec<_>>(),
+            Err(e) => {
+                let _ = writeln!(text, "  ({})", e);
+                continue;
+            }
+        };
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            match entry.metadata() {
+                Ok(m) => {
+                    let _ = writeln!(
+                        text,
+                        "  {} {:o} {} bytes",
+                        entry.file_name().to_string_lossy(),
+                        m.permissions().mode() & 0o7777,
+                        m.len()
+                    );
+                }
+                Err(e) => {
+                    let _ = writeln!(text, "  {} ({})", entry.file_name().to_string_lossy(), e);
+                }
+            }
+        }
+    }
+    text.into_bytes()
+}
+
+/// Describe the parent devices, their supported types and the active mediated devices in sysfs
+pub fn sysfs_snapshot(env: &dyn Environment) -> Vec<u8> {
+    let mut text = String::new();
+    let sorted_dir = |dir: &Path| -> Vec<PathBuf> {
+        let mut paths = dir
+            .read_dir()
+            .map(|rd| rd.filter_map(|e| e.ok().map(|e| e.path())).collect())
+            .unwrap_or_else(|_| Vec::new());
+        paths.sort();
+        paths
+    };
+    let link_target = |path: PathBuf| -> String {
+        fs::canonicalize(path)
+            .map(|p| {
+                p.strip_prefix(env.root())
+                    .unwrap_or(&p)
+                    .display()
+                    .to_string()
+            })
+            .unwrap_or_else(|e| format!("({})", e))
+    };
+
+    let _ = writeln!(text, "{}", env.parent_base().display());
+    for parent in sorted_dir(&env.parent_base()) {
+        let _ = writeln!(
+            text,
+            "  {} -> /{}",
+            parent.file_name().unwrap_or_default().to_string_lossy(),
+            link_target(parent.clone())
+        );
+        if let Ok(driver) = fs::canonicalize(parent.join("driver")) {
+            let _ = writeln!(
+                text,
+                "    driver: {}",
+                driver.file_name().unwrap_or_default().to_string_lossy()
+            );
+        }
+        for typedir in sorted_dir(&parent.join("mdev_supported_types")) {
+            let _ = writeln!(
+                text,
+                "    {}",
+                typedir.file_name().unwrap_or_default().to_string_lossy()
+            );
+            for file in TYPE_FILES {
+                if let Ok(val) = fs::read_to_string(typedir.join(file)) {
+                    let _ = writeln!(text, "      {}: {}", file, val.trim());
+                }
+            }
+        }
+    }
+
+    let _ = writeln!(text, "{}", env.mdev_base().display());
+    for dev in sorted_dir(&env.mdev_base()) {
+        let _ = writeln!(
+            text,
+            "  {} -> /{}",
+            dev.file_name().unwrap_or_default().to_string_lossy(),
+            link_target(dev.clone())
+        );
+        let _ = writeln!(
+            text,
+            "    mdev_type: /{}",
+            link_target(dev.join("mdev_type"))
+        );
+    }
+    text.into_bytes()
+}
+
+/// Recent journal messages related to mediated devices
+pub fn journal(env: &dyn Environment) -> Result<Vec<u8>> {
+    if env.root() != Path::new("/") {
+        return Ok(b"The journal is not collected for alternate root directories\n".to_vec());
+    }
+    let output = Command::new("journalctl")
+        .args(["--no-pager", "--lines", JOURNAL_LINES, "--grep", "mdev"])
+        .output()
+        .with_context(|| "Failed to execute journalctl")?;
+    let mut data = output.stdout;
+    data.extend_from_slice(&output.stderr);
+    Ok(data)
+}
@@ -2,13 +2,31 @@ use anyhow::{anyhow, Context, Result};
 use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::ffi::OsStr;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CString, OsStr};
+#[cfg(feature = "callouts")]
+use std::fmt::Write as _;
 use std::fmt::{self, Display, Formatter};
+use std::fs;
 use std::io::{ErrorKind, Write};
+#[cfg(feature = "callouts")]
+use std::io::{Read, Seek, SeekFrom};
+#[cfg(feature = "callouts")]
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output, Stdio};
-
+use std::process::{Child, Command, Output, Stdio};
+use std::ptr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::audit;
+use crate::environment::Environment;
+use crate::logger::span;
 use crate::mdev::*;
+use crate::namespace;
+use crate::plugins;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -23,17 +41,101 @@ pub enum Event {
     Unknown, // used for forward compatibility to newer callout scripts
 }
 
-fn invocation_failure(path: &PathBuf, code: Option<i32>) -> anyhow::Error {
-    anyhow!(
-        "Script '{:?}' failed with status '{}'",
-        path,
-        match code {
-            Some(i) => i.to_string(),
-            None => "unknown".to_string(),
+fn invocation_failure(path: &Path, output: &Output, report: Option<ScriptReport>) -> anyhow::Error {
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    ScriptFailure {
+        path: path.to_path_buf(),
+        code: output.status.code(),
+        stderr: (!stderr.is_empty()).then_some(ScriptStderr(stderr)),
+        report,
+    }
+    .into()
+}
+
+/// Error for a callout script that rejected an event by exiting with a failure status
+#[derive(Debug)]
+pub struct ScriptFailure {
+    path: PathBuf,
+    code: Option<i32>,
+    /// what the script wrote to standard error, which is the cause of the failure
+    stderr: Option<ScriptStderr>,
+    /// the reason the script reported on standard output, which is the cause of the failure
+    /// instead of the standard error
+    report: Option<ScriptReport>,
+}
+
+impl Display for ScriptFailure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Script '{:?}' failed with status '{}'",
+            self.path,
+            match self.code {
+                Some(i) => i.to_string(),
+                None => "unknown".to_string(),
+            }
+        )
+    }
+}
+
+impl std::error::Error for ScriptFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match (&self.report, &self.stderr) {
+            (Some(report), _) => Some(report),
+            (None, Some(stderr)) => Some(stderr),
+            (None, None) => None,
+        }
+    }
+}
+
+/// The reason a script that negotiated version 5 or later may report on standard output when it
+/// rejects an event, e.g. `{"error": "parent is in maintenance", "hint": "try again later"}`.
+/// Other fields are ignored, so that they can be added later.
+#[derive(Debug, Deserialize)]
+pub struct ScriptReport {
+    error: String,
+    #[serde(default)]
+    hint: Option<String>,
+}
+
+impl ScriptReport {
+    /// The report in the standard output of a script, if it contains one
+    fn parse(stdout: &[u8]) -> Option<ScriptReport> {
+        let stdout = String::from_utf8_lossy(stdout);
+        let stdout = stdout.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+        if stdout.is_empty() {
+            return None;
+        }
+        serde_json::from_str(stdout)
+            .map_err(|e| debug!("Ignoring invalid error report of callout script: {}", e))
+            .ok()
+    }
+}
+
+impl Display for ScriptReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self.hint {
+            Some(hint) => write!(f, "{} (hint: {})", self.error, hint),
+            None => write!(f, "{}", self.error),
         }
-    )
+    }
+}
+
+impl std::error::Error for ScriptReport {}
+
+/// The standard error of a failed callout script, so that it is part of the error chain and ends
+/// up in the journal when devices fail to start at boot
+#[derive(Debug)]
+pub struct ScriptStderr(String);
+
+impl Display for ScriptStderr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
+impl std::error::Error for ScriptStderr {}
+
 impl Display for Event {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
@@ -47,7 +149,7 @@ impl Display for Event {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Action {
     Start,
@@ -81,7 +183,7 @@ impl Display for Action {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub struct CalloutVersion {
     version: Cow<'static, u32>,
     actions: Cow<'static, [Action]>,
@@ -137,6 +239,82 @@ impl CalloutVersion {
         ],
     );
 
+    /// version 2, with the device and the event also passed in environment variables
+    pub const V_3: CalloutVersion = CalloutVersion::new_const(
+        &3,
+        &[
+            Action::Start,
+            Action::Stop,
+            Action::Define,
+            Action::Undefine,
+            Action::Modify,
+            Action::Attributes,
+            Action::Capabilities,
+        ],
+        &[
+            Event::Pre,
+            Event::Post,
+            Event::Notify,
+            Event::Get,
+            Event::Live,
+        ],
+    );
+
+    /// version 3, with scripts allowed to describe themselves with a manifest in their
+    /// capabilities document
+    pub const V_4: CalloutVersion = CalloutVersion::new_const(
+        &4,
+        &[
+            Action::Start,
+            Action::Stop,
+            Action::Define,
+            Action::Undefine,
+            Action::Modify,
+            Action::Attributes,
+            Action::Capabilities,
+        ],
+        &[
+            Event::Pre,
+            Event::Post,
+            Event::Notify,
+            Event::Get,
+            Event::Live,
+        ],
+    );
+
+    /// version 4, with scripts allowed to report why they rejected an event on standard output
+    pub const V_5: CalloutVersion = CalloutVersion::new_const(
+        &5,
+        &[
+            Action::Start,
+            Action::Stop,
+            Action::Define,
+            Action::Undefine,
+            Action::Modify,
+            Action::Attributes,
+            Action::Capabilities,
+        ],
+        &[
+            Event::Pre,
+            Event::Post,
+            Event::Notify,
+            Event::Get,
+            Event::Live,
+        ],
+    );
+
+    pub fn version(&self) -> u32 {
+        *self.version
+    }
+
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
     pub fn has_action(&self, action: Action) -> bool {
         self.actions.contains(&action)
     }
@@ -151,9 +329,86 @@ pub struct CalloutVersionProvides {
     provides: Option<CalloutVersion>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The self-description a script may add to its capabilities document since version 4. It
+/// lets mdevctl skip the script for devices and events it does not handle without invoking it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CalloutManifest {
+    /// the mdev types the script handles, all types if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mdev_types: Option<Vec<String>>,
+    /// the parent devices the script handles, all parents if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parents: Option<Vec<String>>,
+    /// whether the script implements the live-modify event, derived from the supported actions
+    /// and events if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub live_modify: Option<bool>,
+    /// whether the script implements the get-attributes event, derived from the supported
+    /// actions and events if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub get_attributes: Option<bool>,
+}
+
+impl CalloutManifest {
+    /// whether the script handles devices of `mdev_type` on `parent`
+    pub fn handles(&self, parent: &str, mdev_type: &str) -> bool {
+        let listed = |list: &Option<Vec<String>>, item: &str| {
+            list.as_ref().is_none_or(|l| l.iter().any(|i| i == item))
+        };
+        listed(&self.mdev_types, mdev_type) && listed(&self.parents, parent)
+    }
+}
+
+/// The capabilities document returned by a callout script for the get-capabilities event. The
+/// format is described by the JSON schema in `schema/callout-capabilities.schema.json`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CalloutVersionSupports {
-    supports: Option<CalloutVersion>,
+    pub supports: CalloutVersion,
+    #[serde(default)]
+    pub manifest: Option<CalloutManifest>,
+    // scripts are allowed to echo back the document they received on stdin
+    #[serde(default)]
+    #[allow(dead_code)]
+    provides: Option<serde::de::IgnoredAny>,
+}
+
+/// A call-out or notification event that was recorded instead of invoking scripts for it
+#[derive(Debug, Serialize)]
+pub struct CalloutRecord {
+    pub event: Event,
+    pub action: Action,
+    pub state: String,
+    pub uuid: String,
+    pub parent: String,
+    pub mdev_type: String,
+    /// the document that scripts get on stdin, if any, i.e. the device configuration or the
+    /// notification
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<serde_json::Value>,
+    /// additional environment variables for the scripts, e.g. `MDEVCTL_DRY_RUN`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
+}
+
+/// Whether a script supports a device, according to its response to the get-capabilities event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptSupport {
+    /// the script exited with 2, i.e. the device is not of a type it handles
+    Unsupported,
+    /// the script handles the device, but does not support versioning for it
+    Unversioned,
+    Versioned(CalloutVersion),
+}
+
+/// A single invocation of a script, as shown by the `callout-test` command
+#[derive(Debug)]
+pub struct ScriptInvocation {
+    pub script: PathBuf,
+    pub args: Vec<String>,
+    pub stdin: Option<String>,
+    pub output: Output,
 }
 
 #[derive(Clone, Copy)]
@@ -173,12 +428,14 @@ impl Display for State {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CalloutScriptInfo {
     path: PathBuf,
     parent: String,
     mdev_type: String,
     supports: CalloutVersion,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    manifest: Option<CalloutManifest>,
 }
 
 impl CalloutScriptInfo {
@@ -187,15 +444,49 @@ impl CalloutScriptInfo {
         parent: String,
         mdev_type: String,
         supports: CalloutVersion,
+        manifest: Option<CalloutManifest>,
     ) -> CalloutScriptInfo {
         CalloutScriptInfo {
             path,
             parent,
             mdev_type,
             supports,
+            manifest,
         }
     }
 
+    /// The error for the failure of the script with `output`. Since version 5, the script may
+    /// report the reason on standard output.
+    fn failure(&self, output: &Output) -> anyhow::Error {
+        let report = match self.supports.version() >= CalloutVersion::V_5.version() {
+            true => ScriptReport::parse(&output.stdout),
+            false => None,
+        };
+        invocation_failure(&self.path, output, report)
+    }
+
+    /// whether the script implements the live-modify event, as declared in its manifest
+    #[cfg(feature = "callouts")]
+    fn implements_live_modify(&self) -> bool {
+        self.manifest
+            .as_ref()
+            .and_then(|m| m.live_modify)
+            .unwrap_or_else(|| {
+                self.supports.has_event(Event::Live) && self.supports.has_action(Action::Modify)
+            })
+    }
+
+    /// whether the script implements the get-attributes event, as declared in its manifest
+    fn implements_get_attributes(&self) -> bool {
+        self.manifest
+            .as_ref()
+            .and_then(|m| m.get_attributes)
+            .unwrap_or_else(|| {
+                self.supports.has_event(Event::Get) && self.supports.has_action(Action::Attributes)
+            })
+    }
+
+    #[cfg(feature = "callouts")]
     fn supports_event_action(&self, event: Event, action: Action) -> Result<()> {
         if !self.supports.has_action(action) {
             debug!(
@@ -225,6 +516,62 @@ impl CalloutScriptInfo {
     }
 }
 
+/// The callout script negotiated when a device is started is recorded in the runtime state and
+/// used for all further events of the device until it is stopped. This ensures a consistent
+/// protocol version even if scripts are upgraded while the device is running.
+impl CalloutScriptInfo {
+    fn state_path(dev: &MDev) -> PathBuf {
+        dev.env
+            .callout_state_base()
+            .join(dev.uuid.hyphenated().to_string())
+    }
+
+    /// load the script recorded for an active device, if it still applies to the device
+    fn load_recorded(dev: &MDev) -> Option<CalloutScriptInfo> {
+        let path = CalloutScriptInfo::state_path(dev);
+        let contents = fs::read_to_string(&path).ok()?;
+        let script: CalloutScriptInfo = match serde_json::from_str(&contents) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Ignoring invalid callout state {:?}: {}", path, e);
+                return None;
+            }
+        };
+        if Some(&script.parent) != dev.parent.as_ref()
+            || Some(&script.mdev_type) != dev.mdev_type.as_ref()
+        {
+            debug!(
+                "Recorded callout state {:?} belongs to a different device",
+                path
+            );
+            return None;
+        }
+        if !script.path.exists() {
+            debug!("Recorded callout script {:?} no longer exists", script.path);
+            return None;
+        }
+        debug!("Using recorded callout script {:?}", script.path);
+        Some(script)
+    }
+
+    fn record(&self, dev: &MDev) -> Result<()> {
+        let path = CalloutScriptInfo::state_path(dev);
+        debug!("Recording callout script {:?} in {:?}", self.path, path);
+        fs::create_dir_all(dev.env.callout_state_base())?;
+        fs::write(&path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write callout state {:?}", path))
+    }
+
+    fn forget(dev: &MDev) {
+        let path = CalloutScriptInfo::state_path(dev);
+        match fs::remove_file(&path) {
+            Ok(_) => debug!("Removed callout state {:?}", path),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => warn!("Failed to remove callout state {:?}: {}", path, e),
+        }
+    }
+}
+
 impl AsRef<Path> for CalloutScriptInfo {
     fn as_ref(&self) -> &Path {
         &self.path
@@ -233,33 +580,225 @@ impl AsRef<Path> for CalloutScriptInfo {
 
 #[derive(Debug)]
 pub struct CalloutScriptCache {
+    #[cfg(feature = "callouts")]
+    callouts: Vec<CalloutScriptInfo>,
+    fail_on_ambiguous: bool,
+    /// the file that the scripts found are persisted in across invocations, if any
+    persist_path: Option<PathBuf>,
+    /// the scripts read from or to be written to `persist_path`, `None` until it was read
+    persisted: Option<PersistedScripts>,
+}
+
+/// The scripts found for devices by earlier invocations of mdevctl. They are only used as long as
+/// no script was added, removed or modified since, and with the same version of mdevctl, as the
+/// capabilities scripts return depend on the version mdevctl provides.
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedScripts {
+    mdevctl: String,
+    scripts: Vec<ScriptFile>,
     callouts: Vec<CalloutScriptInfo>,
 }
 
+/// all installed callout scripts, in the order in which they are searched
+#[cfg(feature = "callouts")]
+fn script_files(env: &dyn Environment) -> Vec<ScriptFile> {
+    installed_scripts(env)
+        .iter()
+        .filter_map(|p| ScriptFile::new(p))
+        .collect()
+}
+
+/// the files in a directory in sort order, empty if it cannot be read
+fn sorted_files(dir: &Path) -> Vec<PathBuf> {
+    let mut paths = match dir.read_dir() {
+        Ok(rd) => rd
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.is_file())
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+/// The scripts installed for devices of `mdev_type` in the callout directory `dir`: the scripts
+/// in the subdirectory named by the type, followed by the script named by the type with a `.sh`
+/// suffix
+#[cfg(feature = "callouts")]
+fn routed_scripts(dir: &Path, mdev_type: &str) -> Vec<PathBuf> {
+    let mut scripts = sorted_files(&dir.join(mdev_type));
+    let script = dir.join(format!("{}.sh", mdev_type));
+    if script.is_file() {
+        scripts.push(script);
+    }
+    scripts
+}
+
+/// All installed callout scripts, including the scripts installed for specific types in
+/// subdirectories, in the order in which they are searched
+pub fn installed_scripts(env: &dyn Environment) -> Vec<PathBuf> {
+    let mut scripts = Vec::new();
+    for dir in env.callout_dirs() {
+        let mut paths = match dir.read_dir() {
+            Ok(rd) => rd
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .collect::<Vec<_>>(),
+            Err(_) => continue,
+        };
+        paths.sort();
+        for path in paths {
+            match path.is_dir() {
+                true => scripts.extend(sorted_files(&path)),
+                false => scripts.push(path),
+            }
+        }
+    }
+    scripts
+}
+
+impl Default for CalloutScriptCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CalloutScriptCache {
     pub const fn new() -> Self {
         CalloutScriptCache {
+            #[cfg(feature = "callouts")]
             callouts: Vec::new(),
+            fail_on_ambiguous: false,
+            persist_path: None,
+            persisted: None,
         }
     }
 
-    fn parse_script_capabilities(output: &Output) -> Option<CalloutVersion> {
-        let stdout = String::from_utf8(output.clone().stdout).unwrap();
-        match serde_json::from_str::<CalloutVersionSupports>(stdout.trim_end_matches('\0')) {
-            Ok(ce) => ce.supports.or_else(|| {
-                debug!(" Callout script does not provide version support");
-                None
-            }),
-            Err(e) => {
+    /// Fail the lookup of a script instead of only warning if more than one script supports a
+    /// device
+    pub fn set_fail_on_ambiguous(&mut self, fail: bool) {
+        self.fail_on_ambiguous = fail;
+    }
+
+    /// Persist the scripts found for devices in `path`, so that later invocations of mdevctl do
+    /// not have to query the scripts for their capabilities again
+    pub fn set_persist_path(&mut self, path: Option<PathBuf>) {
+        self.persist_path = path;
+        self.persisted = None;
+    }
+
+    /// Read the persisted scripts on the first lookup, unless the scripts changed since they
+    /// were written
+    #[cfg(feature = "callouts")]
+    fn load_persisted(&mut self, env: &dyn Environment) {
+        let path = match (&self.persist_path, &self.persisted) {
+            (Some(p), None) => p,
+            _ => return,
+        };
+        let current = PersistedScripts {
+            mdevctl: env!("CARGO_PKG_VERSION").to_string(),
+            scripts: script_files(env),
+            callouts: Vec::new(),
+        };
+        let persisted = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str::<PersistedScripts>(&contents)
+                .map_err(|e| debug!("Ignoring invalid callout cache {:?}: {}", path, e))
+                .ok(),
+            Err(_) => None,
+        };
+        self.persisted = match persisted {
+            Some(p) if p.mdevctl == current.mdevctl && p.scripts == current.scripts => {
                 debug!(
-                    " Callout script has no version support (unparsable stdout): {:?}",
-                    e
+                    "Using {} callout script lookups cached in {:?}",
+                    p.callouts.len(),
+                    path
                 );
-                None
+                for script in &p.callouts {
+                    if let Some(manifest) = script.manifest.as_ref() {
+                        remember_manifest(&script.path, manifest);
+                    }
+                }
+                self.callouts.extend(p.callouts.iter().cloned());
+                Some(p)
+            }
+            Some(_) => {
+                debug!("Discarding callout cache {:?}, the scripts changed", path);
+                Some(current)
+            }
+            None => Some(current),
+        };
+    }
+
+    /// Add the script found for a device to the persisted scripts
+    #[cfg(feature = "callouts")]
+    fn persist(&mut self, script: &CalloutScriptInfo) {
+        let (path, persisted) = match (&self.persist_path, &mut self.persisted) {
+            (Some(path), Some(persisted)) => (path, persisted),
+            _ => return,
+        };
+        persisted.callouts.push(script.clone());
+        // written to a new temporary file with a random name first, as several instances or
+        // threads may write the cache concurrently at boot
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let res = fs::create_dir_all(dir)
+            .and_then(|_| tempfile::NamedTempFile::new_in(dir))
+            .and_then(|mut tmp| {
+                tmp.write_all(serde_json::to_string(persisted)?.as_bytes())?;
+                tmp.persist(path).map_err(|e| e.error)
+            });
+        if let Err(e) = res {
+            debug!("Unable to write callout cache {:?}: {}", path, e);
+        }
+    }
+
+    /// Parse the capabilities document that a callout script printed on stdout.
+    ///
+    /// Empty output means that the script has no versioning support and results in `Ok(None)`.
+    /// Any other output must conform to the capabilities schema, otherwise an error describing
+    /// the first problem found is returned.
+    pub fn parse_script_capabilities(stdout: &[u8]) -> Result<Option<CalloutVersionSupports>> {
+        let stdout = std::str::from_utf8(stdout)
+            .with_context(|| "Capabilities document is not valid UTF-8")?
+            .trim_end_matches('\0')
+            .trim();
+        if stdout.is_empty() {
+            return Ok(None);
+        }
+
+        let caps = serde_json::from_str::<CalloutVersionSupports>(stdout)
+            .with_context(|| "Capabilities document does not match the schema")?;
+        let supports = &caps.supports;
+        if *supports.version == 0 {
+            return Err(anyhow!(
+                "Capabilities document is invalid: 'version' must be 1 or greater"
+            ));
+        }
+        if supports.actions.is_empty() {
+            return Err(anyhow!(
+                "Capabilities document is invalid: 'actions' must not be empty"
+            ));
+        }
+        if supports.events.is_empty() {
+            return Err(anyhow!(
+                "Capabilities document is invalid: 'events' must not be empty"
+            ));
+        }
+        if let Some(manifest) = caps.manifest.as_ref() {
+            for (name, list) in [
+                ("mdev_types", &manifest.mdev_types),
+                ("parents", &manifest.parents),
+            ] {
+                if list.as_ref().is_some_and(|l| l.is_empty()) {
+                    return Err(anyhow!(
+                        "Capabilities document is invalid: '{}' must not be empty",
+                        name
+                    ));
+                }
             }
         }
+        Ok(Some(caps))
     }
 
+    #[cfg(feature = "callouts")]
     fn lookup_cached_script(&self, parent: &str, mdev_type: &str) -> Option<CalloutScriptInfo> {
         self.callouts
             .iter()
@@ -267,7 +806,13 @@ impl CalloutScriptCache {
             .cloned()
     }
 
-    pub fn find_versioned_script(&mut self, dev: &MDev) -> Option<CalloutScriptInfo> {
+    #[cfg(not(feature = "callouts"))]
+    pub fn find_versioned_script(&mut self, _dev: &MDev) -> Result<Option<CalloutScriptInfo>> {
+        Ok(None)
+    }
+
+    #[cfg(feature = "callouts")]
+    pub fn find_versioned_script(&mut self, dev: &MDev) -> Result<Option<CalloutScriptInfo>> {
         // check already found scripts
         let mut dev = dev.clone();
         let mut callout = match callout(&mut dev) {
@@ -277,23 +822,24 @@ impl CalloutScriptCache {
                     "cannot find a callout script for mdev with uuid {:?}: {:?}",
                     dev.uuid, e
                 );
-                return None;
+                return Ok(None);
             }
         };
         let mdev_type = match callout.dev.mdev_type() {
             Ok(t) => t.clone(),
             Err(_) => {
                 debug!("mdev_type is required on device => cannot find a callout script");
-                return None;
+                return Ok(None);
             }
         };
         let parent = match callout.dev.parent() {
             Ok(p) => p.clone(),
             Err(_) => {
                 debug!("parent is required on device => cannot find a callout script");
-                return None;
+                return Ok(None);
             }
         };
+        self.load_persisted(callout.dev.env.as_ref());
         debug!("Looking up callout script for mdev type '{:?}'", mdev_type);
         match self.lookup_cached_script(&parent, &mdev_type) {
             Some(cs) => {
@@ -303,10 +849,10 @@ impl CalloutScriptCache {
                 );
                 if cs.supports == CalloutVersion::NOT_FOUND && cs.path.as_os_str().is_empty() {
                     debug!("Callout script search returned empty before: no script with versioning available");
-                    return None;
+                    return Ok(None);
                 } else {
                     debug!("Callout script looked up: {:?}", cs.path);
-                    return Some(cs);
+                    return Ok(Some(cs));
                 }
             }
             None => {
@@ -318,7 +864,7 @@ impl CalloutScriptCache {
         }
 
         let ce_ver = CalloutVersionProvides {
-            provides: Some(CalloutVersion::V_2),
+            provides: Some(CalloutVersion::V_5),
         };
         let json_ce_ver =
             serde_json::to_string(&ce_ver).expect("CalloutVersion JSON could not be generated");
@@ -331,25 +877,197 @@ impl CalloutScriptCache {
         ) {
             Ok(op) => match op {
                 Some(_) => {
-                    self.callouts.push(callout.script.clone().unwrap());
-                    callout.script
+                    let script = callout.script.clone().unwrap();
+                    let others = callout.other_versioned_scripts(&script.path, &json_ce_ver);
+                    if !others.is_empty() {
+                        let mut claims =
+                            format!("{:?} (version {})", script.path, *script.supports.version);
+                        for (path, version) in others {
+                            let _ = write!(claims, ", {:?} (version {})", path, *version.version);
+                        }
+                        let msg = format!(
+                            "Multiple callout scripts support mdev type {} on parent {}: {}. \
+                             {:?} is used because it is the first in the search order",
+                            mdev_type, parent, claims, script.path
+                        );
+                        if self.fail_on_ambiguous {
+                            return Err(anyhow!(msg));
+                        }
+                        warn!("{}", msg);
+                    } else {
+                        // ambiguous scripts are queried again, to warn or fail every time
+                        self.persist(&script);
+                    }
+                    self.callouts.push(script.clone());
+                    Ok(Some(script))
                 }
                 None => {
                     // When lookup and search turned out empty create a did-not-find entry.
-                    self.callouts.push(CalloutScriptInfo::new(
+                    let not_found = CalloutScriptInfo::new(
                         PathBuf::new(),
                         parent,
                         mdev_type,
                         CalloutVersion::NOT_FOUND,
-                    ));
-                    None
+                        None,
+                    );
+                    self.persist(&not_found);
+                    self.callouts.push(not_found);
+                    Ok(None)
                 }
             },
-            Err(_) => None,
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// What the output of a callout script invocation without side effects depends on: the script,
+/// which is considered a different script if it is modified, and the type and parent of the
+/// device. The UUID of the device is not part of it, so that the output is shared by all devices
+/// of a type on a parent.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct InvocationKey {
+    script: ScriptFile,
+    mdev_type: String,
+    parent: String,
+}
+
+/// Outputs of callout script invocations that do not have side effects, by what they depend on.
+/// Bulk operations query the same scripts for their capabilities for many devices, so these
+/// results are reused for the lifetime of the process instead of executing the script again.
+static MEMOIZED_OUTPUTS: Mutex<Option<HashMap<InvocationKey, Output>>> = Mutex::new(None);
+
+/// The manifests returned by scripts, by the script file they were returned by. Unlike the
+/// outputs above, a manifest applies to all devices, so that scripts are not invoked at all for
+/// devices their manifest excludes.
+static MANIFESTS: Mutex<Option<HashMap<ScriptFile, CalloutManifest>>> = Mutex::new(None);
+
+/// A script file, which is considered a different script if it is modified
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct ScriptFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl ScriptFile {
+    fn new(path: &Path) -> Option<ScriptFile> {
+        fs::metadata(path).ok().map(|m| ScriptFile {
+            path: path.to_path_buf(),
+            modified: m.modified().ok(),
+            len: m.len(),
+        })
+    }
+}
+
+fn remember_manifest(script: &Path, manifest: &CalloutManifest) {
+    if let Some(file) = ScriptFile::new(script) {
+        MANIFESTS
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert(file, manifest.clone());
+    }
+}
+
+fn cached_manifest(script: &Path) -> Option<CalloutManifest> {
+    let file = ScriptFile::new(script)?;
+    MANIFESTS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|m| m.get(&file).cloned())
+}
+
+/// whether the output of an event may be reused for identical invocations
+fn is_memoizable(event: Event, action: Action) -> bool {
+    event == Event::Get && action == Action::Capabilities
+}
+
+/// Wait for a callout script to exit and collect its output. The script is killed if it does
+/// not exit before the deadline.
+fn wait_before_deadline(mut child: Child, deadline: Option<Instant>) -> Result<Output> {
+    let deadline = match deadline {
+        Some(d) => d,
+        None => return child.wait_with_output().map_err(anyhow::Error::from),
+    };
+    // the output is read while waiting, so that the script does not block on full pipes
+    let stdout = child
+        .stdout
+        .take()
+        .map(|out| thread::spawn(|| read_all(out)));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|err| thread::spawn(|| read_all(err)));
+    // the child is only reaped by this thread, so that it can be killed without its pid having
+    // been reused
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let pid = child.id();
+            if let Err(e) = child.kill() {
+                warn!("Unable to kill callout script (pid {}): {}", pid, e);
+            }
+            let _ = child.wait();
+            // the output is not waited for, processes started by the script may keep the pipes
+            // open
+            return Err(DeadlineExceeded(format!("Callout script (pid {})", pid)).into());
         }
+        thread::sleep(SCRIPT_POLL_INTERVAL);
+    };
+    let join = |reader: Option<thread::JoinHandle<std::io::Result<Vec<u8>>>>| match reader {
+        Some(r) => r
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("reader panicked"))),
+        None => Ok(Vec::new()),
+    };
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// Read a pipe of a callout script until it is closed
+fn read_all<R: std::io::Read>(mut pipe: R) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    pipe.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// how often a callout script with a deadline is checked for having exited
+const SCRIPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Error for a callout script that was killed because it did not exit within its timeout
+#[derive(Debug)]
+pub struct CalloutTimeout(pub u64);
+
+impl Display for CalloutTimeout {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Script did not complete within {} seconds and was killed",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for CalloutTimeout {}
+
+/// Error for a script that is configured to run as a user that does not exist
+#[derive(Debug)]
+pub struct UnknownScriptUser(pub String);
+
+impl Display for UnknownScriptUser {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "User {:?} does not exist", self.0)
     }
 }
 
+impl std::error::Error for UnknownScriptUser {}
+
 pub trait CheckProcessOutput {
     fn check(&self, p: PathBuf, o: Output) -> Result<(PathBuf, Output)>;
     fn process(&self, c: &mut Callout<'_>, p: PathBuf, o: Output) -> Result<Option<Output>>;
@@ -363,7 +1081,7 @@ impl CheckProcessOutput for DefaultCheckProcessOutput {
     }
 
     fn process(&self, c: &mut Callout<'_>, p: PathBuf, o: Output) -> Result<Option<Output>> {
-        c.print_err(&o, &p);
+        c.log_stderr(&o, &p);
         match o.status.code() {
             Some(0) => {
                 c.script = Some(CalloutScriptInfo::new(
@@ -371,32 +1089,56 @@ impl CheckProcessOutput for DefaultCheckProcessOutput {
                     c.dev.parent().unwrap().to_string(),
                     c.dev.mdev_type().unwrap().to_string(),
                     CalloutVersion::V_1,
+                    None,
                 ));
                 Ok(Some(o))
             }
-            Some(n) => Err(invocation_failure(&p, Some(n))),
+            Some(_) => Err(invocation_failure(&p, &o, None)),
             None => Ok(None),
         }
     }
 }
 
+#[cfg(feature = "callouts")]
 struct CapabilitiesCheckProcessOutput;
 
+/// Checks the output of the get-capabilities event.
+///
+/// Fallback policy: a script that prints nothing does not support versioning and is silently
+/// treated as a legacy script. A script that prints a document which does not match the
+/// capabilities schema is rejected with a warning and is likewise only used through the
+/// non-versioned script search.
+#[cfg(feature = "callouts")]
 impl CheckProcessOutput for CapabilitiesCheckProcessOutput {
     fn check(&self, p: PathBuf, o: Output) -> Result<(PathBuf, Output)> {
-        match CalloutScriptCache::parse_script_capabilities(&o) {
-            Some(_) => Ok((p, o)),
-            None => Err(anyhow!(
-                "Output of callout script {:?} is not a valid capabilities XML response",
-                p
-            )),
-        }
-    }
-
+        match CalloutScriptCache::parse_script_capabilities(&o.stdout) {
+            Ok(Some(_)) => Ok((p, o)),
+            Ok(None) => {
+                debug!(" Callout script {:?} does not provide version support", p);
+                Err(anyhow!(
+                    "Callout script {:?} did not return a capabilities document",
+                    p
+                ))
+            }
+            Err(e) => {
+                warn!(
+                    "Ignoring invalid capabilities of callout script {:?}, falling back to \
+                     non-versioned callouts: {:#}",
+                    p, e
+                );
+                Err(e.context(format!(
+                    "Output of callout script {:?} is not a valid capabilities document",
+                    p
+                )))
+            }
+        }
+    }
+
     fn process(&self, c: &mut Callout<'_>, p: PathBuf, o: Output) -> Result<Option<Output>> {
-        c.print_err(&o, &p);
-        match CalloutScriptCache::parse_script_capabilities(&o) {
-            Some(cv) => {
+        c.log_stderr(&o, &p);
+        match CalloutScriptCache::parse_script_capabilities(&o.stdout)? {
+            Some(caps) => {
+                let cv = caps.supports;
                 debug!(" Script supports versioning: {:?}", cv);
                 if cv.has_action(Action::Unknown) {
                     warn!("Callout script {:?} provides unknown Action type", p);
@@ -404,11 +1146,15 @@ impl CheckProcessOutput for CapabilitiesCheckProcessOutput {
                 if cv.has_event(Event::Unknown) {
                     warn!("Callout script {:?} provides unknown Event type", p);
                 }
+                if let Some(manifest) = caps.manifest.as_ref() {
+                    remember_manifest(&p, manifest);
+                }
                 c.script = Some(CalloutScriptInfo::new(
                     p,
                     c.dev.parent().unwrap().to_string(),
                     c.dev.mdev_type().unwrap().to_string(),
                     cv,
+                    caps.manifest,
                 ));
                 Ok(Some(o))
             }
@@ -417,13 +1163,390 @@ impl CheckProcessOutput for CapabilitiesCheckProcessOutput {
     }
 }
 
+/// Resource limits that are applied to callout and notification scripts before they are executed
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CalloutLimits {
+    /// maximum CPU time in seconds
+    pub cpu_time: Option<u64>,
+    /// maximum size of the virtual address space in bytes
+    pub memory: Option<u64>,
+    /// maximum number of open file descriptors
+    pub open_files: Option<u64>,
+    /// maximum wall clock time in seconds, after which a script is killed
+    pub timeout: Option<u64>,
+    /// timeouts of the scripts invoked for specific actions, overriding `timeout`
+    #[serde(default)]
+    pub action_timeouts: HashMap<Action, u64>,
+    /// run scripts in a mount namespace with only the paths they need
+    #[serde(default)]
+    pub mount_namespace: bool,
+    /// additional paths that are available read-write in the mount namespace
+    #[serde(default)]
+    pub namespace_paths: Vec<PathBuf>,
+    /// the user scripts run as instead of the user of mdevctl
+    pub user: Option<String>,
+    /// the users specific scripts run as, by the file name of the script, overriding `user`
+    #[serde(default)]
+    pub script_users: HashMap<String, String>,
+}
+
+impl CalloutLimits {
+    /// Load the limits configured for the given environment. No limits are applied if the
+    /// configuration file does not exist.
+    pub fn load(env: &dyn Environment) -> Result<CalloutLimits> {
+        let path = env.callout_limits_path();
+        let contents = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(CalloutLimits::default()),
+            Err(e) => return Err(e).with_context(|| format!("Unable to read {:?}", path)),
+        };
+        serde_json::from_str(&contents).with_context(|| format!("Invalid limits in {:?}", path))
+    }
+
+    /// the timeout in seconds of the scripts invoked for `action`, if any
+    fn timeout(&self, action: Action) -> Option<u64> {
+        self.action_timeouts
+            .get(&action)
+            .copied()
+            .or(self.timeout)
+            .filter(|t| *t > 0)
+    }
+
+    /// the name of the user `script` runs as, if any
+    pub fn user(&self, script: &Path) -> Option<&str> {
+        script
+            .file_name()
+            .and_then(|name| self.script_users.get(name.to_string_lossy().as_ref()))
+            .or(self.user.as_ref())
+            .map(|user| user.as_str())
+    }
+
+    /// all users that scripts run as
+    pub fn users(&self) -> Vec<&str> {
+        let mut users = self
+            .user
+            .iter()
+            .chain(self.script_users.values())
+            .map(|user| user.as_str())
+            .collect::<Vec<_>>();
+        users.sort_unstable();
+        users.dedup();
+        users
+    }
+
+    fn apply(&self, cmd: &mut Command) {
+        let limits = [
+            (libc::RLIMIT_CPU, self.cpu_time),
+            (libc::RLIMIT_AS, self.memory),
+            (libc::RLIMIT_NOFILE, self.open_files),
+        ];
+        if limits.iter().all(|(_, l)| l.is_none()) {
+            return;
+        }
+
+        // SAFETY: the closure runs in the forked child and only calls getrlimit() and
+        // setrlimit(), which are both async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                for (resource, limit) in limits {
+                    if let Some(limit) = limit {
+                        let mut rlim = libc::rlimit {
+                            rlim_cur: 0,
+                            rlim_max: 0,
+                        };
+                        if libc::getrlimit(resource, &mut rlim) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        // limits can only be lowered, never raised above the current hard limit
+                        let limit = std::cmp::min(limit as libc::rlim_t, rlim.rlim_max);
+                        rlim.rlim_cur = limit;
+                        rlim.rlim_max = limit;
+                        if libc::setrlimit(resource, &rlim) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// The unprivileged user and group a script runs as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScriptUser {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl ScriptUser {
+    /// Look up the user `name` in the user database
+    pub fn lookup(name: &str) -> Result<ScriptUser> {
+        let cname = CString::new(name).with_context(|| format!("Invalid user name {:?}", name))?;
+        let mut buf = vec![0 as libc::c_char; 1024];
+        loop {
+            // SAFETY: passwd only consists of integers and pointers, for which zero is valid
+            let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+            let mut result = ptr::null_mut();
+            // SAFETY: all pointers are valid for the duration of the call and the strings that
+            // the result points to are placed in buf, whose length is passed along
+            let rc = unsafe {
+                libc::getpwnam_r(
+                    cname.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            };
+            match rc {
+                0 if result.is_null() => return Err(UnknownScriptUser(name.to_string()).into()),
+                0 => {
+                    return Ok(ScriptUser {
+                        uid: pwd.pw_uid,
+                        gid: pwd.pw_gid,
+                    })
+                }
+                libc::ERANGE => buf.resize(buf.len() * 2, 0),
+                _ => {
+                    return Err(std::io::Error::from_raw_os_error(rc))
+                        .with_context(|| format!("Unable to look up user {:?}", name))
+                }
+            }
+        }
+    }
+
+    /// Set up `cmd` to drop the privileges of mdevctl and run as this user, with its group as
+    /// the only group. This happens after all other setup of the child, which may need the
+    /// privileges, e.g. to create a mount namespace.
+    fn apply(&self, cmd: &mut Command) {
+        let ScriptUser { uid, gid } = *self;
+        // SAFETY: the closure runs in the forked child and only calls setgroups(), setgid() and
+        // setuid(), which are all async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                let check = |res: libc::c_int| match res {
+                    0 => Ok(()),
+                    _ => Err(std::io::Error::last_os_error()),
+                };
+                // the supplementary groups of mdevctl, e.g. root, must not be kept
+                check(libc::setgroups(1, &gid))?;
+                check(libc::setgid(gid))?;
+                check(libc::setuid(uid))?;
+                Ok(())
+            });
+        }
+    }
+}
+
+/// The suffix of the files next to notification scripts that filter the events they are invoked
+/// for, e.g. `page.sh.conf` for `page.sh`
+const NOTIFIER_CONF_SUFFIX: &str = ".conf";
+
+/// The notification scripts installed in the notification directories, in the order they are
+/// invoked, without the files that filter their events
+pub fn installed_notifiers(env: &dyn Environment) -> Vec<PathBuf> {
+    let mut scripts = Vec::new();
+    for dir in env.notification_dirs() {
+        let mut paths = match dir.read_dir() {
+            Ok(rd) => rd
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| !p.to_string_lossy().ends_with(NOTIFIER_CONF_SUFFIX))
+                .collect::<Vec<_>>(),
+            Err(_) => continue,
+        };
+        paths.sort();
+        scripts.extend(paths);
+    }
+    scripts
+}
+
+/// Whether the action a notification is sent for succeeded, including its pre and post events
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionResult {
+    Success,
+    Failure,
+}
+
+impl ActionResult {
+    pub fn of<T>(res: &Result<T>) -> ActionResult {
+        match res {
+            Ok(_) => ActionResult::Success,
+            Err(_) => ActionResult::Failure,
+        }
+    }
+}
+
+impl Display for ActionResult {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ActionResult::Success => write!(f, "success"),
+            ActionResult::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+/// The events a notification script is invoked for, configured in a JSON file named like the
+/// script with a `.conf` suffix. Scripts without such a file are invoked for all events.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifierFilter {
+    /// the actions the script is invoked for, all if not given
+    pub actions: Option<Vec<Action>>,
+    /// the results of the actions the script is invoked for, all if not given
+    pub results: Option<Vec<ActionResult>>,
+}
+
+impl NotifierFilter {
+    /// Load the filter of the notification script `script`
+    pub fn load(script: &Path) -> Result<NotifierFilter> {
+        let mut path = script.as_os_str().to_os_string();
+        path.push(NOTIFIER_CONF_SUFFIX);
+        let path = PathBuf::from(path);
+        let contents = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(NotifierFilter::default()),
+            Err(e) => return Err(e).with_context(|| format!("Unable to read {:?}", path)),
+        };
+        let filter: NotifierFilter = serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid notifier filter in {:?}", path))?;
+        if filter
+            .actions
+            .iter()
+            .flatten()
+            .any(|a| *a == Action::Unknown)
+        {
+            warn!("Notifier filter {:?} names unknown actions", path);
+        }
+        Ok(filter)
+    }
+
+    /// Whether the script is invoked for `action` with `result`
+    pub fn matches(&self, action: Action, result: ActionResult) -> bool {
+        self.actions.as_ref().is_none_or(|a| a.contains(&action))
+            && self.results.as_ref().is_none_or(|r| r.contains(&result))
+    }
+}
+
+/// The environment variable with the number of identical events a notification stands for, if
+/// notifications are coalesced
+#[cfg(feature = "callouts")]
+const NOTIFY_COUNT_VAR: &str = "MDEVCTL_NOTIFY_COUNT";
+
+/// The document that notification scripts get on stdin
+#[derive(Debug, Serialize)]
+#[cfg(feature = "callouts")]
+struct Notification {
+    action: Action,
+    result: ActionResult,
+    /// the number of identical events the notification stands for, see [`NotifyDedup`]
+    count: u64,
+}
+
+/// The identical notifications of a device, i.e. for the same action with the same result, that
+/// were coalesced since the last one that was sent. They are kept in the runtime directory, as
+/// the repeated events usually come from separate invocations of mdevctl, e.g. by udev.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg(feature = "callouts")]
+struct NotifyDedup {
+    /// when the last notification was sent, in milliseconds since the epoch
+    notified: u64,
+    /// the notifications that were not sent since
+    coalesced: u64,
+}
+
+#[cfg(feature = "callouts")]
+impl NotifyDedup {
+    fn path(dev: &MDev, action: Action, result: ActionResult) -> PathBuf {
+        dev.env.notify_state_base().join(format!(
+            "{}-{}-{}.json",
+            dev.uuid.hyphenated(),
+            action,
+            result
+        ))
+    }
+
+    /// Whether to send a notification of `action` with `result`, i.e. unless an identical one
+    /// was sent less than `window` ago. A notification that is sent stands for the returned
+    /// number of events, itself and the ones coalesced since the last one. The state file is
+    /// locked while it is updated, as concurrent invocations of mdevctl may notify the same
+    /// event, e.g. from udev.
+    fn coalesce(dev: &MDev, action: Action, result: ActionResult, window: Duration) -> Option<u64> {
+        let path = NotifyDedup::path(dev, action, result);
+        match NotifyDedup::update(&path, window) {
+            Ok(Some(count)) => Some(count),
+            Ok(None) => {
+                debug!(
+                    "Coalescing {} notification of {} with {}",
+                    action, dev.uuid, result
+                );
+                None
+            }
+            Err(e) => {
+                // a notification too many is better than a missing one
+                warn!("Failed to update notification state {:?}: {:#}", path, e);
+                Some(1)
+            }
+        }
+    }
+
+    fn update(path: &Path, window: Duration) -> Result<Option<u64>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        // SAFETY: the file descriptor is valid as long as the file is open, the lock is released
+        // when it is closed
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| "Failed to lock");
+        }
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut state: NotifyDedup = serde_json::from_str(&contents).unwrap_or_default();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let count = match state.notified > 0
+            && now.saturating_sub(state.notified) < window.as_millis() as u64
+        {
+            true => {
+                state.coalesced += 1;
+                None
+            }
+            false => {
+                let count = state.coalesced + 1;
+                state = NotifyDedup {
+                    notified: now,
+                    coalesced: 0,
+                };
+                Some(count)
+            }
+        };
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(serde_json::to_string(&state)?.as_bytes())?;
+        Ok(count)
+    }
+}
+
 pub struct Callout<'a> {
     state: State,
     script: Option<CalloutScriptInfo>,
+    limits: CalloutLimits,
+    /// additional environment variables for the scripts
+    vars: Vec<(&'static str, String)>,
     pub dev: &'a mut MDev,
 }
 
-pub fn callout(dev: &mut MDev) -> Result<Callout> {
+pub fn callout(dev: &mut MDev) -> Result<Callout<'_>> {
     Callout::new(dev)
 }
 
@@ -432,19 +1555,158 @@ impl<'a> Callout<'a> {
         if dev.mdev_type.is_none() {
             return Err(anyhow!("Device must have a defined mdev_type"));
         }
+        let limits = CalloutLimits::load(dev.env.as_ref()).unwrap_or_else(|e| {
+            warn!("Ignoring callout script limits: {:#}", e);
+            CalloutLimits::default()
+        });
         Ok(Callout {
             state: State::None,
             script: None,
+            limits,
+            vars: Vec::new(),
             dev,
         })
     }
 
-    fn find_callout_script(&self) -> Option<CalloutScriptInfo> {
+    /// Pass an additional environment variable to all scripts invoked for this callout
+    pub fn set_var(&mut self, name: &'static str, value: String) {
+        self.vars.push((name, value));
+    }
+
+    fn find_callout_script(&self) -> Result<Option<CalloutScriptInfo>> {
+        if self.dev.env.callout_record_path().is_some() {
+            return Ok(None);
+        }
+        if self.dev.active {
+            if let Some(script) = CalloutScriptInfo::load_recorded(self.dev) {
+                return Ok(Some(script));
+            }
+        }
         self.dev.env.find_script(self.dev)
     }
 
+    /// Whether the manifest a script returned earlier excludes this device, in which case the
+    /// script is not invoked for it
+    fn excluded_by_manifest(&self, script: &Path) -> Result<bool> {
+        let (parent, mdev_type) = (self.dev.parent()?, self.dev.mdev_type()?);
+        Ok(cached_manifest(script).is_some_and(|m| !m.handles(parent, mdev_type)))
+    }
+
+    /// The scripts in the callout directory `dir` that are searched for the device, in order.
+    /// If scripts are installed for the type of the device in any callout directory, only those
+    /// are searched instead of all scripts.
+    #[cfg(feature = "callouts")]
+    fn search_scripts(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mdev_type = self.dev.mdev_type()?;
+        let routed = self
+            .dev
+            .env
+            .callout_dirs()
+            .iter()
+            .any(|d| !routed_scripts(d, mdev_type).is_empty());
+        Ok(match routed {
+            true => routed_scripts(dir, mdev_type),
+            false => sorted_files(dir),
+        })
+    }
+
+    /// Find all scripts besides `found` that support the device with versioning, in the order
+    /// in which they are searched
+    #[cfg(feature = "callouts")]
+    fn other_versioned_scripts(&self, found: &Path, stdin: &str) -> Vec<(PathBuf, CalloutVersion)> {
+        let mut others = Vec::new();
+        for dir in self.dev.env.callout_dirs() {
+            let paths = self.search_scripts(&dir).unwrap_or_default();
+            for path in paths.into_iter().filter(|p| p != found) {
+                if self.excluded_by_manifest(&path).unwrap_or(false) {
+                    continue;
+                }
+                let output = match self.invoke_script(
+                    &path,
+                    Event::Get,
+                    Action::Capabilities,
+                    Some(stdin),
+                ) {
+                    Ok(o) => o,
+                    Err(_) => continue,
+                };
+                if matches!(output.status.code(), None | Some(2)) {
+                    continue;
+                }
+                if let Ok(Some(caps)) =
+                    CalloutScriptCache::parse_script_capabilities(&output.stdout)
+                {
+                    if let Some(manifest) = caps.manifest.as_ref() {
+                        remember_manifest(&path, manifest);
+                    }
+                    others.push((path, caps.supports));
+                }
+            }
+        }
+        others
+    }
+
+    /// Query `script` for its capabilities regarding this device, regardless of whether it is
+    /// the script that would be used for the device. Returns `None` if the script does not
+    /// support versioning for the device.
+    pub fn query_capabilities(&self, script: &Path) -> Result<Option<CalloutVersion>> {
+        Ok(match self.query_support(script)? {
+            ScriptSupport::Versioned(version) => Some(version),
+            _ => None,
+        })
+    }
+
+    /// Query whether `script` supports this device, with or without versioning
+    pub fn query_support(&self, script: &Path) -> Result<ScriptSupport> {
+        if self.excluded_by_manifest(script)? {
+            return Ok(ScriptSupport::Unsupported);
+        }
+        let provides = serde_json::to_string(&CalloutVersionProvides {
+            provides: Some(CalloutVersion::V_5),
+        })?;
+        let output =
+            self.invoke_script(script, Event::Get, Action::Capabilities, Some(&provides))?;
+        if matches!(output.status.code(), None | Some(2)) {
+            return Ok(ScriptSupport::Unsupported);
+        }
+        Ok(
+            match CalloutScriptCache::parse_script_capabilities(&output.stdout)? {
+                Some(caps) => {
+                    if let Some(manifest) = caps.manifest.as_ref() {
+                        remember_manifest(script, manifest);
+                    }
+                    ScriptSupport::Versioned(caps.supports)
+                }
+                None => ScriptSupport::Unversioned,
+            },
+        )
+    }
+
+    /// keep the runtime state in sync with the outcome of a successful action
+    fn update_recorded_script(&self, action: Action) {
+        match action {
+            Action::Start => {
+                if let Some(script) = self.script.as_ref() {
+                    if let Err(e) = script.record(self.dev) {
+                        warn!("{:#}", e);
+                    }
+                }
+            }
+            Action::Stop => CalloutScriptInfo::forget(self.dev),
+            _ => (),
+        }
+    }
+
+    #[cfg(not(feature = "callouts"))]
+    pub fn invoke_modify_live(&mut self) -> Result<()> {
+        Err(anyhow!(
+            "mdevctl was built without support for callout scripts"
+        ))
+    }
+
+    #[cfg(feature = "callouts")]
     pub fn invoke_modify_live(&mut self) -> Result<()> {
-        self.script = self.find_callout_script();
+        self.script = self.find_callout_script()?;
         if self.script.is_none() {
             // live is only supported when script with versioning exists
             debug!("No callout script with version support found that supports live modify");
@@ -467,10 +1729,14 @@ impl<'a> Callout<'a> {
                     "Device exists with different type - cannot run live update"
                 ));
             } else {
-                self.script
-                    .clone()
-                    .unwrap()
-                    .supports_event_action(Event::Live, Action::Modify)?;
+                let script = self.script.clone().unwrap();
+                if !script.implements_live_modify() {
+                    return Err(anyhow!(
+                        "Script {:?} does not implement live modify",
+                        script.path
+                    ));
+                }
+                script.supports_event_action(Event::Live, Action::Modify)?;
                 let conf = self.dev.to_json(false)?.to_string();
                 res = self
                     .callout(
@@ -480,7 +1746,8 @@ impl<'a> Callout<'a> {
                         &DefaultCheckProcessOutput,
                     )
                     .map(|_output| ());
-                self.notify(Action::Modify);
+                self.notify(Action::Modify, ActionResult::of(&res));
+                self.audit(Action::Modify, ActionResult::of(&res));
             }
         } // else mdev is not active
         res
@@ -490,15 +1757,18 @@ impl<'a> Callout<'a> {
     where
         F: Fn(&mut Self) -> Result<()>,
     {
-        self.script = self.find_callout_script();
+        let _span = span(format!("{} {}", action, self.dev.uuid));
+        self.script = self.find_callout_script()?;
         if self.script.is_none() {
             debug!("No callout script with version support found");
         }
 
         let conf = self.dev.to_json(false)?.to_string();
-        let res = self
-            .callout(Event::Pre, action, Some(&conf), &DefaultCheckProcessOutput)
-            .map(|_output| ()) // can ignore output for general callouts
+        let res = plugins::dispatch(Event::Pre, action, State::None, self.dev)
+            .and_then(|_| {
+                self.callout(Event::Pre, action, Some(&conf), &DefaultCheckProcessOutput)
+                    .map(|_output| ()) // can ignore output for general callouts
+            })
             .or_else(|e| {
                 force
                     .then(|| {
@@ -510,9 +1780,19 @@ impl<'a> Callout<'a> {
                     .ok_or(e)
             })
             .and_then(|_| {
+                let started = Instant::now();
                 let tmp_res = func(self);
+                debug!(
+                    "{} of device {} took {:.3}s",
+                    action,
+                    self.dev.uuid,
+                    started.elapsed().as_secs_f64()
+                );
                 self.state = match tmp_res {
-                    Ok(_) => State::Success,
+                    Ok(_) => {
+                        self.update_recorded_script(action);
+                        State::Success
+                    }
                     Err(_) => State::Failure,
                 };
 
@@ -521,18 +1801,124 @@ impl<'a> Callout<'a> {
                 if post_res.is_err() {
                     debug!("Error occurred when executing post callout script");
                 }
+                // errors of plugins are only logged for post events
+                let _ = plugins::dispatch(Event::Post, action, self.state, self.dev);
 
                 tmp_res
             });
 
-        self.notify(action);
+        self.notify(action, ActionResult::of(&res));
+        if audit::audited(action) {
+            self.audit(action, ActionResult::of(&res));
+        }
         res
     }
 
+    /// Record the action in the audit subsystem if the policy asks for it
+    fn audit(&self, action: Action, result: ActionResult) {
+        let env = self.dev.env.as_ref();
+        let enabled = Policy::load(env).map(|p| p.audit).unwrap_or_else(|e| {
+            warn!(
+                "Not auditing {} of device {}: {:#}",
+                action, self.dev.uuid, e
+            );
+            false
+        });
+        if !enabled {
+            return;
+        }
+        let message = audit::message(self.dev, action, result);
+        debug!("Auditing {}", message);
+        if let Err(e) = env.audit(&message) {
+            warn!(
+                "Failed to audit {} of device {}: {:#}",
+                action, self.dev.uuid, e
+            );
+        }
+    }
+
+    /// Invoke only the pre event of an action, to check whether the scripts accept it without
+    /// performing it. The scripts find `MDEVCTL_DRY_RUN=1` in their environment.
+    pub fn invoke_dry_run<F>(&mut self, action: Action, force: bool, func: F) -> Result<()>
+    where
+        F: Fn(&mut Self) -> Result<()>,
+    {
+        let _span = span(format!("{} {} (dry run)", action, self.dev.uuid));
+        self.script = self.find_callout_script()?;
+        self.set_var("MDEVCTL_DRY_RUN", "1".to_string());
+
+        let conf = self.dev.to_json(false)?.to_string();
+        self.callout(Event::Pre, action, Some(&conf), &DefaultCheckProcessOutput)
+            .map(|_output| ())
+            .or_else(|e| {
+                force
+                    .then(|| {
+                        warn!(
+                            "Forcing operation '{}' despite callout failure. Error was: {}",
+                            action, e
+                        );
+                    })
+                    .ok_or(e)
+            })?;
+        func(self)
+    }
+
+    /// Invoke `script`, or the script that supports versioning for the device, for a single
+    /// event without acting on its outcome. Unless `stdin` is given, the script gets what mdevctl
+    /// passes for the event.
+    pub fn invoke_raw(
+        &mut self,
+        script: Option<PathBuf>,
+        event: Event,
+        action: Action,
+        state: State,
+        stdin: Option<String>,
+    ) -> Result<ScriptInvocation> {
+        let script = match script {
+            Some(s) => s,
+            None => match self.find_callout_script()? {
+                Some(s) => s.path,
+                None => {
+                    return Err(anyhow!(
+                        "No callout script with version support found for device {}",
+                        self.dev.uuid.hyphenated()
+                    ))
+                }
+            },
+        };
+        let stdin = match (stdin, event, action) {
+            (Some(s), _, _) => Some(s),
+            (None, Event::Get, Action::Capabilities) => {
+                Some(serde_json::to_string(&CalloutVersionProvides {
+                    provides: Some(CalloutVersion::V_5),
+                })?)
+            }
+            (None, Event::Get, _) | (None, Event::Notify, _) => None,
+            (None, _, _) => Some(self.dev.to_json(false)?.to_string()),
+        };
+        self.state = state;
+        let args = self.script_args(event, action)?;
+        let output = self.invoke_script(&script, event, action, stdin.as_deref())?;
+        Ok(ScriptInvocation {
+            script,
+            args,
+            stdin,
+            output,
+        })
+    }
+
     pub fn get_attributes(&mut self) -> Result<serde_json::Value> {
-        self.script = self.find_callout_script();
-        if self.script.is_none() {
-            debug!("No callout script with version support found");
+        self.script = self.find_callout_script()?;
+        match self.script.as_ref() {
+            None => debug!("No callout script with version support found"),
+            Some(s) if !s.implements_get_attributes() => {
+                debug!(
+                    "Callout script {:?} does not implement get attributes",
+                    s.path
+                );
+                return Ok(serde_json::Value::Null);
+            }
+            Some(_) => (),
         }
 
         match self.callout(
@@ -547,44 +1933,96 @@ impl<'a> Callout<'a> {
                     let mut st = String::from_utf8_lossy(&output.stdout).to_string();
 
                     if st.is_empty() {
-                        debug!(
-                            "Script output for {} is empty",
-                            self.dev.uuid.hyphenated().to_string()
-                        );
+                        debug!("Script output for {} is empty", self.dev.uuid.hyphenated());
                         return Ok(serde_json::Value::Null);
                     }
 
                     if &st == "[{}]" {
                         debug!(
                             "Attribute field for {} is empty",
-                            self.dev.uuid.hyphenated().to_string()
+                            self.dev.uuid.hyphenated()
                         );
                         st = "[]".to_string();
                     }
                     debug!(
                         "Script output for {} is: '{}'",
-                        self.dev.uuid.hyphenated().to_string(),
+                        self.dev.uuid.hyphenated(),
                         st
                     );
                     serde_json::from_str(st.trim_end_matches('\0'))
                         .with_context(|| "Invalid JSON received from callout script")
                 } else {
-                    let path = &self.script.as_ref().unwrap().path;
-                    self.print_err(&output, path);
+                    let script = self.script.as_ref().unwrap();
+                    self.log_stderr(&output, &script.path);
 
-                    Err(invocation_failure(path, output.status.code()))
+                    Err(script.failure(&output))
                 }
             }
             None => {
                 debug!(
                     "Script execution for {} returned without error but also without output",
-                    self.dev.uuid.hyphenated().to_string()
+                    self.dev.uuid.hyphenated()
                 );
                 Ok(serde_json::Value::Null)
             }
         }
     }
 
+    /// the arguments that pass the device and the event to a script
+    fn script_args(&self, event: Event, action: Action) -> Result<Vec<String>> {
+        Ok(vec![
+            "-t".to_string(),
+            self.dev.mdev_type()?.clone(),
+            "-e".to_string(),
+            event.to_string(),
+            "-a".to_string(),
+            action.to_string(),
+            "-s".to_string(),
+            self.state.to_string(),
+            "-u".to_string(),
+            self.dev.uuid.to_string(),
+            "-p".to_string(),
+            self.dev.parent()?.clone(),
+        ])
+    }
+
+    /// the command that invokes a script with `args`. The device and the event are also passed
+    /// in the environment, which scripts can rely on if mdevctl provides version 3 or later.
+    fn script_command(
+        &self,
+        script: &Path,
+        event: Event,
+        action: Action,
+        args: &[String],
+    ) -> Result<Command> {
+        let mut cmd = Command::new(script.as_os_str());
+        cmd.args(args)
+            .env("MDEVCTL_UUID", self.dev.uuid.hyphenated().to_string())
+            .env("MDEVCTL_PARENT", self.dev.parent()?)
+            .env("MDEVCTL_TYPE", self.dev.mdev_type()?)
+            .env("MDEVCTL_EVENT", event.to_string())
+            .env("MDEVCTL_ACTION", action.to_string())
+            .env("MDEVCTL_STATE", self.state.to_string())
+            .envs(self.vars.iter().map(|(name, value)| (name, value)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        self.limits.apply(&mut cmd);
+        if self.limits.mount_namespace {
+            namespace::isolate(
+                self.dev.env.as_ref(),
+                &mut cmd,
+                &self.limits.namespace_paths,
+            )?;
+        }
+        if let Some(name) = self.limits.user(script) {
+            let user = ScriptUser::lookup(name)
+                .with_context(|| format!("Unable to run {:?} as user {:?}", script, name))?;
+            user.apply(&mut cmd);
+        }
+        Ok(cmd)
+    }
+
     fn invoke_script<P: AsRef<Path>>(
         &self,
         script: P,
@@ -592,6 +2030,7 @@ impl<'a> Callout<'a> {
         action: Action,
         stdin: Option<&str>,
     ) -> Result<Output> {
+        let _span = span(format!("{}-{}", event, action));
         debug!(
             "{}-{}: executing {:?}",
             event,
@@ -599,24 +2038,31 @@ impl<'a> Callout<'a> {
             script.as_ref().as_os_str()
         );
 
-        let mut cmd = Command::new(script.as_ref().as_os_str());
-
-        cmd.arg("-t")
-            .arg(self.dev.mdev_type()?)
-            .arg("-e")
-            .arg(event.to_string())
-            .arg("-a")
-            .arg(action.to_string())
-            .arg("-s")
-            .arg(self.state.to_string())
-            .arg("-u")
-            .arg(self.dev.uuid.to_string())
-            .arg("-p")
-            .arg(self.dev.parent()?)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+        let args = self.script_args(event, action)?;
 
+        let key = match is_memoizable(event, action) {
+            true => ScriptFile::new(script.as_ref()).map(|script| InvocationKey {
+                script,
+                mdev_type: self.dev.mdev_type().cloned().unwrap_or_default(),
+                parent: self.dev.parent().cloned().unwrap_or_default(),
+            }),
+            false => None,
+        };
+        if let Some(key) = key.as_ref() {
+            let memoized = MEMOIZED_OUTPUTS.lock().unwrap();
+            if let Some(output) = memoized.as_ref().and_then(|m| m.get(key)) {
+                debug!(
+                    "{}-{}: reusing output of {:?}",
+                    event,
+                    action,
+                    script.as_ref().as_os_str()
+                );
+                return Ok(output.clone());
+            }
+        }
+
+        let mut cmd = self.script_command(script.as_ref(), event, action, &args)?;
+        let started = Instant::now();
         let mut child = cmd.spawn()?;
 
         if let Some(input) = stdin {
@@ -634,10 +2080,48 @@ impl<'a> Callout<'a> {
             }
         }
 
-        child.wait_with_output().map_err(anyhow::Error::from)
+        // only the events that are part of an attempt to change the device are bounded by its
+        // deadline, while the configured timeout applies to all events
+        let deadline = match event {
+            Event::Pre | Event::Get => self.dev.deadline,
+            _ => None,
+        };
+        let timeout = self.limits.timeout(action);
+        let timeout_deadline = timeout.map(|t| started + Duration::from_secs(t));
+        let timed_out = timeout_deadline.is_some_and(|t| deadline.is_none_or(|d| t <= d));
+        let output = wait_before_deadline(
+            child,
+            if timed_out {
+                timeout_deadline
+            } else {
+                deadline
+            },
+        )
+        .map_err(|e| match (timed_out, timeout) {
+            (true, Some(t)) if e.is::<DeadlineExceeded>() => CalloutTimeout(t).into(),
+            _ => e,
+        })
+        .with_context(|| format!("Failed to execute callout script {:?}", script.as_ref()))?;
+        debug!(
+            "{}-{}: {:?} exited with {} after {:.3}s",
+            event,
+            action,
+            script.as_ref().as_os_str(),
+            output.status,
+            started.elapsed().as_secs_f64()
+        );
+        if let Some(key) = key {
+            MEMOIZED_OUTPUTS
+                .lock()
+                .unwrap()
+                .get_or_insert_with(HashMap::new)
+                .insert(key, output.clone());
+        }
+        Ok(output)
     }
 
-    fn print_err<P: AsRef<Path>>(&self, output: &Output, script: P) {
+    /// Log the standard error of a script, as warnings if the script failed
+    fn log_stderr<P: AsRef<Path>>(&self, output: &Output, script: P) {
         let sname = script
             .as_ref()
             .file_name()
@@ -645,11 +2129,15 @@ impl<'a> Callout<'a> {
             .to_string_lossy();
 
         let st = String::from_utf8_lossy(&output.stderr);
-        if !st.is_empty() {
-            eprint!("{}: {}", &sname, st);
+        for line in st.lines().filter(|l| !l.trim().is_empty()) {
+            match output.status.success() {
+                true => debug!("{}: {}", sname, line),
+                false => warn!("{}: {}", sname, line),
+            }
         }
     }
 
+    #[cfg(feature = "callouts")]
     fn invoke_first_matching_script<P: AsRef<Path> + std::fmt::Debug>(
         &self,
         dir: P,
@@ -657,25 +2145,26 @@ impl<'a> Callout<'a> {
         action: Action,
         stdin: Option<&str>,
         check_result_fn: impl Fn(PathBuf, Output) -> Result<(PathBuf, Output)>,
-    ) -> Option<(PathBuf, Output)> {
+    ) -> Result<Option<(PathBuf, Output)>> {
+        // scripts match devices by their type, so none matches a device without a type
+        let mdev_type = match self.dev.mdev_type() {
+            Ok(mdev_type) => mdev_type,
+            Err(_) => return Ok(None),
+        };
         debug!(
             "{}-{}: looking for a matching callout script for dev type '{}' in {:?}",
-            event,
-            action,
-            self.dev.mdev_type.as_ref()?,
-            dir
+            event, action, mdev_type, dir
         );
 
-        let mut sorted_paths = dir
-            .as_ref()
-            .read_dir()
-            .ok()?
-            .filter_map(|k| k.ok().map(|e| e.path()))
-            .collect::<Vec<_>>();
-
-        sorted_paths.sort();
-
-        for path in sorted_paths {
+        for path in self.search_scripts(dir.as_ref())? {
+            if self.excluded_by_manifest(&path)? {
+                debug!(
+                    "callout script {:?} does not handle device type {:?} according to its manifest",
+                    path,
+                    mdev_type
+                );
+                continue;
+            }
             match self.invoke_script(&path, event, action, stdin) {
                 Ok(res) => {
                     if res.status.code().is_none() {
@@ -684,17 +2173,15 @@ impl<'a> Callout<'a> {
                     } else if res.status.code() == Some(2) {
                         debug!(
                             "callout script {:?} does not match device type {:?}",
-                            path,
-                            self.dev.mdev_type().ok()?
+                            path, mdev_type
                         );
                     } else {
                         debug!(
                             "found callout script {:?} matching device type {:?}",
-                            path,
-                            self.dev.mdev_type().ok()?
+                            path, mdev_type
                         );
                         match check_result_fn(path, res) {
-                            Ok((p, r)) => return Some((p, r)),
+                            Ok((p, r)) => return Ok(Some((p, r))),
                             Err(_) => {
                                 debug!("found callout script rejected by check_result method");
                                 continue;
@@ -702,15 +2189,64 @@ impl<'a> Callout<'a> {
                         }
                     }
                 }
+                // a script that was killed may well be the one responsible for the device, as
+                // may be one that could not run as its user, so the event fails instead of
+                // moving on to the next script
+                Err(e)
+                    if e.chain()
+                        .any(|c| c.is::<CalloutTimeout>() || c.is::<UnknownScriptUser>()) =>
+                {
+                    return Err(e)
+                }
                 Err(e) => {
                     debug!("failed to execute callout script {:?}: {:?}", path, e);
                     continue;
                 }
             }
         }
-        None
+        Ok(None)
+    }
+
+    /// Append an event to the record instead of invoking scripts for it
+    #[cfg(feature = "callouts")]
+    fn record(&self, path: &Path, event: Event, action: Action, stdin: Option<&str>) -> Result<()> {
+        let record = CalloutRecord {
+            event,
+            action,
+            state: self.state.to_string(),
+            uuid: self.dev.uuid.hyphenated().to_string(),
+            parent: self.dev.parent()?.clone(),
+            mdev_type: self.dev.mdev_type()?.clone(),
+            config: stdin.map(serde_json::from_str).transpose()?,
+            vars: self
+                .vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+        };
+        debug!("{}-{}: recording event in {:?}", event, action, path);
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to record callout event in {:?}", path))
+    }
+
+    #[cfg(not(feature = "callouts"))]
+    fn callout(
+        &mut self,
+        _event: Event,
+        _action: Action,
+        _stdin: Option<&str>,
+        _check_process: &dyn CheckProcessOutput,
+    ) -> Result<Option<Output>> {
+        Ok(None)
     }
 
+    #[cfg(feature = "callouts")]
     fn callout(
         &mut self,
         event: Event,
@@ -718,14 +2254,18 @@ impl<'a> Callout<'a> {
         stdin: Option<&str>,
         check_process: &dyn CheckProcessOutput,
     ) -> Result<Option<Output>> {
+        if let Some(path) = self.dev.env.callout_record_path() {
+            self.record(&path, event, action, stdin)?;
+            return Ok(None);
+        }
         match self.script {
             Some(ref s) => {
                 s.supports_event_action(event, action)?;
                 let output = self.invoke_script(s, event, action, stdin)?;
-                self.print_err(&output, s);
+                self.log_stderr(&output, s);
                 match output.status.code() {
                     None | Some(0) => Ok(Some(output)),
-                    Some(n) => Err(invocation_failure(&s.path, Some(n))),
+                    Some(_) => Err(s.failure(&output)),
                 }
             }
             None => {
@@ -740,7 +2280,7 @@ impl<'a> Callout<'a> {
                         action,
                         stdin,
                         |p, o| check_process.check(p, o),
-                    ) {
+                    )? {
                         Some((p, o)) => match check_process.process(self, p, o)? {
                             Some(o) => Ok(Some(o)),
                             None => continue,
@@ -756,33 +2296,145 @@ impl<'a> Callout<'a> {
         }
     }
 
-    fn notify(&mut self, action: Action) {
+    #[cfg(not(feature = "callouts"))]
+    fn notify(&mut self, _action: Action, _result: ActionResult) {}
+
+    #[cfg(feature = "callouts")]
+    fn notify(&mut self, action: Action, result: ActionResult) {
         let event = Event::Notify;
-        let dirs = self.dev.env.notification_dirs();
+        let policy = Policy::load(self.dev.env.as_ref()).unwrap_or_else(|e| {
+            warn!("Running notification scripts by default: {:#}", e);
+            Policy::default()
+        });
+        let mut count = 1;
+        if policy.notifier_dedup_ms > 0 {
+            let window = Duration::from_millis(policy.notifier_dedup_ms);
+            match NotifyDedup::coalesce(self.dev, action, result, window) {
+                Some(c) => count = c,
+                None => return,
+            }
+            self.set_var(NOTIFY_COUNT_VAR, count.to_string());
+        }
+        let notification = Notification {
+            action,
+            result,
+            count,
+        };
+        let payload = match serde_json::to_string(&notification) {
+            Ok(payload) => payload,
+            Err(e) => {
+                debug!("Unable to execute notification scripts: {:#}", e);
+                return;
+            }
+        };
+        if let Some(path) = self.dev.env.callout_record_path() {
+            if let Err(e) = self.record(&path, event, action, Some(&payload)) {
+                warn!("{:#}", e);
+            }
+            return;
+        }
+        let mode = policy.notifiers;
         debug!(
-            "{}-{}: executing notification scripts for device {}",
-            event, action, self.dev.uuid
+            "{}-{}: executing notification scripts for device {} ({:?})",
+            event, action, self.dev.uuid, mode
         );
 
-        for dir in dirs {
-            if !dir.is_dir() {
-                continue;
-            }
+        let paths = installed_notifiers(self.dev.env.as_ref())
+            .into_iter()
+            .filter(|path| match NotifierFilter::load(path) {
+                Ok(filter) if !filter.matches(action, result) => {
+                    debug!(
+                        "Not notifying {:?} of {} events with {}",
+                        path, action, result
+                    );
+                    false
+                }
+                Ok(_) => true,
+                // a notification too many is better than a missing one
+                Err(e) => {
+                    warn!("Notifying {:?} of all events: {:#}", path, e);
+                    true
+                }
+            })
+            .collect::<Vec<_>>();
 
-            if let Ok(readdir) = dir.read_dir() {
-                for path in readdir.filter_map(|x| x.ok().map(|y| y.path())) {
-                    match self.invoke_script(&path, event, action, None) {
-                        Ok(output) => {
-                            if !output.status.success() {
-                                debug!("Error occurred when executing notify script {:?}", path);
-                            }
+        if mode == NotifierMode::Serial {
+            for path in paths {
+                match self.invoke_script(&path, event, action, Some(&payload)) {
+                    Ok(output) => {
+                        if !output.status.success() {
+                            debug!("Error occurred when executing notify script {:?}", path);
                         }
-                        _ => {
-                            debug!("Failed to execute callout script {:?}", path);
-                            continue;
+                    }
+                    _ => {
+                        debug!("Failed to execute callout script {:?}", path);
+                        continue;
+                    }
+                }
+            }
+            return;
+        }
+
+        let args = match self.script_args(event, action) {
+            Ok(args) => args,
+            Err(e) => {
+                debug!("Unable to execute notification scripts: {:#}", e);
+                return;
+            }
+        };
+        let mut children = Vec::new();
+        for path in paths {
+            let mut cmd = match self.script_command(&path, event, action, &args) {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    debug!("Unable to execute notification script {:?}: {:#}", path, e);
+                    continue;
+                }
+            };
+            if mode == NotifierMode::Detached {
+                cmd.stdout(Stdio::null()).stderr(Stdio::null());
+                // SAFETY: the closure runs in the forked child and only calls setsid(), which is
+                // async-signal-safe. The new session keeps the script running when the terminal
+                // of mdevctl goes away.
+                unsafe {
+                    cmd.pre_exec(|| {
+                        libc::setsid();
+                        Ok(())
+                    });
+                }
+            }
+            match cmd.spawn() {
+                Ok(mut child) => {
+                    // the notification is far smaller than the pipe buffer, so this does not
+                    // wait for the script to read it
+                    if let Some(mut stdin) = child.stdin.take() {
+                        if let Err(e) = stdin.write_all(payload.as_bytes()) {
+                            debug!("Failed to notify {:?}: {}", path, e);
                         }
                     }
+                    children.push((path, child, Instant::now()))
+                }
+                Err(e) => debug!("Failed to execute notification script {:?}: {}", path, e),
+            }
+        }
+
+        if mode == NotifierMode::Detached {
+            for (_, mut child, _) in children {
+                // reap the scripts that complete while mdevctl is still running
+                thread::spawn(move || child.wait());
+            }
+            return;
+        }
+        let timeout = self.limits.timeout(action);
+        for (path, child, started) in children {
+            let deadline = timeout.map(|t| started + Duration::from_secs(t));
+            match wait_before_deadline(child, deadline) {
+                Ok(output) => {
+                    if !output.status.success() {
+                        debug!("Error occurred when executing notify script {:?}", path);
+                    }
                 }
+                Err(e) => debug!("Failed to execute notification script {:?}: {:#}", path, e),
             }
         }
     }
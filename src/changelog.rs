@@ -0,0 +1,185 @@
+//! Per-device journal of attribute changes for the `log` command
+//!
+//! If enabled with `change_log` in the policy, the commands that change the attributes of a
+//! device, i.e. `modify`, `edit`, `commit` and `attr set`, append an entry for every attribute
+//! they change to the log of the device, one JSON object per line.
+
+use crate::environment::Environment;
+use crate::mdev::Policy;
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// A single attribute change
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEntry {
+    /// seconds since the epoch
+    pub time: u64,
+    /// the user that made the change
+    pub uid: u32,
+    /// the command that made the change, e.g. `modify --live`
+    pub command: String,
+    pub parent: String,
+    pub attribute: String,
+    /// the previous value, unless the attribute was added
+    pub old: Option<String>,
+    /// the new value, unless the attribute was removed
+    pub new: Option<String>,
+}
+
+impl fmt::Display for ChangeEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = |v: &Option<String>| v.clone().unwrap_or_else(|| "(unset)".to_string());
+        write!(
+            f,
+            "{} {} {}: {} -> {} (uid {}, {})",
+            format_time(self.time),
+            self.parent,
+            self.attribute,
+            value(&self.old),
+            value(&self.new),
+            self.uid,
+            self.command
+        )
+    }
+}
+
+/// Format seconds since the epoch as UTC date and time, e.g. `2024-03-01T12:00:00Z`
+fn format_time(secs: u64) -> String {
+    // days since the epoch to the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let secs = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn log_path(env: &dyn Environment, uuid: Uuid) -> PathBuf {
+    env.changelog_base().join(uuid.hyphenated().to_string())
+}
+
+/// The changes between two lists of attributes, as tuples of the name, the old and the new value.
+/// The values of an attribute that is given several times are compared by their position.
+pub fn attr_changes(
+    old: &[(String, String)],
+    new: &[(String, String)],
+) -> Vec<(String, Option<String>, Option<String>)> {
+    let mut names: Vec<&String> = Vec::new();
+    for (name, _) in old.iter().chain(new.iter()) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    let values = |attrs: &[(String, String)], name: &String| {
+        attrs
+            .iter()
+            .filter(|(n, _)| n == name)
+            .map(|(_, v)| v.clone())
+            .collect::<Vec<_>>()
+    };
+    let mut changes = Vec::new();
+    for name in names {
+        let (old, new) = (values(old, name), values(new, name));
+        for i in 0..old.len().max(new.len()) {
+            let (o, n) = (old.get(i).cloned(), new.get(i).cloned());
+            if o != n {
+                changes.push((name.clone(), o, n));
+            }
+        }
+    }
+    changes
+}
+
+/// Append the attribute changes of a device to its log if the policy enables it. The changes were
+/// already applied, so failures to record them are only warned about.
+pub fn record(
+    env: &dyn Environment,
+    uuid: Uuid,
+    parent: &str,
+    old: &[(String, String)],
+    new: &[(String, String)],
+    command: &str,
+) {
+    let res = || -> Result<()> {
+        if !Policy::load(env)?.change_log {
+            return Ok(());
+        }
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        // SAFETY: getuid() has no memory safety requirements and cannot fail
+        let uid = unsafe { libc::getuid() };
+        let mut lines = String::new();
+        for (attribute, old, new) in attr_changes(old, new) {
+            let entry = ChangeEntry {
+                time,
+                uid,
+                command: command.to_string(),
+                parent: parent.to_string(),
+                attribute,
+                old,
+                new,
+            };
+            lines.push_str(&serde_json::to_string(&entry)?);
+            lines.push('\n');
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let path = log_path(env, uuid);
+        debug!("Recording attribute changes in {:?}", path);
+        fs::create_dir_all(env.changelog_base())?;
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(lines.as_bytes()))
+            .with_context(|| format!("Failed to write {:?}", path))
+    }();
+    if let Err(e) = res {
+        warn!(
+            "Failed to record the attribute changes of device {}: {:#}",
+            uuid.hyphenated(),
+            e
+        );
+    }
+}
+
+/// Read the recorded changes of a device, oldest first
+pub fn read(env: &dyn Environment, uuid: Uuid) -> Result<Vec<ChangeEntry>> {
+    let path = log_path(env, uuid);
+    let contents = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Unable to read {:?}", path)),
+    };
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid entry in line {} of {:?}", i + 1, path))
+        })
+        .collect()
+}
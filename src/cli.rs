@@ -1,9 +1,48 @@
 //! Command line options for mdevctl
 
 pub use clap::Parser;
+use clap::{Args, Subcommand, ValueEnum};
 use std::path::PathBuf;
 use uuid::Uuid;
 
+/// Parse a UUID in any of the formats commonly presented by other tools: hyphenated or simple,
+/// in upper or lower case, wrapped in braces (`{UUID}`) or as URN (`urn:uuid:UUID`)
+pub fn parse_uuid(s: &str) -> Result<Uuid, String> {
+    let mut u = s.trim();
+    if let Some(prefix) = u.get(..9).filter(|p| p.eq_ignore_ascii_case("urn:uuid:")) {
+        u = &u[prefix.len()..];
+    }
+    if let Some(inner) = u.strip_prefix('{').and_then(|u| u.strip_suffix('}')) {
+        u = inner;
+    }
+    Uuid::parse_str(u).map_err(|e| {
+        format!(
+            "{} (expected a UUID like 976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9, optionally in upper \
+             case, wrapped in braces or prefixed with urn:uuid:)",
+            e
+        )
+    })
+}
+
+/// Parse an attribute filter given as `NAME=VALUE`
+pub fn parse_attr_filter(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!(
+            "invalid attribute filter '{}', expected NAME=VALUE",
+            s
+        )),
+    }
+}
+
+/// Parse a label given as `KEY=VALUE`
+pub fn parse_label(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid label '{}', expected KEY=VALUE", s)),
+    }
+}
+
 const FORCE_HELP: &str = "Force command execution even if device-specific callout script fails
 NOTE: only use this option if you are sure you know what you are doing";
 
@@ -11,14 +50,131 @@ const LIST_LONG_ABOUT: &str = "List mediated devices\n\n\
 With no options, information about the currently running mediated devices is \
 provided. Specifying 'defined' lists the configuration of defined devices, \
 regardless of their running state. This may be further reduced by specifying \
-specific 'uuid' or 'parent' devices to list. The 'dumpjson' option provides output \
+specific 'uuid' or 'parent' devices to list, or to devices with all labels given \
+by 'selector', or to devices with all attributes given by 'attr'. The 'dumpjson' option provides output \
 listing in machine readable JSON format. When a 'uuid' option is provided and the \
 result is a single device, the output contains only the JSON fields necessary to \
 recreate a config file for the device (minus attributes for listings of running \
 devices). When the verbose option is provided, the human readable listing will \
-include attributes for the device(s).";
+include attributes for the device(s). The 'watch' option keeps running and redraws \
+the listing whenever devices appear or disappear or their definitions change. The \
+'output' option lists the selected columns, or with 'table' the UUID, parent, type, \
+startup mode and state, in a table with a header line unless 'noheadings' is given, or \
+provides the output in JSON or YAML format. The 'sort' option orders the devices by UUID, \
+parent, type or state instead of by parent.";
+
+/// The columns that can be selected for the `list` output
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListColumn {
+    /// UUID of the device
+    Uuid,
+    /// parent device
+    Parent,
+    /// mediated device type
+    Type,
+    /// startup mode, auto or manual
+    Start,
+    /// active, stopped or parent-missing
+    State,
+    /// whether the device is defined
+    Defined,
+    /// number of attributes
+    Attrs,
+}
+
+/// Machine readable output formats
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Json,
+    Yaml,
+}
+
+/// The output of the `list` command selected with `--output`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListOutput {
+    /// a table of the given columns
+    Columns(Vec<ListColumn>),
+    /// a table of the most relevant columns
+    Table,
+    /// a machine readable format
+    Format(OutputFormat),
+}
+
+/// Parse the `--output` of the `list` command, either a format, `table` or a comma separated list
+/// of columns
+pub fn parse_list_output(s: &str) -> Result<ListOutput, String> {
+    if s.eq_ignore_ascii_case("table") {
+        return Ok(ListOutput::Table);
+    }
+    if let Ok(format) = OutputFormat::from_str(s, true) {
+        return Ok(ListOutput::Format(format));
+    }
+    s.split(',')
+        .map(|c| {
+            ListColumn::from_str(c.trim(), true).map_err(|_| {
+                format!(
+                    "invalid output '{}', expected table, json, yaml or columns out of {}",
+                    c,
+                    ListColumn::value_variants()
+                        .iter()
+                        .filter_map(|v| v.to_possible_value())
+                        .map(|v| v.get_name().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(ListOutput::Columns)
+}
+
+/// The keys by which the output of the `list` command can be sorted
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ListSort {
+    /// UUID of the device
+    Uuid,
+    /// parent device
+    #[default]
+    Parent,
+    /// mediated device type
+    Type,
+    /// active, stopped or parent-missing
+    State,
+}
+
+/// How the output of the `types` command is grouped
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TypesGroupBy {
+    /// parent device
+    Parent,
+    /// device API, e.g. vfio-pci
+    Api,
+}
 
-#[derive(Parser, Debug)]
+/// How the attributes that call-out scripts provide for an active device are merged into the
+/// attributes of its definition by the `define` command
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttrMerge {
+    /// add all provided attributes after the defined ones
+    Append,
+    /// replace the value of a defined attribute of the same name with the provided one
+    Replace,
+    /// keep a defined attribute and drop a provided one of the same name
+    IgnoreDuplicates,
+}
+
+/// The state a device must be in to be changed by the `modify` command
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModifyCondition {
+    /// the device is running
+    Active,
+    /// the device is not running
+    Inactive,
+    /// the device is defined
+    Defined,
+}
+
+#[derive(Parser, Debug, Default)]
 #[command(version, about = "List mediated devices", long_about = LIST_LONG_ABOUT, name = "lsmdev")]
 pub struct LsmdevOptions {
     #[arg(short, long, help = "Show defined devices")]
@@ -27,19 +183,542 @@ pub struct LsmdevOptions {
     pub dumpjson: bool,
     #[arg(short, long, help = "Print additional information about the devices")]
     pub verbose: bool,
-    #[arg(short, long, help = "List devices matching the specified UUID")]
+    #[arg(
+        long,
+        help = "Print the paths of the configuration file and sysfs directory"
+    )]
+    pub paths: bool,
+    #[arg(
+        short,
+        long,
+        value_parser = parse_uuid,
+        help = "List devices matching the specified UUID"
+    )]
+    pub uuid: Option<Uuid>,
+    #[arg(
+        short,
+        long,
+        help = "List devices associated with the specified Parent device, may be given multiple times"
+    )]
+    pub parent: Vec<String>,
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        value_parser = parse_label,
+        help = "List devices with the specified label"
+    )]
+    pub selector: Vec<(String, String)>,
+    #[arg(
+        long,
+        value_name = "NAME=VALUE",
+        value_parser = parse_attr_filter,
+        help = "List devices with an attribute NAME matching VALUE, which may contain * and ? wildcards"
+    )]
+    pub attr: Vec<(String, String)>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ListSort::Parent,
+        help = "Sort the devices by the specified key, then by parent and UUID"
+    )]
+    pub sort: ListSort,
+    #[arg(short, long, help = "Refresh the output whenever devices change")]
+    pub watch: bool,
+    #[arg(
+        long,
+        conflicts_with("watch"),
+        help = "Exit with an error if no devices are listed"
+    )]
+    pub fail_if_none: bool,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with("watch"),
+        help = "List the devices found within SECONDS, warning if the listing is incomplete"
+    )]
+    pub timeout: Option<u64>,
+    #[arg(
+        short,
+        long,
+        value_name = "COLUMNS|table|json|yaml",
+        value_parser = parse_list_output,
+        conflicts_with_all(&["dumpjson", "verbose", "watch"]),
+        help = "List the comma separated COLUMNS or the default columns in a table, or output in JSON or YAML format"
+    )]
+    pub output: Option<ListOutput>,
+    #[arg(short, long, requires("output"), help = "Do not print a header line")]
+    pub noheadings: bool,
+}
+
+/// The changes to the definition of a device of the 'modify' and 'stage modify' commands
+#[derive(Args, Debug, Default)]
+pub struct DefinitionChanges {
+    #[arg(
+        id = "type",
+        short,
+        long,
+        help = "Modify the mdev type for this device"
+    )]
+    pub mdev_type: Option<String>,
+    #[arg(
+        long,
+        requires("value"),
+        help = "Add a new attribute",
+        value_name = "attr_name"
+    )]
+    pub addattr: Option<String>,
+    #[arg(long, help = "Delete an attribute")]
+    pub delattr: bool,
+    #[arg(
+        long,
+        conflicts_with_all(&["delattr", "index", "jsonfile"]),
+        help = "Delete all attributes"
+    )]
+    pub clear_attrs: bool,
+    #[arg(long, short, help = "Index of the attribute to modify")]
+    pub index: Option<u32>,
+    #[arg(
+        long,
+        conflicts_with("delattr"),
+        requires("addattr"),
+        help = "Value for the attribute specified by --addattr",
+        value_name = "attr_value"
+    )]
+    pub value: Option<String>,
+    #[arg(
+        short,
+        long,
+        conflicts_with_all(&["index", "value"]),
+        help = "Device will be started automatically"
+    )]
+    pub auto: bool,
+    #[arg(
+        short,
+        long,
+        conflicts_with_all(&["index", "value"]),
+        help = "Device must be started manually"
+    )]
+    pub manual: bool,
+    #[arg(
+        long,
+        conflicts_with_all(&["type", "index", "value"]),
+        help = "Specify device details in JSON format"
+    )]
+    pub jsonfile: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        value_parser = parse_label,
+        conflicts_with("jsonfile"),
+        help = "Assign a label to the device, replacing any value of the same key"
+    )]
+    pub label: Vec<(String, String)>,
+    #[arg(
+        long,
+        value_name = "KEY",
+        conflicts_with("jsonfile"),
+        help = "Remove the label KEY from the device"
+    )]
+    pub remove_label: Vec<String>,
+}
+
+/// The arguments of the 'define' command
+#[derive(Args, Debug)]
+pub struct DefineArgs {
+    #[arg(
+        short,
+        long,
+        value_parser = parse_uuid,
+        required_unless_present_any(&["parent", "path"]),
+        help = "Assign UUID to the device"
+    )]
     pub uuid: Option<Uuid>,
     #[arg(
         short,
         long,
-        help = "List devices associated with the specified Parent device"
+        help = "Automatically start device on parent availability"
+    )]
+    pub auto: bool,
+    #[arg(
+        short,
+        long,
+        required_unless_present_any(&["uuid", "path"]),
+        help = "Specify the parent of the device"
     )]
     pub parent: Option<String>,
+    #[arg(id = "type", short, long, help = "Specify the mdev type of the device")]
+    pub mdev_type: Option<String>,
+    #[arg(
+        long,
+        value_name = "SYSFS_PATH",
+        help = "Specify the device or the parent and type by their sysfs path"
+    )]
+    pub path: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with_all(&["type", "auto"]),
+        help = "Specify device details in JSON format"
+    )]
+    pub jsonfile: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        value_parser = parse_label,
+        conflicts_with("jsonfile"),
+        help = "Assign a label to the device"
+    )]
+    pub label: Vec<(String, String)>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with("uuid"),
+        help = "Define N devices with generated UUIDs, expanding {index} and {uuid} in attribute values"
+    )]
+    pub count: u32,
+    #[arg(
+        long,
+        help = "Expand {index} and {uuid} in attribute values also for a single device"
+    )]
+    pub expand_templates: bool,
+    #[arg(
+        long,
+        help = "Start the device right away if its parent has capacity for it"
+    )]
+    pub now: bool,
+    #[arg(
+        long,
+        conflicts_with("now"),
+        help = "Print the definition in the format of 'export' instead of saving it"
+    )]
+    pub print_only: bool,
+    #[arg(
+        long,
+        value_enum,
+        help = "Merge the attributes that call-out scripts provide for an active device into \
+                the defined ones, also with --jsonfile [default: append]"
+    )]
+    pub attr_merge: Option<AttrMerge>,
+    #[arg(
+        short,
+        long,
+        help = FORCE_HELP
+    )]
+    pub force: bool,
+}
+
+impl Default for DefineArgs {
+    fn default() -> Self {
+        DefineArgs {
+            uuid: None,
+            auto: false,
+            parent: None,
+            mdev_type: None,
+            path: None,
+            jsonfile: None,
+            label: Vec::new(),
+            count: 1,
+            expand_templates: false,
+            now: false,
+            print_only: false,
+            attr_merge: None,
+            force: false,
+        }
+    }
+}
+
+/// The arguments of the 'modify' command
+#[derive(Args, Debug, Default)]
+#[command(
+    group(
+        clap::ArgGroup::new("modify")
+            .required(true)
+            .args(&[
+                "auto",
+                "manual",
+                "addattr",
+                "delattr",
+                "regenerate_attrs",
+                "jsonfile",
+                "label",
+                "remove_label",
+            ]),
+    ),
+)]
+pub struct ModifyArgs {
+    #[arg(
+        short,
+        long,
+        value_parser = parse_uuid,
+        required_unless_present("path"),
+        help = "UUID of the mdev to modify"
+    )]
+    pub uuid: Option<Uuid>,
+    #[arg(short, long, help = "Parent of the mdev to modify")]
+    pub parent: Option<String>,
+    #[arg(
+        long,
+        value_name = "SYSFS_PATH",
+        help = "Sysfs path of the mdev to modify"
+    )]
+    pub path: Option<PathBuf>,
+    #[command(flatten)]
+    pub changes: DefinitionChanges,
+    #[arg(
+        long,
+        conflicts_with_all(&["clear_attrs", "live", "jsonfile"]),
+        help = "Replace the attributes provided by call-out scripts when the device was \
+                defined with the ones they provide for the active device now"
+    )]
+    pub regenerate_attrs: bool,
+    #[arg(
+        short,
+        long,
+        requires("clear_attrs"),
+        help = "Do not ask for confirmation to delete all attributes"
+    )]
+    pub yes: bool,
+    #[arg(
+        short,
+        long,
+        conflicts_with_all(&[
+            "type",
+            "clear_attrs",
+            "index",
+            "value",
+            "label",
+            "remove_label"
+        ]),
+        requires("jsonfile"),
+        help = "Modify the running device definition only unless used together with defined option"
+    )]
+    pub live: bool,
+    #[arg(short, long, help = "Modify the stored device definition")]
+    pub defined: bool,
+    #[arg(
+        short,
+        long,
+        help = FORCE_HELP
+    )]
+    pub force: bool,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "STATE",
+        help = "Only modify the device if it is in STATE, and succeed without changes otherwise"
+    )]
+    pub only_if: Option<ModifyCondition>,
+}
+
+/// The arguments of the 'start' command
+#[derive(Args, Debug, Default)]
+pub struct StartArgs {
+    #[arg(
+        short,
+        long,
+        value_parser = parse_uuid,
+        required_unless_present_any(&["parent", "path", "all_defined"]),
+        help = "UUID of the device to start"
+    )]
+    pub uuid: Option<Uuid>,
+    #[arg(
+        short,
+        long,
+        required_unless_present_any(&["uuid", "path", "all_defined"]),
+        help = "Parent of the device to start"
+    )]
+    pub parent: Option<String>,
+    #[arg(id = "type", short, long, help = "Mdev type of the device to start")]
+    pub mdev_type: Option<String>,
+    #[arg(
+        long,
+        value_name = "SYSFS_PATH",
+        help = "Sysfs path of the parent's mdev type to start the device with"
+    )]
+    pub path: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with("type"),
+        help = "Details of the device to be started, in JSON format"
+    )]
+    pub jsonfile: Option<PathBuf>,
+    #[arg(
+        short,
+        long,
+        help = FORCE_HELP
+    )]
+    pub force: bool,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        num_args = 0..=1,
+        default_missing_value = "30",
+        help = "Wait up to SECONDS (default 30) until the device is usable"
+    )]
+    pub wait: Option<u64>,
+    #[arg(
+        long,
+        requires("uuid"),
+        help = "Do nothing if the device is already active on the parent"
+    )]
+    pub skip_active: bool,
+    #[arg(
+        long,
+        conflicts_with_all(&["uuid", "parent", "type", "path", "jsonfile", "wait", "skip_active"]),
+        help = "Start all defined devices that are not active"
+    )]
+    pub all_defined: bool,
+    #[arg(
+        long,
+        requires("all_defined"),
+        conflicts_with_all(&["force", "strict_parent_check"]),
+        help = "Only start the defined devices of present parents that are set to start \
+                automatically, like start-all-parents"
+    )]
+    pub auto_only: bool,
+    #[arg(
+        long,
+        help = "Check the active instances of the type against the maximum reported by the parent driver, if any"
+    )]
+    pub strict_parent_check: bool,
+    #[arg(
+        long,
+        requires("all_defined"),
+        conflicts_with("keep_going"),
+        help = "Cancel starting the remaining devices once a device fails to start"
+    )]
+    pub fail_fast: bool,
+    #[arg(
+        long,
+        requires("all_defined"),
+        help = "Start the remaining devices when a device fails to start, the default"
+    )]
+    pub keep_going: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with("all_defined"),
+        help = "Record how the device was started in a JSON report, for bug reports"
+    )]
+    pub capture_profile: Option<PathBuf>,
+}
+
+/// The options of the commands that start the devices of parents automatically
+#[derive(Args, Debug)]
+pub struct AutostartArgs {
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        help = "Abandon starting a device after SECONDS, 0 waits indefinitely"
+    )]
+    pub timeout: u64,
+    #[arg(
+        long,
+        conflicts_with("keep_going"),
+        help = "Cancel starting the remaining devices of a parent once a device fails to start"
+    )]
+    pub fail_fast: bool,
+    #[arg(
+        long,
+        help = "Start the remaining devices when a device fails to start, the default"
+    )]
+    pub keep_going: bool,
+    #[arg(
+        short,
+        long,
+        value_name = "N",
+        help = "Start up to N devices of a parent concurrently, overriding autostart_jobs of the \
+                policy"
+    )]
+    pub jobs: Option<usize>,
+    #[arg(
+        long,
+        visible_alias = "json",
+        help = "Output the result of every device in json format"
+    )]
+    pub dumpjson: bool,
+}
+
+impl Default for AutostartArgs {
+    fn default() -> Self {
+        AutostartArgs {
+            timeout: 60,
+            fail_fast: false,
+            keep_going: false,
+            jobs: None,
+            dumpjson: false,
+        }
+    }
+}
+
+/// The arguments of the 'callout-test' command
+#[derive(Args, Debug, Default)]
+pub struct CalloutTestArgs {
+    #[arg(
+        short,
+        long,
+        value_parser = parse_uuid,
+        required_unless_present("parent"),
+        help = "UUID of the device, generated if omitted"
+    )]
+    pub uuid: Option<Uuid>,
+    #[arg(
+        short,
+        long,
+        required_unless_present("uuid"),
+        help = "Parent of the device"
+    )]
+    pub parent: Option<String>,
+    #[arg(id = "type", short, long, help = "Mdev type of the device")]
+    pub mdev_type: Option<String>,
+    #[arg(
+        long,
+        help = "Script to invoke instead of the script found for the device"
+    )]
+    pub script: Option<PathBuf>,
+    #[arg(
+        long,
+        value_parser = ["pre", "post", "live", "get", "notify"],
+        help = "Event to invoke the script for"
+    )]
+    pub event: String,
+    #[arg(
+        long,
+        value_parser = [
+            "start", "stop", "define", "undefine", "modify", "attributes", "capabilities"
+        ],
+        help = "Action to invoke the script for"
+    )]
+    pub action: String,
+    #[arg(
+        long,
+        value_parser = ["none", "success", "failure"],
+        default_value = "none",
+        help = "State of the action passed to the script"
+    )]
+    pub state: String,
+    #[arg(long, help = "File to pass to the script on standard input instead")]
+    pub jsonfile: Option<PathBuf>,
 }
 
 // command-line argument definitions.
 #[derive(Parser)]
 #[command(version, about = "A mediated device management utility for Linux")]
+pub struct Mdevctl {
+    #[arg(
+        long,
+        global = true,
+        help = "Fail instead of warning when several callout scripts support the same device"
+    )]
+    pub fail_on_ambiguous_callout: bool,
+    #[command(subcommand)]
+    pub command: MdevctlCommands,
+}
+
+#[derive(Subcommand)]
 pub enum MdevctlCommands {
     #[command(
         about = "Define a persistent mediated device",
@@ -50,35 +729,82 @@ pub enum MdevctlCommands {
                 attributes are provided via the file.\n\n\
                 Running devices are unaffected by this command."
     )]
-    Define {
+    Define(DefineArgs),
+
+    #[command(
+        about = "Undefine a persistent mediated device",
+        long_about = "Undefine, or remove a config for an mdev device\n\n\
+                If a UUID exists for multiple parents, all will be removed unless a parent is
+                specified. \n\n\
+                Running devices are unaffected by this command."
+    )]
+    Undefine {
         #[arg(
             short,
             long,
-            required_unless_present("parent"),
-            help = "Assign UUID to the device"
+            value_parser = parse_uuid,
+            required_unless_present_any(&["path", "all"]),
+            help = "UUID of the device to be undefined"
         )]
         uuid: Option<Uuid>,
+        #[arg(short, long, help = "Parent of the device to be undefined")]
+        parent: Option<String>,
+        #[arg(
+            long,
+            value_name = "SYSFS_PATH",
+            help = "Sysfs path of the device to be undefined"
+        )]
+        path: Option<PathBuf>,
+        #[arg(
+            long,
+            requires("parent"),
+            conflicts_with_all(&["uuid", "path"]),
+            help = "Undefine all devices of the parent"
+        )]
+        all: bool,
         #[arg(
             short,
             long,
-            help = "Automatically start device on parent availability"
+            help = FORCE_HELP
         )]
-        auto: bool,
+        force: bool,
+    },
+
+    #[command(
+        about = "Copy the definition of a mediated device",
+        long_about = "Copy the definition of a mediated device\n\n\
+                A copy of the definition of the device is defined with a new UUID, which is \
+                generated and printed unless it is given with the 'new-uuid' option. The \
+                'parent' option further identifies the source device if its UUID is not unique. \
+                The copy is defined on the same parent unless 'new-parent' is given. Labels \
+                that must be unique are not copied, they can be assigned with 'label'."
+    )]
+    Clone {
         #[arg(
             short,
             long,
-            required_unless_present("uuid"),
-            help = "Specify the parent of the device"
+            value_parser = parse_uuid,
+            help = "UUID of the device to be copied"
         )]
+        uuid: Uuid,
+        #[arg(short, long, help = "Parent of the device to be copied")]
         parent: Option<String>,
-        #[arg(id = "type", short, long, help = "Specify the mdev type of the device")]
-        mdev_type: Option<String>,
         #[arg(
             long,
-            conflicts_with_all(&["type", "auto"]),
-            help = "Specify device details in JSON format"
+            value_name = "UUID",
+            value_parser = parse_uuid,
+            help = "Assign UUID to the copy"
         )]
-        jsonfile: Option<PathBuf>,
+        new_uuid: Option<Uuid>,
+        #[arg(long, value_name = "PARENT", help = "Define the copy on PARENT")]
+        new_parent: Option<String>,
+        #[arg(
+            long,
+            value_name = "KEY=VALUE",
+            value_parser = parse_label,
+            help = "Assign a label to the copy, replacing the value of the source device"
+        )]
+        label: Vec<(String, String)>,
         #[arg(
             short,
             long,
@@ -88,17 +814,32 @@ pub enum MdevctlCommands {
     },
 
     #[command(
-        about = "Undefine a persistent mediated device",
-        long_about = "Undefine, or remove a config for an mdev device\n\n\
-                If a UUID exists for multiple parents, all will be removed unless a parent is
-                specified. \n\n\
-                Running devices are unaffected by this command."
+        visible_alias = "rename",
+        about = "Change the UUID of a defined mediated device",
+        long_about = "Change the UUID of a defined mediated device\n\n\
+                The definition is moved to the new UUID in a single step, keeping its \
+                configuration unchanged. The 'parent' option further identifies a UUID if it \
+                is not unique. The device must not be active. Callout scripts are invoked for \
+                the 'modify' action with the new UUID, and the previous UUID is passed in the \
+                MDEVCTL_OLD_UUID environment variable."
     )]
-    Undefine {
-        #[arg(short, long, help = "UUID of the device to be undefined")]
+    SetUuid {
+        #[arg(
+            short,
+            long,
+            value_parser = parse_uuid,
+            help = "UUID of the device to be changed"
+        )]
         uuid: Uuid,
-        #[arg(short, long, help = "Parent of the device to be undefined")]
+        #[arg(short, long, help = "Parent of the device to be changed")]
         parent: Option<String>,
+        #[arg(
+            long,
+            value_name = "UUID",
+            value_parser = parse_uuid,
+            help = "New UUID of the device"
+        )]
+        new_uuid: Uuid,
         #[arg(
             short,
             long,
@@ -118,75 +859,95 @@ pub enum MdevctlCommands {
                 format that is accepted by the attribute. Upon device start, mdevctl will go \
                 through each attribute in order, writing the value into the corresponding sysfs \
                 attribute for the device. The startup mode of the device can also be selected, auto \
-                or manual. Alternatively, the 'jsonfile' option may be used to replace the startup \
+                or manual. Labels can be assigned with 'label' and removed with 'remove-label'. \
+                Alternatively, the 'jsonfile' option may be used to replace the startup \
                 mode and any attributes with the contents of the specified file.\n\n\
-                Running devices are unaffected by this command.",
-        group(
-            clap::ArgGroup::new("modify")
-                .required(true)
-                .args(&["auto", "manual", "addattr", "delattr", "jsonfile"]),
-        ),
+                Running devices are unaffected by this command."
     )]
-    Modify {
-        #[arg(short, long, help = "UUID of the mdev to modify")]
+    Modify(ModifyArgs),
+    #[command(
+        about = "Edit the definition of a mediated device",
+        long_about = "Edit the definition of a mediated device\n\n\
+                The JSON definition of the device is opened in the editor named by the VISUAL or \
+                EDITOR environment variable, or vi if neither is set. Once the editor exits, the \
+                edited definition is validated and applied like with the 'modify' command, \
+                including the call-out scripts. An invalid definition is not applied, but kept \
+                in a temporary file so that the changes are not lost."
+    )]
+    Edit {
+        #[arg(short, long, value_parser = parse_uuid, help = "UUID of the mdev to edit")]
         uuid: Uuid,
-        #[arg(short, long, help = "Parent of the mdev to modify")]
+        #[arg(short, long, help = "Parent of the mdev to edit")]
         parent: Option<String>,
-        #[arg(
-            id = "type",
-            short,
-            long,
-            help = "Modify the mdev type for this device"
-        )]
-        mdev_type: Option<String>,
-        #[arg(
-            long,
-            requires("value"),
-            help = "Add a new attribute",
-            value_name = "attr_name"
-        )]
-        addattr: Option<String>,
-        #[arg(long, help = "Delete an attribute")]
-        delattr: bool,
-        #[arg(long, short, help = "Index of the attribute to modify")]
-        index: Option<u32>,
-        #[arg(
-            long,
-            conflicts_with("delattr"),
-            requires("addattr"),
-            help = "Value for the attribute specified by --addattr",
-            value_name = "attr_value"
-        )]
-        value: Option<String>,
         #[arg(
             short,
             long,
-            conflicts_with_all(&["index", "value"]),
-            help = "Device will be started automatically")]
-        auto: bool,
-        #[arg(
-            short,
-            long,
-            conflicts_with_all(&["index", "value"]),
-            help = "Device must be started manually"
-        )]
-        manual: bool,
-        #[arg(
-            short,
-            long,
-            conflicts_with_all(&["type", "index", "value"]),
-            requires("jsonfile"),
-            help = "Modify the running device definition only unless used together with defined option"
+            help = FORCE_HELP
         )]
-        live: bool,
-        #[arg(short, long, help = "Modify the stored device definition")]
-        defined: bool,
+        force: bool,
+    },
+    #[command(
+        about = "Show the recorded attribute changes of a mediated device",
+        long_about = "Show the recorded attribute changes of a mediated device\n\n\
+                Attribute changes of the 'modify' command are only recorded if 'change_log' is \
+                enabled in the policy. Each change is shown with its time, the user and command \
+                that made it and the old and new value of the attribute."
+    )]
+    Log {
+        #[arg(short, long, value_parser = parse_uuid, help = "UUID of the mdev")]
+        uuid: Uuid,
+        #[arg(short, long, help = "Only show the changes made on this parent")]
+        parent: Option<String>,
         #[arg(
             long,
-            conflicts_with_all(&["type", "index", "value"]),
-            help = "Specify device details in JSON format"
+            visible_alias = "json",
+            help = "Output the changes in json format"
         )]
-        jsonfile: Option<PathBuf>,
+        dumpjson: bool,
+    },
+    #[command(
+        about = "Read or change the attributes of a running mediated device",
+        long_about = "Read or change the attributes of a running mediated device\n\n\
+                Attributes are read from sysfs. Changes are applied by the call-out script like \
+                with 'modify --live', which requires a script that supports live changes. The \
+                definition of the device is not changed."
+    )]
+    Attr {
+        #[command(subcommand)]
+        command: AttrCommands,
+    },
+    #[command(
+        about = "Stage changes to device definitions",
+        long_about = "Stage changes to device definitions\n\n\
+                Staged changes are kept apart from the device definitions until they are applied \
+                to all devices at once with the 'commit' command, e.g. during a maintenance \
+                window. Until then they can be reviewed with 'stage list' and dropped with \
+                'stage discard'."
+    )]
+    Stage {
+        #[command(subcommand)]
+        command: StageCommands,
+    },
+    #[command(
+        about = "Back up and restore the state of mdevctl",
+        long_about = "Back up and restore the state of mdevctl\n\n\
+                The state archive contains the device definitions, the devices running at the \
+                time of the export, the configuration files of mdevctl and the staged changes, \
+                e.g. to restore them after reinstalling the host."
+    )]
+    State {
+        #[command(subcommand)]
+        command: StateCommands,
+    },
+    #[command(
+        about = "Apply all staged changes to the device definitions",
+        long_about = "Apply all staged changes to the device definitions\n\n\
+                The staged changes of each device are applied like with the 'modify' command, \
+                including the call-out scripts. If the changes of any device cannot be applied, \
+                the definitions of all devices are restored and the changes remain staged.\n\n\
+                Running devices are unaffected by this command."
+    )]
+    Commit {
         #[arg(
             short,
             long,
@@ -206,40 +967,63 @@ pub enum MdevctlCommands {
                 'jsonfile' may replace the 'type' specification and also include additional \
                 attributes to be applied to the started device."
     )]
-    Start {
+    Start(StartArgs),
+    #[command(
+        about = "Stop a mediated device",
+        long_about = "Stop a mediated device\n\n\
+                While the device is in use, e.g. by a VM that is shutting down, its removal is \
+                retried for the stop_timeout of its definition. With --kill-users, the processes \
+                using the device are then sent SIGTERM, and SIGKILL if it is still busy."
+    )]
+    Stop {
         #[arg(
             short,
             long,
-            required_unless_present("parent"),
-            help = "UUID of the device to start"
+            value_parser = parse_uuid,
+            required_unless_present("path"),
+            help = "UUID of the device to stop"
         )]
         uuid: Option<Uuid>,
+        #[arg(
+            long,
+            value_name = "SYSFS_PATH",
+            help = "Sysfs path of the device to stop"
+        )]
+        path: Option<PathBuf>,
         #[arg(
             short,
             long,
-            required_unless_present("uuid"),
-            help = "Parent of the device to start"
+            help = FORCE_HELP
         )]
-        parent: Option<String>,
-        #[arg(id = "type", short, long, help = "Mdev type of the device to start")]
-        mdev_type: Option<String>,
+        force: bool,
         #[arg(
             long,
-            conflicts_with("type"),
-            help = "Details of the device to be started, in JSON format"
+            help = "Terminate the processes using the device if it is still busy"
         )]
-        jsonfile: Option<PathBuf>,
+        kill_users: bool,
+    },
+    #[command(
+        about = "Restart a mediated device",
+        long_about = "Restart a mediated device\n\n\
+                Stop the running device and start it again. If the device is defined on its \
+                current parent, it is started from the stored definition, so that changes to the \
+                definition take effect. Otherwise it is started again with its current type."
+    )]
+    Restart {
         #[arg(
             short,
             long,
-            help = FORCE_HELP
+            value_parser = parse_uuid,
+            required_unless_present("path"),
+            help = "UUID of the device to restart"
         )]
-        force: bool,
-    },
-    #[command(about = "Stop a mediated device")]
-    Stop {
-        #[arg(short, long, help = "UUID of the device to stop")]
-        uuid: Uuid,
+        uuid: Option<Uuid>,
+        #[arg(
+            long,
+            value_name = "SYSFS_PATH",
+            help = "Sysfs path of the device to restart"
+        )]
+        path: Option<PathBuf>,
         #[arg(
             short,
             long,
@@ -256,20 +1040,564 @@ pub enum MdevctlCommands {
         about = "List available mediated device types",
         long_about = "List available mediated device types\n\n\
                 Specifying a 'parent' lists only the types provided by the given parent device. \
-                The 'dumpjson' option provides output in machine readable JSON format."
+                The 'device-api' option lists only the types with the given device API. The \
+                'dumpjson' option provides output in machine readable JSON format, and the \
+                'output' option in JSON or YAML format. The 'group-by' option groups the types by \
+                their device API instead of their parent."
     )]
     Types {
-        #[arg(short, long, help = "Show supported types for the specified parent")]
-        parent: Option<String>,
+        #[arg(
+            short,
+            long,
+            help = "Show supported types for the specified parent, may be given multiple times"
+        )]
+        parent: Vec<String>,
+        #[arg(
+            long,
+            value_name = "API",
+            value_parser = ["vfio-pci", "vfio-ccw", "vfio-ap"],
+            help = "Show only types with the specified device API"
+        )]
+        device_api: Option<String>,
         #[arg(long, help = "Output mdev types list in JSON format")]
         dumpjson: bool,
+        #[arg(
+            short,
+            long,
+            value_enum,
+            value_name = "FORMAT",
+            conflicts_with("dumpjson"),
+            help = "Output mdev types list in the specified format"
+        )]
+        output: Option<OutputFormat>,
+        #[arg(
+            long,
+            value_enum,
+            default_value_t = TypesGroupBy::Parent,
+            help = "Group the types by parent device or by device API"
+        )]
+        group_by: TypesGroupBy,
+        #[arg(
+            long,
+            help = "Show the PCI vendor and device IDs and names of the parent devices"
+        )]
+        pci_ids: bool,
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "List the types found within SECONDS, warning if the listing is incomplete"
+        )]
+        timeout: Option<u64>,
+    },
+    #[command(
+        about = "Estimate the resources used by instances of an mdev type",
+        long_about = "Estimate the resources used by instances of an mdev type\n\n\
+                Computes the resources that N instances of the type use on the parent \
+                device, where the driver states them in the description of the type, e.g. the \
+                framebuffer size of NVIDIA vGPU types or the graphics memory of Intel GVT-g \
+                types. Also shows the instances that remain available on the parent once the \
+                requested instances and the defined devices of the type that are not active \
+                are started, to help plan how many devices to define."
+    )]
+    Estimate {
+        #[arg(short, long, help = "Parent of the instances")]
+        parent: String,
+        #[arg(id = "type", short, long, help = "Mdev type of the instances")]
+        mdev_type: String,
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u32).range(1..),
+            help = "Estimate for N instances"
+        )]
+        count: u32,
+        #[arg(
+            long,
+            visible_alias = "json",
+            help = "Output the estimate in json format"
+        )]
+        dumpjson: bool,
+    },
+    #[command(
+        about = "Reconcile the desired and actual state of defined devices",
+        long_about = "Reconcile the desired and actual state of defined devices\n\n\
+                Defined devices that are started automatically should always be running. Such \
+                devices that are not running are started if their parent device is present. \
+                The 'report' option only reports whether each defined device is in sync with \
+                its desired state and why it is not, without changing any device. The \
+                'dumpjson' option provides the report in machine readable JSON format. The \
+                'changed-only' option lists the devices that were started or failed to start, \
+                or with 'report' the devices that are out of sync, followed by their counts, and \
+                prints nothing if all devices are in sync."
+    )]
+    Reconcile {
+        #[arg(short, long, help = "Reconcile devices of the specified parent")]
+        parent: Option<String>,
+        #[arg(long, help = "Only report the reconciliation status of devices")]
+        report: bool,
+        #[arg(long, requires("report"), help = "Output the report in JSON format")]
+        dumpjson: bool,
+        #[arg(
+            long,
+            help = "Only list the devices that are out of sync or were changed"
+        )]
+        changed_only: bool,
+    },
+    #[command(
+        about = "Export all defined devices",
+        long_about = "Export all defined devices\n\n\
+                The definitions of all devices are written as a single JSON document, grouped \
+                by parent device. The document can be imported with the 'import' command, \
+                e.g. on another host."
+    )]
+    Export,
+    #[command(
+        about = "Import defined devices",
+        long_about = "Import defined devices\n\n\
+                Define all devices of a JSON document created by the 'export' command. Nothing \
+                is imported if any device is already defined on the same parent, unless the \
+                'force' option is given to overwrite the existing definitions."
+    )]
+    Import {
+        #[arg(
+            value_name = "FILE",
+            help = "Read the devices from FILE, or '-' for stdin"
+        )]
+        file: PathBuf,
+        #[arg(short, long, help = "Overwrite existing definitions")]
+        force: bool,
+        #[arg(
+            long,
+            conflicts_with("keep_going"),
+            help = "Cancel importing the remaining devices once a device fails to import, the default"
+        )]
+        fail_fast: bool,
+        #[arg(
+            long,
+            help = "Import the remaining devices when a device fails to import"
+        )]
+        keep_going: bool,
+    },
+    #[command(
+        about = "Generate systemd units for the devices that start automatically",
+        long_about = "Generate systemd units for the devices that start automatically\n\n\
+                Write a unit mdev@UUID.service to DIR for every defined device that is set to \
+                start automatically, which starts the device after the device unit of its parent \
+                and stops it again. Units that were generated before for devices that no longer \
+                start automatically are removed, unless the 'parent' option is given."
+    )]
+    GenerateSystemd {
+        #[arg(
+            value_name = "DIR",
+            help = "Write the units to DIR, e.g. /etc/systemd/system"
+        )]
+        dir: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "Only generate units for the devices of this parent"
+        )]
+        parent: Option<String>,
+    },
+    #[command(
+        about = "Generate udev rules for the parents of devices that start automatically",
+        long_about = "Generate udev rules for the parents of devices that start automatically\n\n\
+                Write udev rules to DIR/60-mdevctl.rules that start the devices of exactly the \
+                parent devices that have devices set to start automatically once the parent is \
+                registered, replacing the rules shipped with mdevctl, which match every parent. \
+                The rules need to be generated again when the devices that start automatically \
+                change. Rules that were not generated by mdevctl are not overwritten."
+    )]
+    GenerateUdevRules {
+        #[arg(
+            value_name = "DIR",
+            default_value = "/etc/udev/rules.d",
+            help = "Write the rules to DIR"
+        )]
+        dir: PathBuf,
+        #[arg(
+            long = "stdout",
+            help = "Write the rules to standard output instead, e.g. for packaging"
+        )]
+        to_stdout: bool,
+    },
+    #[command(
+        about = "Check the stored device definitions for problems",
+        long_about = "Check the stored device definitions for problems\n\n\
+                Every device definition is checked for invalid contents, parent devices that \
+                are not present and mdev types that are not supported by their parent. For \
+                active devices, the attributes are also checked against sysfs. The command \
+                fails if any problem is found. The 'dumpjson' option provides the results in \
+                machine readable JSON format."
+    )]
+    Validate {
+        #[arg(long, help = "Output the results in JSON format")]
+        dumpjson: bool,
+    },
+    #[command(
+        about = "Collect information about mediated devices for a bug report",
+        long_about = "Collect information about mediated devices for a bug report\n\n\
+                The support bundle is a gzip compressed tar archive containing the device \
+                definitions, the output of the 'list' and 'types' commands in JSON format, the \
+                installed callout and notification scripts, recent journal messages and a \
+                snapshot of the relevant sysfs attributes. The host name and machine ID are \
+                redacted from all collected data."
+    )]
+    SupportBundle {
+        #[arg(
+            short,
+            long,
+            value_name = "FILE",
+            help = "Write the support bundle to FILE, e.g. mdevctl.tar.gz"
+        )]
+        output: PathBuf,
+    },
+    #[command(
+        about = "Report the host's support for mediated devices",
+        long_about = "Report the host's support for mediated devices\n\n\
+                Lists the kernel version, whether the kernel modules for mediated devices and \
+                VFIO are loaded, whether the IOMMU is enabled along with the related kernel \
+                parameters, and the parent devices that support mediated devices. These are the \
+                first things to check when the 'types' command lists nothing."
+    )]
+    HostInfo {
+        #[arg(
+            long,
+            visible_alias = "json",
+            help = "Output the host information in json format"
+        )]
+        dumpjson: bool,
+    },
+    #[command(
+        about = "Diagnose problems of the mdevctl installation",
+        long_about = "Diagnose problems of the mdevctl installation\n\n\
+                Checks that the mdev bus exists, that the required directories exist and are \
+                writable, that the call-out and notification scripts are executable and that \
+                the call-out scripts respond to capability queries for the defined devices, and \
+                lists the parent devices that support mediated devices. Each check passes, \
+                warns or fails, and the command fails if any check fails."
+    )]
+    Doctor {
+        #[arg(
+            long,
+            visible_alias = "json",
+            help = "Output the diagnostics in json format"
+        )]
+        dumpjson: bool,
+    },
+    #[command(about = "Inspect the installed call-out and notification scripts")]
+    Callouts {
+        #[command(subcommand)]
+        command: CalloutsCommands,
+    },
+    #[command(
+        about = "Invoke a call-out script for a single event and show the exchange",
+        long_about = "Invoke a call-out script for a single event and show the exchange\n\n\
+                Invokes the script given with --script, or the script that supports versioning \
+                for the device, for the event and action as mdevctl would, and prints the \
+                arguments and standard input passed to the script along with its standard output, \
+                standard error and exit code. Standard input is the device configuration or, for \
+                the capabilities action, the versioning JSON, unless a file is given with \
+                --jsonfile. Nothing else is done, e.g. no device is started. The device is the \
+                defined device with the UUID or the device given by parent and type."
+    )]
+    CalloutTest(CalloutTestArgs),
+    #[command(
+        about = "Print an event whenever a mediated device or a parent device appears or disappears",
+        long_about = "Print an event whenever a mediated device or a parent device appears or \
+                disappears\n\n\
+                Runs until mdevctl is interrupted. Each event is printed on a line of its own, \
+                e.g. 'mdev-added UUID PARENT TYPE', 'mdev-removed UUID PARENT TYPE', \
+                'parent-added PARENT' or 'parent-removed PARENT', where parent devices are those \
+                that support mediated devices."
+    )]
+    Monitor {
+        #[arg(
+            long,
+            visible_alias = "json",
+            help = "Output each event as a JSON object on a line of its own"
+        )]
+        dumpjson: bool,
+    },
+    #[command(
+        about = "Migrate legacy configuration and script locations to the current layout",
+        long_about = "Migrate legacy configuration and script locations to the current layout\n\n\
+                Moves call-out and notification scripts from /etc/mdevctl.d/scripts.d to \
+                /usr/lib/mdevctl/scripts.d and renames device definitions that are not named by \
+                the canonical lower case UUID. Files that would overwrite a different file are \
+                skipped. Each action is reported."
+    )]
+    MigrateConfig {
+        #[arg(long, help = "Report the actions without changing anything")]
+        dry_run: bool,
+    },
+    #[command(
+        about = "Print the version and capabilities of mdevctl",
+        long_about = "Print the version and capabilities of mdevctl\n\n\
+                Lists the supported call-out protocol versions, the optional features mdevctl \
+                was built with, the available commands and the directories used for \
+                configuration files and scripts, so that management tools can detect what \
+                mdevctl supports."
+    )]
+    Version {
+        #[arg(
+            long,
+            visible_alias = "json",
+            help = "Output the version and capabilities in json format"
+        )]
+        dumpjson: bool,
+    },
+    #[command(
+        about = "Start the devices of all present parents that start automatically",
+        long_about = "Start the devices of all present parents that start automatically\n\n\
+                Start the devices that are set to start automatically on every parent device \
+                that is present, like the udev rules of mdevctl do once a parent is registered, \
+                e.g. from a single service at boot on systems where rules for every parent are \
+                impractical. Devices that are already active are skipped. The result is reported \
+                for every device and the command fails if any device could not be started. \
+                'start --all-defined --auto-only' does the same."
+    )]
+    StartAllParents {
+        #[command(flatten)]
+        args: AutostartArgs,
     },
     #[command(hide = true)]
-    StartParentMdevs { parent: String },
+    StartParentMdevs {
+        parent: String,
+        #[command(flatten)]
+        args: AutostartArgs,
+        #[arg(
+            long,
+            conflicts_with_all(&["timeout", "fail_fast", "keep_going", "jobs"]),
+            help = "Print which devices would be started or skipped without starting them"
+        )]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AttrCommands {
+    #[command(about = "Print the value of an attribute of a running mediated device")]
+    Get {
+        #[arg(short, long, value_parser = parse_uuid, help = "UUID of the mdev")]
+        uuid: Uuid,
+        #[arg(short, long, help = "Parent of the mdev")]
+        parent: Option<String>,
+        #[arg(value_name = "NAME", help = "Name of the attribute")]
+        name: String,
+    },
+    #[command(
+        about = "Change an attribute of a running mediated device",
+        long_about = "Change an attribute of a running mediated device\n\n\
+                The call-out script receives the attributes of the definition of the device, in \
+                which the attribute is replaced, or added if the definition doesn't have it."
+    )]
+    Set {
+        #[arg(short, long, value_parser = parse_uuid, help = "UUID of the mdev")]
+        uuid: Uuid,
+        #[arg(short, long, help = "Parent of the mdev")]
+        parent: Option<String>,
+        #[arg(value_name = "NAME", help = "Name of the attribute")]
+        name: String,
+        #[arg(value_name = "VALUE", help = "New value of the attribute")]
+        value: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CalloutsCommands {
+    #[command(
+        about = "List the installed scripts and the device types they support",
+        long_about = "List the installed scripts and the device types they support\n\n\
+                Call-out scripts are listed in the order in which they are searched and queried \
+                for their capabilities for each type of the defined devices and of the present \
+                parent devices, showing the types each script supports, with or without \
+                versioning. Notification scripts are listed as well, since they are invoked for \
+                all devices."
+    )]
+    List {
+        #[arg(
+            long,
+            visible_alias = "json",
+            help = "Output the scripts in json format"
+        )]
+        dumpjson: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum StageCommands {
+    #[command(
+        about = "Stage changes to the definition of a mediated device",
+        long_about = "Stage changes to the definition of a mediated device\n\n\
+                Accepts the same changes as the 'modify' command. They are applied on top of any \
+                changes already staged for the device.",
+        group(
+            clap::ArgGroup::new("modify")
+                .required(true)
+                .multiple(true)
+                .args(&[
+                    "type",
+                    "auto",
+                    "manual",
+                    "addattr",
+                    "delattr",
+                    "clear_attrs",
+                    "jsonfile",
+                    "label",
+                    "remove_label",
+                ]),
+        ),
+    )]
+    Modify {
+        #[arg(short, long, value_parser = parse_uuid, help = "UUID of the mdev to modify")]
+        uuid: Uuid,
+        #[arg(short, long, help = "Parent of the mdev to modify")]
+        parent: Option<String>,
+        #[command(flatten)]
+        changes: DefinitionChanges,
+    },
+    #[command(about = "List the devices with staged changes")]
+    List {
+        #[arg(long, help = "Output the staged definitions in JSON format")]
+        dumpjson: bool,
+    },
+    #[command(
+        about = "Discard staged changes",
+        long_about = "Discard staged changes\n\n\
+                Without options the staged changes of all devices are discarded."
+    )]
+    Discard {
+        #[arg(short, long, value_parser = parse_uuid, help = "UUID of the mdev")]
+        uuid: Option<Uuid>,
+        #[arg(short, long, help = "Parent of the mdev")]
+        parent: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum StateCommands {
+    #[command(about = "Write the state of mdevctl to an archive")]
+    Export {
+        #[arg(
+            value_name = "FILE",
+            help = "Write the state archive to FILE, e.g. mdevctl-state.tar.gz"
+        )]
+        file: PathBuf,
+    },
+    #[command(
+        about = "Restore the state of mdevctl from an archive",
+        long_about = "Restore the state of mdevctl from an archive\n\n\
+                Define the devices and restore the files of an archive created by 'state \
+                export'. Nothing is restored if any device is already defined on the same parent \
+                or any file exists with different contents, unless the 'force' option is given \
+                to overwrite them. With the 'start' option, the restored devices that start \
+                automatically or that were running at the time of the export are started. A \
+                device that fails to start does not keep the others from being started."
+    )]
+    Import {
+        #[arg(value_name = "FILE", help = "Read the state archive from FILE")]
+        file: PathBuf,
+        #[arg(long, help = "Start the restored devices")]
+        start: bool,
+        #[arg(short, long, help = "Overwrite existing definitions and files")]
+        force: bool,
+        #[arg(
+            long,
+            requires("start"),
+            help = "Start the restored devices even if a call-out script rejects it"
+        )]
+        force_callouts: bool,
+    },
 }
 
 #[test]
 fn test_cli() {
     use clap::CommandFactory;
-    MdevctlCommands::command().debug_assert()
+    Mdevctl::command().debug_assert()
+}
+
+#[test]
+fn test_parse_uuid() {
+    let expected = Uuid::parse_str("976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9").unwrap();
+    for input in [
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "976D8CC2-4BFC-43B9-B9F9-F4AF2DE91AB9",
+        "976d8cc24bfc43b9b9f9f4af2de91ab9",
+        "{976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9}",
+        "{976D8CC2-4BFC-43B9-B9F9-F4AF2DE91AB9}",
+        "urn:uuid:976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "URN:UUID:976D8CC2-4BFC-43B9-B9F9-F4AF2DE91AB9",
+        " 976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9\n",
+    ] {
+        assert_eq!(Ok(expected), parse_uuid(input), "{:?}", input);
+    }
+    for input in [
+        "",
+        "{976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "urn:976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab",
+        "urn:uuid\u{e9}",
+    ] {
+        assert!(parse_uuid(input).is_err(), "{:?}", input);
+    }
+
+    match Mdevctl::try_parse_from([
+        "mdevctl",
+        "stop",
+        "-u",
+        "{976D8CC2-4BFC-43B9-B9F9-F4AF2DE91AB9}",
+    ]) {
+        Ok(Mdevctl {
+            command: MdevctlCommands::Stop { uuid, .. },
+            ..
+        }) => assert_eq!(Some(expected), uuid),
+        _ => panic!("failed to parse braced UUID"),
+    }
+}
+
+#[test]
+fn test_parse_list_output() {
+    assert_eq!(Ok(ListOutput::Table), parse_list_output("table"));
+    assert_eq!(
+        Ok(ListOutput::Format(OutputFormat::Yaml)),
+        parse_list_output("YAML")
+    );
+    assert_eq!(
+        Ok(ListOutput::Columns(vec![
+            ListColumn::Uuid,
+            ListColumn::State
+        ])),
+        parse_list_output("uuid,state")
+    );
+    assert!(parse_list_output("uuid,table").is_err());
+}
+
+#[test]
+fn test_parse_dry_run() {
+    let parse = |args: &[&str]| {
+        Mdevctl::try_parse_from(
+            ["mdevctl", "start-parent-mdevs", "--dry-run", "0000:00:02.0"]
+                .iter()
+                .chain(args),
+        )
+    };
+    assert!(parse(&[]).is_ok());
+    assert!(parse(&["--dumpjson"]).is_ok());
+    // the options that control starting the devices are not silently ignored
+    for args in [
+        &["--timeout", "10"][..],
+        &["--fail-fast"],
+        &["--keep-going"],
+        &["--jobs", "2"],
+    ] {
+        assert_eq!(
+            clap::error::ErrorKind::ArgumentConflict,
+            parse(args).map(|_| ()).unwrap_err().kind(),
+            "{:?}",
+            args
+        );
+    }
 }
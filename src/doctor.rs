@@ -0,0 +1,280 @@
+//! Diagnostics of the environment for the `doctor` command
+//!
+//! Unlike [`Environment::self_check`], which only aborts commands in a broken installation, the
+//! checks here look at everything mdevctl depends on and report all problems at once.
+
+use crate::callouts::{
+    callout, installed_notifiers, installed_scripts, CalloutLimits, NotifierFilter, ScriptUser,
+};
+use crate::environment::Environment;
+use crate::mdev::MDev;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The outcome of a single check, ordered by severity
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckStatus::Pass => write!(f, "PASS"),
+            CheckStatus::Warn => write!(f, "WARN"),
+            CheckStatus::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub check: String,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+impl Check {
+    fn new<S: Into<String>>(check: S, status: CheckStatus, message: String) -> Check {
+        Check {
+            check: check.into(),
+            status,
+            message,
+        }
+    }
+}
+
+/// the path as it appears on the host, i.e. without the root of a test environment
+pub fn host_path(env: &dyn Environment, path: &Path) -> String {
+    match path.strip_prefix(env.root()) {
+        Ok(p) => Path::new("/").join(p).display().to_string(),
+        Err(_) => path.display().to_string(),
+    }
+}
+
+fn is_writable(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        // SAFETY: the path is a valid nul terminated string
+        Ok(p) => unsafe { libc::access(p.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+pub fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+pub fn sorted_entries(dir: &Path) -> Vec<PathBuf> {
+    let mut paths = dir
+        .read_dir()
+        .map(|rd| rd.filter_map(|e| e.ok().map(|e| e.path())).collect())
+        .unwrap_or_else(|_| Vec::new());
+    paths.sort();
+    paths
+}
+
+fn check_mdev_bus(env: &dyn Environment) -> Check {
+    let bus = env.mdev_base();
+    let bus = bus.parent().unwrap();
+    match bus.is_dir() {
+        true => Check::new(
+            "mdev bus",
+            CheckStatus::Pass,
+            format!("{} exists", host_path(env, bus)),
+        ),
+        false => Check::new(
+            "mdev bus",
+            CheckStatus::Fail,
+            format!(
+                "{} doesn't exist, the mdev kernel module may not be loaded",
+                host_path(env, bus)
+            ),
+        ),
+    }
+}
+
+fn check_dirs(env: &dyn Environment) -> Vec<Check> {
+    env.required_dirs()
+        .iter()
+        .map(|dir| {
+            let name = format!("directory {}", host_path(env, dir));
+            if !dir.is_dir() {
+                Check::new(
+                    name,
+                    CheckStatus::Fail,
+                    "doesn't exist, which may indicate a packaging or installation error"
+                        .to_string(),
+                )
+            } else if !is_writable(dir) {
+                Check::new(
+                    name,
+                    CheckStatus::Warn,
+                    "is not writable by the current user".to_string(),
+                )
+            } else {
+                Check::new(
+                    name,
+                    CheckStatus::Pass,
+                    "exists and is writable".to_string(),
+                )
+            }
+        })
+        .collect()
+}
+
+/// Check that the callout scripts can be executed and query their capabilities for a device of
+/// each defined type
+fn check_callout_scripts(env: Rc<dyn Environment>) -> Vec<Check> {
+    let mut probes: BTreeMap<(String, String), MDev> = BTreeMap::new();
+    if let Ok(devices) = env.clone().get_defined_devices(None, None) {
+        for dev in devices.into_values().flatten() {
+            if let (Some(parent), Some(mdev_type)) = (dev.parent.clone(), dev.mdev_type.clone()) {
+                probes.entry((parent, mdev_type)).or_insert(dev);
+            }
+        }
+    }
+
+    let mut checks = Vec::new();
+    for script in installed_scripts(env.as_ref()) {
+        let name = format!("callout script {}", host_path(env.as_ref(), &script));
+        if !is_executable(&script) {
+            checks.push(Check::new(
+                name,
+                CheckStatus::Fail,
+                "is not executable".to_string(),
+            ));
+            continue;
+        }
+        if probes.is_empty() {
+            checks.push(Check::new(
+                name,
+                CheckStatus::Pass,
+                "is executable, no defined devices to query its capabilities for".to_string(),
+            ));
+            continue;
+        }
+
+        let mut versioned = Vec::new();
+        let mut errors = Vec::new();
+        for ((parent, mdev_type), dev) in probes.iter() {
+            let mut dev = dev.clone();
+            let res = callout(&mut dev).and_then(|c| c.query_capabilities(&script));
+            match res {
+                Ok(Some(version)) => versioned.push(format!(
+                    "{} on {} (version {})",
+                    mdev_type,
+                    parent,
+                    version.version()
+                )),
+                Ok(None) => (),
+                Err(e) => errors.push(format!("{} on {}: {:#}", mdev_type, parent, e)),
+            }
+        }
+        checks.push(match (errors.is_empty(), versioned.is_empty()) {
+            (false, _) => Check::new(
+                name,
+                CheckStatus::Warn,
+                format!("capability query failed for {}", errors.join(", ")),
+            ),
+            (true, true) => Check::new(
+                name,
+                CheckStatus::Pass,
+                "is executable, supports no defined device type with versioning".to_string(),
+            ),
+            (true, false) => Check::new(
+                name,
+                CheckStatus::Pass,
+                format!("supports {}", versioned.join(", ")),
+            ),
+        });
+    }
+    checks
+}
+
+fn check_notification_scripts(env: &dyn Environment) -> Vec<Check> {
+    installed_notifiers(env)
+        .into_iter()
+        .map(|script| {
+            let name = format!("notification script {}", host_path(env, &script));
+            match (is_executable(&script), NotifierFilter::load(&script)) {
+                (false, _) => Check::new(name, CheckStatus::Fail, "is not executable".to_string()),
+                (true, Err(e)) => Check::new(
+                    name,
+                    CheckStatus::Warn,
+                    format!("is executable, notified of all events: {:#}", e),
+                ),
+                (true, Ok(_)) => Check::new(name, CheckStatus::Pass, "is executable".to_string()),
+            }
+        })
+        .collect()
+}
+
+/// the users configured for scripts must exist, otherwise the scripts do not run at all
+fn check_script_users(env: &dyn Environment) -> Vec<Check> {
+    let limits = match CalloutLimits::load(env) {
+        Ok(limits) => limits,
+        Err(e) => {
+            return vec![Check::new(
+                "callout limits",
+                CheckStatus::Warn,
+                format!("are ignored: {:#}", e),
+            )]
+        }
+    };
+    limits
+        .users()
+        .into_iter()
+        .map(|user| {
+            let name = format!("script user {}", user);
+            match ScriptUser::lookup(user) {
+                Ok(u) => Check::new(
+                    name,
+                    CheckStatus::Pass,
+                    format!("exists with uid {} and gid {}", u.uid, u.gid),
+                ),
+                Err(e) => Check::new(name, CheckStatus::Fail, format!("{:#}", e)),
+            }
+        })
+        .collect()
+}
+
+fn check_parents(env: &dyn Environment) -> Check {
+    let parents = sorted_entries(&env.parent_base())
+        .into_iter()
+        .filter(|p| p.join("mdev_supported_types").is_dir())
+        .filter_map(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+        .collect::<Vec<_>>();
+    match parents.is_empty() {
+        true => Check::new(
+            "parent devices",
+            CheckStatus::Warn,
+            "no device supports mediated devices".to_string(),
+        ),
+        false => Check::new(
+            "parent devices",
+            CheckStatus::Pass,
+            format!("{} supported: {}", parents.len(), parents.join(", ")),
+        ),
+    }
+}
+
+/// Run all checks, in the order of the dependencies of mdevctl on the host
+pub fn run_checks(env: Rc<dyn Environment>) -> Vec<Check> {
+    let mut checks = vec![check_mdev_bus(env.as_ref())];
+    checks.extend(check_dirs(env.as_ref()));
+    checks.extend(check_callout_scripts(env.clone()));
+    checks.extend(check_notification_scripts(env.as_ref()));
+    checks.extend(check_script_users(env.as_ref()));
+    checks.push(check_parents(env.as_ref()));
+    checks
+}
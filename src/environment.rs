@@ -1,17 +1,29 @@
 //! A filesystem environment for mdevctl
 
 use crate::callouts::{callout, CalloutScriptCache, CalloutScriptInfo};
-use crate::mdev::{MDev, MDevType};
-use anyhow::{anyhow, Result};
+use crate::cli::AttrMerge;
+use crate::error::MdevError;
+use crate::mdev::{
+    read_available_instances, read_before_deadline, DeadlineExceeded, MDev, MDevType,
+    MdevUnavailable, Policy,
+};
+use anyhow::{Context, Result};
 use log::{debug, warn};
 use std::collections::BTreeMap;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use std::{env, fs};
 use uuid::Uuid;
 
+/// the installed mdevctl executable, which generated systemd units and udev rules run
+pub const MDEVCTL: &str = "/usr/sbin/mdevctl";
+
+/// Creates an environment for another thread, see [`Environment::worker_environment`]
+pub type WorkerEnvironment = Arc<dyn Fn() -> Rc<dyn Environment> + Send + Sync>;
+
 /// A trait which provides filesystem paths for certain system resources and provides functions to
 /// query the state of that environment.
 ///
@@ -21,10 +33,27 @@ use uuid::Uuid;
 pub trait Environment: std::fmt::Debug {
     fn root(&self) -> &Path;
 
-    fn find_script(&self, dev: &MDev) -> Option<CalloutScriptInfo>;
+    fn find_script(&self, dev: &MDev) -> Result<Option<CalloutScriptInfo>>;
 
     fn as_env(self: Rc<Self>) -> Rc<dyn Environment>;
 
+    /// The time at which read-only commands given a `--timeout` stop collecting results. Devices
+    /// inherit it as their deadline, which bounds the sysfs reads and callout invocations for
+    /// them.
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Whether the deadline has passed, in which case a warning that the results of `what` are
+    /// incomplete is logged
+    fn deadline_exceeded(&self, what: &str) -> bool {
+        let exceeded = self.deadline().is_some_and(|d| Instant::now() >= d);
+        if exceeded {
+            warn!("Timeout expired, the {} may be incomplete", what);
+        }
+        exceeded
+    }
+
     fn mdev_base(&self) -> PathBuf {
         self.root().join("sys/bus/mdev/devices")
     }
@@ -37,6 +66,107 @@ pub trait Environment: std::fmt::Debug {
         self.root().join("sys/class/mdev_bus")
     }
 
+    fn module_base(&self) -> PathBuf {
+        self.root().join("sys/module")
+    }
+
+    fn iommu_groups_base(&self) -> PathBuf {
+        self.root().join("sys/kernel/iommu_groups")
+    }
+
+    fn proc_base(&self) -> PathBuf {
+        self.root().join("proc")
+    }
+
+    fn osrelease_path(&self) -> PathBuf {
+        self.root().join("proc/sys/kernel/osrelease")
+    }
+
+    fn meminfo_path(&self) -> PathBuf {
+        self.root().join("proc/meminfo")
+    }
+
+    fn kmsg_path(&self) -> PathBuf {
+        self.root().join("dev/kmsg")
+    }
+
+    /// The file that call-out and notification events are recorded in instead of invoking
+    /// scripts for them, if any
+    fn callout_record_path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// A function creating an environment like this one in another thread, as environments and
+    /// the devices using them are not shared between threads. Devices are only started
+    /// concurrently in environments that provide one.
+    fn worker_environment(&self) -> Option<WorkerEnvironment> {
+        None
+    }
+
+    /// Record `message` in the audit subsystem
+    fn audit(&self, message: &str) -> Result<()> {
+        crate::audit::send(message)
+    }
+
+    /// Send `signal` to the process `pid`
+    fn kill(&self, pid: i32, signal: libc::c_int) -> Result<()> {
+        // SAFETY: plain system call
+        if unsafe { libc::kill(pid, signal) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Unable to send signal {} to process {}", signal, pid));
+        }
+        Ok(())
+    }
+
+    /// Write `contents` to the sysfs file `path`
+    fn write_sysfs(&self, path: &Path, contents: &str) -> std::io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn callout_limits_path(&self) -> PathBuf {
+        self.config_base().join("callout-limits.json")
+    }
+
+    fn labels_path(&self) -> PathBuf {
+        self.config_base().join("labels.json")
+    }
+
+    fn policy_path(&self) -> PathBuf {
+        self.config_base().join("policy.json")
+    }
+
+    fn staging_base(&self) -> PathBuf {
+        self.root().join("var/lib/mdevctl/staged")
+    }
+
+    fn changelog_base(&self) -> PathBuf {
+        self.root().join("var/lib/mdevctl/changelog")
+    }
+
+    fn runtime_base(&self) -> PathBuf {
+        self.root().join("run/mdevctl")
+    }
+
+    fn callout_state_base(&self) -> PathBuf {
+        self.runtime_base().join("callouts")
+    }
+
+    fn callout_cache_path(&self) -> PathBuf {
+        self.runtime_base().join("callout-cache.json")
+    }
+
+    fn autostart_state_base(&self) -> PathBuf {
+        self.runtime_base().join("autostart")
+    }
+
+    fn notify_state_base(&self) -> PathBuf {
+        self.runtime_base().join("notify")
+    }
+
+    fn start_lock_base(&self) -> PathBuf {
+        self.runtime_base().join("locks")
+    }
+
     fn config_scripts_base(&self) -> PathBuf {
         self.config_base().join("scripts.d")
     }
@@ -69,50 +199,125 @@ pub trait Environment: std::fmt::Debug {
         vec![self.notification_dir(), self.old_notification_dir()]
     }
 
+    fn systemd_units_base(&self) -> PathBuf {
+        self.root().join("run/systemd/units")
+    }
+
+    /// locations of the PCI ID database on different distributions, in order of preference
+    fn pci_ids_paths(&self) -> Vec<PathBuf> {
+        vec![
+            self.root().join("usr/share/hwdata/pci.ids"),
+            self.root().join("usr/share/misc/pci.ids"),
+        ]
+    }
+
+    fn cmdline_path(&self) -> PathBuf {
+        self.root().join("proc/cmdline")
+    }
+
+    /// Get the systemd target used for system recovery if the system is booted into it or has
+    /// been isolated to it, e.g. with `systemctl rescue`
+    fn recovery_target(&self) -> Option<&'static str> {
+        const TARGETS: [&str; 2] = ["emergency.target", "rescue.target"];
+        // systemd records the invocation of every active unit
+        for target in TARGETS {
+            let invocation = self
+                .systemd_units_base()
+                .join(format!("invocation:{}", target));
+            if invocation.symlink_metadata().is_ok() {
+                return Some(target);
+            }
+        }
+        // the state of systemd may not be available in an early boot environment
+        let cmdline = fs::read_to_string(self.cmdline_path()).unwrap_or_default();
+        for field in cmdline_fields(&cmdline) {
+            // the runlevel arguments only count as fields of their own, while the unit is
+            // selected by the value of the systemd.unit parameter
+            match (field.strip_prefix("systemd.unit="), field) {
+                (Some("emergency.target"), _) | (None, "emergency" | "-b") => {
+                    return Some(TARGETS[0])
+                }
+                (Some("rescue.target"), _) | (None, "rescue" | "single" | "s" | "S" | "1") => {
+                    return Some(TARGETS[1])
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+
+    /// directories that distro packages or 'make install' are expected to create
+    fn required_dirs(&self) -> Vec<PathBuf> {
+        vec![
+            self.config_base(),
+            self.callout_dir(),
+            self.notification_dir(),
+        ]
+    }
+
     fn self_check(&self) -> Result<()> {
         debug!("checking that the environment is sane");
+        let recovery = self.recovery_target();
         // ensure required system dirs exist. Generally distro packages or 'make install' should
         // create these dirs.
-        for dir in [
-            self.config_base(),
-            self.callout_dir(),
-            self.notification_dir(),
-        ] {
+        for dir in self.required_dirs() {
             if !dir.exists() {
-                return Err(anyhow!("Required directory {:?} doesn't exist. This may indicate a packaging or installation error", dir));
+                // a broken installation should not prevent inspecting and repairing devices
+                // while recovering the system
+                if let Some(target) = recovery {
+                    warn!("Required directory {:?} doesn't exist. This may indicate a packaging or installation error. Continuing in {}", dir, target);
+                    continue;
+                }
+                return Err(MdevError::NotFound(format!("Required directory {:?} doesn't exist. This may indicate a packaging or installation error", dir)).into());
             }
         }
         Ok(())
     }
 
+    /// Check that the kernel provides the mediated device framework, which registers the class
+    /// of parent devices in sysfs even if no parent device is present
+    fn check_mdev_support(&self) -> Result<()> {
+        match self.parent_base().is_dir() {
+            true => Ok(()),
+            false => {
+                debug!("{:?} doesn't exist", self.parent_base());
+                Err(MdevUnavailable.into())
+            }
+        }
+    }
+
     /// convenience function to lookup an active device by uuid and parent
     fn get_active_device(self: Rc<Self>, uuid: Uuid, parent: Option<&String>) -> Result<MDev> {
         let devs = self.get_active_devices(Some(&uuid), parent)?;
         if devs.is_empty() {
             match parent {
-                None => Err(anyhow!(
+                None => Err(MdevError::NotFound(format!(
                     "Mediated device {} is not active",
-                    uuid.hyphenated().to_string()
-                )),
-                Some(p) => Err(anyhow!(
+                    uuid.hyphenated()
+                ))
+                .into()),
+                Some(p) => Err(MdevError::NotFound(format!(
                     "Mediated device {}/{} is not active",
                     p,
-                    uuid.hyphenated().to_string()
-                )),
+                    uuid.hyphenated()
+                ))
+                .into()),
             }
         } else if devs.len() > 1 {
-            Err(anyhow!(
+            Err(MdevError::SysfsIo(format!(
                 "Multiple parents found for {}. System error?",
-                uuid.hyphenated().to_string()
+                uuid.hyphenated()
             ))
+            .into())
         } else {
             let (parent, children) = devs.iter().next().unwrap();
             if children.len() > 1 {
-                return Err(anyhow!(
+                return Err(MdevError::InvalidConfig(format!(
                     "Multiple definitions found for {}/{}",
                     parent,
-                    uuid.hyphenated().to_string()
-                ));
+                    uuid.hyphenated()
+                ))
+                .into());
             }
             Ok(children.first().unwrap().clone())
         }
@@ -125,12 +330,16 @@ pub trait Environment: std::fmt::Debug {
         parent: Option<&String>,
     ) -> Result<BTreeMap<String, Vec<MDev>>> {
         let mut devices: BTreeMap<String, Vec<MDev>> = BTreeMap::new();
+        let merge = Policy::load(self.clone().as_env().as_ref())?.attr_merge;
         debug!(
             "Looking up active mdevs: uuid={:?}, parent={:?}",
             uuid, parent
         );
         if let Ok(dir) = self.mdev_base().read_dir() {
             for dir_dev in dir {
+                if self.deadline_exceeded("list of active devices") {
+                    break;
+                }
                 let dir_dev = dir_dev?;
                 let fname = dir_dev.file_name();
                 let basename = fname.to_str().unwrap();
@@ -143,22 +352,20 @@ pub trait Environment: std::fmt::Debug {
                 }
                 let u = u.unwrap();
 
-                if uuid.is_some() && uuid != Some(&u) {
+                if let Some(uuid) = uuid.filter(|uuid| **uuid != u) {
                     debug!(
                         "Ignoring device {} because it doesn't match uuid {}",
-                        u,
-                        uuid.unwrap()
+                        u, uuid
                     );
                     continue;
                 }
 
                 let mut dev = MDev::new(self.clone().as_env(), u);
                 if dev.load_from_sysfs().is_ok() {
-                    if parent.is_some() && (parent != dev.parent.as_ref()) {
+                    if let Some(parent) = parent.filter(|p| Some(*p) != dev.parent.as_ref()) {
                         debug!(
                             "Ignoring device {} because it doesn't match parent {}",
-                            dev.uuid,
-                            parent.as_ref().unwrap()
+                            dev.uuid, parent
                         );
                         continue;
                     }
@@ -166,15 +373,30 @@ pub trait Environment: std::fmt::Debug {
                     // retrieve autostart from persisted mdev if possible
                     let mut per_dev = MDev::new(self.clone().as_env(), u);
                     per_dev.parent.clone_from(&dev.parent);
-                    if per_dev.load_definition().is_ok() {
+                    let defined = per_dev.load_definition().is_ok();
+                    if defined {
                         dev.autostart = per_dev.autostart;
+                        dev.labels = per_dev.labels;
                     }
 
                     // if the device is supported by a callout script that gets attributes, show
-                    // those in the output
+                    // those in the output. With the attr_merge policy, they are merged into the
+                    // defined attributes like they are by `define`, otherwise only they are shown.
                     let mut c = callout(&mut dev)?;
-                    if let Ok(attrs) = c.get_attributes() {
-                        let _ = c.dev.add_attributes(&attrs);
+                    match c.get_attributes() {
+                        Ok(attrs) => {
+                            if let (true, Some(_)) = (defined, merge) {
+                                c.dev.attrs = per_dev.attrs;
+                            }
+                            let merge = merge.unwrap_or(AttrMerge::Append);
+                            if let Err(e) = c.dev.add_callout_attributes(&attrs, merge) {
+                                debug!("Ignoring attributes of device {}: {:#}", u, e);
+                            }
+                        }
+                        Err(e) if e.chain().any(|c| c.is::<DeadlineExceeded>()) => {
+                            warn!("Attributes of device {} are not listed: {:#}", u, e);
+                        }
+                        Err(_) => (),
                     }
 
                     let devparent = dev.parent()?;
@@ -201,10 +423,22 @@ pub trait Environment: std::fmt::Debug {
             uuid, parent
         );
         let thisenv = self.as_env();
-        for parentpath in thisenv.config_base().read_dir()?.skip_while(|x| match x {
+        let configdir = match thisenv.config_base().read_dir() {
+            Ok(dir) => dir,
+            // only possible if the self check was relaxed, see recovery_target()
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                debug!("No configuration directory {:?}", thisenv.config_base());
+                return Ok(devices);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        for parentpath in configdir.skip_while(|x| match x {
             Ok(d) => d.path() == thisenv.scripts_base(),
             _ => false,
         }) {
+            if thisenv.deadline_exceeded("list of defined devices") {
+                break;
+            }
             let parentpath = parentpath?;
             let parentname = parentpath.file_name();
             let parentname = parentname.to_str().unwrap();
@@ -215,8 +449,9 @@ pub trait Environment: std::fmt::Debug {
                 continue;
             }
 
-            let mut childdevices = Vec::new();
-
+            // the same UUID may be spelled in several ways, e.g. in upper case, so collect all
+            // definitions of each device before loading the one taking precedence
+            let mut definitions: BTreeMap<Uuid, Vec<PathBuf>> = BTreeMap::new();
             match parentpath.path().read_dir() {
                 Ok(res) => {
                     for child in res {
@@ -235,6 +470,11 @@ pub trait Environment: std::fmt::Debug {
 
                         let path = child.path();
                         let basename = path.file_name().unwrap().to_str().unwrap();
+                        // e.g. a config that's being replaced, see MDev::write_config()
+                        if basename.starts_with('.') {
+                            debug!("Ignoring hidden file '{}'", basename);
+                            continue;
+                        }
                         let u = Uuid::parse_str(basename);
                         if u.is_err() {
                             warn!("Can't determine uuid for file '{}'", basename);
@@ -243,35 +483,49 @@ pub trait Environment: std::fmt::Debug {
                         let u = u.unwrap();
 
                         debug!("found mdev {:?}", u);
-                        if uuid.is_some() && uuid != Some(&u) {
+                        if let Some(uuid) = uuid.filter(|uuid| **uuid != u) {
                             debug!(
                                 "Ignoring device {} because it doesn't match uuid {}",
-                                u,
-                                uuid.unwrap()
+                                u, uuid
                             );
                             continue;
                         }
-
-                        match fs::File::open(&path) {
-                            Ok(mut f) => {
-                                let mut contents = String::new();
-                                f.read_to_string(&mut contents)?;
-                                let val = serde_json::from_str(&contents)?;
-                                let mut dev = MDev::new(thisenv.clone(), u);
-                                dev.load_from_json(parentname.to_string(), &val)?;
-                                dev.load_from_sysfs()?;
-
-                                childdevices.push(dev);
-                            }
-                            Err(e) => {
-                                warn!("Unable to open file {:?}: {}", path, e);
-                                continue;
-                            }
-                        };
+                        definitions.entry(u).or_default().push(path);
                     }
                 }
                 Err(e) => warn!("Unable to read directory {:?}: {}", parentpath.path(), e),
             }
+
+            let mut childdevices = Vec::new();
+            for (u, mut paths) in definitions {
+                order_by_precedence(&u, &mut paths);
+                let path = &paths[0];
+                for ignored in paths.iter().skip(1) {
+                    warn!(
+                        "Conflicting definitions of device {} on parent {}: using {:?}, ignoring {:?}",
+                        u.hyphenated(),
+                        parentname,
+                        path,
+                        ignored
+                    );
+                }
+                match fs::File::open(path) {
+                    Ok(mut f) => {
+                        let mut contents = String::new();
+                        f.read_to_string(&mut contents)?;
+                        let val = serde_json::from_str(&contents)?;
+                        let mut dev = MDev::new(thisenv.clone(), u);
+                        dev.load_from_json(parentname.to_string(), &val)?;
+                        dev.load_from_sysfs()?;
+
+                        childdevices.push(dev);
+                    }
+                    Err(e) => {
+                        warn!("Unable to open file {:?}: {}", path, e);
+                        continue;
+                    }
+                };
+            }
             if !childdevices.is_empty() {
                 devices.insert(parentname.to_string(), childdevices);
             }
@@ -284,41 +538,115 @@ pub trait Environment: std::fmt::Debug {
         let devs = self.get_defined_devices(Some(&uuid), parent)?;
         if devs.is_empty() {
             match parent {
-                None => Err(anyhow!(
+                None => Err(MdevError::NotFound(format!(
                     "Mediated device {} is not defined",
-                    uuid.hyphenated().to_string()
-                )),
-                Some(p) => Err(anyhow!(
+                    uuid.hyphenated()
+                ))
+                .into()),
+                Some(p) => Err(MdevError::NotFound(format!(
                     "Mediated device {}/{} is not defined",
                     p,
-                    uuid.hyphenated().to_string()
-                )),
+                    uuid.hyphenated()
+                ))
+                .into()),
             }
         } else if devs.len() > 1 {
             match parent {
-                None => Err(anyhow!(
+                None => Err(MdevError::InvalidConfig(format!(
                     "Multiple definitions found for {}, specify a parent",
-                    uuid.hyphenated().to_string()
-                )),
-                Some(p) => Err(anyhow!(
+                    uuid.hyphenated()
+                ))
+                .into()),
+                Some(p) => Err(MdevError::InvalidConfig(format!(
                     "Multiple definitions found for {}/{}",
                     p,
-                    uuid.hyphenated().to_string()
-                )),
+                    uuid.hyphenated()
+                ))
+                .into()),
             }
         } else {
             let (parent, children) = devs.iter().next().unwrap();
             if children.len() > 1 {
-                return Err(anyhow!(
+                return Err(MdevError::InvalidConfig(format!(
                     "Multiple definitions found for {}/{}",
                     parent,
-                    uuid.hyphenated().to_string()
-                ));
+                    uuid.hyphenated()
+                ))
+                .into());
             }
             Ok(children.first().unwrap().clone())
         }
     }
 
+    /// Get a map of all devices with staged changes, optionally filtered by uuid and parent. The
+    /// staged definitions are laid out like the defined devices, see [`MDev::staged_path`].
+    fn get_staged_devices(
+        self: Rc<Self>,
+        uuid: Option<&Uuid>,
+        parent: Option<&String>,
+    ) -> Result<BTreeMap<String, Vec<MDev>>> {
+        let mut devices: BTreeMap<String, Vec<MDev>> = BTreeMap::new();
+        let thisenv = self.as_env();
+        let stagingdir = match thisenv.staging_base().read_dir() {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(devices),
+            Err(e) => return Err(e.into()),
+        };
+        for parentpath in stagingdir {
+            let parentpath = parentpath?.path();
+            let parentname = parentpath.file_name().unwrap().to_str().unwrap();
+            if parent.is_some_and(|p| p != parentname) || !parentpath.is_dir() {
+                continue;
+            }
+            let mut childdevices = Vec::new();
+            for child in parentpath.read_dir()? {
+                let path = child?.path();
+                let u = match Uuid::parse_str(path.file_name().unwrap().to_str().unwrap()) {
+                    Ok(u) => u,
+                    Err(_) => {
+                        warn!("Can't determine uuid for staged file {:?}", path);
+                        continue;
+                    }
+                };
+                if uuid.is_some_and(|uuid| *uuid != u) {
+                    continue;
+                }
+                let contents = fs::read_to_string(&path)?;
+                let val = serde_json::from_str(&contents).map_err(|e| {
+                    MdevError::InvalidConfig(format!("Invalid staged definition {:?}: {}", path, e))
+                })?;
+                let mut dev = MDev::new(thisenv.clone(), u);
+                dev.load_from_json(parentname.to_string(), &val)?;
+                childdevices.push(dev);
+            }
+            if !childdevices.is_empty() {
+                childdevices.sort_by_key(|d| d.uuid);
+                devices.insert(parentname.to_string(), childdevices);
+            }
+        }
+        Ok(devices)
+    }
+
+    /// convenience function to lookup the staged changes of a device by uuid and parent
+    fn get_staged_device(
+        self: Rc<Self>,
+        uuid: Uuid,
+        parent: Option<&String>,
+    ) -> Result<Option<MDev>> {
+        let mut devs = self
+            .get_staged_devices(Some(&uuid), parent)?
+            .into_values()
+            .flatten();
+        match (devs.next(), devs.next()) {
+            (Some(_), Some(_)) => Err(MdevError::InvalidConfig(format!(
+                "Multiple staged definitions found for {}, specify a parent",
+                uuid.hyphenated()
+            ))
+            .into()),
+            (dev, _) => Ok(dev),
+        }
+    }
+
     /// Get a map of all mediated device types that are supported on this machine
     fn get_supported_types(
         self: Rc<Self>,
@@ -329,6 +657,9 @@ pub trait Environment: std::fmt::Debug {
 
         if let Ok(dir) = self.parent_base().read_dir() {
             for parentpath in dir {
+                if self.deadline_exceeded("list of supported types") {
+                    break;
+                }
                 let parentpath = parentpath?;
                 let parentname = parentpath.file_name();
                 let parentname = parentname.to_str().unwrap();
@@ -338,48 +669,23 @@ pub trait Environment: std::fmt::Debug {
                     continue;
                 }
 
-                let mut childtypes = Vec::new();
-                let mut parentpath = parentpath.path();
-                parentpath.push("mdev_supported_types");
-                for child in parentpath.read_dir()? {
-                    let child = child?;
-                    if !child.metadata()?.is_dir() {
-                        continue;
+                match read_parent_types(parentname, &parentpath.path(), self.deadline()) {
+                    Ok(childtypes) => {
+                        types.insert(parentname.to_string(), childtypes);
                     }
-
-                    let mut t = MDevType::new();
-                    t.parent = parentname.to_string();
-
-                    let mut path = child.path();
-                    t.typename = path.file_name().unwrap().to_str().unwrap().to_string();
-                    debug!("found mdev type {}", t.typename);
-
-                    path.push("available_instances");
-                    debug!("Checking available instances: {:?}", path);
-                    t.available_instances = fs::read_to_string(&path)?.trim().parse()?;
-
-                    path.pop();
-                    path.push("device_api");
-                    t.device_api = fs::read_to_string(&path)?.trim().to_string();
-
-                    path.pop();
-                    path.push("name");
-                    if path.exists() {
-                        t.name = fs::read_to_string(&path)?.trim().to_string();
+                    // the parent device may be removed while it is scanned, e.g. if its driver
+                    // is unbound concurrently
+                    Err(e) if is_not_found(&e) => {
+                        warn!(
+                            "Skipping parent device {} which disappeared while reading its types: {:#}",
+                            parentname, e
+                        );
                     }
-
-                    path.pop();
-                    path.push("description");
-                    if path.exists() {
-                        t.description = fs::read_to_string(&path)?
-                            .trim()
-                            .replace('\n', ", ")
-                            .to_string();
+                    Err(e) if e.chain().any(|c| c.is::<DeadlineExceeded>()) => {
+                        warn!("Skipping parent device {}: {:#}", parentname, e);
                     }
-
-                    childtypes.push(t);
+                    Err(e) => return Err(e),
                 }
-                types.insert(parentname.to_string(), childtypes);
             }
         }
         for v in types.values_mut() {
@@ -389,11 +695,126 @@ pub trait Environment: std::fmt::Debug {
     }
 }
 
+/// Read the mediated device types supported by the parent device at `parentpath`. If the deadline
+/// passes, the types read until then are returned.
+fn read_parent_types(
+    parentname: &str,
+    parentpath: &Path,
+    deadline: Option<Instant>,
+) -> Result<Vec<MDevType>> {
+    let mut childtypes = Vec::new();
+    let typesdir = parentpath.join("mdev_supported_types");
+    let children = typesdir
+        .read_dir()
+        .with_context(|| format!("Failed to read {:?}", typesdir))?;
+    for child in children {
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            warn!(
+                "Timeout expired, the types of parent device {} may be incomplete",
+                parentname
+            );
+            break;
+        }
+        let child = child?;
+        if !child.metadata()?.is_dir() {
+            continue;
+        }
+
+        let mut t = MDevType::new();
+        t.parent = parentname.to_string();
+
+        let mut path = child.path();
+        t.typename = path.file_name().unwrap().to_str().unwrap().to_string();
+        debug!("found mdev type {}", t.typename);
+
+        path.push("available_instances");
+        debug!("Checking available instances: {:?}", path);
+        t.available_instances =
+            match read_available_instances(&path, parentname, &t.typename, deadline) {
+                Ok(n) => n,
+                Err(e) => {
+                    // don't let a single misbehaving driver abort the whole scan
+                    warn!("{:#}, assuming 0", e);
+                    0
+                }
+            };
+
+        path.pop();
+        path.push("device_api");
+        t.device_api = read_before_deadline(&path, deadline)
+            .with_context(|| format!("Failed to read {:?}", path))?
+            .trim()
+            .to_string();
+
+        path.pop();
+        path.push("name");
+        if path.exists() {
+            t.name = fs::read_to_string(&path)?.trim().to_string();
+        }
+
+        path.pop();
+        path.push("description");
+        if path.exists() {
+            t.description = fs::read_to_string(&path)?
+                .trim()
+                .replace('\n', ", ")
+                .to_string();
+        }
+
+        childtypes.push(t);
+    }
+    Ok(childtypes)
+}
+
+/// whether an error was caused by a file that does not exist (anymore)
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.chain().any(|c| {
+        c.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == ErrorKind::NotFound)
+    })
+}
+
+/// Split the kernel command line into its fields like the kernel does: at whitespace, except
+/// within double quotes, so that a quoted parameter value is part of its field
+fn cmdline_fields(cmdline: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    for (i, c) in cmdline.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if let Some(s) = start.take() {
+                    fields.push(&cmdline[s..i]);
+                }
+                continue;
+            }
+            _ => (),
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(s) = start {
+        fields.push(&cmdline[s..]);
+    }
+    fields
+}
+
+/// Sort the definitions of the device `uuid` on a parent such that the one taking precedence
+/// comes first: a file named by the canonical, lower case hyphenated UUID, otherwise the first
+/// file name in sort order.
+pub fn order_by_precedence(uuid: &Uuid, paths: &mut [PathBuf]) {
+    let canonical = uuid.hyphenated().to_string();
+    paths.sort_by_key(|p| (p.file_name() != Some(canonical.as_ref()), p.clone()));
+}
+
 /// A default implementation of the Environment trait which uses '/' as the filesystem root.
 #[derive(Debug)]
 pub struct DefaultEnvironment {
     rootpath: PathBuf,
+    fail_on_ambiguous_callout: bool,
     callout_scripts: Mutex<CalloutScriptCache>,
+    deadline: Option<Instant>,
+    callout_record: Option<PathBuf>,
 }
 
 impl Environment for DefaultEnvironment {
@@ -401,7 +822,7 @@ impl Environment for DefaultEnvironment {
         self.rootpath.as_path()
     }
 
-    fn find_script(&self, dev: &MDev) -> Option<CalloutScriptInfo> {
+    fn find_script(&self, dev: &MDev) -> Result<Option<CalloutScriptInfo>> {
         return self
             .callout_scripts
             .lock()
@@ -412,18 +833,67 @@ impl Environment for DefaultEnvironment {
     fn as_env(self: Rc<Self>) -> Rc<dyn Environment> {
         self.clone()
     }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn callout_record_path(&self) -> Option<PathBuf> {
+        self.callout_record.clone()
+    }
+
+    fn worker_environment(&self) -> Option<WorkerEnvironment> {
+        let rootpath = self.rootpath.clone();
+        let fail_on_ambiguous_callout = self.fail_on_ambiguous_callout;
+        let deadline = self.deadline;
+        let callout_record = self.callout_record.clone();
+        Some(Arc::new(move || {
+            DefaultEnvironment::with_root(
+                rootpath.clone(),
+                fail_on_ambiguous_callout,
+                deadline,
+                callout_record.clone(),
+            )
+        }))
+    }
 }
 
 impl DefaultEnvironment {
     #[allow(clippy::new_ret_no_self)]
-    pub fn new() -> Rc<dyn Environment> {
+    pub fn new(fail_on_ambiguous_callout: bool, deadline: Option<Instant>) -> Rc<dyn Environment> {
         let root = match env::var("MDEVCTL_ENV_ROOT") {
             Ok(d) => d,
             _ => "/".to_string(),
         };
-        Rc::new(DefaultEnvironment {
-            rootpath: PathBuf::from(root),
-            callout_scripts: Mutex::new(CalloutScriptCache::new()),
-        })
+        DefaultEnvironment::with_root(
+            PathBuf::from(root),
+            fail_on_ambiguous_callout,
+            deadline,
+            env::var_os("MDEVCTL_RECORD_CALLOUTS")
+                .filter(|p| !p.is_empty())
+                .map(PathBuf::from),
+        )
+    }
+
+    fn with_root(
+        rootpath: PathBuf,
+        fail_on_ambiguous_callout: bool,
+        deadline: Option<Instant>,
+        callout_record: Option<PathBuf>,
+    ) -> Rc<dyn Environment> {
+        let mut callout_scripts = CalloutScriptCache::new();
+        callout_scripts.set_fail_on_ambiguous(fail_on_ambiguous_callout);
+        let env = Rc::new(DefaultEnvironment {
+            rootpath,
+            fail_on_ambiguous_callout,
+            callout_scripts: Mutex::new(callout_scripts),
+            deadline,
+            callout_record,
+        });
+        env.callout_scripts
+            .lock()
+            .unwrap()
+            .set_persist_path(Some(env.callout_cache_path()));
+        env
     }
 }
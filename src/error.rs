@@ -0,0 +1,120 @@
+//! Typed errors and their categories
+//!
+//! Errors are passed around as [`anyhow::Error`], with context describing what failed. Where
+//! callers need to tell failures apart, the errors in a chain are typed, either as an
+//! [`MdevError`] or as one of the errors of call-out scripts and sysfs accesses, such as
+//! [`ScriptFailure`] and [`DeadlineExceeded`]. Every error falls into an [`ErrorCategory`], which
+//! determines the exit code of mdevctl and is reported in JSON output. The categories and their
+//! exit codes are stable, while the messages may change.
+
+use crate::callouts::{CalloutTimeout, ScriptFailure, UnknownScriptUser};
+use crate::mdev::{DeadlineExceeded, MdevUnavailable};
+use serde::Serialize;
+use std::fmt;
+
+/// The category of an error, see [`ErrorCategory::of`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCategory {
+    /// a device, parent device, type or attribute does not exist, or the mediated device
+    /// framework is not available
+    NotFound,
+    /// a device already exists, or a unique label is already assigned
+    AlreadyExists,
+    /// a device definition or a configuration file is invalid
+    InvalidConfig,
+    /// a call-out script rejected the action
+    CalloutRejected,
+    /// reading or writing sysfs failed
+    SysfsIo,
+    /// an operation was abandoned because it did not complete in time
+    Timeout,
+    Other,
+}
+
+impl ErrorCategory {
+    /// The category of `e`. An expired timeout or a rejection by a call-out script anywhere in
+    /// the chain takes precedence, as it explains the errors that follow from it. Otherwise the
+    /// outermost typed error determines the category.
+    pub fn of(e: &anyhow::Error) -> ErrorCategory {
+        if e.chain()
+            .any(|c| c.is::<DeadlineExceeded>() || c.is::<CalloutTimeout>())
+        {
+            return ErrorCategory::Timeout;
+        }
+        if e.chain().any(|c| c.is::<ScriptFailure>()) {
+            return ErrorCategory::CalloutRejected;
+        }
+        // also finds errors that were added as context
+        if let Some(e) = e.downcast_ref::<MdevError>() {
+            return e.category();
+        }
+        if e.chain().any(|c| c.is::<MdevUnavailable>()) {
+            return ErrorCategory::NotFound;
+        }
+        if e.chain()
+            .any(|c| c.is::<UnknownScriptUser>() || c.is::<serde_json::Error>())
+        {
+            return ErrorCategory::InvalidConfig;
+        }
+        ErrorCategory::Other
+    }
+
+    /// The exit code of mdevctl when failing with an error of this category. Exit code 2 is
+    /// left to invalid command lines.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::Other => 1,
+            ErrorCategory::NotFound => 3,
+            ErrorCategory::AlreadyExists => 4,
+            ErrorCategory::InvalidConfig => 5,
+            ErrorCategory::CalloutRejected => 6,
+            ErrorCategory::SysfsIo => 7,
+            ErrorCategory::Timeout => 8,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCategory::NotFound => write!(f, "not-found"),
+            ErrorCategory::AlreadyExists => write!(f, "already-exists"),
+            ErrorCategory::InvalidConfig => write!(f, "invalid-config"),
+            ErrorCategory::CalloutRejected => write!(f, "callout-rejected"),
+            ErrorCategory::SysfsIo => write!(f, "sysfs-io"),
+            ErrorCategory::Timeout => write!(f, "timeout"),
+            ErrorCategory::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// An error of mdevctl itself, with the message describing it
+#[derive(Debug, thiserror::Error)]
+pub enum MdevError {
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    AlreadyExists(String),
+    #[error("{0}")]
+    InvalidConfig(String),
+    #[error("{0}")]
+    SysfsIo(String),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl MdevError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            MdevError::NotFound(_) => ErrorCategory::NotFound,
+            MdevError::AlreadyExists(_) => ErrorCategory::AlreadyExists,
+            MdevError::InvalidConfig(_) => ErrorCategory::InvalidConfig,
+            MdevError::SysfsIo(_) => ErrorCategory::SysfsIo,
+            MdevError::Timeout(_) => ErrorCategory::Timeout,
+            MdevError::Other(_) => ErrorCategory::Other,
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Estimation of the resources used by instances of an mdev type, for the `estimate` command
+//!
+//! Drivers describe the resources of a type in its `description` attribute, in a format of their
+//! own. NVIDIA vGPU types list e.g. `num_heads=4, frl_config=60, framebuffer=2048M,
+//! max_resolution=5120x2880, max_instance=12`, while Intel GVT-g types list e.g.
+//! `low_gm_size: 128MB` and `high_gm_size: 512MB` on lines of their own. Only the resources known
+//! below are recognized, all other items of a description are ignored.
+
+use crate::mdev::MDevType;
+use serde::Serialize;
+
+/// The resources used by each instance that are recognized in descriptions, and their names
+const RESOURCES: [(&str, &str); 4] = [
+    ("framebuffer", "framebuffer memory"),
+    ("low_gm_size", "low graphics memory"),
+    ("high_gm_size", "high graphics memory"),
+    ("fence", "fence registers"),
+];
+
+/// The items of descriptions that limit the number of instances of a type
+const LIMITS: [&str; 1] = ["max_instance"];
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+
+/// A resource used by each instance of a type
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Resource {
+    /// the item of the description, e.g. `framebuffer`
+    pub key: String,
+    pub name: String,
+    /// the amount used by each instance, in bytes if `bytes` is set
+    pub per_instance: u64,
+    /// the amount used by all requested instances
+    pub total: u64,
+    pub bytes: bool,
+}
+
+/// The footprint of a number of instances of a type and the headroom left on its parent
+#[derive(Debug, Serialize)]
+pub struct Estimate {
+    pub parent: String,
+    #[serde(rename = "type")]
+    pub mdev_type: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    pub count: u32,
+    pub available_instances: i32,
+    /// devices of the type that are defined on the parent but not active, which take available
+    /// instances once they are started
+    pub defined_inactive: u32,
+    /// the instances left once the defined and the requested devices are started, negative if
+    /// they do not all fit
+    pub remaining_instances: i64,
+    pub fits: bool,
+    /// the limit of instances stated by the driver, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_instances: Option<u64>,
+    pub resources: Vec<Resource>,
+}
+
+/// The `key=value` or `key: value` items of a description, which are separated by commas or by
+/// the newlines that were replaced by commas when it was read
+fn description_items(description: &str) -> Vec<(String, &str)> {
+    description
+        .split([',', '\n'])
+        .filter_map(|item| {
+            let (key, value) = item.split_once(['=', ':'])?;
+            Some((key.trim().to_lowercase(), value.trim()))
+        })
+        .collect()
+}
+
+/// Parse an amount like `12`, `2048M` or `128MB`. Amounts with a size suffix are returned in
+/// bytes, together with whether they are sizes.
+fn parse_amount(value: &str) -> Option<(u64, bool)> {
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let number = value[..digits].parse::<u64>().ok()?;
+    let unit = match value[digits..].trim().to_uppercase().as_str() {
+        "" => return Some((number, false)),
+        "B" => 1,
+        "K" | "KB" | "KIB" => KIB,
+        "M" | "MB" | "MIB" => MIB,
+        "G" | "GB" | "GIB" => GIB,
+        _ => return None,
+    };
+    Some((number.checked_mul(unit)?, true))
+}
+
+/// Format an amount in the largest binary unit it is a multiple of
+fn format_amount(amount: u64, bytes: bool) -> String {
+    if !bytes {
+        return amount.to_string();
+    }
+    for (unit, name) in [(GIB, "GiB"), (MIB, "MiB"), (KIB, "KiB")] {
+        if amount >= unit && amount.is_multiple_of(unit) {
+            return format!("{} {}", amount / unit, name);
+        }
+    }
+    format!("{} bytes", amount)
+}
+
+impl Estimate {
+    /// Estimate the footprint of `count` instances of `t`, given the number of devices of the
+    /// type that are defined on the parent but not active
+    pub fn new(t: &MDevType, count: u32, defined_inactive: u32) -> Estimate {
+        let items = description_items(&t.description);
+        let resources = RESOURCES
+            .iter()
+            .filter_map(|(key, name)| {
+                let (_, value) = items.iter().find(|(k, _)| k == key)?;
+                let (per_instance, bytes) = parse_amount(value)?;
+                Some(Resource {
+                    key: key.to_string(),
+                    name: name.to_string(),
+                    per_instance,
+                    total: per_instance.saturating_mul(count.into()),
+                    bytes,
+                })
+            })
+            .collect();
+        let max_instances = items
+            .iter()
+            .filter(|(k, _)| LIMITS.contains(&k.as_str()))
+            .find_map(|(_, v)| parse_amount(v).filter(|(_, bytes)| !bytes))
+            .map(|(n, _)| n);
+        let remaining_instances =
+            i64::from(t.available_instances) - i64::from(defined_inactive) - i64::from(count);
+        Estimate {
+            parent: t.parent.clone(),
+            mdev_type: t.typename.clone(),
+            name: t.name.clone(),
+            count,
+            available_instances: t.available_instances,
+            defined_inactive,
+            remaining_instances,
+            fits: remaining_instances >= 0,
+            max_instances,
+            resources,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{} {}", self.parent, self.mdev_type);
+        if !self.name.is_empty() {
+            text.push_str(&format!(" ({})", self.name));
+        }
+        text.push('\n');
+        let headroom = match self.fits {
+            true => format!("{} remaining", self.remaining_instances),
+            false => format!("{} too many", -self.remaining_instances),
+        };
+        text.push_str(&format!(
+            "  Instances: {} requested, {} available, {} defined but not active, {}\n",
+            self.count, self.available_instances, self.defined_inactive, headroom
+        ));
+        if let Some(max) = self.max_instances {
+            text.push_str(&format!("  Limit: {} instances per parent\n", max));
+        }
+        if self.resources.is_empty() {
+            text.push_str("  No resources stated in the description of the type\n");
+        }
+        for r in self.resources.iter() {
+            text.push_str(&format!(
+                "  {}: {} per instance, {} total\n",
+                r.name,
+                format_amount(r.per_instance, r.bytes),
+                format_amount(r.total, r.bytes)
+            ));
+        }
+        text
+    }
+}
@@ -0,0 +1,80 @@
+//! Access to the kernel log for reporting driver messages
+
+use crate::environment::Environment;
+use log::debug;
+use std::fs;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+
+/// maximum number of kernel messages included in an error report
+const MAX_MESSAGES: usize = 10;
+
+/// Follows the kernel log so that messages logged by a driver while mdevctl performs a sysfs
+/// operation can be reported along with a failure of that operation.
+pub struct KernelLog {
+    file: Option<fs::File>,
+}
+
+impl KernelLog {
+    /// Start following the kernel log. Only messages logged after this call are returned by
+    /// [`KernelLog::messages`]. A regular file (e.g. a saved log) is read from the beginning.
+    pub fn follow(env: &dyn Environment) -> KernelLog {
+        let path = env.kmsg_path();
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .and_then(|mut f| {
+                if f.metadata()?.file_type().is_char_device() {
+                    f.seek(SeekFrom::End(0))?;
+                }
+                Ok(f)
+            });
+        match file {
+            Ok(f) => KernelLog { file: Some(f) },
+            Err(e) => {
+                debug!("Unable to follow kernel log {:?}: {}", path, e);
+                KernelLog { file: None }
+            }
+        }
+    }
+
+    /// Return the most recent new messages which mention any of the given `keywords`
+    pub fn messages(&mut self, keywords: &[&str]) -> Vec<String> {
+        let file = match self.file.as_mut() {
+            Some(f) => f,
+            None => return Vec::new(),
+        };
+
+        // /dev/kmsg returns a single record per read and signals the end with EAGAIN
+        let mut data = Vec::new();
+        let mut buf = vec![0u8; 8192];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                // the record was overwritten in the ring buffer before we could read it
+                Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+                Err(e) => {
+                    debug!("Failed to read kernel log: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let text = String::from_utf8_lossy(&data);
+        let mut messages: Vec<String> = text
+            .lines()
+            // continuation lines carry device metadata, not message text
+            .filter(|l| !l.starts_with(' '))
+            // records are formatted as 'prio,seq,timestamp,flags;message'
+            .map(|l| l.split_once(';').map_or(l, |(_, msg)| msg).to_string())
+            .filter(|msg| keywords.iter().any(|k| !k.is_empty() && msg.contains(k)))
+            .collect();
+        if messages.len() > MAX_MESSAGES {
+            messages.drain(..messages.len() - MAX_MESSAGES);
+        }
+        messages
+    }
+}
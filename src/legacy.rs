@@ -0,0 +1,151 @@
+//! Translation of the command line syntax of the original shell script implementation
+//!
+//! Old automation may still call the commands of the shell script, e.g. `mdevctl create-mdev
+//! UUID PARENT TYPE`. With `--legacy-compat` as the first argument, such command lines are
+//! translated to the current syntax before they are parsed.
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::ffi::OsString;
+
+/// The option that enables the translation, only recognized as the first argument
+pub const LEGACY_COMPAT: &str = "--legacy-compat";
+
+/// Legacy commands, their positional arguments and the current command they translate to. The
+/// positional arguments are passed as the options of the same name.
+const COMMANDS: &[(&str, &[&str], &[&str])] = &[
+    ("create-mdev", &["uuid", "parent", "type"], &["start"]),
+    ("define-mdev", &["uuid", "parent", "type"], &["define"]),
+    ("list-available", &[], &["types"]),
+    ("list-mdevs", &[], &["list"]),
+    ("list-persistent", &[], &["list", "--defined"]),
+    ("remove-mdev", &["uuid"], &["stop"]),
+    ("start-mdev", &["uuid"], &["start"]),
+    ("stop-mdev", &["uuid"], &["stop"]),
+    ("undefine-mdev", &["uuid"], &["undefine"]),
+];
+
+/// Options that were renamed
+const OPTIONS: &[(&str, &str)] = &[("--persistent", "--defined")];
+
+/// Translate the arguments following `--legacy-compat`. Arguments in the current syntax are
+/// passed through, except for renamed options.
+pub fn translate(args: &[String]) -> Result<Vec<String>> {
+    let (command, rest) = match args.split_first() {
+        Some(split) => split,
+        None => return Ok(Vec::new()),
+    };
+    let mut translated = Vec::new();
+    let mut rest = rest.iter();
+    if command == "set-start" {
+        let (uuid, start) = match (rest.next(), rest.next()) {
+            (Some(uuid), Some(start)) if start == "auto" || start == "manual" => (uuid, start),
+            _ => return Err(anyhow!("Usage: mdevctl set-start UUID auto|manual")),
+        };
+        translated.extend(["modify", "--uuid", uuid, &format!("--{}", start)].map(String::from));
+    } else if let Some((name, positional, current)) = COMMANDS.iter().find(|c| c.0 == command) {
+        translated.extend(current.iter().map(|a| a.to_string()));
+        for option in positional.iter() {
+            let value = rest.next().ok_or_else(|| {
+                anyhow!(
+                    "Usage: mdevctl {} {}",
+                    name,
+                    positional.join(" ").to_uppercase()
+                )
+            })?;
+            translated.push(format!("--{}", option));
+            translated.push(value.clone());
+        }
+    } else {
+        translated.push(command.clone());
+    }
+    for arg in rest {
+        translated.push(match OPTIONS.iter().find(|o| o.0 == arg) {
+            Some((_, current)) => current.to_string(),
+            None => arg.clone(),
+        });
+    }
+
+    if translated != args {
+        warn!(
+            "Deprecated command line translated to 'mdevctl {}'",
+            translated.join(" ")
+        );
+    }
+    Ok(translated)
+}
+
+/// Translate the command line of the process if it starts with `--legacy-compat`, otherwise
+/// return it unchanged
+pub fn translate_args(args: Vec<OsString>) -> Result<Vec<OsString>> {
+    if args.get(1).is_none_or(|a| a != LEGACY_COMPAT) {
+        return Ok(args);
+    }
+    let legacy = args[2..]
+        .iter()
+        .map(|a| {
+            a.clone()
+                .into_string()
+                .map_err(|a| anyhow!("Invalid argument {:?}", a))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut translated = vec![args[0].clone()];
+    translated.extend(translate(&legacy)?.into_iter().map(OsString::from));
+    Ok(translated)
+}
+
+#[test]
+fn test_translate() {
+    let translate = |args: &str| {
+        translate(
+            &args
+                .split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>(),
+        )
+        .map(|a| a.join(" "))
+    };
+    let uuid = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    for (legacy, current) in [
+        (
+            format!("create-mdev {} 0000:00:02.0 i915-GVTg_V5_4", uuid),
+            format!(
+                "start --uuid {} --parent 0000:00:02.0 --type i915-GVTg_V5_4",
+                uuid
+            ),
+        ),
+        (
+            format!("define-mdev {} 0000:00:02.0 i915-GVTg_V5_4 --auto", uuid),
+            format!(
+                "define --uuid {} --parent 0000:00:02.0 --type i915-GVTg_V5_4 --auto",
+                uuid
+            ),
+        ),
+        (
+            format!("remove-mdev {}", uuid),
+            format!("stop --uuid {}", uuid),
+        ),
+        (
+            format!("set-start {} auto", uuid),
+            format!("modify --uuid {} --auto", uuid),
+        ),
+        (
+            "list-mdevs --dumpjson".to_string(),
+            "list --dumpjson".to_string(),
+        ),
+        ("list-persistent".to_string(), "list --defined".to_string()),
+        (
+            "list --persistent".to_string(),
+            "list --defined".to_string(),
+        ),
+        ("list-available".to_string(), "types".to_string()),
+        // the current syntax is passed through
+        (format!("start -u {}", uuid), format!("start -u {}", uuid)),
+        (String::new(), String::new()),
+    ] {
+        assert_eq!(current, translate(&legacy).unwrap());
+    }
+    for legacy in ["create-mdev 976d8cc2", "set-start 976d8cc2 always"] {
+        assert!(translate(legacy).is_err());
+    }
+}
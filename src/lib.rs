@@ -0,0 +1,34 @@
+//! The modules of mdevctl, built as a library for the `mdevctl` executable and for other programs
+//! that manage mediated devices, e.g. init systems that start the devices of a parent with
+//! [`autostart::start_parent_mdevs`] rather than running `mdevctl start-parent-mdevs`, and the
+//! fuzz targets.
+//!
+//! The interface follows the needs of the executable and is not stable across releases.
+
+pub mod audit;
+pub mod autostart;
+pub mod bulk;
+#[cfg(feature = "archives")]
+pub mod bundle;
+pub mod callouts;
+pub mod changelog;
+pub mod cli;
+pub mod doctor;
+pub mod environment;
+pub mod error;
+pub mod estimate;
+pub mod kmsg;
+pub mod legacy;
+pub mod logger;
+pub mod mdev;
+pub mod migrate;
+#[cfg(feature = "monitor")]
+pub mod monitor;
+pub mod namespace;
+pub mod pciids;
+pub mod plugins;
+pub mod profile;
+#[cfg(feature = "archives")]
+pub mod state;
+pub mod systemd;
+pub mod udev;
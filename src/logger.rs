@@ -1,8 +1,336 @@
 //! Logging implementation for mdevctl
+//!
+//! Messages are logged with the `log` macros and collected by a `tracing` subscriber, which
+//! writes them to stderr filtered by `MDEVCTL_LOG`. Log messages are prefixed with the spans they
+//! were logged in, i.e. the phases of the command execution such as the command itself, the
+//! device it acts on and the callout script being run, so that the messages of interleaved
+//! operations can be told apart. With the `otlp` feature, the spans are also exported with OTLP
+//! if an endpoint is configured, so that the device lifecycle operations of a fleet of hosts can
+//! be traced centrally.
+//!
+//! The messages of a thread can also be captured with [`capture`], including the debug messages
+//! that are not logged, e.g. for the profile of `start --capture-profile`.
 
-pub fn logger() -> env_logger::Builder {
-    let env = env_logger::Env::new()
-        .filter_or("MDEVCTL_LOG", "warn")
-        .write_style("MDEVCTL_LOG_STYLE");
-    env_logger::Builder::from_env(env)
+use log::debug;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fmt::{self, Write as _};
+use std::io::IsTerminal;
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, EnteredSpan, Id};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::filter::{DynFilterFn, Targets};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Scope};
+use tracing_subscriber::util::{SubscriberInitExt, TryInitError};
+use tracing_subscriber::{Layer, Registry};
+
+thread_local! {
+    static CAPTURED: RefCell<Option<(Instant, Vec<Record>)>> = const { RefCell::new(None) };
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Install the subscriber that writes the log to stderr, or to the output captured by the test
+/// harness with `is_test`
+pub fn init(is_test: bool) -> Result<(), TryInitError> {
+    let targets = std::env::var("MDEVCTL_LOG")
+        .ok()
+        .and_then(|s| s.parse::<Targets>().ok())
+        .unwrap_or_else(|| Targets::new().with_default(Level::WARN));
+    let ansi = match std::env::var("MDEVCTL_LOG_STYLE").as_deref() {
+        Ok("always") => true,
+        Ok("never") => false,
+        _ => std::io::stderr().is_terminal(),
+    };
+    // spans are always enabled, so that the messages are prefixed with them at any level
+    let filter = DynFilterFn::new(move |meta: &Metadata<'_>, _: &Context<'_, Registry>| {
+        meta.is_span() || targets.would_enable(meta.target(), meta.level())
+    });
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_ansi(ansi)
+        .event_format(Format);
+    let fmt: BoxedLayer = match is_test {
+        true => Box::new(fmt.with_test_writer().with_filter(filter)),
+        false => Box::new(fmt.with_writer(std::io::stderr).with_filter(filter)),
+    };
+    let phases: BoxedLayer = Box::new(PhaseLayer.with_filter(DynFilterFn::new(
+        |meta: &Metadata<'_>, _: &Context<'_, Registry>| meta.is_span() || capturing(),
+    )));
+    #[cfg(feature = "otlp")]
+    let otlp = otlp::layer();
+    #[cfg(not(feature = "otlp"))]
+    let otlp = None;
+    let layers: Vec<_> = vec![Some(phases), Some(fmt), otlp]
+        .into_iter()
+        .flatten()
+        .collect();
+    tracing_subscriber::registry().with(layers).try_init()
+}
+
+/// Flush the spans that were not exported yet before mdevctl exits
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    otlp::shutdown();
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use super::BoxedLayer;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use std::sync::OnceLock;
+    use tracing_subscriber::Layer;
+
+    static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+    /// The layer exporting the spans to the endpoint of `OTEL_EXPORTER_OTLP_ENDPOINT` or
+    /// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`, if either is set. Spans are exported as they end,
+    /// as mdevctl does not run long enough to batch them.
+    pub fn layer() -> Option<BoxedLayer> {
+        let configured = [
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        ]
+        .iter()
+        .any(|v| std::env::var_os(v).is_some_and(|s| !s.is_empty()));
+        if !configured {
+            return None;
+        }
+        let exporter = match opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+        {
+            Ok(exporter) => exporter,
+            Err(e) => {
+                eprintln!("Unable to create the OTLP exporter: {}", e);
+                return None;
+            }
+        };
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("mdevctl").build())
+            .build();
+        let tracer = provider.tracer("mdevctl");
+        let _ = PROVIDER.set(provider);
+        Some(Box::new(
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(tracing_subscriber::filter::LevelFilter::INFO),
+        ))
+    }
+
+    pub fn shutdown() {
+        if let Some(provider) = PROVIDER.get() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// The name of a span, see [`span`]
+struct Phase(String);
+
+/// The names of the spans from the outermost to the innermost, e.g. `start > pre-start`
+fn phases<R>(scope: Option<Scope<'_, R>>) -> String
+where
+    R: for<'a> LookupSpan<'a>,
+{
+    let mut phases = Vec::new();
+    for span in scope.into_iter().flat_map(|s| s.from_root()) {
+        if let Some(phase) = span.extensions().get::<Phase>() {
+            phases.push(phase.0.clone());
+        }
+    }
+    phases.join(" > ")
+}
+
+/// The message of an event, followed by any other fields, e.g. `name=value`
+#[derive(Default)]
+struct Message {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => {
+                let _ = write!(self.message, "{:?}", value);
+            }
+            // the metadata of messages logged with the `log` macros
+            name if name.starts_with("log.") => (),
+            name => {
+                let _ = write!(self.fields, " {}={:?}", name, value);
+            }
+        }
+    }
+}
+
+impl Message {
+    fn of(event: &Event<'_>) -> String {
+        let mut message = Message::default();
+        event.record(&mut message);
+        message.message + &message.fields
+    }
+}
+
+/// The name of a span, from its `otel.name` field, which is also the name of the exported span
+#[derive(Default)]
+struct SpanName(Option<String>);
+
+impl Visit for SpanName {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "otel.name" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// Records the names of the spans, and captures the messages of threads while [`capture`] is
+/// active
+struct PhaseLayer;
+
+impl<S> Layer<S> for PhaseLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut name = SpanName::default();
+        attrs.record(&mut name);
+        if let (Some(name), Some(span)) = (name.0, ctx.span(id)) {
+            span.extensions_mut().insert(Phase(name));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        CAPTURED.with(|c| {
+            if let Some((begin, records)) = c.borrow_mut().as_mut() {
+                records.push(Record {
+                    time: begin.elapsed().as_secs_f64(),
+                    level: event.metadata().level().to_string(),
+                    spans: phases(ctx.event_scope(event)),
+                    message: Message::of(event),
+                });
+            }
+        });
+    }
+}
+
+/// The format of the log, e.g.
+/// `[2022-01-01T00:00:00.000000Z WARN  mdevctl::callouts] start 976d...: message`
+struct Format;
+
+impl<S, N> FormatEvent<S, N> for Format
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        // the metadata of messages logged with the `log` macros is that of the log record
+        let normalized = event.normalized_metadata();
+        let meta = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        write!(writer, "[")?;
+        SystemTime.format_time(&mut writer)?;
+        let level = format!("{:<5}", meta.level());
+        match writer.has_ansi_escapes() {
+            true => write!(writer, " {}{}\x1b[0m", color(meta.level()), level)?,
+            false => write!(writer, " {}", level)?,
+        }
+        write!(writer, " {}] ", meta.target())?;
+        let phases = phases(ctx.event_scope());
+        if !phases.is_empty() {
+            write!(writer, "{}: ", phases)?;
+        }
+        writeln!(writer, "{}", Message::of(event))
+    }
+}
+
+fn color(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "\x1b[31m",
+        Level::WARN => "\x1b[33m",
+        Level::INFO => "\x1b[32m",
+        Level::DEBUG => "\x1b[34m",
+        Level::TRACE => "\x1b[36m",
+    }
+}
+
+fn capturing() -> bool {
+    CAPTURED.with(|c| c.borrow().is_some())
+}
+
+/// A message captured with [`capture`]
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    /// seconds since the capture began
+    pub time: f64,
+    pub level: String,
+    pub spans: String,
+    pub message: String,
+}
+
+/// The capture of the messages of a thread, from [`capture`] until [`Capture::finish`]
+pub struct Capture(());
+
+/// Capture all messages logged on this thread, including debug messages, until the returned
+/// [`Capture`] is finished or dropped
+pub fn capture() -> Capture {
+    CAPTURED.with(|c| *c.borrow_mut() = Some((Instant::now(), Vec::new())));
+    Capture(())
+}
+
+impl Capture {
+    /// Stop capturing and return the captured messages
+    pub fn finish(self) -> Vec<Record> {
+        CAPTURED.with(|c| c.borrow_mut().take().map(|(_, r)| r).unwrap_or_default())
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        CAPTURED.with(|c| *c.borrow_mut() = None);
+    }
+}
+
+/// A phase of the command execution that is entered with [`span`] and left when it is dropped
+pub struct Span {
+    name: String,
+    begin: Instant,
+    entered: Option<EnteredSpan>,
+}
+
+/// Enter a span named `name`. All messages logged on this thread until the returned [`Span`] is
+/// dropped are prefixed with its name, and its duration is logged when it is left.
+pub fn span<S: Into<String>>(name: S) -> Span {
+    let name = name.into();
+    let entered = tracing::info_span!("phase", otel.name = %name).entered();
+    Span {
+        name,
+        begin: Instant::now(),
+        entered: Some(entered),
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        debug!(
+            "{} finished after {:.3}s",
+            self.name,
+            self.begin.elapsed().as_secs_f64()
+        );
+        if let Some(entered) = self.entered.take() {
+            entered.exit();
+        }
+    }
 }
@@ -6,49 +6,150 @@
 //! See `mdevctl help` or the manpage for more information.
 
 use anyhow::{anyhow, ensure, Context, Result};
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use log::{debug, warn};
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::fmt::Write;
 use std::fs;
 use std::io::stdout;
-use std::path::PathBuf;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use uuid::Uuid;
 
+use crate::bulk::{Bulk, OnFailure, Outcome};
+#[cfg(feature = "archives")]
+use crate::bundle::SupportBundle;
 use crate::callouts::*;
-use crate::cli::{LsmdevOptions, MdevctlCommands};
-use crate::environment::{DefaultEnvironment, Environment};
-use crate::logger::logger;
+#[cfg(feature = "callouts")]
+use crate::cli::CalloutTestArgs;
+#[cfg(feature = "archives")]
+use crate::cli::StateCommands;
+use crate::cli::{
+    AttrCommands, AttrMerge, AutostartArgs, CalloutsCommands, DefineArgs, DefinitionChanges,
+    ListColumn, ListOutput, ListSort, LsmdevOptions, Mdevctl, MdevctlCommands, ModifyArgs,
+    ModifyCondition, OutputFormat, StageCommands, StartArgs, TypesGroupBy,
+};
+use crate::environment::{order_by_precedence, DefaultEnvironment, Environment};
+use crate::error::ErrorCategory;
+use crate::estimate::Estimate;
+use crate::logger::span;
 use crate::mdev::*;
+#[cfg(feature = "monitor")]
+use crate::monitor::{Monitor, Snapshot};
+use crate::pciids::{PciId, PciIdDatabase};
+use crate::profile::ProfileCapture;
+#[cfg(feature = "archives")]
+use crate::state::{StateArchive, StateWriter};
 
-mod callouts;
-mod cli;
-mod environment;
-mod logger;
-mod mdev;
+#[cfg(feature = "monitor")]
+use mdevctl::monitor;
+use mdevctl::{
+    autostart, bulk, callouts, changelog, cli, doctor, environment, error, estimate, legacy,
+    logger, mdev, migrate, pciids, profile, systemd, udev,
+};
+#[cfg(feature = "archives")]
+use mdevctl::{bundle, state};
 
 #[cfg(test)]
 mod tests;
 
 /// Format a map of mediated devices into a json string
 fn format_json(devices: BTreeMap<String, Vec<MDev>>) -> Result<String> {
+    format_structured(&devices_json(devices, false)?, OutputFormat::Json)
+}
+
+/// Format a value of the machine readable output in the given format
+fn format_structured(value: &serde_json::Value, format: OutputFormat) -> Result<String> {
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_string_pretty(value).map_err(|_e| anyhow!("Unable to serialize json"))
+        }
+        #[cfg(feature = "rich-output")]
+        OutputFormat::Yaml => {
+            serde_yaml_ng::to_string(value).map_err(|_e| anyhow!("Unable to serialize yaml"))
+        }
+        #[cfg(not(feature = "rich-output"))]
+        OutputFormat::Yaml => Err(anyhow!("mdevctl was built without support for YAML output")),
+    }
+}
+
+/// the JSON representation of a map of mediated devices
+fn devices_json<I>(devices: I, paths: bool) -> Result<serde_json::Value>
+where
+    I: IntoIterator<Item = (String, Vec<MDev>)>,
+{
     let mut parents = serde_json::map::Map::new();
     for (parentname, children) in devices {
         let mut childrenarray = Vec::new();
         for child in children {
-            childrenarray.push(child.to_json(true)?);
+            childrenarray.push(list_json(&child, true, paths)?);
         }
         parents.insert(parentname, childrenarray.into());
     }
     // don't serialize an empty object if there are no devices
-    let jsonval = match parents.len() {
+    Ok(match parents.len() {
         0 => serde_json::json!([]),
         _ => serde_json::json!([parents]),
-    };
-    serde_json::to_string_pretty(&jsonval).map_err(|_e| anyhow!("Unable to serialize json"))
+    })
+}
+
+/// the JSON representation of groups of mediated devices in the given order. Groups are added
+/// to the same object as long as their parents differ, and a group whose parent is already in the
+/// object starts a new one, so that the order of the groups is kept.
+#[cfg(feature = "rich-output")]
+fn devices_groups_json(groups: Vec<(String, Vec<MDev>)>, paths: bool) -> Result<serde_json::Value> {
+    let mut objects: Vec<serde_json::map::Map<String, serde_json::Value>> = Vec::new();
+    for (parentname, children) in groups {
+        let mut childrenarray = Vec::new();
+        for child in children {
+            childrenarray.push(list_json(&child, true, paths)?);
+        }
+        match objects.last_mut() {
+            Some(parents) if !parents.contains_key(&parentname) => {
+                parents.insert(parentname, childrenarray.into());
+            }
+            _ => {
+                let mut parents = serde_json::map::Map::new();
+                parents.insert(parentname, childrenarray.into());
+                objects.push(parents);
+            }
+        }
+    }
+    Ok(objects.into())
+}
+
+/// the JSON representation of a device in the output of the `list` command. In addition to its
+/// configuration, it includes the name of its type if available, and defined devices whose parent
+/// device is not present are marked by their state.
+/// With `paths`, the configuration file and sysfs directory of the device are included where
+/// present.
+fn list_json(dev: &MDev, include_uuid: bool, paths: bool) -> Result<serde_json::Value> {
+    let mut json = dev.to_json(false)?;
+    if let Some(name) = dev.type_name() {
+        json["type_name"] = name.into();
+    }
+    if dev.parent_missing() {
+        json["state"] = "parent-missing".into();
+    }
+    if let Some(assignable) = dev.iommu_group_assignable() {
+        json["assignable"] = assignable.into();
+    }
+    if paths {
+        if let Some(path) = dev.persist_path().filter(|p| p.exists()) {
+            json["config_path"] = path.display().to_string().into();
+        }
+        if dev.active {
+            json["sysfs_path"] = dev.path().display().to_string().into();
+        }
+    }
+    match include_uuid {
+        true => Ok(serde_json::json!({ dev.uuid.hyphenated().to_string(): json })),
+        false => Ok(json),
+    }
 }
 
 /// convert 'define' command arguments into a MDev struct
@@ -85,7 +186,7 @@ fn define_command_helper(
             return Err(anyhow!(
                 "Cowardly refusing to overwrite existing config for {}/{}",
                 parent,
-                uuid.hyphenated().to_string()
+                uuid.hyphenated()
             ));
         }
 
@@ -93,6 +194,8 @@ fn define_command_helper(
             .with_context(|| format!("Unable to read jsonfile {:?}", jsonfile))?;
         let jsonval = serde_json::from_str(&filecontents)?;
         dev.load_from_json(parent, &jsonval)?;
+        // whether the device is already active on the same parent with the same type
+        dev.load_from_sysfs()?;
     } else {
         if uuid_provided {
             dev.load_from_sysfs()?;
@@ -119,7 +222,7 @@ fn define_command_helper(
         if dev.is_defined() {
             return Err(anyhow!(
                 "Device {} on {} already defined",
-                dev.uuid.hyphenated().to_string(),
+                dev.uuid.hyphenated(),
                 dev.parent()?
             ));
         }
@@ -128,54 +231,300 @@ fn define_command_helper(
     Ok(dev)
 }
 
-/// Implementation of the `mdevctl define` command
-fn define_command(
+/// Derive device properties from the sysfs path given with `--path`, if any
+fn path_spec(env: &Rc<dyn Environment>, path: Option<&PathBuf>) -> Result<DeviceSpec> {
+    match path {
+        Some(p) => DeviceSpec::from_sysfs_path(env.as_ref(), p),
+        None => Ok(DeviceSpec::default()),
+    }
+}
+
+/// Combine a value specified on the command line with the value derived from a sysfs path. Both
+/// values must match if they are given.
+fn merge_path_arg<T: PartialEq + std::fmt::Display>(
+    name: &str,
+    arg: Option<T>,
+    derived: Option<T>,
+    path: Option<&PathBuf>,
+) -> Result<Option<T>> {
+    match (arg, derived) {
+        (Some(a), Some(d)) if a != d => Err(anyhow!(
+            "Specified {} '{}' does not match {} '{}' of path {:?}",
+            name,
+            a,
+            name,
+            d,
+            path.unwrap()
+        )),
+        (a, d) => Ok(a.or(d)),
+    }
+}
+
+/// Get the UUID of a device that may be specified either directly or by a sysfs path
+fn require_uuid(uuid: Option<Uuid>, spec: &DeviceSpec, path: Option<&PathBuf>) -> Result<Uuid> {
+    merge_path_arg("UUID", uuid, spec.uuid, path)?.ok_or_else(|| {
+        anyhow!(
+            "Path {:?} does not identify a mediated device",
+            path.unwrap()
+        )
+    })
+}
+
+/// Ask the user to confirm an action on the terminal. Anything but an answer starting with 'y'
+/// declines, including the end of the input.
+fn confirm(prompt: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", prompt);
+    std::io::Write::flush(&mut std::io::stderr())?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .with_context(|| "Failed to read answer")?;
+    Ok(answer.trim_start().to_lowercase().starts_with('y'))
+}
+
+/// The definition of the device with the given `index` among the devices defined at once
+fn prepare_definition(
     env: Rc<dyn Environment>,
-    uuid: Option<Uuid>,
-    auto: bool,
-    parent: Option<String>,
-    mdev_type: Option<String>,
-    jsonfile: Option<PathBuf>,
-    force: bool,
-) -> Result<()> {
-    debug!("Defining mdev {:?}", uuid);
+    args: &DefineArgs,
+    index: Option<u32>,
+) -> Result<MDev> {
+    let mut dev = define_command_helper(
+        env,
+        args.uuid,
+        args.auto,
+        args.parent.clone(),
+        args.mdev_type.clone(),
+        args.jsonfile.clone(),
+    )?;
+    if let Some(index) = index {
+        dev.expand_attr_templates(index)?;
+    }
+    for (key, value) in args.label.iter() {
+        dev.set_label(key.clone(), value.clone())?;
+    }
+    Ok(dev)
+}
+
+/// How the attributes that call-out scripts provide for an active device are added to its
+/// definition, if at all. A definition from a JSON file is expected to be complete, so they are
+/// only merged into it if `--attr-merge` is given or the `attr_merge` policy is set.
+fn callout_attr_merge(attr_merge: Option<AttrMerge>, jsonfile: bool) -> Option<AttrMerge> {
+    match (attr_merge, jsonfile) {
+        (Some(merge), _) => Some(merge),
+        (None, false) => Some(AttrMerge::Append),
+        (None, true) => None,
+    }
+}
+
+/// Implementation of the `mdevctl define` command
+///
+/// With a `count` greater than one, that many devices are defined with generated UUIDs, and the
+/// template expressions in their attribute values are expanded with the index of each device, as
+/// they are for a single device with `expand_templates`. If defining one of several devices
+/// fails, the devices defined before it are undefined again. The UUID, parent and type of `args`
+/// are those resolved from its sysfs path, if given.
+fn define_command(env: Rc<dyn Environment>, args: &DefineArgs) -> Result<()> {
+    debug!("Defining mdev {:?}", args.uuid);
+    let count = args.count;
+    if count > 1 && args.uuid.is_some() {
+        return Err(anyhow!("A UUID cannot be assigned to more than one device"));
+    }
+
+    let mut defined = Vec::new();
+    for index in 0..count {
+        let index_arg = (count > 1 || args.expand_templates).then_some(index);
+        let res = define_one(env.clone(), args, index_arg).and_then(|dev| {
+            defined.push(dev);
+            let dev = defined.last().unwrap();
+            if args.now || Policy::load(dev.env.as_ref())?.auto_start_on_define {
+                start_after_define(dev, args.force)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            return match count {
+                1 => Err(e),
+                _ => Err(undo_define(defined, index, count, args.force, e)),
+            };
+        }
+    }
+    Ok(())
+}
 
-    let mut dev = define_command_helper(env, uuid, auto, parent, mdev_type, jsonfile)?;
+/// Define a single device for `define`, returning it once it is defined
+fn define_one(env: Rc<dyn Environment>, args: &DefineArgs, index: Option<u32>) -> Result<MDev> {
+    let mut dev = prepare_definition(env, args, index)?;
 
     /*
-        Call Callout::get_attributes() when defining an active device without a config file.
-        This function allows callout script to acquire device-specific attributes from sysfs,
-        and populate the attrs field correspondingly before the device is defined in the system.
-        The device config file will contain the same attributes that were used to start this device。
+        Call Callout::get_attributes() when defining an active device, so that callout scripts
+        can acquire device-specific attributes from sysfs and merge them into the attributes of
+        the definition, as selected by --attr-merge or the attr_merge policy. By default, they
+        are only queried if the definition is not from a config file, and are then appended, so
+        that the config file contains the same attributes that were used to start the device.
     */
+    let attr_merge = match args.attr_merge {
+        Some(merge) => Some(merge),
+        None => Policy::load(dev.env.as_ref())?.attr_merge,
+    };
+    let merge = callout_attr_merge(attr_merge, args.jsonfile.is_some());
     let mut c = callout(&mut dev)?;
-    c.invoke(Action::Define, force, |c| {
-        if c.dev.active {
+    c.invoke(Action::Define, args.force, |c| {
+        if let (true, Some(merge)) = (c.dev.active, merge) {
             let attrs = c.get_attributes()?;
-            c.dev.add_attributes(&attrs)?;
+            c.dev.add_callout_attributes(&attrs, merge)?;
         }
         c.dev.define()
-    })
-    .map(|_| {
-        if uuid.is_none() {
-            println!("{}", dev.uuid.hyphenated());
+    })?;
+    if args.uuid.is_none() {
+        println!("{}", dev.uuid.hyphenated());
+    }
+    Ok(dev)
+}
+
+/// Undo the devices that `define --count` defined before defining the device at `index` of
+/// `count` failed with `error`: the devices it started are stopped and all of them are undefined
+/// again. The returned error names the devices that could not be undone.
+fn undo_define(
+    defined: Vec<MDev>,
+    index: u32,
+    count: u32,
+    force: bool,
+    error: anyhow::Error,
+) -> anyhow::Error {
+    let mut remaining = Vec::new();
+    for mut dev in defined.into_iter().rev() {
+        // devices that were active before they were defined are left running
+        let was_active = dev.active;
+        let res = dev.load_from_sysfs().and_then(|_| {
+            if dev.active && !was_active {
+                callout(&mut dev)?.invoke(Action::Stop, force, |c| c.dev.stop())?;
+            }
+            callout(&mut dev)?.invoke(Action::Undefine, force, |c| c.dev.undefine())
+        });
+        if let Err(e) = res {
+            warn!(
+                "Unable to undo the definition of {}: {:#}",
+                dev.uuid.hyphenated(),
+                e
+            );
+            remaining.push(dev.uuid.hyphenated().to_string());
         }
-    })
+    }
+    let summary = format!("Failed to define device {} of {}", index + 1, count);
+    match remaining.is_empty() {
+        true => error.context(format!(
+            "{}, the devices defined before were undefined again",
+            summary
+        )),
+        false => error.context(format!(
+            "{}, devices {} remain defined",
+            summary,
+            remaining.join(", ")
+        )),
+    }
+}
+
+/// Implementation of `mdevctl define --print-only`
+///
+/// The devices are checked and the call-out scripts are invoked for the pre event of the define
+/// action like for `define`, but the definitions are printed in the format of `export` instead of
+/// being saved, so that they can be reviewed and later defined with `import`.
+fn define_print_only_command(
+    env: Rc<dyn Environment>,
+    args: &DefineArgs,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let count = args.count;
+    if count > 1 && args.uuid.is_some() {
+        return Err(anyhow!("A UUID cannot be assigned to more than one device"));
+    }
+
+    let mut devs: BTreeMap<String, Vec<MDev>> = BTreeMap::new();
+    for index in 0..count {
+        let index = (count > 1 || args.expand_templates).then_some(index);
+        let mut dev = prepare_definition(env.clone(), args, index)?;
+        dev.check_unique_labels()?;
+        let merge = callout_attr_merge(args.attr_merge, args.jsonfile.is_some());
+        callout(&mut dev)?.invoke_dry_run(Action::Define, args.force, |c| {
+            if let (true, Some(merge)) = (c.dev.active, merge) {
+                let attrs = c.get_attributes()?;
+                c.dev.add_callout_attributes(&attrs, merge)?;
+            }
+            Ok(())
+        })?;
+        devs.entry(dev.parent()?.clone()).or_default().push(dev);
+    }
+
+    let mut parents = serde_json::Map::new();
+    for (parent, children) in devs {
+        let children = children
+            .iter()
+            .map(|dev| dev.to_json(true))
+            .collect::<Result<Vec<_>>>()?;
+        parents.insert(parent, children.into());
+    }
+    let mut text = serde_json::to_string_pretty(&serde_json::json!([parents]))
+        .with_context(|| "Unable to serialize json")?;
+    text.push('\n');
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")
+}
+
+/// Start a device that was just defined, unless it is already active or its parent has no
+/// capacity for it
+fn start_after_define(dev: &MDev, force: bool) -> Result<()> {
+    if dev.active {
+        debug!("Device {} is already active", dev.uuid.hyphenated());
+        return Ok(());
+    }
+    match dev.available_instances() {
+        Ok(n) if n > 0 => {
+            start_command_helper(
+                dev.env.clone(),
+                Some(dev.uuid),
+                dev.parent.clone(),
+                None,
+                None,
+                force,
+                false,
+            )?;
+        }
+        Ok(_) => warn!(
+            "Not starting device {}: no available instances of {} on {}",
+            dev.uuid.hyphenated(),
+            dev.mdev_type()?,
+            dev.parent()?
+        ),
+        Err(e) => warn!("Not starting device {}: {:#}", dev.uuid.hyphenated(), e),
+    }
+    Ok(())
 }
 
 /// Implementation of the `mdevctl undefine` command
 fn undefine_command(
     env: Rc<dyn Environment>,
-    uuid: Uuid,
+    uuid: Option<Uuid>,
     parent: Option<String>,
+    all: bool,
     force: bool,
 ) -> Result<()> {
     debug!("Undefining mdev {:?}", uuid);
+    match (uuid, all, parent.as_ref()) {
+        (Some(_), false, _) => (),
+        (None, true, Some(_)) => (),
+        (None, true, None) => return Err(anyhow!("A parent is required to undefine all devices")),
+        _ => return Err(anyhow!("Either a UUID or --all is required")),
+    }
     let mut failed = false;
     let devs = env
         .clone()
-        .get_defined_devices(Some(&uuid), parent.as_ref())?;
+        .get_defined_devices(uuid.as_ref(), parent.as_ref())?;
     if devs.is_empty() {
+        if all {
+            debug!("No devices defined on parent {:?}", parent);
+            return Ok(());
+        }
         return Err(anyhow!("No devices match the specified uuid"));
     }
     for (_, mut children) in devs {
@@ -187,7 +536,7 @@ fn undefine_command(
                     warn!(
                         "Undefine of {} on parent {} failed with error: {}",
                         c.dev.uuid,
-                        c.dev.parent().unwrap().to_string(),
+                        c.dev.parent().unwrap(),
                         x
                     );
                 }
@@ -200,25 +549,144 @@ fn undefine_command(
     Ok(())
 }
 
-/// Implementation of the `mdevctl modify` command
-#[allow(clippy::too_many_arguments)]
-fn modify_command(
+/// Implementation of the `mdevctl clone` command. Labels that must be unique are not copied,
+/// as the copy would conflict with the source device, unless they are assigned with `labels`.
+fn clone_command(
     env: Rc<dyn Environment>,
     uuid: Uuid,
     parent: Option<String>,
-    mdev_type: Option<String>,
-    addattr: Option<String>,
-    delattr: bool,
-    index: Option<u32>,
-    value: Option<String>,
-    auto: bool,
-    manual: bool,
-    live: bool,
-    defined: bool,
-    jsonfile: Option<PathBuf>,
+    new_uuid: Option<Uuid>,
+    new_parent: Option<String>,
+    labels: Vec<(String, String)>,
+    force: bool,
+) -> Result<()> {
+    let source = env.clone().get_defined_device(uuid, parent.as_ref())?;
+    let parent = new_parent.unwrap_or(source.parent()?.clone());
+    let policy = LabelPolicy::load(env.as_ref())?;
+
+    let mut dev = MDev::new(env, new_uuid.unwrap_or_else(Uuid::new_v4));
+    dev.load_from_json(parent, &source.to_json(false)?)?;
+    for key in policy.unique.iter() {
+        if dev.labels.remove(key).is_some() {
+            debug!("Not copying the unique label '{}'", key);
+        }
+    }
+    for (key, value) in labels {
+        dev.set_label(key, value)?;
+    }
+    ensure!(
+        !dev.is_defined(),
+        "Device {} on {} already defined",
+        dev.uuid.hyphenated(),
+        dev.parent()?
+    );
+    debug!("Cloning mdev {:?} as {:?}", uuid, dev.uuid);
+
+    callout(&mut dev)?.invoke(Action::Define, force, |c| c.dev.define())?;
+    if new_uuid.is_none() {
+        println!("{}", dev.uuid.hyphenated());
+    }
+    Ok(())
+}
+
+/// Implementation of the `mdevctl set-uuid` command
+fn set_uuid_command(
+    env: Rc<dyn Environment>,
+    uuid: Uuid,
+    parent: Option<String>,
+    new_uuid: Uuid,
     force: bool,
+) -> Result<()> {
+    let mut dev = env.get_defined_device(uuid, parent.as_ref())?;
+    ensure!(
+        !dev.active,
+        "Device {} is active, stop it before changing its UUID",
+        uuid.hyphenated()
+    );
+    let old_path = dev.persist_path().unwrap();
+    dev.uuid = new_uuid;
+    ensure!(
+        !dev.is_defined(),
+        "Device {} on {} already defined",
+        new_uuid.hyphenated(),
+        dev.parent()?
+    );
+    debug!("Changing UUID of mdev {:?} to {:?}", uuid, new_uuid);
+
+    let mut c = callout(&mut dev)?;
+    c.set_var("MDEVCTL_OLD_UUID", uuid.hyphenated().to_string());
+    c.invoke(Action::Modify, force, |c| {
+        // the definition does not include the UUID, so renaming it is sufficient
+        let new_path = c.dev.persist_path().unwrap();
+        fs::rename(&old_path, &new_path)
+            .with_context(|| format!("Failed to rename {:?} to {:?}", old_path, new_path))
+    })
+}
+
+/// Implementation of the `mdevctl modify` command, for the device `uuid`. The parent of `args` is
+/// the one resolved from its sysfs path, if given.
+fn modify_command(
+    env: Rc<dyn Environment>,
+    uuid: Uuid,
+    args: ModifyArgs,
+    output: &mut dyn std::io::Write,
 ) -> Result<()> {
     debug!("Modifying mdev {:?}", uuid);
+    let ModifyArgs {
+        parent,
+        changes,
+        regenerate_attrs,
+        live,
+        defined,
+        force,
+        only_if,
+        ..
+    } = args;
+    let DefinitionChanges {
+        mdev_type,
+        addattr,
+        delattr,
+        clear_attrs,
+        index,
+        value,
+        auto,
+        manual,
+        jsonfile,
+        label: labels,
+        remove_label: remove_labels,
+    } = changes;
+    if let Some(condition) = only_if {
+        let (met, state) = match condition {
+            ModifyCondition::Active => (
+                !env.clone()
+                    .get_active_devices(Some(&uuid), parent.as_ref())?
+                    .is_empty(),
+                "active",
+            ),
+            ModifyCondition::Inactive => (
+                env.clone()
+                    .get_active_devices(Some(&uuid), parent.as_ref())?
+                    .is_empty(),
+                "inactive",
+            ),
+            ModifyCondition::Defined => (
+                !env.clone()
+                    .get_defined_devices(Some(&uuid), parent.as_ref())?
+                    .is_empty(),
+                "defined",
+            ),
+        };
+        if !met {
+            writeln!(
+                output,
+                "Device {} is not {}, not modified",
+                uuid.hyphenated(),
+                state
+            )
+            .with_context(|| "Failed to write data")?;
+            return Ok(());
+        }
+    }
     if live {
         if mdev_type.is_some() {
             return Err(anyhow!("'type' cannot be changed on active mdev"));
@@ -230,6 +698,7 @@ fn modify_command(
             return Err(anyhow!("'manual' cannot be changed on active mdev"));
         }
         let mut act_dev = env.clone().get_active_device(uuid, parent.as_ref())?;
+        let old = act_dev.attrs.clone();
         if let Some(f) = jsonfile {
             let act_parent = act_dev
                 .parent
@@ -256,400 +725,2647 @@ fn modify_command(
                 return Err(anyhow!("'type' of active and defined mdev does not match"));
             }
 
-            let mut c = callout(&mut act_dev)?;
-            debug!("mdev device used for live update '{:?}'", c.dev);
-            return c
-                .invoke_modify_live()
-                .and_then(|_| c.invoke(Action::Modify, force, |c| c.dev.write_config()));
+            debug!("mdev device used for live update '{:?}'", act_dev);
+            let command = "modify --live --defined";
+            return apply_changes(&mut act_dev, &old, true, true, force, command);
         }
         // live modify only
-        callout(&mut act_dev)?.invoke_modify_live()
+        apply_changes(&mut act_dev, &old, true, false, force, "modify --live")
     } else {
         let mut dev: MDev;
+        let old: Vec<(String, String)>;
         // stored configuration modify
         if let Some(f) = jsonfile {
             let parent = parent
                 .ok_or_else(|| anyhow!("Parent device required to modify device via json file"))?;
+            old = env
+                .clone()
+                .get_defined_device(uuid, Some(&parent))
+                .map(|d| d.attrs)
+                .unwrap_or_default();
             dev = MDev::new_from_jsonfile(env.clone(), uuid, parent, f)?;
         } else {
             dev = env.clone().get_defined_device(uuid, parent.as_ref())?;
-            if mdev_type.is_some() {
-                dev.mdev_type = mdev_type;
-            }
-            if auto && manual {
-                return Err(anyhow!("'auto' and 'manual' are mutually exclusive"));
-            }
-            if auto {
-                dev.autostart = true;
-            } else if manual {
-                dev.autostart = false;
-            }
+            old = dev.attrs.clone();
+            modify_definition(&mut dev, mdev_type, auto, manual, labels, remove_labels)?;
         }
 
-        let index = index.map(|n| n as usize);
-        match addattr {
-            Some(attr) => match value {
-                None => return Err(anyhow!("No attribute value provided")),
-                Some(v) => dev.add_attribute(attr, v, index)?,
-            },
-            None => {
-                if delattr {
-                    dev.delete_attribute(index)?;
-                }
-            }
+        if regenerate_attrs {
+            regenerate_callout_attributes(&mut dev)?;
         }
-        callout(&mut dev)?.invoke(Action::Modify, force, |c| c.dev.write_config())
+        modify_attributes(&mut dev, addattr, delattr, clear_attrs, index, value)?;
+        apply_changes(&mut dev, &old, false, true, force, "modify")
     }
 }
 
-/// convert 'start' command arguments into a MDev struct
-fn start_command_helper(
-    env: Rc<dyn Environment>,
-    uuid: Option<Uuid>,
-    parent: Option<String>,
-    mdev_type: Option<String>,
-    jsonfile: Option<PathBuf>,
+/// Apply the changes to a device with the call-outs of the `modify` command, to the running
+/// device with `live` and to its definition with `define`, and record the attribute changes
+/// against `old` in the change log of the device
+fn apply_changes(
+    dev: &mut MDev,
+    old: &[(String, String)],
+    live: bool,
+    define: bool,
     force: bool,
-) -> Result<MDev> {
-    debug!("Starting device '{:?}'", uuid);
-    let mut dev: Option<MDev> = None;
-    match jsonfile {
-        Some(fname) => {
-            let contents = fs::read_to_string(&fname)
-                .with_context(|| format!("Unable to read jsonfile {:?}", fname))?;
-            let val = serde_json::from_str(&contents)?;
-
-            if mdev_type.is_some() {
-                return Err(anyhow!(
-                    "Device type cannot be specified separately from json file"
-                ));
-            }
-
-            let parent = parent
-                .ok_or_else(|| anyhow!("Parent device required to start device via json file"))?;
-
-            let mut d = MDev::new(env.clone(), uuid.unwrap_or_else(Uuid::new_v4));
-            d.load_from_json(parent, &val)?;
-            dev = Some(d);
-        }
-        _ => {
-            // if the user specified a uuid, check to see if they're referring to a defined device
-            if uuid.is_some() {
-                let devs = env
-                    .clone()
-                    .get_defined_devices(uuid.as_ref(), parent.as_ref())?;
-                let n = devs.values().flatten().count();
-                match n.cmp(&1) {
-                    Ordering::Greater => {
-                        return Err(anyhow!(
-                            "Multiple definitions found for device {}. Please specify a parent.",
-                            uuid.unwrap().hyphenated().to_string()
-                        ));
-                    }
-                    Ordering::Equal => {
-                        // FIXME: use into_values() to consume the iterator and avoid cloning below
-                        // when we can require rust 1.54.0
-                        let d = devs.values().flatten().next();
-                        if let Some(d) = d {
-                            // See https://github.com/mdevctl/mdevctl/issues/38
-                            // If a user specifies the uuid (and optional parent) of a defined device
-                            if mdev_type.is_some() && mdev_type != d.mdev_type {
-                                return Err(anyhow!(
-                                    "Device {} already exists on parent {} with type {}",
-                                    d.uuid.hyphenated().to_string(),
-                                    d.parent().unwrap(),
-                                    d.mdev_type.as_ref().unwrap()
-                                ));
-                            } else {
-                                dev = Some(d.clone());
-                            }
-                        }
-                    }
-                    _ => (),
-                }
-            }
-
-            if dev.is_none() {
-                let mut d = MDev::new(env.clone(), uuid.unwrap_or_else(Uuid::new_v4));
-                d.parent = parent;
-                d.mdev_type = mdev_type;
-                dev = Some(d);
-            }
-
-            if let Some(ref d) = dev {
-                if d.mdev_type.is_some() && d.parent.is_none() {
-                    return Err(anyhow!("can't provide type without parent"));
-                }
-                if d.mdev_type.is_none() || d.parent.is_none() {
-                    return Err(anyhow!("Device is insufficiently specified"));
-                }
-            }
-        }
+    command: &str,
+) -> Result<()> {
+    let mut c = callout(dev)?;
+    if live {
+        c.invoke_modify_live()?;
     }
-    let mut dev = dev.ok_or_else(|| anyhow!("Unknown error"))?;
-
-    callout(&mut dev)?.invoke(Action::Start, force, |c| c.dev.start())?;
-    Ok(dev)
+    if define {
+        c.invoke(Action::Modify, force, |c| c.dev.write_config())?;
+    }
+    changelog::record(
+        dev.env.as_ref(),
+        dev.uuid,
+        dev.parent()?,
+        old,
+        &dev.attrs,
+        command,
+    );
+    Ok(())
 }
 
-/// Implementation of the `mdevctl start` command
-fn start_command(
-    env: Rc<dyn Environment>,
-    uuid: Option<Uuid>,
-    parent: Option<String>,
+/// Apply the changes of the `modify` command other than to the attributes to a definition
+fn modify_definition(
+    dev: &mut MDev,
     mdev_type: Option<String>,
-    jsonfile: Option<PathBuf>,
-    force: bool,
+    auto: bool,
+    manual: bool,
+    labels: Vec<(String, String)>,
+    remove_labels: Vec<String>,
 ) -> Result<()> {
-    let dev = start_command_helper(env, uuid, parent, mdev_type, jsonfile, force)?;
-
-    if uuid.is_none() {
-        println!("{}", dev.uuid.hyphenated());
+    if mdev_type.is_some() {
+        dev.mdev_type = mdev_type;
+    }
+    if auto && manual {
+        return Err(anyhow!("'auto' and 'manual' are mutually exclusive"));
+    }
+    if auto {
+        dev.autostart = true;
+    } else if manual {
+        dev.autostart = false;
+    }
+    for key in remove_labels {
+        dev.remove_label(&key)?;
+    }
+    for (key, value) in labels {
+        dev.set_label(key, value)?;
     }
     Ok(())
 }
 
-/// Implementation of the `mdevctl stop` command
-fn stop_command(env: Rc<dyn Environment>, uuid: Uuid, force: bool) -> Result<()> {
-    debug!("Stopping '{}'", uuid);
-    let mut dev = MDev::new(env, uuid);
-    dev.load_from_sysfs()?;
+/// Replace the attributes of a definition that call-out scripts provided when it was defined
+/// with the ones they provide for the active device now, keeping the attributes set by the user
+fn regenerate_callout_attributes(dev: &mut MDev) -> Result<()> {
+    if !dev.active {
+        return Err(anyhow!(
+            "Device {} must be active to regenerate its attributes",
+            dev.uuid.hyphenated()
+        ));
+    }
+    let attrs = callout(dev)?.get_attributes()?;
+    dev.replace_callout_attributes(&attrs)
+}
+
+/// Apply the attribute changes of the `modify` command to a definition
+fn modify_attributes(
+    dev: &mut MDev,
+    addattr: Option<String>,
+    delattr: bool,
+    clear_attrs: bool,
+    index: Option<u32>,
+    value: Option<String>,
+) -> Result<()> {
+    if clear_attrs {
+        dev.attrs.clear();
+    }
+    let index = index.map(|n| n as usize);
+    match addattr {
+        Some(attr) => match value {
+            None => return Err(anyhow!("No attribute value provided")),
+            Some(v) => dev.add_attribute(attr, v, index)?,
+        },
+        None => {
+            if delattr {
+                dev.delete_attribute(index)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of the `mdevctl edit` command
+///
+/// The definition of the device is edited in a temporary file with `editor`, which is run by the
+/// shell so that it may contain arguments. The edited definition only replaces the current one
+/// if it is valid and accepted by the call-out scripts.
+fn edit_command(
+    env: Rc<dyn Environment>,
+    uuid: Uuid,
+    parent: Option<String>,
+    editor: &str,
+    force: bool,
+) -> Result<()> {
+    let dev = env.clone().get_defined_device(uuid, parent.as_ref())?;
+    let parent = dev.parent()?.clone();
+    let old = dev.attrs.clone();
+    let original = serde_json::to_string_pretty(&dev.to_json(false)?)? + "\n";
+    // the definition is edited as root, so other users must not be able to read or replace the
+    // file, or to have it written anywhere else through a link
+    let dir = tempfile::Builder::new()
+        .prefix("mdevctl-edit-")
+        .permissions(fs::Permissions::from_mode(0o700))
+        .tempdir()
+        .with_context(|| "Failed to create a temporary directory")?;
+    let path = dir.path().join(format!("{}.json", uuid.hyphenated()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut f| std::io::Write::write_all(&mut f, original.as_bytes()))
+        .with_context(|| format!("Failed to write file {:?}", path))?;
+
+    debug!(
+        "Editing definition of {} in {:?} with '{}'",
+        uuid, path, editor
+    );
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg(editor)
+        .arg(&path)
+        .status();
+    let edited = match status {
+        Ok(status) if status.success() => fs::read_to_string(&path).map_err(|e| anyhow!(e)),
+        Ok(status) => Err(anyhow!("Editor '{}' failed ({})", editor, status)),
+        Err(e) => Err(anyhow!(e).context(format!("Unable to run editor '{}'", editor))),
+    };
+    let edited =
+        edited.with_context(|| "Failed to edit the definition, no changes were applied")?;
+    if edited == original {
+        debug!("Definition of {} was not changed", uuid);
+        return Ok(());
+    }
+
+    // keep the edited file if it is rejected, so that the changes are not lost
+    let res = MDev::new_from_jsonfile(env, uuid, parent, path.clone())
+        .with_context(|| "Invalid definition")
+        .and_then(|mut edited_dev| {
+            apply_changes(&mut edited_dev, &old, false, true, force, "edit")
+                .with_context(|| "Failed to apply the changes")
+        });
+    if res.is_err() {
+        let _ = dir.keep();
+    }
+    res.with_context(|| format!("The changes were kept in {:?}", path))
+}
+
+/// Implementation of the `mdevctl attr get` command
+fn attr_get_command(
+    env: Rc<dyn Environment>,
+    uuid: Uuid,
+    parent: Option<String>,
+    name: &str,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let dev = env.get_active_device(uuid, parent.as_ref())?;
+    let path = dev.path().join(name);
+    if name.contains('/') || !path.is_file() {
+        return Err(anyhow!(
+            "Device {} has no attribute '{}'",
+            uuid.hyphenated(),
+            name
+        ));
+    }
+    let value = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read attribute '{}'", name))?;
+    writeln!(output, "{}", value.trim_end()).with_context(|| "Failed to write data")
+}
+
+/// Implementation of the `mdevctl attr set` command
+///
+/// The change is applied by the call-out script like with `modify --live`, which receives the
+/// attributes of the definition of the device with the changed value. The definition itself is
+/// not changed.
+fn attr_set_command(
+    env: Rc<dyn Environment>,
+    uuid: Uuid,
+    parent: Option<String>,
+    name: String,
+    value: String,
+) -> Result<()> {
+    let mut dev = env.clone().get_active_device(uuid, parent.as_ref())?;
+    // the attributes of a running device are only known from its definition
+    if let Ok(def) = env.get_defined_device(uuid, dev.parent.as_ref()) {
+        if def.mdev_type == dev.mdev_type {
+            dev.attrs = def.attrs;
+        }
+    }
+    let old = dev.attrs.clone();
+    let mut existing = dev.attrs.iter_mut().filter(|(n, _)| *n == name);
+    match (existing.next(), existing.next()) {
+        (None, _) => dev.attrs.push((name, value)),
+        (Some(attr), None) => attr.1 = value,
+        (Some(_), Some(_)) => {
+            return Err(anyhow!(
+                "Attribute '{}' is set more than once, use 'modify --live' to change it",
+                name
+            ))
+        }
+    }
+    apply_changes(&mut dev, &old, true, false, false, "attr set")
+}
+
+/// Implementation of the `mdevctl log` command
+fn log_command(
+    env: Rc<dyn Environment>,
+    uuid: Uuid,
+    parent: Option<String>,
+    dumpjson: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let entries = changelog::read(env.as_ref(), uuid)?
+        .into_iter()
+        .filter(|e| parent.as_ref().is_none_or(|p| *p == e.parent))
+        .collect::<Vec<_>>();
+    let text = match dumpjson {
+        true => serde_json::to_string_pretty(&entries)? + "\n",
+        false => entries.iter().map(|e| format!("{}\n", e)).collect(),
+    };
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")
+}
+
+/// Implementation of the `mdevctl stage modify` command
+///
+/// The changes are applied to the staged definition of the device if it already has staged
+/// changes, otherwise to its current definition, and are kept in the staging area until they are
+/// committed with `mdevctl commit`. The hash of the definition that the changes are based on is
+/// staged along with them, so that they are not committed over changes made in the meantime.
+fn stage_modify_command(
+    env: Rc<dyn Environment>,
+    uuid: Uuid,
+    parent: Option<String>,
+    changes: DefinitionChanges,
+) -> Result<()> {
+    debug!("Staging changes of mdev {:?}", uuid);
+    let (mut dev, base) = match changes.jsonfile {
+        Some(f) => {
+            let parent = parent
+                .ok_or_else(|| anyhow!("Parent device required to stage device via json file"))?;
+            let dev = MDev::new_from_jsonfile(env.clone(), uuid, parent, f)?;
+            let base = match env.clone().get_staged_device(uuid, dev.parent.as_ref())? {
+                Some(staged) => staged.staged_base()?,
+                None => dev.config_hash()?,
+            };
+            (dev, base)
+        }
+        None => {
+            let (mut dev, base) = match env.clone().get_staged_device(uuid, parent.as_ref())? {
+                Some(dev) => {
+                    let base = dev.staged_base()?;
+                    (dev, base)
+                }
+                None => {
+                    let dev = env.clone().get_defined_device(uuid, parent.as_ref())?;
+                    let base = dev.config_hash()?;
+                    (dev, base)
+                }
+            };
+            modify_definition(
+                &mut dev,
+                changes.mdev_type,
+                changes.auto,
+                changes.manual,
+                changes.label,
+                changes.remove_label,
+            )?;
+            (dev, base)
+        }
+    };
+    modify_attributes(
+        &mut dev,
+        changes.addattr,
+        changes.delattr,
+        changes.clear_attrs,
+        changes.index,
+        changes.value,
+    )?;
+    dev.write_staged(base.as_deref())
+}
+
+/// Implementation of the `mdevctl stage list` command
+fn stage_list_command(
+    env: Rc<dyn Environment>,
+    dumpjson: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let devices = env.get_staged_devices(None, None)?;
+    let text = match dumpjson {
+        true => format_json(devices)?,
+        false => devices
+            .values()
+            .flatten()
+            .flat_map(|d| d.to_text(FormatType::Defined, true))
+            .collect(),
+    };
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")
+}
+
+/// Implementation of the `mdevctl stage discard` command
+fn stage_discard_command(
+    env: Rc<dyn Environment>,
+    uuid: Option<Uuid>,
+    parent: Option<String>,
+) -> Result<()> {
+    for dev in env
+        .get_staged_devices(uuid.as_ref(), parent.as_ref())?
+        .values()
+        .flatten()
+    {
+        debug!("Discarding staged changes of {:?}", dev.uuid);
+        dev.discard_staged()?;
+    }
+    Ok(())
+}
+
+/// Implementation of the `mdevctl commit` command
+///
+/// The staged changes of all devices are applied like with `mdevctl modify`, including the
+/// callouts. Changes are refused if the definition they are based on changed since they were
+/// staged. If the changes of a device cannot be applied, the configuration files of the devices
+/// that were already changed are restored and all changes are kept in the staging area.
+fn commit_command(env: Rc<dyn Environment>, force: bool) -> Result<()> {
+    let mut staged: Vec<MDev> = env
+        .clone()
+        .get_staged_devices(None, None)?
+        .into_values()
+        .flatten()
+        .collect();
+    if staged.is_empty() {
+        debug!("No staged changes to commit");
+        return Ok(());
+    }
+
+    // check all changes before any of them are applied
+    let mut previous = Vec::new();
+    let mut old = Vec::new();
+    for dev in &staged {
+        dev.mdev_type()?;
+        dev.check_unique_labels()?;
+        if dev.staged_base()? != dev.config_hash()? {
+            return Err(anyhow!(
+                "The definition of device {} changed since its changes were staged, discard \
+                 and stage them again",
+                dev.uuid.hyphenated()
+            ));
+        }
+        previous.push(dev.read_config()?);
+        old.push(
+            env.clone()
+                .get_defined_device(dev.uuid, dev.parent.as_ref())
+                .map(|d| d.attrs)
+                .unwrap_or_default(),
+        );
+    }
+
+    for i in 0..staged.len() {
+        let res = callout(&mut staged[i])?.invoke(Action::Modify, force, |c| c.dev.write_config());
+        if let Err(e) = res {
+            let failed = rollback_commit(env.clone(), &staged[..i], &previous[..i]);
+            let outcome = match failed.is_empty() {
+                true => "no changes were applied".to_string(),
+                false => format!(
+                    "the definitions of devices {} could not be restored",
+                    failed.join(", ")
+                ),
+            };
+            return Err(e.context(format!(
+                "Failed to commit the staged changes of device {}, {}",
+                staged[i].uuid.hyphenated(),
+                outcome
+            )));
+        }
+    }
+
+    // the changes are only recorded once they were all applied, as they are rolled back otherwise
+    for (dev, old) in staged.iter().zip(old) {
+        changelog::record(
+            env.as_ref(),
+            dev.uuid,
+            dev.parent()?,
+            &old,
+            &dev.attrs,
+            "commit",
+        );
+        dev.discard_staged()?;
+    }
+    Ok(())
+}
+
+/// Restore the configuration files of the devices whose staged changes were already committed
+/// to their `previous` contents, `None` if the device was not defined. The files are restored
+/// before the callouts are invoked for the restored definitions, so that the definitions are
+/// consistent even if the callouts fail. Returns the UUIDs of the devices whose files could not
+/// be restored.
+fn rollback_commit(
+    env: Rc<dyn Environment>,
+    committed: &[MDev],
+    previous: &[Option<Vec<u8>>],
+) -> Vec<String> {
+    let mut failed = Vec::new();
+    for (dev, previous) in committed.iter().zip(previous).rev() {
+        let path = match dev.persist_path() {
+            Some(p) => p,
+            None => continue,
+        };
+        debug!("Restoring the definition of {:?} in {:?}", dev.uuid, path);
+        let res = match previous {
+            Some(contents) => restore_file(&path, contents),
+            None => fs::remove_file(&path).map_err(|e| e.into()),
+        };
+        if let Err(e) = res {
+            warn!("Failed to restore {:?}: {:#}", path, e);
+            failed.push(dev.uuid.hyphenated().to_string());
+            continue;
+        }
+        if previous.is_some() {
+            let res = env
+                .clone()
+                .get_defined_device(dev.uuid, dev.parent.as_ref())
+                .and_then(|mut prev| callout(&mut prev)?.invoke(Action::Modify, true, |_| Ok(())));
+            if let Err(e) = res {
+                warn!(
+                    "Failed to invoke the callouts for the restored definition of device {}: {:#}",
+                    dev.uuid.hyphenated(),
+                    e
+                );
+            }
+        }
+    }
+    failed
+}
+
+/// Replace the file `path` with `contents` atomically
+fn restore_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid path {:?}", path))?;
+    let tmp = tempfile::NamedTempFile::new_in(dir)?;
+    fs::write(tmp.path(), contents)?;
+    tmp.persist(path)?;
+    Ok(())
+}
+
+/// convert 'start' command arguments into a MDev struct
+fn start_command_helper(
+    env: Rc<dyn Environment>,
+    uuid: Option<Uuid>,
+    parent: Option<String>,
+    mdev_type: Option<String>,
+    jsonfile: Option<PathBuf>,
+    force: bool,
+    strict_parent_check: bool,
+) -> Result<MDev> {
+    let mut dev = start_device_spec(env, uuid, parent, mdev_type, jsonfile)?;
+    callout(&mut dev)?.invoke(Action::Start, force, |c| c.dev.start(strict_parent_check))?;
+    Ok(dev)
+}
+
+/// Determine the device to start, from a JSON file, a definition or the command line
+fn start_device_spec(
+    env: Rc<dyn Environment>,
+    uuid: Option<Uuid>,
+    parent: Option<String>,
+    mdev_type: Option<String>,
+    jsonfile: Option<PathBuf>,
+) -> Result<MDev> {
+    debug!("Starting device '{:?}'", uuid);
+    env.check_mdev_support()?;
+    let mut dev: Option<MDev> = None;
+    match jsonfile {
+        Some(fname) => {
+            let contents = fs::read_to_string(&fname)
+                .with_context(|| format!("Unable to read jsonfile {:?}", fname))?;
+            let val = serde_json::from_str(&contents)?;
+
+            if mdev_type.is_some() {
+                return Err(anyhow!(
+                    "Device type cannot be specified separately from json file"
+                ));
+            }
+
+            let parent = parent
+                .ok_or_else(|| anyhow!("Parent device required to start device via json file"))?;
+
+            let mut d = MDev::new(env.clone(), uuid.unwrap_or_else(Uuid::new_v4));
+            d.load_from_json(parent, &val)?;
+            dev = Some(d);
+        }
+        _ => {
+            // if the user specified a uuid, check to see if they're referring to a defined device
+            if let Some(uuid) = uuid {
+                let devs = env
+                    .clone()
+                    .get_defined_devices(Some(&uuid), parent.as_ref())?;
+                let n = devs.values().flatten().count();
+                match n.cmp(&1) {
+                    Ordering::Greater => {
+                        return Err(anyhow!(
+                            "Multiple definitions found for device {}. Please specify a parent.",
+                            uuid.hyphenated()
+                        ));
+                    }
+                    Ordering::Equal => {
+                        // FIXME: use into_values() to consume the iterator and avoid cloning below
+                        // when we can require rust 1.54.0
+                        let d = devs.values().flatten().next();
+                        if let Some(d) = d {
+                            // See https://github.com/mdevctl/mdevctl/issues/38
+                            // If a user specifies the uuid (and optional parent) of a defined device
+                            if mdev_type.is_some() && mdev_type != d.mdev_type {
+                                return Err(anyhow!(
+                                    "Device {} already exists on parent {} with type {}",
+                                    d.uuid.hyphenated(),
+                                    d.parent().unwrap(),
+                                    d.mdev_type.as_ref().unwrap()
+                                ));
+                            } else {
+                                dev = Some(d.clone());
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+
+            if dev.is_none() {
+                let mut d = MDev::new(env.clone(), uuid.unwrap_or_else(Uuid::new_v4));
+                d.parent = parent;
+                d.mdev_type = mdev_type;
+                dev = Some(d);
+            }
+
+            if let Some(ref d) = dev {
+                if d.mdev_type.is_some() && d.parent.is_none() {
+                    return Err(anyhow!("can't provide type without parent"));
+                }
+                if d.mdev_type.is_none() || d.parent.is_none() {
+                    return Err(anyhow!("Device is insufficiently specified"));
+                }
+            }
+        }
+    }
+    dev.ok_or_else(|| anyhow!("Unknown error"))
+}
+
+/// Whether the device is active, on `parent` if given, for `start --skip-active`, e.g. as the
+/// udev rules started the device before its systemd unit did
+fn is_active(env: &Rc<dyn Environment>, uuid: Uuid, parent: Option<&String>) -> bool {
+    env.clone().get_active_device(uuid, parent).is_ok()
+}
+
+/// Implementation of the `mdevctl start` command for a single device. The UUID, parent and type
+/// of `args` are those resolved from its sysfs path, if given.
+fn start_command(env: Rc<dyn Environment>, args: StartArgs) -> Result<()> {
+    let profile = args
+        .capture_profile
+        .map(|path| (path, ProfileCapture::begin()));
+    let (uuid, force, wait) = (args.uuid, args.force, args.wait);
+    let strict_parent_check = args.strict_parent_check;
+    let mut device = None;
+    let res = start_device_spec(
+        env.clone(),
+        uuid,
+        args.parent,
+        args.mdev_type,
+        args.jsonfile,
+    )
+    .and_then(|mut dev| {
+        device = dev.to_json(false).ok();
+        callout(&mut dev)?.invoke(Action::Start, force, |c| c.dev.start(strict_parent_check))?;
+        if uuid.is_none() {
+            println!("{}", dev.uuid.hyphenated());
+        }
+        if let Some(secs) = wait {
+            dev.wait_until_ready(Duration::from_secs(secs))?;
+        }
+        Ok(())
+    });
+
+    if let Some((path, capture)) = profile {
+        let written = capture.finish(env.as_ref(), device, &res).write(&path);
+        if res.is_ok() {
+            written?;
+        } else if let Err(e) = written {
+            // the failure to start matters more
+            warn!("{:#}", e);
+        }
+    }
+    res
+}
+
+/// Implementation of the `mdevctl start --all-defined` command. With `--auto-only`, the devices
+/// are started like with `start-all-parents`.
+fn start_all_defined_command(
+    env: Rc<dyn Environment>,
+    args: &StartArgs,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    if args.auto_only {
+        let autostart = AutostartArgs {
+            fail_fast: args.fail_fast,
+            keep_going: args.keep_going,
+            ..Default::default()
+        };
+        return start_all_parents_command(env, &autostart, output);
+    }
+    env.check_mdev_support()?;
+    let mut devs = env.get_defined_devices(None, None)?;
+    for v in devs.values_mut() {
+        v.sort_by_key(|e| e.uuid);
+    }
+
+    let on_failure = OnFailure::from_options(args.fail_fast, args.keep_going, OnFailure::Continue);
+    let mut bulk = Bulk::new(on_failure);
+    let devs = devs.values_mut().flatten().collect();
+    for dev in autostart::start_order(devs) {
+        let name = format!("{} {}", dev.uuid.hyphenated(), dev.parent()?);
+        if dev.active {
+            bulk.skip(name, "already active");
+            continue;
+        }
+        bulk.run(name, || {
+            debug!("Starting {:?}", dev.uuid);
+            callout(dev)?.invoke(Action::Start, args.force, |c| {
+                c.dev.start(args.strict_parent_check)
+            })?;
+            Ok("started")
+        });
+    }
+    output
+        .write_all(bulk.to_text().as_bytes())
+        .with_context(|| "Failed to write data")?;
+    bulk.finish("start")
+}
+
+/// Implementation of the `mdevctl stop` command
+fn stop_command(env: Rc<dyn Environment>, uuid: Uuid, force: bool, kill_users: bool) -> Result<()> {
+    debug!("Stopping '{}'", uuid);
+    let mut dev = MDev::new(env.clone(), uuid);
+    dev.load_from_sysfs()?;
+    dev.kill_users = kill_users;
+
+    // the stop timeout is part of the definition on the current parent, if any
+    let mut defined = MDev::new(env, uuid);
+    defined.parent.clone_from(&dev.parent);
+    if dev.active && defined.is_defined() {
+        match defined.load_definition() {
+            Ok(_) => dev.stop_timeout = defined.stop_timeout,
+            Err(e) => warn!("Unable to load the definition of {}: {:#}", uuid, e),
+        }
+    }
 
     callout(&mut dev)?.invoke(Action::Stop, force, |c| c.dev.stop())
 }
 
-/// Implementation of the `mdevctl list` command
-fn list_command(
+/// Implementation of the `mdevctl restart` command
+fn restart_command(env: Rc<dyn Environment>, uuid: Uuid, force: bool) -> Result<()> {
+    debug!("Restarting '{}'", uuid);
+    let mut active = MDev::new(env.clone(), uuid);
+    active.load_from_sysfs()?;
+    if !active.active {
+        return Err(anyhow!(
+            "Mediated device {} is not active",
+            uuid.hyphenated()
+        ));
+    }
+
+    // start the device from its stored definition if it is defined on the same parent, otherwise
+    // recreate it with its current type and the attributes reported by a callout script
+    let mut dev = MDev::new(env, uuid);
+    dev.parent.clone_from(&active.parent);
+    if dev.is_defined() {
+        dev.load_definition()?;
+    } else {
+        dev.mdev_type.clone_from(&active.mdev_type);
+        let mut c = callout(&mut active)?;
+        if let Ok(attrs) = c.get_attributes() {
+            dev.add_attributes(&attrs)?;
+        }
+    }
+    active.stop_timeout = dev.stop_timeout;
+
+    callout(&mut active)?.invoke(Action::Stop, force, |c| c.dev.stop())?;
+    callout(&mut dev)?
+        .invoke(Action::Start, force, |c| c.dev.start(false))
+        .with_context(|| {
+            format!(
+                "Device {} was stopped, but could not be started again",
+                uuid.hyphenated()
+            )
+        })
+}
+
+/// Query the devices or types of each of the given parents and merge them, or query those of all
+/// parents if none are given
+fn for_parents<T>(
+    parents: &[String],
+    mut query: impl FnMut(Option<&String>) -> Result<BTreeMap<String, Vec<T>>>,
+) -> Result<BTreeMap<String, Vec<T>>> {
+    if parents.is_empty() {
+        return query(None);
+    }
+    let mut merged = BTreeMap::new();
+    for parent in parents {
+        if !merged.contains_key(parent) {
+            merged.extend(query(Some(parent))?);
+        }
+    }
+    Ok(merged)
+}
+
+/// Sort devices for the `list` command by the given key, and by parent and UUID otherwise
+fn sort_devices(devices: &mut [MDev], sort: ListSort) {
+    devices.sort_by(|a, b| {
+        let key = match sort {
+            ListSort::Uuid => a.uuid.cmp(&b.uuid),
+            ListSort::Parent => Ordering::Equal,
+            ListSort::Type => a.mdev_type.cmp(&b.mdev_type),
+            ListSort::State => a
+                .column_text(ListColumn::State)
+                .ok()
+                .cmp(&b.column_text(ListColumn::State).ok()),
+        };
+        key.then_with(|| a.parent.cmp(&b.parent))
+            .then_with(|| a.uuid.cmp(&b.uuid))
+    });
+}
+
+/// Group consecutive devices with the same parent, keeping the order of the devices. A parent
+/// has several groups if its devices are not adjacent in the sort order.
+#[cfg(feature = "rich-output")]
+fn group_by_parent(devices: Vec<MDev>) -> Vec<(String, Vec<MDev>)> {
+    let mut groups: Vec<(String, Vec<MDev>)> = Vec::new();
+    for dev in devices {
+        let parent = dev.parent.clone().unwrap_or_default();
+        match groups.last_mut() {
+            Some((p, children)) if *p == parent => children.push(dev),
+            _ => groups.push((parent, vec![dev])),
+        }
+    }
+    groups
+}
+
+/// The devices selected by the options of the `mdevctl list` command, in the order to list them
+fn list_devices(env: &Rc<dyn Environment>, opts: &LsmdevOptions) -> Result<Vec<MDev>> {
+    let uuid = opts.uuid.as_ref();
+    let mut devices = for_parents(&opts.parent, |parent| match opts.defined {
+        true => env.clone().get_defined_devices(uuid, parent),
+        false => env.clone().get_active_devices(uuid, parent),
+    })?
+    .into_values()
+    .flatten()
+    .filter(|d| d.matches_labels(&opts.selector) && d.matches_attrs(&opts.attr))
+    .collect::<Vec<_>>();
+    sort_devices(&mut devices, opts.sort);
+    Ok(devices)
+}
+
+/// Implementation of the `mdevctl list` command, returning the number of devices listed
+fn list_command(
+    env: Rc<dyn Environment>,
+    opts: &LsmdevOptions,
+    output: &mut dyn std::io::Write,
+) -> Result<usize> {
+    let devices = list_devices(&env, opts)?;
+    let count = devices.len();
+    let (defined, verbose, paths) = (opts.defined, opts.verbose, opts.paths);
+    let format = match opts.output {
+        Some(ListOutput::Format(format)) => Some(format),
+        _ => opts.dumpjson.then_some(OutputFormat::Json),
+    };
+
+    match format {
+        #[cfg(not(feature = "rich-output"))]
+        Some(_) => {
+            return Err(anyhow!(
+                "mdevctl was built without support for structured list output"
+            ))
+        }
+        #[cfg(feature = "rich-output")]
+        Some(format) => {
+            // if specified to a single device, output such that it can be piped into a config
+            // file, else print entire heirarchy
+            let jsonval = if opts.uuid.is_none() || devices.len() > 1 {
+                devices_groups_json(group_by_parent(devices), paths)?
+            } else {
+                match devices.first() {
+                    Some(dev) => list_json(dev, false, paths)?,
+                    None => serde_json::json!([]),
+                }
+            };
+            output.write(
+                format_structured(&jsonval, format)
+                    .with_context(|| "Failed to format the output")?
+                    .as_bytes(),
+            )
+        }
+        None => {
+            let ft = match defined {
+                true => FormatType::Defined,
+                false => FormatType::Active,
+            };
+            output.write(
+                devices
+                    .iter()
+                    // convert MDev elements to a text representation, filtering out errors
+                    .flat_map(|d| {
+                        d.to_text(ft, verbose).map(|text| match paths {
+                            true => text + &d.fmt_paths(),
+                            false => text,
+                        })
+                    })
+                    .collect::<String>()
+                    .as_bytes(),
+            )
+        }
+    }
+    .map(|_| count)
+    .with_context(|| "Failed to write data")
+}
+
+/// how often `list --watch` re-reads the devices even if no change was detected, since the
+/// kernel does not report added or removed devices in sysfs via inotify
+#[cfg(feature = "monitor")]
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Implementation of the `mdevctl list --watch` command. The listing is redrawn whenever it
+/// changes. If `max_redraws` is given, the command returns after redrawing that many times.
+#[cfg(feature = "monitor")]
+fn list_watch_command(
+    env: Rc<dyn Environment>,
+    opts: &LsmdevOptions,
+    output: &mut dyn std::io::Write,
+    interval: Duration,
+    max_redraws: Option<usize>,
+) -> Result<()> {
+    let mut monitor = Monitor::new(env.as_ref())?;
+    let mut previous: Option<Vec<u8>> = None;
+    let mut redraws = 0;
+    loop {
+        let mut current = Vec::new();
+        list_command(env.clone(), opts, &mut current)?;
+        if previous.as_ref() != Some(&current) {
+            // clear the screen and move the cursor to the top left corner before redrawing
+            output
+                .write_all(b"\x1b[2J\x1b[H")
+                .and_then(|_| output.write_all(&current))
+                .and_then(|_| output.flush())
+                .with_context(|| "Failed to write data")?;
+            previous = Some(current);
+            redraws += 1;
+            if max_redraws.is_some_and(|max| redraws >= max) {
+                return Ok(());
+            }
+        }
+        monitor.wait(interval)?;
+    }
+}
+
+/// Implementation of the `mdevctl monitor` command. An event is written whenever a mediated
+/// device or a parent device appears or disappears. If `max_events` is given, the command returns
+/// after writing that many events.
+#[cfg(feature = "monitor")]
+fn monitor_command(
+    env: Rc<dyn Environment>,
+    dumpjson: bool,
+    output: &mut dyn std::io::Write,
+    interval: Duration,
+    max_events: Option<usize>,
+) -> Result<()> {
+    let mut monitor = Monitor::new(env.as_ref())?;
+    let mut snapshot = Snapshot::read(env.as_ref());
+    let mut events = 0;
+    loop {
+        monitor.wait(interval)?;
+        let next = Snapshot::read(env.as_ref());
+        for event in snapshot.changes(&next) {
+            let line = match dumpjson {
+                true => serde_json::to_string(&event)?,
+                false => event.to_string(),
+            };
+            writeln!(output, "{}", line)
+                .and_then(|_| output.flush())
+                .with_context(|| "Failed to write data")?;
+            events += 1;
+            if max_events.is_some_and(|max| events >= max) {
+                return Ok(());
+            }
+        }
+        snapshot = next;
+    }
+}
+
+/// Implementation of the `mdevctl list --output` command, listing the selected columns in a table.
+/// Returns the number of devices listed.
+fn list_columns_command(
+    env: Rc<dyn Environment>,
+    opts: &LsmdevOptions,
+    columns: &[ListColumn],
+    output: &mut dyn std::io::Write,
+) -> Result<usize> {
+    let devices = list_devices(&env, opts)?;
+    output
+        .write_all(format_table(&devices, columns, !opts.noheadings)?.as_bytes())
+        .with_context(|| "Failed to write data")?;
+    Ok(devices.len())
+}
+
+/// dispatch the `mdevctl list` and `lsmdev` commands
+fn list(
+    env: Rc<dyn Environment>,
+    opts: LsmdevOptions,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let columns = match opts.output {
+        Some(ListOutput::Columns(ref columns)) => columns.as_slice(),
+        Some(ListOutput::Table) => TABLE_COLUMNS,
+        Some(ListOutput::Format(_)) | None => &[],
+    };
+    let count = if !columns.is_empty() {
+        list_columns_command(env, &opts, columns, output)?
+    } else {
+        #[cfg(not(feature = "rich-output"))]
+        ensure!(
+            !opts.verbose,
+            "mdevctl was built without support for verbose list output"
+        );
+        match opts.watch {
+            #[cfg(not(feature = "monitor"))]
+            true => return Err(anyhow!("mdevctl was built without support for --watch")),
+            #[cfg(feature = "monitor")]
+            true => return list_watch_command(env, &opts, output, WATCH_INTERVAL, None),
+            false => list_command(env, &opts, output)?,
+        }
+    };
+    ensure!(count > 0 || !opts.fail_if_none, "No matching devices found");
+    Ok(())
+}
+
+/// convert 'types' command arguments into a text output
+fn types_command(
+    env: Rc<dyn Environment>,
+    parents: &[String],
+    device_api: Option<&str>,
+    format: Option<OutputFormat>,
+    group_by: TypesGroupBy,
+    pci_ids: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    if let Err(e) = env.check_mdev_support() {
+        // scripts parsing the output learn why there are no types
+        if let Some(format) = format {
+            let status = serde_json::json!({
+                "mdev_support": false,
+                "error": e.to_string(),
+                "category": ErrorCategory::of(&e),
+            });
+            output.write_all(format_structured(&status, format)?.as_bytes())?;
+        }
+        return Err(e);
+    }
+    let mut types = for_parents(parents, |parent| {
+        env.clone().get_supported_types(parent.cloned())
+    })?;
+    if pci_ids {
+        let database = PciIdDatabase::load(env.as_ref());
+        if database.is_none() {
+            warn!("No PCI ID database found, only showing the IDs of parent devices");
+        }
+        for (parent, children) in types.iter_mut() {
+            let mut id = PciId::read(env.as_ref(), parent);
+            if let (Some(id), Some(database)) = (id.as_mut(), database.as_ref()) {
+                database.resolve(id);
+            }
+            for child in children.iter_mut() {
+                child.parent_pci_id = id.clone();
+            }
+        }
+    }
+    if let Some(api) = device_api {
+        for children in types.values_mut() {
+            children.retain(|t| t.device_api == api);
+        }
+        types.retain(|_, children| !children.is_empty());
+    }
+    debug!("{:?}", types);
+    if group_by == TypesGroupBy::Api {
+        return types_by_api(types, format, output);
+    }
+    if let Some(format) = format {
+        let mut parents = serde_json::map::Map::new();
+        for (parent, children) in types {
+            let mut childarray = Vec::new();
+            for child in children {
+                childarray.push(child.to_json()?);
+            }
+            parents.insert(parent, childarray.into());
+        }
+
+        let jsonval = match parents.len() {
+            0 => serde_json::json!([]),
+            _ => serde_json::json!([parents]),
+        };
+        output.write(format_structured(&jsonval, format)?.as_bytes())
+    } else {
+        let mut text: String = Default::default();
+        for (parent, children) in types {
+            match children.first().and_then(|c| c.parent_pci_id.as_ref()) {
+                Some(id) => writeln!(text, "{} {}", parent, id)?,
+                None => writeln!(text, "{}", parent)?,
+            }
+            for child in children {
+                let _ = writeln!(text, "  {}", child.typename);
+                let _ = writeln!(
+                    text,
+                    "    Available instances: {}",
+                    child.available_instances
+                );
+                let _ = writeln!(text, "    Device API: {}", child.device_api);
+                if !child.name.is_empty() {
+                    let _ = writeln!(text, "    Name: {}", child.name);
+                }
+                if !child.description.is_empty() {
+                    let _ = writeln!(text, "    Description: {}", child.description);
+                }
+            }
+        }
+        output.write(text.as_bytes())
+    }
+    .map(|_| ())
+    .with_context(|| "Unable to write output")
+}
+
+/// Output of the `types` command grouped by the device API of the types
+fn types_by_api(
+    types: BTreeMap<String, Vec<MDevType>>,
+    format: Option<OutputFormat>,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let mut apis: BTreeMap<String, Vec<MDevType>> = BTreeMap::new();
+    for t in types.into_values().flatten() {
+        apis.entry(t.device_api.clone()).or_default().push(t);
+    }
+
+    let mut text = String::new();
+    if let Some(format) = format {
+        let mut groups = serde_json::Map::new();
+        for (api, children) in apis {
+            let mut childarray = Vec::new();
+            for child in children {
+                let mut json = child.to_json()?;
+                if let Some(obj) = json[&child.typename].as_object_mut() {
+                    obj.insert("parent".to_string(), child.parent.clone().into());
+                }
+                childarray.push(json);
+            }
+            groups.insert(api, childarray.into());
+        }
+        let jsonval = match groups.len() {
+            0 => serde_json::json!([]),
+            _ => serde_json::json!([groups]),
+        };
+        text = format_structured(&jsonval, format)?;
+    } else {
+        for (api, children) in apis {
+            writeln!(text, "{}", api)?;
+            for child in children {
+                writeln!(text, "  {}", child.typename)?;
+                match &child.parent_pci_id {
+                    Some(id) => writeln!(text, "    Parent: {} {}", child.parent, id)?,
+                    None => writeln!(text, "    Parent: {}", child.parent)?,
+                }
+                writeln!(
+                    text,
+                    "    Available instances: {}",
+                    child.available_instances
+                )?;
+                if !child.name.is_empty() {
+                    writeln!(text, "    Name: {}", child.name)?;
+                }
+                if !child.description.is_empty() {
+                    writeln!(text, "    Description: {}", child.description)?;
+                }
+            }
+        }
+    }
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Unable to write output")
+}
+
+/// Implementation of the `estimate` command
+fn estimate_command(
+    env: Rc<dyn Environment>,
+    parent: String,
+    mdev_type: String,
+    count: u32,
+    dumpjson: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    env.check_mdev_support()?;
+    let types = env.clone().get_supported_types(Some(parent.clone()))?;
+    let t = types
+        .get(&parent)
+        .ok_or_else(|| anyhow!("Parent device {} does not exist", parent))?
+        .iter()
+        .find(|t| t.typename == mdev_type)
+        .ok_or_else(|| anyhow!("Parent {} does not support mdev type {}", parent, mdev_type))?;
+    // defined devices take available instances once they are started
+    let defined_inactive = env
+        .get_defined_devices(None, Some(&parent))?
+        .into_values()
+        .flatten()
+        .filter(|d| !d.active && d.mdev_type.as_ref() == Some(&mdev_type))
+        .count() as u32;
+
+    let estimate = Estimate::new(t, count, defined_inactive);
+    let text = match dumpjson {
+        true => serde_json::to_string_pretty(&estimate)? + "\n",
+        false => estimate.to_text(),
+    };
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Unable to write output")
+}
+
+/// The number of devices of a parent that are started concurrently, from `--jobs` or the policy
+fn autostart_jobs(env: &Rc<dyn Environment>, args: &AutostartArgs) -> Result<usize> {
+    match args.jobs {
+        Some(jobs) => Ok(jobs),
+        None => Ok(Policy::load(env.as_ref())?.autostart_jobs),
+    }
+}
+
+/// Implementation of the `start-parent-mdevs` command
+fn start_parent_mdevs_command(
+    env: Rc<dyn Environment>,
+    parent: String,
+    args: &AutostartArgs,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let jobs = autostart_jobs(&env, args)?;
+    let on_failure = OnFailure::from_options(args.fail_fast, args.keep_going, OnFailure::Continue);
+    let autostart = autostart::start_parent_mdevs(env, &parent, args.timeout, on_failure, jobs)?;
+    if args.dumpjson {
+        let json =
+            serde_json::to_string_pretty(&autostart).with_context(|| "Unable to serialize json")?;
+        writeln!(output, "{}", json).with_context(|| "Failed to write data")?;
+    } else if let Some(summary) = autostart.summary() {
+        writeln!(output, "{}", summary).with_context(|| "Failed to write data")?;
+    }
+    Ok(())
+}
+
+/// Implementation of the `mdevctl start-all-parents` command
+fn start_all_parents_command(
+    env: Rc<dyn Environment>,
+    args: &AutostartArgs,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    env.check_mdev_support()?;
+    let jobs = autostart_jobs(&env, args)?;
+    let on_failure = OnFailure::from_options(args.fail_fast, args.keep_going, OnFailure::Continue);
+    let autostarts = autostart::start_all_parents(env, args.timeout, on_failure, jobs)?;
+    let text = match args.dumpjson {
+        true => {
+            serde_json::to_string_pretty(&autostarts).with_context(|| "Unable to serialize json")?
+                + "\n"
+        }
+        false => autostarts.iter().map(|a| a.to_text()).collect(),
+    };
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")?;
+    autostart::finish(&autostarts)
+}
+
+/// Implementation of `start-parent-mdevs --dry-run`
+fn preview_parent_mdevs_command(
+    env: Rc<dyn Environment>,
+    parent: String,
+    dumpjson: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let preview = autostart::preview_parent_mdevs(env, &parent)?;
+    let text = match dumpjson {
+        true => {
+            serde_json::to_string_pretty(&preview).with_context(|| "Unable to serialize json")?
+                + "\n"
+        }
+        false => preview.to_text(),
+    };
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")
+}
+
+/// Implementation of the `mdevctl reconcile` command
+fn reconcile_command(
+    env: Rc<dyn Environment>,
+    parent: Option<String>,
+    report: bool,
+    dumpjson: bool,
+    changed_only: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let mut devs = env.get_defined_devices(None, parent.as_ref())?;
+    for v in devs.values_mut() {
+        v.sort_by_key(|e| e.uuid);
+    }
+
+    if report {
+        let mut text = String::new();
+        let mut json = Vec::new();
+        let total = devs.values().map(|v| v.len()).sum::<usize>();
+        for dev in devs.values().flatten() {
+            let status = dev.sync_status()?;
+            let (status, reason) = match status {
+                SyncStatus::InSync if changed_only => continue,
+                SyncStatus::InSync => ("InSync", None),
+                SyncStatus::OutOfSync(r) => ("OutOfSync", Some(r.to_string())),
+            };
+            let actual = match dev.active {
+                true => "running",
+                false => "stopped",
+            };
+            match reason.as_ref() {
+                Some(r) => writeln!(
+                    text,
+                    "{} {} desired={} actual={} {} ({})",
+                    dev.uuid.hyphenated(),
+                    dev.parent()?,
+                    dev.desired_state(),
+                    actual,
+                    status,
+                    r
+                )?,
+                None => writeln!(
+                    text,
+                    "{} {} desired={} actual={} {}",
+                    dev.uuid.hyphenated(),
+                    dev.parent()?,
+                    dev.desired_state(),
+                    actual,
+                    status
+                )?,
+            }
+            let mut obj = serde_json::Map::new();
+            obj.insert("uuid".into(), dev.uuid.hyphenated().to_string().into());
+            obj.insert("parent".into(), dev.parent()?.clone().into());
+            obj.insert("desired".into(), dev.desired_state().to_string().into());
+            obj.insert("actual".into(), actual.into());
+            obj.insert("status".into(), status.into());
+            if let Some(r) = reason {
+                obj.insert("reason".into(), r.into());
+            }
+            json.push(serde_json::Value::Object(obj));
+        }
+        if changed_only && !json.is_empty() {
+            writeln!(text, "{} of {} devices out of sync", json.len(), total)?;
+        }
+        if dumpjson {
+            text =
+                serde_json::to_string_pretty(&json).with_context(|| "Unable to serialize json")?;
+            text.push('\n');
+        }
+        return output
+            .write_all(text.as_bytes())
+            .with_context(|| "Failed to write data");
+    }
+
+    // with changed_only, the devices that were started or failed to start are listed
+    let mut text = String::new();
+    let (mut started, mut failed, mut unchanged) = (0, 0, 0);
+    for dev in devs.values_mut().flatten() {
+        match dev.sync_status()? {
+            SyncStatus::InSync => unchanged += 1,
+            SyncStatus::OutOfSync(OutOfSyncReason::NotRunning) => {
+                debug!("Starting {:?} to reconcile its state", dev.uuid);
+                let name = format!("{} {}", dev.uuid.hyphenated(), dev.parent()?);
+                match callout(dev)?.invoke(Action::Start, false, |c| c.dev.start(false)) {
+                    Ok(_) => {
+                        started += 1;
+                        writeln!(text, "{}: started", name)?;
+                    }
+                    Err(e) => {
+                        for x in e.chain() {
+                            warn!("{}", x);
+                        }
+                        failed += 1;
+                        writeln!(text, "{}: failed to start: {:#}", name, e)?;
+                    }
+                }
+            }
+            SyncStatus::OutOfSync(reason) => {
+                unchanged += 1;
+                warn!("Unable to reconcile device {}: {}", dev.uuid, reason)
+            }
+        }
+    }
+    if !changed_only || text.is_empty() {
+        return Ok(());
+    }
+    writeln!(
+        text,
+        "{} started, {} failed, {} unchanged",
+        started, failed, unchanged
+    )?;
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")
+}
+
+/// Load a stored device definition and check it against sysfs
+fn validate_definition(
+    env: &Rc<dyn Environment>,
+    uuid: Uuid,
+    parent: &str,
+    path: &std::path::Path,
+) -> Vec<ConfigProblem> {
+    let load = || -> Result<MDev> {
+        let contents = fs::read_to_string(path).with_context(|| "Unable to read file")?;
+        let json = serde_json::from_str(&contents)?;
+        let mut dev = MDev::new(env.clone(), uuid);
+        dev.load_from_json(parent.to_string(), &json)?;
+        dev.load_from_sysfs()?;
+        Ok(dev)
+    };
+    match load() {
+        Ok(dev) => dev.validate_definition(),
+        Err(e) => vec![ConfigProblem::Malformed(format!("{:#}", e))],
+    }
+}
+
+/// Implementation of the `mdevctl validate` command
+fn validate_command(
+    env: Rc<dyn Environment>,
+    dumpjson: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let sorted_dir = |dir: &std::path::Path| -> Result<Vec<PathBuf>> {
+        let mut paths = dir
+            .read_dir()
+            .with_context(|| format!("Unable to read directory {:?}", dir))?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        paths.sort();
+        Ok(paths)
+    };
+
+    let mut text = String::new();
+    let mut json = Vec::new();
+    let mut count = 0;
+    for parentpath in sorted_dir(&env.config_base())? {
+        if parentpath == env.scripts_base() || !parentpath.is_dir() {
+            continue;
+        }
+        let parent = parentpath
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        let mut definitions: BTreeMap<Uuid, Vec<PathBuf>> = BTreeMap::new();
+        for path in sorted_dir(&parentpath)? {
+            match path
+                .file_name()
+                .and_then(|f| f.to_str())
+                .map(Uuid::parse_str)
+            {
+                Some(Ok(u)) if path.is_file() => definitions.entry(u).or_default().push(path),
+                _ => debug!("Ignoring {:?}, which is not a device definition", path),
+            };
+        }
+        for (uuid, mut paths) in definitions {
+            order_by_precedence(&uuid, &mut paths);
+            let mut problems = validate_definition(&env, uuid, &parent, &paths[0]);
+            let name = |p: &PathBuf| p.file_name().unwrap().to_string_lossy().to_string();
+            for ignored in paths.iter().skip(1) {
+                problems.push(ConfigProblem::Duplicate(name(ignored), name(&paths[0])));
+            }
+            for problem in problems {
+                count += 1;
+                writeln!(text, "{} {}: {}", uuid.hyphenated(), parent, problem)?;
+                json.push(serde_json::json!({
+                    "uuid": uuid.hyphenated().to_string(),
+                    "parent": parent,
+                    "problem": problem.kind(),
+                    "message": problem.to_string(),
+                }));
+            }
+        }
+    }
+
+    if dumpjson {
+        text = serde_json::to_string_pretty(&json).with_context(|| "Unable to serialize json")?;
+        text.push('\n');
+    } else if count == 0 {
+        text.push_str("All device definitions are valid\n");
+    }
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")?;
+    ensure!(
+        count == 0,
+        "Found {} problem(s) in device definitions",
+        count
+    );
+    Ok(())
+}
+
+/// Implementation of the `mdevctl export` command
+fn export_command(env: Rc<dyn Environment>, output: &mut dyn std::io::Write) -> Result<()> {
+    let mut devs = env.get_defined_devices(None, None)?;
+    let mut parents = serde_json::Map::new();
+    for (parent, children) in devs.iter_mut() {
+        children.sort_by_key(|e| e.uuid);
+        let children = children
+            .iter()
+            .map(|dev| dev.to_json(true))
+            .collect::<Result<Vec<_>>>()?;
+        parents.insert(parent.clone(), children.into());
+    }
+    let json = match parents.len() {
+        0 => serde_json::json!([]),
+        _ => serde_json::json!([parents]),
+    };
+    let mut text =
+        serde_json::to_string_pretty(&json).with_context(|| "Unable to serialize json")?;
+    text.push('\n');
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")
+}
+
+/// Parse the device definitions of a document in the format of `mdevctl export`
+fn parse_export(env: &Rc<dyn Environment>, json: &serde_json::Value) -> Result<Vec<MDev>> {
+    let mut devs = Vec::new();
+    let groups = json
+        .as_array()
+        .ok_or_else(|| anyhow!("invalid json: document is not an array"))?;
+    for group in groups {
+        let group = group
+            .as_object()
+            .ok_or_else(|| anyhow!("invalid json: parent group is not an object"))?;
+        for (parent, children) in group {
+            let children = children.as_array().ok_or_else(|| {
+                anyhow!(
+                    "invalid json: devices of parent {} are not an array",
+                    parent
+                )
+            })?;
+            for child in children {
+                let (uuid, config) = match child.as_object() {
+                    Some(obj) if obj.len() == 1 => obj.iter().next().unwrap(),
+                    _ => {
+                        return Err(anyhow!(
+                            "invalid json: device of parent {} is not an object with a single UUID",
+                            parent
+                        ))
+                    }
+                };
+                let uuid = Uuid::parse_str(uuid)
+                    .with_context(|| format!("invalid json: '{}' is not a UUID", uuid))?;
+                let mut dev = MDev::new(env.clone(), uuid);
+                dev.load_from_json(parent.clone(), config)
+                    .with_context(|| format!("Invalid definition of {}/{}", parent, uuid))?;
+                devs.push(dev);
+            }
+        }
+    }
+    Ok(devs)
+}
+
+/// Implementation of the `mdevctl generate-systemd` command
+fn generate_systemd_command(
+    env: Rc<dyn Environment>,
+    dir: PathBuf,
+    parent: Option<String>,
+) -> Result<()> {
+    let units = systemd::units(env, parent.as_ref())?;
+    fs::create_dir_all(&dir).with_context(|| format!("Unable to create directory {:?}", dir))?;
+    // units that were written by the administrator are left alone
+    let generated = |path: &Path| {
+        fs::read_to_string(path)
+            .map(|c| c.starts_with(systemd::HEADER))
+            .unwrap_or(false)
+    };
+    for unit in units.iter() {
+        let path = dir.join(&unit.name);
+        if path.exists() && !generated(&path) {
+            warn!(
+                "Not overwriting {:?}, which was not generated by mdevctl",
+                path
+            );
+            continue;
+        }
+        debug!("Writing unit {:?}", path);
+        fs::write(&path, &unit.contents).with_context(|| format!("Unable to write {:?}", path))?;
+    }
+    if parent.is_some() {
+        return Ok(());
+    }
+
+    // remove the units of devices that no longer start automatically
+    for entry in fs::read_dir(&dir).with_context(|| format!("Unable to read {:?}", dir))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with("mdev@")
+            || !name.ends_with(".service")
+            || units.iter().any(|u| u.name == name)
+        {
+            continue;
+        }
+        if generated(&entry.path()) {
+            debug!("Removing stale unit {:?}", entry.path());
+            fs::remove_file(entry.path())
+                .with_context(|| format!("Unable to remove {:?}", entry.path()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of the `mdevctl generate-udev-rules` command
+fn generate_udev_rules_command(
+    env: Rc<dyn Environment>,
+    dir: PathBuf,
+    to_stdout: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let rules = udev::rules(env)?;
+    if to_stdout {
+        return output
+            .write_all(rules.as_bytes())
+            .with_context(|| "Failed to write data");
+    }
+    fs::create_dir_all(&dir).with_context(|| format!("Unable to create directory {:?}", dir))?;
+    let path = dir.join(udev::RULES);
+    // rules that were written by the administrator are left alone
+    if path.exists()
+        && !fs::read_to_string(&path)
+            .map(|c| c.starts_with(udev::HEADER))
+            .unwrap_or(false)
+    {
+        return Err(anyhow!(
+            "Not overwriting {:?}, which was not generated by mdevctl",
+            path
+        ));
+    }
+    debug!("Writing rules {:?}", path);
+    // udev watches the rules directory, so the rules are replaced atomically to never be loaded
+    // half written
+    let tmppath = dir.join(format!(".{}.tmp", udev::RULES));
+    fs::write(&tmppath, rules)
+        .and_then(|_| fs::rename(&tmppath, &path))
+        .with_context(|| format!("Unable to write {:?}", path))
+}
+
+/// Implementation of the `mdevctl import` command
+fn import_command(
     env: Rc<dyn Environment>,
-    defined: bool,
-    dumpjson: bool,
-    verbose: bool,
-    uuid: Option<Uuid>,
-    parent: Option<String>,
-    output: &mut dyn std::io::Write,
+    file: PathBuf,
+    force: bool,
+    on_failure: OnFailure,
 ) -> Result<()> {
-    let mut devices: BTreeMap<String, Vec<MDev>>;
-    if defined {
-        devices = env
-            .clone()
-            .get_defined_devices(uuid.as_ref(), parent.as_ref())?;
-    } else {
-        devices = env
-            .clone()
-            .get_active_devices(uuid.as_ref(), parent.as_ref())?;
+    let contents = match file.to_str() {
+        Some("-") => std::io::read_to_string(std::io::stdin())
+            .with_context(|| "Unable to read standard input")?,
+        _ => fs::read_to_string(&file).with_context(|| format!("Unable to read {:?}", file))?,
+    };
+    let json = serde_json::from_str(&contents).with_context(|| "Invalid JSON document")?;
+    let mut devs = parse_export(&env, &json)?;
+    check_import(&devs, force)?;
+    define_imported(&mut devs, on_failure)
+}
+
+/// Make sure that devices to be imported can all be defined, so that the whole import is refused
+/// before any definition is written
+fn check_import(devs: &[MDev], force: bool) -> Result<()> {
+    let mut seen = std::collections::BTreeSet::new();
+    for dev in devs.iter() {
+        ensure!(
+            seen.insert((dev.parent()?.clone(), dev.uuid)),
+            "Device {} on {} is included more than once",
+            dev.uuid.hyphenated(),
+            dev.parent()?
+        );
+        ensure!(
+            force || !dev.is_defined(),
+            "Device {} on {} already defined, use --force to overwrite it",
+            dev.uuid.hyphenated(),
+            dev.parent()?
+        );
+    }
+    Ok(())
+}
+
+fn define_imported(devs: &mut [MDev], on_failure: OnFailure) -> Result<()> {
+    let mut bulk = Bulk::new(on_failure);
+    for dev in devs.iter_mut() {
+        let (uuid, parent) = (dev.uuid, dev.parent()?.clone());
+        let outcome = bulk.run(format!("{} {}", uuid.hyphenated(), parent), || {
+            debug!("Importing mdev {:?}", uuid);
+            callout(dev)?
+                .invoke(Action::Define, false, |c| c.dev.define())
+                .with_context(|| format!("Failed to import device {} on {}", uuid, parent))?;
+            Ok("imported")
+        });
+        if let Outcome::Failed(e) = outcome {
+            warn!("{:#}", e);
+        }
     }
+    bulk.finish("import")
+}
 
-    // ensure that devices are sorted in a stable order
-    for v in devices.values_mut() {
-        v.sort_by_key(|e| e.uuid);
+/// the configuration files of mdevctl that are included in state archives
+#[cfg(feature = "archives")]
+fn state_config_files(env: &dyn Environment) -> Vec<PathBuf> {
+    vec![
+        env.labels_path(),
+        env.policy_path(),
+        env.callout_limits_path(),
+    ]
+}
+
+/// Implementation of the `mdevctl state export` command
+#[cfg(feature = "archives")]
+fn state_export_command(env: Rc<dyn Environment>, output: PathBuf) -> Result<()> {
+    let file = fs::File::create(&output)
+        .with_context(|| format!("Failed to create state archive {:?}", output))?;
+    let mut archive = StateWriter::new(file);
+
+    let version = serde_json::json!({
+        "format": state::FORMAT_VERSION,
+        "mdevctl": env!("CARGO_PKG_VERSION"),
+    });
+    archive.add(
+        "version.json",
+        serde_json::to_string_pretty(&version)?.as_bytes(),
+    )?;
+
+    let mut definitions = Vec::new();
+    export_command(env.clone(), &mut definitions)?;
+    archive.add("definitions.json", &definitions)?;
+
+    let active = env
+        .clone()
+        .get_active_devices(None, None)?
+        .into_values()
+        .flatten()
+        .map(|dev| {
+            Ok(serde_json::json!({
+                "uuid": dev.uuid.hyphenated().to_string(),
+                "parent": dev.parent()?,
+                "mdev_type": dev.mdev_type()?,
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    archive.add(
+        "active.json",
+        serde_json::to_string_pretty(&active)?.as_bytes(),
+    )?;
+
+    for path in state_config_files(env.as_ref()) {
+        if path.is_file() {
+            let data = fs::read(&path).with_context(|| format!("Unable to read {:?}", path))?;
+            let name = path.file_name().unwrap().to_string_lossy();
+            archive.add(&format!("config/{}", name), &data)?;
+        }
+    }
+    archive.add_dir("staged", &env.staging_base())?;
+    archive.finish()?;
+
+    println!("State written to {:?}", output);
+    Ok(())
+}
+
+/// Implementation of the `mdevctl state import` command
+#[cfg(feature = "archives")]
+fn state_import_command(
+    env: Rc<dyn Environment>,
+    file: PathBuf,
+    start: bool,
+    force: bool,
+    force_callouts: bool,
+) -> Result<()> {
+    let input = fs::File::open(&file).with_context(|| format!("Unable to read {:?}", file))?;
+    let archive = StateArchive::read(input)
+        .with_context(|| format!("Unable to read state archive {:?}", file))?;
+    let mut devs = parse_export(&env, &archive.json("definitions.json")?)?;
+    let active = archive.json("active.json")?;
+    let active = active
+        .as_array()
+        .ok_or_else(|| anyhow!("invalid json: active devices are not an array"))?
+        .iter()
+        .filter_map(|dev| Some((dev["uuid"].as_str()?, dev["parent"].as_str()?)))
+        .filter_map(|(uuid, parent)| Some((Uuid::parse_str(uuid).ok()?, parent.to_string())))
+        .collect::<std::collections::BTreeSet<_>>();
+
+    // collect the files to restore and refuse the whole import before anything is written
+    let config_files = state_config_files(env.as_ref());
+    let mut files = Vec::new();
+    for (relpath, data) in archive.files_below("config") {
+        let path = config_files
+            .iter()
+            .find(|p| p.file_name() == Some(relpath.as_os_str()))
+            .ok_or_else(|| anyhow!("Unknown configuration file {:?} in state archive", relpath))?;
+        files.push((path.clone(), data));
+    }
+    for (relpath, data) in archive.files_below("staged") {
+        files.push((env.staging_base().join(relpath), data));
+    }
+    for (path, data) in files.iter() {
+        ensure!(
+            force || fs::read(path).map_or(true, |current| current == *data),
+            "{:?} already exists, use --force to overwrite it",
+            path
+        );
+    }
+    check_import(&devs, force)?;
+    for dev in devs.iter() {
+        for problem in dev.validate_definition() {
+            warn!(
+                "Device {} on {}: {}",
+                dev.uuid.hyphenated(),
+                dev.parent()?,
+                problem
+            );
+        }
+    }
+
+    define_imported(&mut devs, OnFailure::Cancel)?;
+    for (path, data) in files {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        }
+        fs::write(&path, data).with_context(|| format!("Failed to write {:?}", path))?;
+    }
+
+    if !start {
+        return Ok(());
     }
+    // the state is restored at this point, so a device that fails to start does not keep the
+    // others from being started
+    let mut bulk = Bulk::new(OnFailure::Continue);
+    for dev in devs.iter() {
+        let parent = dev.parent()?.clone();
+        if !dev.autostart && !active.contains(&(dev.uuid, parent.clone())) {
+            continue;
+        }
+        let outcome = bulk.run(format!("{} {}", dev.uuid.hyphenated(), parent), || {
+            let defined = env.clone().get_defined_device(dev.uuid, Some(&parent))?;
+            start_after_define(&defined, force_callouts)?;
+            Ok("started")
+        });
+        if let Outcome::Failed(e) = outcome {
+            warn!("{:#}", e);
+        }
+    }
+    bulk.finish("start")
+}
+
+/// Implementation of the `mdevctl support-bundle` command
+#[cfg(feature = "archives")]
+fn support_bundle_command(env: Rc<dyn Environment>, output: PathBuf) -> Result<()> {
+    let file = fs::File::create(&output)
+        .with_context(|| format!("Failed to create support bundle {:?}", output))?;
+    let mut bundle = SupportBundle::new(env.as_ref(), file);
+
+    let mut version = format!("mdevctl {}\n", env!("CARGO_PKG_VERSION"));
+    if let Ok(kernel) = fs::read_to_string(env.osrelease_path()) {
+        write!(version, "kernel {}", kernel)?;
+    }
+    bundle.add("version.txt", version.as_bytes())?;
+
+    for (name, defined) in [("list-active.json", false), ("list-defined.json", true)] {
+        let mut data = Vec::new();
+        let opts = LsmdevOptions {
+            defined,
+            dumpjson: true,
+            ..Default::default()
+        };
+        let res = list_command(env.clone(), &opts, &mut data);
+        bundle.add_result(name, res.map(|_| data))?;
+    }
+    let mut data = Vec::new();
+    let res = types_command(
+        env.clone(),
+        &[],
+        None,
+        Some(OutputFormat::Json),
+        TypesGroupBy::Parent,
+        false,
+        &mut data,
+    );
+    bundle.add_result("types.json", res.map(|_| data))?;
+
+    bundle.add_dir("definitions", &env.config_base())?;
+    bundle.add("scripts.txt", &bundle::script_inventory(env.as_ref()))?;
+    bundle.add("sysfs.txt", &bundle::sysfs_snapshot(env.as_ref()))?;
+    bundle.add_result("journal.txt", bundle::journal(env.as_ref()))?;
+    bundle.finish()?;
+
+    println!("Support bundle written to {:?}", output);
+    Ok(())
+}
 
-    match dumpjson {
+/// kernel modules needed to create mediated devices and assign them to virtual machines. The
+/// former `vfio_mdev` module was merged into `mdev` and `vfio` with Linux 5.16, while
+/// `vfio_pci_core` was split off `vfio_pci` in the same release.
+const HOST_MODULES: [&str; 5] = [
+    "mdev",
+    "vfio",
+    "vfio_iommu_type1",
+    "vfio_pci",
+    "vfio_pci_core",
+];
+
+/// Implementation of the `mdevctl doctor` command
+fn doctor_command(
+    env: Rc<dyn Environment>,
+    dumpjson: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let checks = doctor::run_checks(env);
+    let text = match dumpjson {
         true => {
-            // if specified to a single device, output such that it can be piped into a config
-            // file, else print entire heirarchy
-            if uuid.is_none() || devices.values().flatten().count() > 1 {
-                output.write(
-                    format_json(devices)
-                        .with_context(|| "Failed to format as JSON")?
-                        .as_bytes(),
-                )
-            } else {
-                let jsonval = match devices.values().next() {
-                    Some(children) => children
-                        .first()
-                        .ok_or_else(|| anyhow!("Failed to get device"))?
-                        .to_json(false)?,
-                    None => serde_json::json!([]),
-                };
-                output.write(
-                    serde_json::to_string_pretty(&jsonval)
-                        .with_context(|| "Unable to serialize json")?
-                        .as_bytes(),
-                )
-            }
+            let mut text = serde_json::to_string_pretty(&checks)
+                .with_context(|| "Unable to serialize json")?;
+            text.push('\n');
+            text
         }
         false => {
-            let ft = match defined {
-                true => FormatType::Defined,
-                false => FormatType::Active,
-            };
-            output.write(
-                devices
-                    .values()
-                    // convert child vector into an iterator over the vector's elements
-                    .flat_map(|v| v.iter())
-                    // convert MDev elements to a text representation, filtering out errors
-                    .flat_map(|d| d.to_text(ft, verbose))
-                    .collect::<String>()
-                    .as_bytes(),
-            )
+            let mut text = String::new();
+            for check in checks.iter() {
+                writeln!(
+                    text,
+                    "[{}] {}: {}",
+                    check.status, check.check, check.message
+                )?;
+            }
+            text
         }
-    }
-    .map(|_| ())
-    .with_context(|| "Failed to write data")
+    };
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")?;
+
+    let failed = checks
+        .iter()
+        .filter(|c| c.status == doctor::CheckStatus::Fail)
+        .count();
+    ensure!(failed == 0, "{} of {} checks failed", failed, checks.len());
+    Ok(())
 }
 
-/// convert 'types' command arguments into a text output
-fn types_command(
+/// Implementation of the `mdevctl callouts list` command
+fn callouts_list_command(
     env: Rc<dyn Environment>,
-    parent: Option<String>,
     dumpjson: bool,
     output: &mut dyn std::io::Write,
 ) -> Result<()> {
-    let types = env.clone().get_supported_types(parent)?;
-    debug!("{:?}", types);
-    if dumpjson {
-        let mut parents = serde_json::map::Map::new();
-        for (parent, children) in types {
-            let mut childarray = Vec::new();
-            for child in children {
-                childarray.push(child.to_json()?);
+    // a device of each type that is defined or supported by a present parent device
+    let mut probes: BTreeMap<(String, String), MDev> = BTreeMap::new();
+    for dev in env
+        .clone()
+        .get_defined_devices(None, None)?
+        .into_values()
+        .flatten()
+    {
+        if let (Some(parent), Some(mdev_type)) = (dev.parent.clone(), dev.mdev_type.clone()) {
+            probes.entry((parent, mdev_type)).or_insert(dev);
+        }
+    }
+    for (parent, types) in env.clone().get_supported_types(None)? {
+        for t in types {
+            probes
+                .entry((parent.clone(), t.typename.clone()))
+                .or_insert_with(|| {
+                    let mut dev = MDev::new(env.clone(), Uuid::nil());
+                    dev.parent = Some(parent.clone());
+                    dev.mdev_type = Some(t.typename.clone());
+                    dev
+                });
+        }
+    }
+
+    let mut text = String::new();
+    let mut callouts = Vec::new();
+    writeln!(text, "Call-out scripts:")?;
+    for script in installed_scripts(env.as_ref()) {
+        let path = doctor::host_path(env.as_ref(), &script);
+        let executable = doctor::is_executable(&script);
+        let mut supports = Vec::new();
+        let mut errors = Vec::new();
+        match executable {
+            true => writeln!(text, "  {}", path)?,
+            false => writeln!(text, "  {} (not executable)", path)?,
+        }
+        if executable {
+            for ((parent, mdev_type), dev) in probes.iter() {
+                let mut dev = dev.clone();
+                match callout(&mut dev).and_then(|c| c.query_support(&script)) {
+                    Ok(ScriptSupport::Unsupported) => (),
+                    Ok(ScriptSupport::Unversioned) => {
+                        writeln!(text, "    {} on {}: without versioning", mdev_type, parent)?;
+                        supports.push(serde_json::json!({
+                            "parent": parent,
+                            "mdev_type": mdev_type,
+                            "capabilities": null,
+                        }));
+                    }
+                    Ok(ScriptSupport::Versioned(version)) => {
+                        let list = |items: Vec<String>| items.join(", ");
+                        writeln!(
+                            text,
+                            "    {} on {}: version {} (actions: {}; events: {})",
+                            mdev_type,
+                            parent,
+                            version.version(),
+                            list(version.actions().iter().map(|a| a.to_string()).collect()),
+                            list(version.events().iter().map(|e| e.to_string()).collect()),
+                        )?;
+                        supports.push(serde_json::json!({
+                            "parent": parent,
+                            "mdev_type": mdev_type,
+                            "capabilities": version,
+                        }));
+                    }
+                    Err(e) => {
+                        writeln!(
+                            text,
+                            "    {} on {}: capability query failed: {:#}",
+                            mdev_type, parent, e
+                        )?;
+                        errors.push(serde_json::json!({
+                            "parent": parent,
+                            "mdev_type": mdev_type,
+                            "error": format!("{:#}", e),
+                            "category": ErrorCategory::of(&e),
+                        }));
+                    }
+                }
+            }
+            if supports.is_empty() && errors.is_empty() {
+                writeln!(text, "    supports none of the known device types")?;
             }
-            parents.insert(parent, childarray.into());
         }
+        callouts.push(serde_json::json!({
+            "path": path,
+            "executable": executable,
+            "supports": supports,
+            "errors": errors,
+        }));
+    }
+    if callouts.is_empty() {
+        writeln!(text, "  (none)")?;
+    }
 
-        let jsonval = match parents.len() {
-            0 => serde_json::json!([]),
-            _ => serde_json::json!([parents]),
-        };
-        output.write(
-            serde_json::to_string_pretty(&jsonval)
-                .map_err(|_e| anyhow!("Unable to serialize json"))?
-                .as_bytes(),
-        )
-    } else {
-        let mut text: String = Default::default();
-        for (parent, children) in types {
-            let _ = writeln!(text, "{}", parent);
-            for child in children {
-                let _ = writeln!(text, "  {}", child.typename);
-                let _ = writeln!(
-                    text,
-                    "    Available instances: {}",
-                    child.available_instances
-                );
-                let _ = writeln!(text, "    Device API: {}", child.device_api);
-                if !child.name.is_empty() {
-                    let _ = writeln!(text, "    Name: {}", child.name);
+    let mut notifiers = Vec::new();
+    writeln!(text, "Notification scripts:")?;
+    for script in installed_notifiers(env.as_ref()) {
+        let path = doctor::host_path(env.as_ref(), &script);
+        let executable = doctor::is_executable(&script);
+        match executable {
+            true => writeln!(text, "  {}", path)?,
+            false => writeln!(text, "  {} (not executable)", path)?,
+        }
+        let mut notifier = serde_json::json!({
+            "path": path,
+            "executable": executable,
+        });
+        match NotifierFilter::load(&script) {
+            Ok(filter) => {
+                if let Some(actions) = filter.actions {
+                    let names = actions.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                    writeln!(text, "    notified of: {}", names.join(", "))?;
+                    notifier["actions"] = names.into();
                 }
-                if !child.description.is_empty() {
-                    let _ = writeln!(text, "    Description: {}", child.description);
+                if let Some(results) = filter.results {
+                    let names = results.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+                    writeln!(text, "    notified on: {}", names.join(", "))?;
+                    notifier["results"] = names.into();
                 }
             }
+            // the cause only, the path of the filter is the one on the host
+            Err(e) => {
+                let cause = e.root_cause().to_string();
+                writeln!(
+                    text,
+                    "    invalid filter, notified of all events: {}",
+                    cause
+                )?;
+                notifier["error"] = format!("Invalid notifier filter: {}", cause).into();
+            }
         }
-        output.write(text.as_bytes())
+        notifiers.push(notifier);
     }
-    .map(|_| ())
-    .with_context(|| "Unable to write output")
+    if notifiers.is_empty() {
+        writeln!(text, "  (none)")?;
+    }
+
+    if dumpjson {
+        let json = serde_json::json!({
+            "callouts": callouts,
+            "notifiers": notifiers,
+        });
+        text = serde_json::to_string_pretty(&json).with_context(|| "Unable to serialize json")?;
+        text.push('\n');
+    }
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")
 }
 
-/// Implementation of the `start-parent-mdevs` command
-fn start_parent_mdevs_command(env: Rc<dyn Environment>, parent: String) -> Result<()> {
-    let mut devs = env.clone().get_defined_devices(None, Some(&parent))?;
-    if devs.is_empty() {
-        // nothing to do
+/// Implementation of the `mdevctl callout-test` command
+#[cfg(feature = "callouts")]
+fn callout_test_command(
+    env: Rc<dyn Environment>,
+    args: CalloutTestArgs,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let CalloutTestArgs {
+        uuid,
+        parent,
+        mdev_type,
+        script,
+        event,
+        action,
+        state,
+        jsonfile,
+    } = args;
+    let defined = uuid.and_then(|u| env.clone().get_defined_device(u, parent.as_ref()).ok());
+    let mut dev = match defined {
+        Some(dev) => dev,
+        None => {
+            let (parent, mdev_type) = match (parent, mdev_type) {
+                (Some(p), Some(t)) => (p, t),
+                _ => {
+                    return Err(anyhow!(
+                        "Parent and type are required for a device that is not defined"
+                    ))
+                }
+            };
+            let mut dev = MDev::new(env.clone(), uuid.unwrap_or_else(Uuid::new_v4));
+            dev.parent = Some(parent);
+            dev.mdev_type = Some(mdev_type);
+            dev
+        }
+    };
+    let event: Event = serde_json::from_value(event.into())?;
+    let action: Action = serde_json::from_value(action.into())?;
+    let state = match state.as_str() {
+        "success" => State::Success,
+        "failure" => State::Failure,
+        _ => State::None,
+    };
+    let stdin = jsonfile
+        .map(|f| fs::read_to_string(&f).with_context(|| format!("Unable to read file {:?}", f)))
+        .transpose()?;
+
+    let invocation = callout(&mut dev)?.invoke_raw(script, event, action, state, stdin)?;
+    let mut text = String::new();
+    writeln!(
+        text,
+        "Script: {}",
+        doctor::host_path(env.as_ref(), &invocation.script)
+    )?;
+    writeln!(text, "Arguments: {}", invocation.args.join(" "))?;
+    let section = |text: &mut String, name: &str, contents: Option<&str>| match contents {
+        Some(c) if !c.is_empty() => {
+            let _ = writeln!(text, "{}:", name);
+            text.push_str(c);
+            if !c.ends_with('\n') {
+                text.push('\n');
+            }
+        }
+        _ => {
+            let _ = writeln!(text, "{}: (empty)", name);
+        }
+    };
+    section(&mut text, "Standard input", invocation.stdin.as_deref());
+    match invocation.output.status.code() {
+        Some(code) => writeln!(text, "Exit code: {}", code)?,
+        None => writeln!(text, "Exit code: none, terminated by a signal")?,
+    }
+    let stdout = String::from_utf8_lossy(&invocation.output.stdout);
+    section(&mut text, "Standard output", Some(&stdout));
+    let stderr = String::from_utf8_lossy(&invocation.output.stderr);
+    section(&mut text, "Standard error", Some(&stderr));
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")
+}
+
+/// Implementation of the `mdevctl migrate-config` command
+fn migrate_config_command(
+    env: Rc<dyn Environment>,
+    dry_run: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let steps = migrate::plan(env.as_ref())?;
+    if steps.is_empty() {
+        writeln!(output, "Nothing to migrate").with_context(|| "Failed to write data")?;
         return Ok(());
     }
+    let mut skipped = 0;
+    for step in steps.iter() {
+        if !dry_run {
+            step.apply()?;
+        }
+        if matches!(step, migrate::Step::Skip { .. }) {
+            skipped += 1;
+        }
+        writeln!(output, "{}", step.describe(env.as_ref(), !dry_run))
+            .with_context(|| "Failed to write data")?;
+    }
+    ensure!(
+        skipped == 0,
+        "{} files could not be migrated and need to be resolved manually",
+        skipped
+    );
+    Ok(())
+}
 
-    ensure!(devs.len() == 1, "More than one parent found");
+/// Implementation of the `mdevctl version` command
+fn version_command(
+    env: Rc<dyn Environment>,
+    dumpjson: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let callout_versions = [
+        CalloutVersion::V_1,
+        CalloutVersion::V_2,
+        CalloutVersion::V_3,
+        CalloutVersion::V_4,
+        CalloutVersion::V_5,
+    ];
+    let features = [
+        ("archives", cfg!(feature = "archives")),
+        ("callouts", cfg!(feature = "callouts")),
+        ("monitor", cfg!(feature = "monitor")),
+        ("rich-output", cfg!(feature = "rich-output")),
+        ("wrap-help", cfg!(feature = "wrap-help")),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| *name)
+    .collect::<Vec<_>>();
+    // commands with subcommands of their own are listed along with them, e.g. 'stage commit'
+    let mut commands = Vec::new();
+    for cmd in Mdevctl::command()
+        .get_subcommands()
+        .filter(|c| !c.is_hide_set())
+    {
+        commands.push(cmd.get_name().to_string());
+        for sub in cmd.get_subcommands().filter(|c| !c.is_hide_set()) {
+            commands.push(format!("{} {}", cmd.get_name(), sub.get_name()));
+        }
+    }
+    let paths = [
+        ("config", "Configuration", env.config_base()),
+        ("callouts", "Call-out scripts", env.callout_dir()),
+        ("notifiers", "Notification scripts", env.notification_dir()),
+        ("runtime", "Runtime state", env.runtime_base()),
+        ("staged", "Staged changes", env.staging_base()),
+        ("changelog", "Change logs", env.changelog_base()),
+    ]
+    .iter()
+    .map(|(name, desc, path)| (*name, *desc, doctor::host_path(env.as_ref(), path)))
+    .collect::<Vec<_>>();
 
-    for (_, children) in devs.iter_mut() {
-        for child in children {
-            if child.autostart {
-                debug!("Autostarting {:?}", child.uuid);
-                if let Err(e) = callout(child)?.invoke(Action::Start, false, |c| c.dev.start()) {
-                    for x in e.chain() {
-                        warn!("{}", x);
-                    }
-                }
+    let text = match dumpjson {
+        true => {
+            let mut pathmap = serde_json::Map::new();
+            for (name, _, path) in paths.iter() {
+                pathmap.insert(name.to_string(), path.clone().into());
+            }
+            let version = serde_json::json!({
+                "mdevctl": env!("CARGO_PKG_VERSION"),
+                "callout_versions": callout_versions,
+                "features": features,
+                "commands": commands,
+                "paths": pathmap,
+            });
+            let mut text = serde_json::to_string_pretty(&version)
+                .with_context(|| "Unable to serialize json")?;
+            text.push('\n');
+            text
+        }
+        false => {
+            let mut text = format!("mdevctl {}\n", env!("CARGO_PKG_VERSION"));
+            let versions = callout_versions
+                .iter()
+                .map(|v| v.version().to_string())
+                .collect::<Vec<_>>();
+            writeln!(text, "Call-out protocol versions: {}", versions.join(", "))?;
+            writeln!(text, "Features: {}", features.join(", "))?;
+            writeln!(text, "Commands: {}", commands.join(", "))?;
+            for (_, desc, path) in paths.iter() {
+                writeln!(text, "{} directory: {}", desc, path)?;
+            }
+            text
+        }
+    };
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")
+}
+
+/// Implementation of the `mdevctl host-info` command
+fn host_info_command(
+    env: Rc<dyn Environment>,
+    dumpjson: bool,
+    output: &mut dyn std::io::Write,
+) -> Result<()> {
+    let sorted_names = |dir: PathBuf| -> Vec<String> {
+        let mut names = dir
+            .read_dir()
+            .map(|rd| {
+                rd.filter_map(|e| e.ok())
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        names.sort();
+        names
+    };
+
+    let kernel = fs::read_to_string(env.osrelease_path())
+        .ok()
+        .map(|k| k.trim().to_string());
+    let modules = HOST_MODULES
+        .iter()
+        .map(|m| (m.to_string(), module_loaded(env.as_ref(), m)))
+        .collect::<BTreeMap<_, _>>();
+    let groups = sorted_names(env.iommu_groups_base()).len();
+    let cmdline = fs::read_to_string(env.cmdline_path())
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter(|p| {
+            ["intel_iommu=", "amd_iommu=", "iommu=", "iommu."]
+                .iter()
+                .any(|prefix| p.starts_with(prefix))
+        })
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>();
+    let parents = sorted_names(env.parent_base());
+
+    let text = match dumpjson {
+        true => {
+            let json = serde_json::json!({
+                "kernel": kernel,
+                "modules": modules,
+                "iommu": {
+                    "enabled": groups > 0,
+                    "groups": groups,
+                    "cmdline": cmdline,
+                },
+                "parents": parents,
+            });
+            let mut text =
+                serde_json::to_string_pretty(&json).with_context(|| "Unable to serialize json")?;
+            text.push('\n');
+            text
+        }
+        false => {
+            let mut text = String::new();
+            writeln!(text, "Kernel: {}", kernel.as_deref().unwrap_or("unknown"))?;
+            writeln!(text, "Modules:")?;
+            for (module, loaded) in modules.iter() {
+                let state = match loaded {
+                    true => "loaded",
+                    false => "not loaded",
+                };
+                writeln!(text, "  {}: {}", module, state)?;
             }
+            match groups {
+                0 => writeln!(text, "IOMMU: disabled")?,
+                n => writeln!(text, "IOMMU: enabled ({} groups)", n)?,
+            }
+            if !cmdline.is_empty() {
+                writeln!(text, "  Kernel parameters: {}", cmdline.join(" "))?;
+            }
+            writeln!(text, "Parent devices: {}", parents.len())?;
+            for parent in parents.iter() {
+                writeln!(text, "  {}", parent)?;
+            }
+            text
         }
+    };
+    output
+        .write_all(text.as_bytes())
+        .with_context(|| "Failed to write data")
+}
+
+/// Run mdevctl and exit with the code of the category of its error, if any, see
+/// [`ErrorCategory::exit_code`]
+fn main() {
+    let res = run();
+    logger::shutdown();
+    if let Err(e) = res {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(ErrorCategory::of(&e).exit_code());
     }
-    Ok(())
 }
 
 /// parse command line arguments and dispatch to command-specific functions
-fn main() -> Result<()> {
-    logger().init();
+fn run() -> Result<()> {
+    logger::init(false).expect("Unable to install the logger");
     debug!("Starting up");
 
-    let env = DefaultEnvironment::new();
-    debug!("{:?}", env);
-
-    // make sure the environment is sane
-    env.self_check()?;
-
     // check if we're running as the symlink executable 'lsmdev'. If so, just execute the 'list'
     // command directly
     let exe = std::env::args_os().next().unwrap();
-    match exe.to_str() {
+    let (command, fail_on_ambiguous_callout, name) = match exe.to_str() {
         Some(val) if val.ends_with("lsmdev") => {
             debug!("running as 'lsmdev'");
             let opts = LsmdevOptions::parse();
-            list_command(
-                env,
-                opts.defined,
-                opts.dumpjson,
-                opts.verbose,
-                opts.uuid,
-                opts.parent,
-                &mut stdout(),
-            )
+            (MdevctlCommands::List(opts), false, "list".to_string())
         }
-        _ => match MdevctlCommands::parse() {
-            MdevctlCommands::Define {
-                uuid,
-                auto,
-                parent,
-                mdev_type,
-                jsonfile,
-                force,
-            } => define_command(env, uuid, auto, parent, mdev_type, jsonfile, force),
-            MdevctlCommands::Undefine {
-                uuid,
-                parent,
-                force,
-            } => undefine_command(env, uuid, parent, force),
-            MdevctlCommands::Modify {
+        _ => {
+            let args = legacy::translate_args(std::env::args_os().collect())?;
+            let matches = Mdevctl::command().get_matches_from(args);
+            let name = matches.subcommand_name().unwrap_or_default().to_string();
+            let cli = Mdevctl::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+            (cli.command, cli.fail_on_ambiguous_callout, name)
+        }
+    };
+    let _span = span(name);
+
+    // read-only commands can be bounded by an overall deadline, e.g. for monitoring probes
+    let timeout = match &command {
+        MdevctlCommands::List(opts) => opts.timeout,
+        MdevctlCommands::Types { timeout, .. } => *timeout,
+        _ => None,
+    };
+    let deadline = timeout.map(|t| Instant::now() + Duration::from_secs(t));
+    let env = DefaultEnvironment::new(fail_on_ambiguous_callout, deadline);
+    debug!("{:?}", env);
+
+    // make sure the environment is sane, unless that's what is asked for
+    if !matches!(
+        command,
+        MdevctlCommands::Doctor { .. } | MdevctlCommands::Version { .. }
+    ) {
+        env.self_check()?;
+    }
+
+    match command {
+        MdevctlCommands::Define(mut args) => {
+            let path = args.path.take();
+            let spec = path_spec(&env, path.as_ref())?;
+            args.uuid = merge_path_arg("UUID", args.uuid, spec.uuid, path.as_ref())?;
+            args.parent = merge_path_arg("parent", args.parent, spec.parent, path.as_ref())?;
+            args.mdev_type = merge_path_arg("type", args.mdev_type, spec.mdev_type, path.as_ref())?;
+            match args.print_only {
+                true => define_print_only_command(env, &args, &mut stdout()),
+                false => define_command(env, &args),
+            }
+        }
+        MdevctlCommands::Undefine {
+            uuid,
+            parent,
+            path,
+            all,
+            force,
+        } => {
+            let spec = path_spec(&env, path.as_ref())?;
+            let uuid = match all {
+                true => None,
+                false => Some(require_uuid(uuid, &spec, path.as_ref())?),
+            };
+            let parent = merge_path_arg("parent", parent, spec.parent, path.as_ref())?;
+            undefine_command(env, uuid, parent, all, force)
+        }
+        MdevctlCommands::Clone {
+            uuid,
+            parent,
+            new_uuid,
+            new_parent,
+            label,
+            force,
+        } => clone_command(env, uuid, parent, new_uuid, new_parent, label, force),
+        MdevctlCommands::SetUuid {
+            uuid,
+            parent,
+            new_uuid,
+            force,
+        } => set_uuid_command(env, uuid, parent, new_uuid, force),
+        MdevctlCommands::Modify(mut args) => {
+            let path = args.path.take();
+            let spec = path_spec(&env, path.as_ref())?;
+            let uuid = require_uuid(args.uuid, &spec, path.as_ref())?;
+            args.parent = merge_path_arg("parent", args.parent, spec.parent, path.as_ref())?;
+            if args.changes.clear_attrs
+                && !args.yes
+                && !confirm(&format!(
+                    "Delete all attributes of device {}?",
+                    uuid.hyphenated()
+                ))?
+            {
+                return Err(anyhow!("Aborted, no attributes were deleted"));
+            }
+            modify_command(env, uuid, args, &mut stdout())
+        }
+        MdevctlCommands::Edit {
+            uuid,
+            parent,
+            force,
+        } => {
+            let editor = ["VISUAL", "EDITOR"]
+                .iter()
+                .filter_map(|var| std::env::var(var).ok())
+                .find(|editor| !editor.is_empty())
+                .unwrap_or_else(|| "vi".to_string());
+            edit_command(env, uuid, parent, &editor, force)
+        }
+        MdevctlCommands::Log {
+            uuid,
+            parent,
+            dumpjson,
+        } => log_command(env, uuid, parent, dumpjson, &mut stdout()),
+        MdevctlCommands::Attr { command } => match command {
+            AttrCommands::Get { uuid, parent, name } => {
+                attr_get_command(env, uuid, parent, &name, &mut stdout())
+            }
+            AttrCommands::Set {
                 uuid,
                 parent,
-                mdev_type,
-                addattr,
-                delattr,
-                index,
+                name,
                 value,
-                auto,
-                manual,
-                live,
-                defined,
-                jsonfile,
-                force,
-            } => modify_command(
-                env, uuid, parent, mdev_type, addattr, delattr, index, value, auto, manual, live,
-                defined, jsonfile, force,
-            ),
-            MdevctlCommands::Start {
+            } => attr_set_command(env, uuid, parent, name, value),
+        },
+        MdevctlCommands::Stage { command } => match command {
+            StageCommands::Modify {
                 uuid,
                 parent,
-                mdev_type,
-                jsonfile,
+                changes,
+            } => stage_modify_command(env, uuid, parent, changes),
+            StageCommands::List { dumpjson } => stage_list_command(env, dumpjson, &mut stdout()),
+            StageCommands::Discard { uuid, parent } => stage_discard_command(env, uuid, parent),
+        },
+        #[cfg(not(feature = "archives"))]
+        MdevctlCommands::State { .. } => Err(anyhow!(
+            "mdevctl was built without support for state archives"
+        )),
+        #[cfg(feature = "archives")]
+        MdevctlCommands::State { command } => match command {
+            StateCommands::Export { file } => state_export_command(env, file),
+            StateCommands::Import {
+                file,
+                start,
                 force,
-            } => start_command(env, uuid, parent, mdev_type, jsonfile, force),
-            MdevctlCommands::Stop { uuid, force } => stop_command(env, uuid, force),
-            MdevctlCommands::List(list) => list_command(
-                env,
-                list.defined,
-                list.dumpjson,
-                list.verbose,
-                list.uuid,
-                list.parent,
-                &mut stdout(),
-            ),
-            MdevctlCommands::Types { parent, dumpjson } => {
-                types_command(env, parent, dumpjson, &mut stdout())
+                force_callouts,
+            } => state_import_command(env, file, start, force, force_callouts),
+        },
+        MdevctlCommands::Commit { force } => commit_command(env, force),
+        MdevctlCommands::Start(mut args) => {
+            if args.all_defined {
+                return start_all_defined_command(env, &args, &mut stdout());
+            }
+            let path = args.path.take();
+            let spec = path_spec(&env, path.as_ref())?;
+            args.uuid = merge_path_arg("UUID", args.uuid, spec.uuid, path.as_ref())?;
+            args.parent = merge_path_arg("parent", args.parent, spec.parent, path.as_ref())?;
+            args.mdev_type = merge_path_arg("type", args.mdev_type, spec.mdev_type, path.as_ref())?;
+            if let (true, Some(uuid)) = (args.skip_active, args.uuid) {
+                if is_active(&env, uuid, args.parent.as_ref()) {
+                    debug!("Device {} is already active", uuid.hyphenated());
+                    return Ok(());
+                }
+            }
+            start_command(env, args)
+        }
+        MdevctlCommands::Stop {
+            uuid,
+            path,
+            force,
+            kill_users,
+        } => {
+            let spec = path_spec(&env, path.as_ref())?;
+            let uuid = require_uuid(uuid, &spec, path.as_ref())?;
+            stop_command(env, uuid, force, kill_users)
+        }
+        MdevctlCommands::Restart { uuid, path, force } => {
+            let spec = path_spec(&env, path.as_ref())?;
+            let uuid = require_uuid(uuid, &spec, path.as_ref())?;
+            restart_command(env, uuid, force)
+        }
+        MdevctlCommands::List(opts) => list(env, opts, &mut stdout()),
+        #[cfg(not(feature = "rich-output"))]
+        MdevctlCommands::Types { dumpjson: true, .. }
+        | MdevctlCommands::Types {
+            output: Some(_), ..
+        } => Err(anyhow!(
+            "mdevctl was built without support for structured types output"
+        )),
+        MdevctlCommands::Types {
+            parent,
+            device_api,
+            dumpjson,
+            output,
+            group_by,
+            pci_ids,
+            ..
+        } => types_command(
+            env,
+            &parent,
+            device_api.as_deref(),
+            output.or(dumpjson.then_some(OutputFormat::Json)),
+            group_by,
+            pci_ids,
+            &mut stdout(),
+        ),
+        MdevctlCommands::Estimate {
+            parent,
+            mdev_type,
+            count,
+            dumpjson,
+        } => estimate_command(env, parent, mdev_type, count, dumpjson, &mut stdout()),
+        MdevctlCommands::Reconcile {
+            parent,
+            report,
+            dumpjson,
+            changed_only,
+        } => reconcile_command(env, parent, report, dumpjson, changed_only, &mut stdout()),
+        MdevctlCommands::Export => export_command(env, &mut stdout()),
+        MdevctlCommands::Import {
+            file,
+            force,
+            fail_fast,
+            keep_going,
+        } => import_command(
+            env,
+            file,
+            force,
+            OnFailure::from_options(fail_fast, keep_going, OnFailure::Cancel),
+        ),
+        MdevctlCommands::GenerateSystemd { dir, parent } => {
+            generate_systemd_command(env, dir, parent)
+        }
+        MdevctlCommands::GenerateUdevRules { dir, to_stdout } => {
+            generate_udev_rules_command(env, dir, to_stdout, &mut stdout())
+        }
+        MdevctlCommands::Validate { dumpjson } => validate_command(env, dumpjson, &mut stdout()),
+        #[cfg(not(feature = "archives"))]
+        MdevctlCommands::SupportBundle { .. } => Err(anyhow!(
+            "mdevctl was built without support for support bundles"
+        )),
+        #[cfg(feature = "archives")]
+        MdevctlCommands::SupportBundle { output } => support_bundle_command(env, output),
+        MdevctlCommands::HostInfo { dumpjson } => host_info_command(env, dumpjson, &mut stdout()),
+        MdevctlCommands::Doctor { dumpjson } => doctor_command(env, dumpjson, &mut stdout()),
+        MdevctlCommands::Callouts { command } => match command {
+            CalloutsCommands::List { dumpjson } => {
+                callouts_list_command(env, dumpjson, &mut stdout())
             }
-            MdevctlCommands::StartParentMdevs { parent } => start_parent_mdevs_command(env, parent),
+        },
+        #[cfg(not(feature = "callouts"))]
+        MdevctlCommands::CalloutTest { .. } => Err(anyhow!(
+            "mdevctl was built without support for callout scripts"
+        )),
+        #[cfg(feature = "callouts")]
+        MdevctlCommands::CalloutTest(args) => callout_test_command(env, args, &mut stdout()),
+        MdevctlCommands::MigrateConfig { dry_run } => {
+            migrate_config_command(env, dry_run, &mut stdout())
+        }
+        MdevctlCommands::Version { dumpjson } => version_command(env, dumpjson, &mut stdout()),
+        #[cfg(not(feature = "monitor"))]
+        MdevctlCommands::Monitor { .. } => Err(anyhow!(
+            "mdevctl was built without support for the monitor command"
+        )),
+        #[cfg(feature = "monitor")]
+        MdevctlCommands::Monitor { dumpjson } => {
+            monitor_command(env, dumpjson, &mut stdout(), WATCH_INTERVAL, None)
+        }
+        MdevctlCommands::StartAllParents { args } => {
+            start_all_parents_command(env, &args, &mut stdout())
+        }
+        MdevctlCommands::StartParentMdevs {
+            parent,
+            args,
+            dry_run,
+        } => match dry_run {
+            true => preview_parent_mdevs_command(env, parent, args.dumpjson, &mut stdout()),
+            false => start_parent_mdevs_command(env, parent, &args, &mut stdout()),
         },
     }
 }
@@ -1,12 +1,26 @@
 //! Structures for representing a mediated device
 
+use crate::cli::{AttrMerge, ListColumn};
 use crate::environment::Environment;
-use anyhow::{anyhow, Context, Result};
+use crate::error::{ErrorCategory, MdevError};
+use crate::kmsg::KernelLog;
+use crate::pciids::PciId;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
 use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
 use std::fs;
-use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::io::{ErrorKind, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use uuid::Uuid;
 
@@ -16,6 +30,112 @@ pub enum FormatType {
     Defined,
 }
 
+/// The key of the [`MDev::staged_base`] in the staged definition of a device
+const STAGED_BASE_KEY: &str = "staged_base";
+
+/// The columns of the `list --output table` listing
+pub const TABLE_COLUMNS: &[ListColumn] = &[
+    ListColumn::Uuid,
+    ListColumn::Parent,
+    ListColumn::Type,
+    ListColumn::Start,
+    ListColumn::State,
+];
+
+/// Format devices as a table of the given columns, aligned to the widest cell of each column and
+/// with an optional header row
+pub fn format_table(devices: &[MDev], columns: &[ListColumn], headings: bool) -> Result<String> {
+    let mut rows = Vec::new();
+    if headings {
+        rows.push(
+            columns
+                .iter()
+                .map(|c| {
+                    c.to_possible_value()
+                        .map(|v| v.get_name().to_uppercase())
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<_>>(),
+        );
+    }
+    for dev in devices {
+        rows.push(
+            columns
+                .iter()
+                .map(|c| dev.column_text(*c))
+                .collect::<Result<Vec<_>>>()?,
+        );
+    }
+
+    let mut widths = vec![0; columns.len()];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = std::cmp::max(*width, cell.chars().count());
+        }
+    }
+    let mut text = String::new();
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join(" ");
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+/// Parse the JSON representation of attributes. All attributes are validated before any is
+/// returned.
+fn parse_attributes(attrs: &serde_json::Value) -> Result<Vec<(String, String)>> {
+    if !attrs.is_array() && !attrs.is_null() {
+        return Err(
+            MdevError::InvalidConfig("attributes field is not an array".to_string()).into(),
+        );
+    }
+
+    let mut parsed = Vec::new();
+    for (i, attr) in attrs.as_array().into_iter().flatten().enumerate() {
+        let attrobj = attr.as_object().ok_or_else(|| {
+            MdevError::InvalidConfig(format!(
+                "invalid JSON format for attribute {}: not an object",
+                i
+            ))
+        })?;
+        // attributes are represented by JSON objects with a single field.
+        match attrobj.len() {
+            0 => {
+                return Err(MdevError::InvalidConfig(format!(
+                    "invalid JSON format for attribute {}: no fields",
+                    i
+                ))
+                .into())
+            }
+            1 => (),
+            _ => {
+                return Err(MdevError::InvalidConfig(format!(
+                    "invalid JSON format for attribute {}: too many fields",
+                    i
+                ))
+                .into())
+            }
+        }
+        // get the key and value from the first (only) map entry
+        if let Some((key, val)) = attrobj.iter().next() {
+            let valstr = val.as_str().ok_or_else(|| {
+                MdevError::InvalidConfig(format!(
+                    "invalid JSON format for attribute {} {{{:?}, {}}}: value must be of type str",
+                    i, key, val
+                ))
+            })?;
+            parsed.push((key.to_string(), valstr.to_string()));
+        }
+    }
+    Ok(parsed)
+}
+
 /// Representation of a mediated device
 #[derive(Debug, Clone)]
 pub struct MDev {
@@ -25,6 +145,25 @@ pub struct MDev {
     pub parent: Option<String>,
     pub mdev_type: Option<String>,
     pub attrs: Vec<(String, String)>,
+    /// the attributes that call-out scripts provided when the device was defined, rather than
+    /// the user. An attribute whose value is changed is no longer considered one of them.
+    pub callout_attrs: Vec<(String, String)>,
+    pub requires: Requirements,
+    pub health_check: Option<HealthCheck>,
+    /// how many seconds starting the device may take before it is abandoned
+    pub start_timeout: Option<u64>,
+    /// how many seconds stopping the device retries the removal while the device is busy
+    pub stop_timeout: Option<u64>,
+    /// devices with a higher priority are started before those with a lower one
+    pub priority: i32,
+    /// the devices that must be active before this device is started
+    pub depends_on: Vec<Uuid>,
+    pub labels: BTreeMap<String, String>,
+    /// time after which an attempt to start the device is abandoned
+    pub deadline: Option<Instant>,
+    /// whether stopping the device kills the processes using it if it cannot be removed
+    /// otherwise, see [`MDev::stop`]
+    pub kill_users: bool,
     pub env: Rc<dyn Environment>,
 }
 
@@ -37,6 +176,16 @@ impl MDev {
             parent: None,
             mdev_type: None,
             attrs: Vec::new(),
+            callout_attrs: Vec::new(),
+            requires: Requirements::default(),
+            health_check: None,
+            start_timeout: None,
+            stop_timeout: None,
+            priority: 0,
+            depends_on: Vec::new(),
+            labels: BTreeMap::new(),
+            deadline: env.deadline(),
+            kill_users: false,
             env,
         }
     }
@@ -67,20 +216,22 @@ impl MDev {
     // get parent and propagate a consistent error to the caller if absent
     pub fn parent(&self) -> Result<&String> {
         self.parent.as_ref().ok_or_else(|| {
-            anyhow!(
+            MdevError::InvalidConfig(format!(
                 "Device {} is missing a parent",
-                self.uuid.hyphenated().to_string()
-            )
+                self.uuid.hyphenated()
+            ))
+            .into()
         })
     }
 
     // get mdev_type and propagate a consistent error to the caller if absent
     pub fn mdev_type(&self) -> Result<&String> {
         self.mdev_type.as_ref().ok_or_else(|| {
-            anyhow!(
+            MdevError::InvalidConfig(format!(
                 "Device {} is missing a mdev_type",
-                self.uuid.hyphenated().to_string()
-            )
+                self.uuid.hyphenated()
+            ))
+            .into()
         })
     }
 
@@ -93,6 +244,16 @@ impl MDev {
         })
     }
 
+    /// the path of the staged changes of this device, see the `stage` command
+    pub fn staged_path(&self) -> Option<PathBuf> {
+        self.parent.as_ref().map(|x| {
+            let mut path = self.env.staging_base();
+            path.push(x);
+            path.push(self.uuid.hyphenated().to_string());
+            path
+        })
+    }
+
     pub fn is_defined(&self) -> bool {
         match self.persist_path() {
             Some(p) => p.exists(),
@@ -100,6 +261,94 @@ impl MDev {
         }
     }
 
+    /// whether this is an inactive device whose parent device is not present
+    pub fn parent_missing(&self) -> bool {
+        !self.active && self.find_parent_dir().is_err()
+    }
+
+    /// the desired state of a defined device
+    pub fn desired_state(&self) -> DesiredState {
+        match self.autostart {
+            true => DesiredState::Running,
+            false => DesiredState::Any,
+        }
+    }
+
+    /// Compare the desired state of a defined device with its actual state in sysfs
+    pub fn sync_status(&self) -> Result<SyncStatus> {
+        if self.active {
+            return Ok(match self.health_problem() {
+                Some(problem) => SyncStatus::OutOfSync(OutOfSyncReason::Unhealthy(problem)),
+                None => SyncStatus::InSync,
+            });
+        }
+        if self.desired_state() == DesiredState::Any {
+            return Ok(SyncStatus::InSync);
+        }
+
+        // the definition only matches an active device with the same parent and type
+        let mut actual = MDev::new(self.env.clone(), self.uuid);
+        actual.load_from_sysfs()?;
+        if actual.active {
+            return Ok(SyncStatus::OutOfSync(OutOfSyncReason::Mismatch {
+                parent: actual.parent()?.clone(),
+                mdev_type: actual.mdev_type()?.clone(),
+            }));
+        }
+
+        match self.find_parent_dir() {
+            Ok(_) => Ok(SyncStatus::OutOfSync(OutOfSyncReason::NotRunning)),
+            Err(_) => Ok(SyncStatus::OutOfSync(OutOfSyncReason::ParentMissing)),
+        }
+    }
+
+    /// Evaluate the health check of an active device, if it has one. A device can remain in sysfs
+    /// without being functional, e.g. after its parent's driver recovered from an error.
+    pub fn health_problem(&self) -> Option<String> {
+        let check = self.health_check.as_ref()?;
+        match fs::read_to_string(self.path().join(&check.attribute)) {
+            Ok(value) if value.trim() == check.expected => None,
+            Ok(value) => Some(format!(
+                "attribute {} is '{}' instead of '{}'",
+                check.attribute,
+                value.trim(),
+                check.expected
+            )),
+            Err(e) => Some(format!(
+                "unable to read attribute {}: {}",
+                check.attribute, e
+            )),
+        }
+    }
+
+    /// Check a loaded device definition against the parent devices and mdev types in sysfs.
+    /// Attributes can only be checked while the device is active.
+    pub fn validate_definition(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        let typedir = match self.find_parent_dir() {
+            Ok(dir) => dir.join("mdev_supported_types"),
+            Err(_) => {
+                problems.push(ConfigProblem::ParentMissing);
+                return problems;
+            }
+        };
+        if let Some(t) = self
+            .mdev_type
+            .as_ref()
+            .filter(|t| !typedir.join(t).is_dir())
+        {
+            problems.push(ConfigProblem::UnsupportedType(t.clone()));
+        }
+        if self.active {
+            for (name, _) in self.attrs.iter() {
+                if !self.path().join(name).exists() {
+                    problems.push(ConfigProblem::UnknownAttribute(name.clone()));
+                }
+            }
+        }
+        problems
+    }
+
     pub fn load_from_sysfs(&mut self) -> Result<()> {
         debug!("Loading device '{:?}' from sysfs", self.uuid);
         if !self.path().exists() {
@@ -110,24 +359,20 @@ impl MDev {
         let canonpath = self.path().canonicalize()?;
         let sysfsparent = canonpath.parent().unwrap();
         let parentname = canonical_basename(sysfsparent)?;
-        if self.parent.is_some() && self.parent.as_ref() != Some(&parentname) {
+        if let Some(parent) = self.parent.as_ref().filter(|p| **p != parentname) {
             debug!(
                 "Active mdev {:?} has different parent: {}!={}. No match.",
-                self.uuid,
-                self.parent.as_ref().unwrap(),
-                parentname
+                self.uuid, parent, parentname
             );
             return Ok(());
         }
         let mut typepath = self.path();
         typepath.push("mdev_type");
         let mdev_type = canonical_basename(typepath)?;
-        if self.mdev_type.is_some() && self.mdev_type.as_ref() != Some(&mdev_type) {
+        if let Some(t) = self.mdev_type.as_ref().filter(|t| **t != mdev_type) {
             debug!(
                 "Active mdev {:?} has different type: {}!={}. No match.",
-                self.uuid,
-                self.mdev_type.as_ref().unwrap(),
-                mdev_type
+                self.uuid, t, mdev_type
             );
             return Ok(());
         }
@@ -141,33 +386,98 @@ impl MDev {
     }
 
     pub fn add_attributes(&mut self, attrs: &serde_json::Value) -> Result<()> {
-        if !attrs.is_array() && !attrs.is_null() {
-            return Err(anyhow!("attributes field is not an array"));
-        }
-
-        if let Some(attrarray) = attrs.as_array() {
-            if !attrarray.is_empty() {
-                for attr in attrarray {
-                    let attrobj = attr.as_object().ok_or_else(|| {
-                        anyhow!("invalid JSON format for attribute: not an object")
-                    })?;
-                    // attributes are represented by JSON objects with a single field.
-                    if attrobj.len() != 1 {
-                        return Err(anyhow!(
-                            "invalid JSON format for attribute: too many fields"
-                        ));
-                    }
-                    // get the key and value from the first (only) map entry
-                    if let Some((key, val)) = attrobj.iter().next() {
-                        let valstr = val.as_str().ok_or_else(|| {
-                            anyhow!("invalid JSON format for attribute {{{:?}, {}}}: value must be of type str", key, val)
-                        })?;
-                        self.attrs.push((key.to_string(), valstr.to_string()));
-                    }
+        self.attrs.extend(parse_attributes(attrs)?);
+        Ok(())
+    }
+
+    /// Mark the attributes named in the `callout_attrs` field of a definition as provided by a
+    /// call-out script. The names are kept apart from the attributes themselves, so that
+    /// consumers of the definition that do not know about them still see plain attributes.
+    fn mark_callout_attributes(&mut self, names: &serde_json::Value) -> Result<()> {
+        if names.is_null() {
+            return Ok(());
+        }
+        let names = names
+            .as_array()
+            .and_then(|a| a.iter().map(|n| n.as_str()).collect::<Option<Vec<_>>>())
+            .ok_or_else(|| {
+                MdevError::InvalidConfig("callout_attrs field is not an array of names".into())
+            })?;
+        self.callout_attrs = self
+            .attrs
+            .iter()
+            .filter(|(name, _)| names.contains(&name.as_str()))
+            .cloned()
+            .collect();
+        Ok(())
+    }
+
+    /// Add the attributes provided by a call-out script, which are marked as such in the
+    /// definition. Provided attributes with the same name as one the device already has are
+    /// merged according to `merge`.
+    pub fn add_callout_attributes(
+        &mut self,
+        attrs: &serde_json::Value,
+        merge: AttrMerge,
+    ) -> Result<()> {
+        // only the attributes the device had before are duplicates, a script may well provide
+        // an attribute more than once
+        let existing = self.attrs.len();
+        for attr in parse_attributes(attrs)? {
+            let duplicate = self.attrs[..existing]
+                .iter()
+                .position(|(name, _)| *name == attr.0);
+            match (merge, duplicate) {
+                (AttrMerge::IgnoreDuplicates, Some(_)) => {
+                    debug!("Ignoring provided attribute {} of {}", attr.0, self.uuid);
+                    continue;
                 }
+                (AttrMerge::Replace, Some(i)) => self.attrs[i] = attr.clone(),
+                _ => self.attrs.push(attr.clone()),
             }
+            self.callout_attrs.push(attr);
         }
+        Ok(())
+    }
 
+    /// Replace the attributes provided by call-out scripts with `attrs`, newly provided by them.
+    /// The new attributes take the place of the first replaced one, or are appended if there is
+    /// none. Attributes set by the user are kept.
+    pub fn replace_callout_attributes(&mut self, attrs: &serde_json::Value) -> Result<()> {
+        let parsed = parse_attributes(attrs)?;
+        let sources = self.attrs_from_callout();
+        let position = sources.iter().position(|c| *c).unwrap_or(self.attrs.len());
+        let mut sources = sources.into_iter();
+        self.attrs.retain(|_| !sources.next().unwrap());
+        let position = position.min(self.attrs.len());
+        self.callout_attrs = parsed;
+        self.attrs
+            .splice(position..position, self.callout_attrs.iter().cloned());
+        Ok(())
+    }
+
+    /// for each attribute, whether it was provided by a call-out script
+    pub fn attrs_from_callout(&self) -> Vec<bool> {
+        let mut remaining = self.callout_attrs.iter().collect::<Vec<_>>();
+        self.attrs
+            .iter()
+            .map(|attr| match remaining.iter().position(|c| *c == attr) {
+                Some(i) => {
+                    remaining.remove(i);
+                    true
+                }
+                None => false,
+            })
+            .collect()
+    }
+
+    /// Expand the template expressions in the attribute values of a device that is defined as
+    /// the `index`th of several devices, see [`expand_template`]
+    pub fn expand_attr_templates(&mut self, index: u32) -> Result<()> {
+        for (name, value) in self.attrs.iter_mut() {
+            *value = expand_template(value, index, &self.uuid)
+                .with_context(|| format!("Invalid template in attribute {}", name))?;
+        }
         Ok(())
     }
 
@@ -176,32 +486,91 @@ impl MDev {
             "Loading device '{:?}' from json (parent: {})",
             self.uuid, parent
         );
-        if self.parent.is_some() && self.parent.as_ref() != Some(&parent) {
+        if let Some(p) = self.parent.as_ref().filter(|p| **p != parent) {
             warn!(
                 "Overwriting parent for mdev {:?}: {} => {}",
-                self.uuid,
-                self.parent.as_ref().unwrap(),
-                parent
+                self.uuid, p, parent
             );
         }
         self.parent = Some(parent);
-        if json["mdev_type"].is_null() || json["start"].is_null() {
-            return Err(anyhow!("invalid json"));
+        if !json.is_object() {
+            return Err(MdevError::InvalidConfig(
+                "invalid json: device configuration is not an object".to_string(),
+            )
+            .into());
         }
-        let mdev_type = json["mdev_type"].as_str().unwrap().to_string();
-        if self.mdev_type.is_some() && self.mdev_type.as_ref() != Some(&mdev_type) {
+        let mdev_type = match &json["mdev_type"] {
+            serde_json::Value::Null => {
+                return Err(MdevError::InvalidConfig(
+                    "invalid json: 'mdev_type' is missing".to_string(),
+                )
+                .into())
+            }
+            serde_json::Value::String(t) => t.to_string(),
+            _ => {
+                return Err(MdevError::InvalidConfig(
+                    "invalid json: 'mdev_type' is not a string".to_string(),
+                )
+                .into())
+            }
+        };
+        if let Some(t) = self.mdev_type.as_ref().filter(|t| **t != mdev_type) {
             warn!(
                 "Overwriting mdev type for mdev {:?}: {} => {}",
-                self.uuid,
-                self.mdev_type.as_ref().unwrap(),
-                mdev_type
+                self.uuid, t, mdev_type
             );
         }
         self.mdev_type = Some(mdev_type);
-        let startval = json["start"].as_str();
-        self.autostart = matches!(startval, Some("auto"));
+        let startval = match &json["start"] {
+            serde_json::Value::Null => {
+                return Err(MdevError::InvalidConfig(
+                    "invalid json: 'start' is missing".to_string(),
+                )
+                .into())
+            }
+            serde_json::Value::String(s) => s.as_str(),
+            _ => {
+                return Err(MdevError::InvalidConfig(
+                    "invalid json: 'start' is not a string".to_string(),
+                )
+                .into())
+            }
+        };
+        self.autostart = startval == "auto";
 
+        self.requires = Requirements::from_json(&json["requires"])?;
+        self.health_check = HealthCheck::from_json(&json["health_check"])?;
+        self.start_timeout = match &json["start_timeout"] {
+            serde_json::Value::Null => None,
+            t => Some(t.as_u64().ok_or_else(|| {
+                MdevError::InvalidConfig(
+                    "invalid json: 'start_timeout' is not a number of seconds".to_string(),
+                )
+            })?),
+        };
+        self.stop_timeout = match &json["stop_timeout"] {
+            serde_json::Value::Null => None,
+            t => Some(t.as_u64().ok_or_else(|| {
+                MdevError::InvalidConfig(
+                    "invalid json: 'stop_timeout' is not a number of seconds".to_string(),
+                )
+            })?),
+        };
+        self.priority = match &json["priority"] {
+            serde_json::Value::Null => 0,
+            p => p
+                .as_i64()
+                .and_then(|p| i32::try_from(p).ok())
+                .ok_or_else(|| {
+                    MdevError::InvalidConfig(
+                        "invalid json: 'priority' is not an integer".to_string(),
+                    )
+                })?,
+        };
+        self.depends_on = depends_on_from_json(&json["depends_on"])?;
+        self.labels = labels_from_json(&json["labels"])?;
         self.add_attributes(&json["attrs"])?;
+        self.mark_callout_attributes(&json["callout_attrs"])?;
         debug!("loaded device {:?}", self);
 
         Ok(())
@@ -220,16 +589,39 @@ impl MDev {
         Ok(())
     }
 
+    /// the value of this device in a column of [`format_table`]
+    pub fn column_text(&self, column: ListColumn) -> Result<String> {
+        Ok(match column {
+            ListColumn::Uuid => self.uuid.hyphenated().to_string(),
+            ListColumn::Parent => self.parent()?.clone(),
+            ListColumn::Type => self.mdev_type()?.clone(),
+            ListColumn::Start => match self.autostart {
+                true => "auto".to_string(),
+                false => "manual".to_string(),
+            },
+            ListColumn::State => match (self.active, self.parent_missing()) {
+                (true, _) => "active".to_string(),
+                (false, true) => "parent-missing".to_string(),
+                (false, false) => "stopped".to_string(),
+            },
+            ListColumn::Defined => match self.is_defined() {
+                true => "yes".to_string(),
+                false => "no".to_string(),
+            },
+            ListColumn::Attrs => self.attrs.len().to_string(),
+        })
+    }
+
     pub fn to_text(&self, fmt: FormatType, verbose: bool) -> Result<String> {
         match fmt {
             FormatType::Defined => {
                 if !self.is_defined() {
-                    return Err(anyhow!("Device is not defined"));
+                    return Err(MdevError::NotFound("Device is not defined".to_string()).into());
                 }
             }
             FormatType::Active => {
                 if !self.active {
-                    return Err(anyhow!("Device is not active"));
+                    return Err(MdevError::NotFound("Device is not active".to_string()).into());
                 }
             }
         }
@@ -249,6 +641,8 @@ impl MDev {
             FormatType::Defined => {
                 if self.active {
                     output.push_str(" (active)");
+                } else if self.parent_missing() {
+                    output.push_str(" (parent missing)");
                 }
             }
             FormatType::Active => {
@@ -260,8 +654,34 @@ impl MDev {
 
         output.push('\n');
         if verbose {
+            if let Some(name) = self.type_name() {
+                output.push_str(&format!("  Type name: {}\n", name));
+            }
             let attr_string = self.fmt_attrs();
             output.push_str(&attr_string);
+            output.push_str(&self.requires.to_text());
+            if let Some(check) = &self.health_check {
+                output.push_str(&format!(
+                    "  Health check: {} = {}\n",
+                    check.attribute, check.expected
+                ));
+            }
+            if let Some(timeout) = self.start_timeout {
+                output.push_str(&format!("  Start timeout: {}s\n", timeout));
+            }
+            if let Some(timeout) = self.stop_timeout {
+                output.push_str(&format!("  Stop timeout: {}s\n", timeout));
+            }
+            if self.priority != 0 {
+                output.push_str(&format!("  Priority: {}\n", self.priority));
+            }
+            if !self.depends_on.is_empty() {
+                output.push_str("  Depends on:\n");
+                for uuid in self.depends_on.iter() {
+                    output.push_str(&format!("    {}\n", uuid.hyphenated()));
+                }
+            }
+            output.push_str(&self.fmt_labels());
         }
         Ok(output)
     }
@@ -270,14 +690,174 @@ impl MDev {
         let mut output = String::new();
         if !self.attrs.is_empty() {
             output.push_str("  Attrs:\n");
-            for (i, (key, value)) in self.attrs.iter().enumerate() {
-                let txtattr = format!("    @{{{}}}: {{\"{}\":\"{}\"}}\n", i, key, value);
+            let sources = self.attrs_from_callout();
+            for (i, ((key, value), from_callout)) in self.attrs.iter().zip(sources).enumerate() {
+                let txtattr = format!(
+                    "    @{{{}}}: {{\"{}\":\"{}\"}}{}\n",
+                    i,
+                    key,
+                    value,
+                    if from_callout { " (from callout)" } else { "" }
+                );
                 output.push_str(&txtattr);
             }
         }
         output
     }
 
+    fn fmt_labels(&self) -> String {
+        let mut output = String::new();
+        if !self.labels.is_empty() {
+            output.push_str("  Labels:\n");
+            for (key, value) in self.labels.iter() {
+                output.push_str(&format!("    {}={}\n", key, value));
+            }
+        }
+        output
+    }
+
+    /// the human readable name of the type of the device, e.g. "GRID P40-2B", if the parent
+    /// device provides one
+    pub fn type_name(&self) -> Option<String> {
+        let path = self
+            .env
+            .parent_base()
+            .join(self.parent.as_ref()?)
+            .join("mdev_supported_types")
+            .join(self.mdev_type.as_ref()?)
+            .join("name");
+        read_before_deadline(&path, self.deadline)
+            .ok()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+    }
+
+    /// the device API of the type of the device, e.g. `vfio-pci`
+    pub fn device_api(&self) -> Option<String> {
+        let path = self
+            .env
+            .parent_base()
+            .join(self.parent.as_ref()?)
+            .join("mdev_supported_types")
+            .join(self.mdev_type.as_ref()?)
+            .join("device_api");
+        read_before_deadline(&path, self.deadline)
+            .ok()
+            .map(|api| api.trim().to_string())
+    }
+
+    /// Whether the IOMMU group of an active vfio-pci device can be assigned to a guest through
+    /// VFIO: the device itself must be bound to a driver, and every other device in the group
+    /// must be bound to a VFIO driver or to none. `None` for other devices and for devices that
+    /// are not in an IOMMU group.
+    pub fn iommu_group_assignable(&self) -> Option<bool> {
+        if !self.active || self.device_api().as_deref() != Some("vfio-pci") {
+            return None;
+        }
+        let members = self.path().join("iommu_group/devices").read_dir().ok()?;
+        let this = fs::canonicalize(self.path()).ok();
+        for member in members.filter_map(|m| m.ok()) {
+            let path = member.path();
+            let driver = fs::read_link(path.join("driver"))
+                .ok()
+                .and_then(|d| d.file_name().map(|n| n.to_string_lossy().to_string()));
+            let viable = match (fs::canonicalize(&path).ok() == this, driver.as_deref()) {
+                (true, driver) => driver.is_some(),
+                (false, None) => true,
+                (false, Some(driver)) => {
+                    VFIO_VIABLE_DRIVERS.contains(&driver) || driver.starts_with("vfio")
+                }
+            };
+            if !viable {
+                debug!(
+                    "Device {:?} in the IOMMU group of {:?} is bound to {:?}",
+                    member.file_name(),
+                    self.uuid,
+                    driver
+                );
+                return Some(false);
+            }
+        }
+        Some(true)
+    }
+
+    /// the configuration file and sysfs directory of the device, where present
+    pub fn fmt_paths(&self) -> String {
+        let mut output = String::new();
+        if let Some(path) = self.persist_path().filter(|p| p.exists()) {
+            output.push_str(&format!("  Config: {}\n", path.display()));
+        }
+        if self.active {
+            output.push_str(&format!("  Sysfs: {}\n", self.path().display()));
+        }
+        output
+    }
+
+    /// assign a label to the device, replacing any previous value for the same key
+    pub fn set_label(&mut self, key: String, value: String) -> Result<()> {
+        validate_label_key(&key)?;
+        self.labels.insert(key, value);
+        Ok(())
+    }
+
+    pub fn remove_label(&mut self, key: &str) -> Result<()> {
+        self.labels.remove(key).map(|_| ()).ok_or_else(|| {
+            MdevError::NotFound(format!(
+                "Device {} has no label '{}'",
+                self.uuid.hyphenated(),
+                key
+            ))
+            .into()
+        })
+    }
+
+    /// whether the device has all of the given labels
+    pub fn matches_labels(&self, selector: &[(String, String)]) -> bool {
+        selector
+            .iter()
+            .all(|(key, value)| self.labels.get(key) == Some(value))
+    }
+
+    /// whether the device has a matching attribute for each of the given filters. The values of
+    /// the filters may contain the wildcards `*` and `?`.
+    pub fn matches_attrs(&self, filters: &[(String, String)]) -> bool {
+        filters.iter().all(|(name, pattern)| {
+            self.attrs
+                .iter()
+                .any(|(n, v)| n == name && glob_match(pattern, v))
+        })
+    }
+
+    /// Make sure that no other defined device has the same value for any label that is configured
+    /// to be unique. Definitions of the same UUID on other parents are not considered conflicts.
+    pub fn check_unique_labels(&self) -> Result<()> {
+        let policy = LabelPolicy::load(self.env.as_ref())?;
+        let unique = self
+            .labels
+            .iter()
+            .filter(|(key, _)| policy.unique.contains(key))
+            .collect::<Vec<_>>();
+        if unique.is_empty() {
+            return Ok(());
+        }
+        let devs = self.env.clone().get_defined_devices(None, None)?;
+        for other in devs.values().flatten().filter(|d| d.uuid != self.uuid) {
+            for (key, value) in unique.iter() {
+                if other.labels.get(*key) == Some(*value) {
+                    return Err(MdevError::AlreadyExists(format!(
+                        "Label {}={} is already assigned to device {} on parent {}",
+                        key,
+                        value,
+                        other.uuid.hyphenated(),
+                        other.parent()?
+                    ))
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn to_json(&self, include_uuid: bool) -> Result<serde_json::Value> {
         let autostart = match self.autostart {
             true => "auto",
@@ -292,6 +872,41 @@ impl MDev {
             .map(|(key, value)| serde_json::json!({ key: value }))
             .collect();
         partial.insert("attrs".to_string(), jsonattrs.into());
+        let mut callout_attrs = Vec::new();
+        for ((key, _), from_callout) in self.attrs.iter().zip(self.attrs_from_callout()) {
+            if from_callout && !callout_attrs.contains(key) {
+                callout_attrs.push(key.clone());
+            }
+        }
+        if !callout_attrs.is_empty() {
+            partial.insert("callout_attrs".to_string(), callout_attrs.into());
+        }
+        if !self.requires.is_empty() {
+            partial.insert("requires".to_string(), self.requires.to_json());
+        }
+        if let Some(check) = &self.health_check {
+            partial.insert("health_check".to_string(), check.to_json());
+        }
+        if let Some(timeout) = self.start_timeout {
+            partial.insert("start_timeout".to_string(), timeout.into());
+        }
+        if let Some(timeout) = self.stop_timeout {
+            partial.insert("stop_timeout".to_string(), timeout.into());
+        }
+        if self.priority != 0 {
+            partial.insert("priority".to_string(), self.priority.into());
+        }
+        if !self.depends_on.is_empty() {
+            let uuids: Vec<_> = self
+                .depends_on
+                .iter()
+                .map(|u| u.hyphenated().to_string())
+                .collect();
+            partial.insert("depends_on".to_string(), uuids.into());
+        }
+        if !self.labels.is_empty() {
+            partial.insert("labels".to_string(), serde_json::json!(self.labels));
+        }
 
         let full = serde_json::json!({ self.uuid.hyphenated().to_string(): partial });
 
@@ -301,12 +916,43 @@ impl MDev {
         }
     }
 
+    /// Remove the device. A device that is still in use, e.g. by a VM that is shutting down,
+    /// cannot be removed, so the removal is retried for the `stop_timeout` of the device. If the
+    /// device is still busy and `kill_users` is set, the processes using the device are asked to
+    /// terminate with SIGTERM and then killed with SIGKILL, retrying the removal after each.
     pub fn stop(&mut self) -> Result<()> {
         debug!("Removing mdev {:?}", self.uuid);
         let mut remove_path = self.path();
         remove_path.push("remove");
         debug!("remove path '{:?}'", remove_path);
-        match fs::write(remove_path, "1") {
+        let timeout = Duration::from_secs(self.stop_timeout.unwrap_or(0));
+        let env = self.env.as_ref();
+        let mut res = remove_until(env, &remove_path, Instant::now() + timeout);
+        if self.kill_users {
+            for signal in [libc::SIGTERM, libc::SIGKILL].iter() {
+                if !is_busy(&res) {
+                    break;
+                }
+                let users = self.users();
+                if users.is_empty() {
+                    debug!("No processes are using device {:?}", self.uuid);
+                    break;
+                }
+                for pid in users {
+                    warn!(
+                        "Sending signal {} to process {} which is using device {}",
+                        signal,
+                        pid,
+                        self.uuid.hyphenated()
+                    );
+                    if let Err(e) = self.env.kill(pid, *signal) {
+                        warn!("{:#}", e);
+                    }
+                }
+                res = remove_until(env, &remove_path, Instant::now() + KILL_GRACE);
+            }
+        }
+        match res {
             Ok(_) => {
                 self.active = false;
                 Ok(())
@@ -315,6 +961,50 @@ impl MDev {
         }
     }
 
+    /// The processes that have a device node of the device open, i.e. its VFIO group or its
+    /// VFIO device
+    pub fn users(&self) -> Vec<i32> {
+        let mut nodes = Vec::new();
+        if let Ok(group) = fs::read_link(self.path().join("iommu_group")) {
+            if let Some(name) = group.file_name() {
+                nodes.push(Path::new("/dev/vfio").join(name));
+            }
+        }
+        if let Ok(entries) = self.path().join("vfio-dev").read_dir() {
+            for entry in entries.flatten() {
+                nodes.push(Path::new("/dev/vfio/devices").join(entry.file_name()));
+            }
+        }
+        let mut users = Vec::new();
+        if nodes.is_empty() {
+            return users;
+        }
+        let procs = match self.env.proc_base().read_dir() {
+            Ok(procs) => procs,
+            Err(_) => return users,
+        };
+        for entry in procs.flatten() {
+            let pid = match entry.file_name().to_string_lossy().parse::<i32>() {
+                Ok(pid) => pid,
+                Err(_) => continue,
+            };
+            // processes may exit or deny access while they are inspected
+            let fds = match entry.path().join("fd").read_dir() {
+                Ok(fds) => fds,
+                Err(_) => continue,
+            };
+            if fds
+                .flatten()
+                .filter_map(|fd| fs::read_link(fd.path()).ok())
+                .any(|target| nodes.contains(&target))
+            {
+                users.push(pid);
+            }
+        }
+        users.sort_unstable();
+        users
+    }
+
     fn find_parent_dir(&self) -> Result<PathBuf> {
         let parent = self.parent()?;
         let path: PathBuf = self.env.parent_base().join(parent);
@@ -329,17 +1019,18 @@ impl MDev {
             let dir = subdir?;
             let parentname = dir.file_name();
             if parentname.to_string_lossy().to_lowercase() == parent.to_lowercase() {
-                return Err(anyhow!(
+                return Err(MdevError::NotFound(format!(
                     "Unable to find parent device '{}'. Did you mean '{}'?",
                     parent,
                     parentname.to_string_lossy()
-                ));
+                ))
+                .into());
             }
         }
-        Err(anyhow!("Unable to find parent device '{}'", parent))
+        Err(MdevError::NotFound(format!("Unable to find parent device '{}'", parent)).into())
     }
 
-    fn create(&mut self) -> Result<()> {
+    fn create(&mut self, strict_parent_check: bool) -> Result<()> {
         debug!("Creating mdev {:?}", self.uuid);
         let parent = self.parent()?;
         let mdev_type = self.mdev_type()?;
@@ -347,69 +1038,224 @@ impl MDev {
 
         if existing.load_from_sysfs().is_ok() && existing.active {
             if existing.parent != self.parent {
-                return Err(anyhow!("Device exists under different parent"));
+                return Err(MdevError::AlreadyExists(
+                    "Device exists under different parent".to_string(),
+                )
+                .into());
             }
             if existing.mdev_type != self.mdev_type {
-                return Err(anyhow!("Device exists with different type"));
+                return Err(MdevError::AlreadyExists(
+                    "Device exists with different type".to_string(),
+                )
+                .into());
             }
-            return Err(anyhow!("Device already exists"));
+            return Err(MdevError::AlreadyExists("Device already exists".to_string()).into());
         }
 
         let mut path = self.find_parent_dir()?;
         path.push("mdev_supported_types");
         debug!("Checking parent for mdev support: {:?}", path);
         if !path.is_dir() {
-            return Err(anyhow!(
+            return Err(MdevError::NotFound(format!(
                 "Parent {} is not currently registered for mdev support",
                 parent
-            ));
+            ))
+            .into());
         }
         path.push(mdev_type);
         debug!("Checking parent for mdev type {}: {:?}", mdev_type, path);
         if !path.is_dir() {
-            return Err(anyhow!(
+            return Err(MdevError::NotFound(format!(
                 "Parent {} does not support mdev type {}",
-                parent,
-                mdev_type
-            ));
+                parent, mdev_type
+            ))
+            .into());
+        }
+        if strict_parent_check {
+            self.check_parent_capacity(&path)?;
         }
         path.push("available_instances");
         debug!("Checking available instances: {:?}", path);
-        let avail: i32 = fs::read_to_string(&path)?.trim().parse()?;
+        let avail = read_available_instances(&path, parent, mdev_type, self.deadline)?;
 
         debug!("Available instances: {}", avail);
         if avail == 0 {
-            return Err(anyhow!(
+            return Err(MdevError::Other(format!(
                 "No available instances of {} on {}",
-                mdev_type,
-                parent
-            ));
+                mdev_type, parent
+            ))
+            .into());
         }
         path.pop();
         path.push("create");
         debug!("Creating mediated device: {:?} -> {:?}", self.uuid, path);
-        match fs::write(path, self.uuid.hyphenated().to_string()) {
+        let mut kmsg = KernelLog::follow(self.env.as_ref());
+        match write_before_deadline(&path, &self.uuid.hyphenated().to_string(), self.deadline) {
             Ok(_) => {
                 self.active = true;
                 Ok(())
             }
-            Err(e) => Err(e).with_context(|| {
-                format!(
+            Err(e) => {
+                // the errno alone rarely explains why a driver refused to create the device,
+                // so include whatever the parent driver logged
+                let driver = self.parent_driver().unwrap_or_default();
+                let uuid = self.uuid.hyphenated().to_string();
+                let messages = kmsg.messages(&[parent, &driver, &uuid]);
+                let mut msg = format!(
                     "Failed to create mdev {}, type {} on {}",
-                    self.uuid.hyphenated(),
-                    mdev_type,
-                    parent
-                )
-            }),
+                    uuid, mdev_type, parent
+                );
+                if !messages.is_empty() {
+                    msg.push_str("\nKernel messages:");
+                    for m in messages {
+                        msg.push_str("\n  ");
+                        msg.push_str(&m);
+                    }
+                }
+                Err(e).context(MdevError::SysfsIo(msg))
+            }
+        }
+    }
+
+    /// Check that one more device of the type fits within the maximum number of instances that
+    /// the parent reports in `max_instances` of the type directory `typepath`, counting the
+    /// active devices of the type. Drivers are inconsistent in how they reject devices beyond
+    /// their capacity, often with a bare EINVAL, so this reports it precisely. `max_instances` is
+    /// not part of the mdev sysfs ABI, only some drivers provide it, and this check passes for
+    /// parents that do not. The `available_instances` of the type are checked for every device
+    /// that is started regardless.
+    pub fn check_parent_capacity(&self, typepath: &Path) -> Result<()> {
+        let parent = self.parent()?;
+        let mdev_type = self.mdev_type()?;
+        let maxpath = typepath.join("max_instances");
+        let max = match read_before_deadline(&maxpath, self.deadline) {
+            Ok(contents) => contents.trim().parse::<usize>().with_context(|| {
+                format!("Invalid maximum instances {:?} in {:?}", contents, maxpath)
+            })?,
+            Err(e) if e.chain().any(|c| c.is::<DeadlineExceeded>()) => return Err(e),
+            Err(_) => {
+                debug!(
+                    "Parent {} does not report the maximum instances of {}",
+                    parent, mdev_type
+                );
+                return Ok(());
+            }
+        };
+        let active = self
+            .env
+            .clone()
+            .get_active_devices(None, Some(parent))?
+            .into_values()
+            .flatten()
+            .filter(|d| d.mdev_type.as_ref() == Some(mdev_type))
+            .count();
+        debug!(
+            "{} of {} instances of {} on {} are active",
+            active, max, mdev_type, parent
+        );
+        if active + 1 > max {
+            return Err(MdevError::Other(format!(
+                "Capacity of {} on {} exceeded: {} of {} instances are active",
+                mdev_type, parent, active, max
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// name of the kernel driver bound to the parent device
+    fn parent_driver(&self) -> Result<String> {
+        canonical_basename(self.find_parent_dir()?.join("driver"))
+    }
+
+    /// the number of devices of the device's type that can still be created on its parent
+    pub fn available_instances(&self) -> Result<i32> {
+        let parent = self.parent()?;
+        let mdev_type = self.mdev_type()?;
+        let path = self
+            .find_parent_dir()?
+            .join("mdev_supported_types")
+            .join(mdev_type)
+            .join("available_instances");
+        read_available_instances(&path, parent, mdev_type, self.deadline)
+    }
+
+    /// Check that the host resources the device requires are available and that the devices it
+    /// depends on are active
+    pub fn check_requirements(&self) -> Result<()> {
+        let mut problems = self.requires.unmet(self.env.as_ref());
+        for uuid in self.depends_on.iter() {
+            let mut dep = MDev::new(self.env.clone(), *uuid);
+            if dep.load_from_sysfs().is_err() || !dep.active {
+                problems.push(format!("device {} is not active", uuid.hyphenated()));
+            }
+        }
+        if problems.is_empty() {
+            return Ok(());
         }
+        let mut msg = format!(
+            "Requirements for device {} are not met:",
+            self.uuid.hyphenated()
+        );
+        for p in problems {
+            msg.push_str("\n  - ");
+            msg.push_str(&p);
+        }
+        Err(MdevError::Other(msg).into())
     }
 
-    pub fn start(&mut self) -> Result<()> {
-        self.create()?;
+    /// Create the device and set its attributes. With a `start_timeout`, creating the device and
+    /// setting its attributes is abandoned once it expires, as some drivers block the creation
+    /// for a long time, e.g. while their firmware is busy, and a device that was created by then,
+    /// or shortly after, is removed again. With `strict_parent_check`, the active instances of the
+    /// type are checked against the maximum reported by the parent before the device is created,
+    /// see [`MDev::check_parent_capacity`].
+    pub fn start(&mut self, strict_parent_check: bool) -> Result<()> {
+        let _lock = StartLock::acquire(self.env.as_ref(), &self.uuid)?;
+        self.check_requirements()?;
+
+        let deadline = self.deadline;
+        if let Some(timeout) = self.start_timeout {
+            let limit = Instant::now() + Duration::from_secs(timeout);
+            self.deadline = Some(deadline.map_or(limit, |d| d.min(limit)));
+        }
+        let res = self.create_and_configure(strict_parent_check);
+        self.deadline = deadline;
+        if let Err(e) = res {
+            if e.chain().any(|c| c.is::<DeadlineExceeded>()) {
+                self.remove_after_timeout(&e);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Remove the device after its start timed out. The driver may still be creating the device,
+    /// so wait a little for the pending write to complete, and remove the device if it was
+    /// created by then.
+    fn remove_after_timeout(&mut self, e: &anyhow::Error) {
+        let pending = e
+            .downcast_ref::<PendingWrite>()
+            .map(|p| p.wait(PENDING_WRITE_GRACE));
+        if self.path().exists() {
+            debug!("Removing mdev {:?} after the start timed out", self.uuid);
+            if let Err(e) = self.stop() {
+                warn!("{:#}", e);
+            }
+        } else if let Some(None) = pending {
+            warn!(
+                "The driver may still create device {} later, it then needs to be stopped",
+                self.uuid.hyphenated()
+            );
+        }
+    }
+
+    fn create_and_configure(&mut self, strict_parent_check: bool) -> Result<()> {
+        self.create(strict_parent_check)?;
 
         debug!("Setting attributes for mdev {:?}", self.uuid);
         for (k, v) in self.attrs.iter() {
-            if let Err(e) = write_attr(&self.path(), k, v) {
+            if let Err(e) = write_attr(&self.path(), k, v, self.deadline) {
                 self.stop()?;
                 return Err(e);
             }
@@ -418,25 +1264,150 @@ impl MDev {
         Ok(())
     }
 
+    /// Wait until a started device is usable, i.e. the kernel assigned it to an IOMMU group, for
+    /// at most `timeout`
+    pub fn wait_until_ready(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let group = self.path().join("iommu_group");
+        debug!("Waiting for {:?}", group);
+        while !group.exists() {
+            if Instant::now() >= deadline {
+                return Err(MdevError::Timeout(format!(
+                    "Device {} did not become ready within {} seconds",
+                    self.uuid.hyphenated(),
+                    timeout.as_secs()
+                ))
+                .into());
+            }
+            thread::sleep(READY_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    fn autostart_failure_path(&self) -> PathBuf {
+        self.env
+            .autostart_state_base()
+            .join(self.uuid.hyphenated().to_string())
+    }
+
+    /// Record why the device could not be started automatically
+    pub fn record_autostart_failure(&self, failure: StartFailure, e: &anyhow::Error) -> Result<()> {
+        let path = self.autostart_failure_path();
+        let json = serde_json::json!({
+            "parent": self.parent()?,
+            "mdev_type": self.mdev_type()?,
+            "failure": failure.to_string(),
+            "error": format!("{:#}", e),
+            "category": ErrorCategory::of(e),
+        });
+        debug!(
+            "Recording autostart failure of {:?} in {:?}",
+            self.uuid, path
+        );
+        fs::create_dir_all(self.env.autostart_state_base())?;
+        fs::write(&path, serde_json::to_string_pretty(&json)?)
+            .with_context(|| format!("Failed to write autostart failure {:?}", path))
+    }
+
+    /// Remove the record of an earlier failure to start the device automatically
+    pub fn clear_autostart_failure(&self) {
+        let path = self.autostart_failure_path();
+        match fs::remove_file(&path) {
+            Ok(_) => debug!("Removed autostart failure {:?}", path),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => warn!("Failed to remove autostart failure {:?}: {}", path, e),
+        }
+    }
+
     pub fn write_config(&self) -> Result<()> {
+        self.check_unique_labels()?;
         let jsonstring = serde_json::to_string_pretty(&self.to_json(false)?)?;
         let path = self.persist_path().unwrap();
         let parentdir = path.parent().unwrap();
         debug!("Ensuring parent directory {:?} exists", parentdir);
         fs::create_dir_all(parentdir)?;
         debug!("Writing config for {:?} to {:?}", self.uuid, path);
-        fs::write(path, jsonstring.as_bytes())
+        // replace the config atomically so that it's never seen half written
+        let tmppath = parentdir.join(format!(".{}.tmp", self.uuid.hyphenated()));
+        fs::write(&tmppath, jsonstring.as_bytes())
+            .and_then(|_| fs::rename(&tmppath, &path))
             .with_context(|| format!("Failed to write config for device {:?}", self.uuid))
     }
 
+    /// the contents of the configuration file of this device, or `None` if it is not defined
+    pub fn read_config(&self) -> Result<Option<Vec<u8>>> {
+        let path = match self.persist_path() {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        match fs::read(&path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {:?}", path)),
+        }
+    }
+
+    /// the SHA-256 hash of the configuration file of this device, or `None` if it is not defined
+    pub fn config_hash(&self) -> Result<Option<String>> {
+        Ok(self
+            .read_config()?
+            .map(|contents| format!("{:x}", Sha256::digest(contents))))
+    }
+
+    /// the [`MDev::config_hash`] of the definition that the staged changes of this device are
+    /// based on, as given to [`MDev::write_staged`]
+    pub fn staged_base(&self) -> Result<Option<String>> {
+        let path = self.staged_path().ok_or_else(|| {
+            MdevError::InvalidConfig(format!(
+                "Parent device required for staged device {}",
+                self.uuid
+            ))
+        })?;
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read staged definition {:?}", path))?;
+        let val: serde_json::Value = serde_json::from_str(&contents).map_err(|e| {
+            MdevError::InvalidConfig(format!("Invalid staged definition {:?}: {}", path, e))
+        })?;
+        Ok(val[STAGED_BASE_KEY].as_str().map(|s| s.to_string()))
+    }
+
+    /// write the definition of this device to the staging area instead of its configuration,
+    /// along with the [`MDev::config_hash`] of the definition that the changes are based on
+    pub fn write_staged(&self, base: Option<&str>) -> Result<()> {
+        self.check_unique_labels()?;
+        let mut json = self.to_json(false)?;
+        if let Some(obj) = json.as_object_mut() {
+            obj.insert(STAGED_BASE_KEY.to_string(), base.into());
+        }
+        let jsonstring = serde_json::to_string_pretty(&json)?;
+        let path = self.staged_path().ok_or_else(|| {
+            MdevError::InvalidConfig(format!(
+                "Parent device required to stage device {}",
+                self.uuid
+            ))
+        })?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        debug!("Staging config for {:?} in {:?}", self.uuid, path);
+        fs::write(&path, jsonstring.as_bytes())
+            .with_context(|| format!("Failed to stage config for device {:?}", self.uuid))
+    }
+
+    /// remove the staged changes of this device
+    pub fn discard_staged(&self) -> Result<()> {
+        if let Some(path) = self.staged_path() {
+            fs::remove_file(&path).with_context(|| format!("Failed to remove file {:?}", path))?;
+        }
+        Ok(())
+    }
+
     pub fn define(&self) -> Result<()> {
         self.write_config()
     }
 
     pub fn undefine(&mut self) -> Result<()> {
-        let p = self
-            .persist_path()
-            .ok_or_else(|| anyhow!("Failed to undefine {}", self.uuid.hyphenated().to_string()))?;
+        let p = self.persist_path().ok_or_else(|| {
+            MdevError::InvalidConfig(format!("Failed to undefine {}", self.uuid.hyphenated()))
+        })?;
 
         fs::remove_file(&p).with_context(|| format!("Failed to remove file {:?}", p))?;
         Ok(())
@@ -458,11 +1429,12 @@ impl MDev {
         match index {
             Some(i) => {
                 if i > self.attrs.len() {
-                    return Err(anyhow!(
+                    return Err(MdevError::NotFound(format!(
                         "Attribute index {} is invalid\n{}",
                         i,
                         self.attribute_hint()
-                    ));
+                    ))
+                    .into());
                 }
                 self.attrs.insert(i, (name, value));
             }
@@ -476,11 +1448,12 @@ impl MDev {
         match index {
             Some(i) => {
                 if i >= self.attrs.len() {
-                    return Err(anyhow!(
+                    return Err(MdevError::NotFound(format!(
                         "Attribute index {} is invalid\n{}",
                         i,
                         self.attribute_hint()
-                    ));
+                    ))
+                    .into());
                 }
                 self.attrs.remove(i);
             }
@@ -493,30 +1466,791 @@ impl MDev {
     }
 }
 
+/// Device properties derived from the sysfs path of a mediated device or a mediated device type
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DeviceSpec {
+    pub uuid: Option<Uuid>,
+    pub parent: Option<String>,
+    pub mdev_type: Option<String>,
+}
+
+impl DeviceSpec {
+    /// Derive the device properties from a path such as /sys/bus/mdev/devices/<uuid> or
+    /// /sys/class/mdev_bus/<parent>/mdev_supported_types/<type>. Absolute paths are interpreted
+    /// relative to the root of the environment.
+    pub fn from_sysfs_path(env: &dyn Environment, path: &Path) -> Result<DeviceSpec> {
+        let fullpath = env.root().join(path.strip_prefix("/").unwrap_or(path));
+        let canonpath = fullpath
+            .canonicalize()
+            .with_context(|| format!("Unable to resolve path {:?}", path))?;
+        let basename = canonical_basename(&canonpath)?;
+        let dirname = canonpath.parent().map(canonical_basename).transpose()?;
+
+        // a mediated device has a link to its type
+        if canonpath.join("mdev_type").exists() {
+            if let Ok(uuid) = Uuid::parse_str(&basename) {
+                return Ok(DeviceSpec {
+                    uuid: Some(uuid),
+                    parent: dirname,
+                    mdev_type: Some(canonical_basename(canonpath.join("mdev_type"))?),
+                });
+            }
+        }
+
+        if dirname.as_deref() == Some("mdev_supported_types") {
+            let parent = canonpath
+                .parent()
+                .and_then(Path::parent)
+                .map(canonical_basename)
+                .transpose()?;
+            return Ok(DeviceSpec {
+                uuid: None,
+                parent,
+                mdev_type: Some(basename),
+            });
+        }
+
+        Err(MdevError::NotFound(format!(
+            "Path {:?} is neither a mediated device nor a mediated device type",
+            path
+        ))
+        .into())
+    }
+}
+
+/// The state that a defined device should be in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DesiredState {
+    /// the device is started automatically and should always be running
+    Running,
+    /// the device is started manually, either state is acceptable
+    Any,
+}
+
+impl fmt::Display for DesiredState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DesiredState::Running => write!(f, "running"),
+            DesiredState::Any => write!(f, "any"),
+        }
+    }
+}
+
+/// Whether the actual state of a device matches its desired state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncStatus {
+    InSync,
+    OutOfSync(OutOfSyncReason),
+}
+
+/// The reason why a device is not in its desired state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutOfSyncReason {
+    /// the device is not running although its parent is present
+    NotRunning,
+    /// the device cannot run because its parent is not present
+    ParentMissing,
+    /// a device with the same UUID is running with a different parent or type
+    Mismatch { parent: String, mdev_type: String },
+    /// the device is running, but its health check failed
+    Unhealthy(String),
+}
+
+impl fmt::Display for OutOfSyncReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutOfSyncReason::NotRunning => write!(f, "not running"),
+            OutOfSyncReason::ParentMissing => write!(f, "parent device not present"),
+            OutOfSyncReason::Mismatch { parent, mdev_type } => write!(
+                f,
+                "running with type {} on parent {} instead",
+                mdev_type, parent
+            ),
+            OutOfSyncReason::Unhealthy(problem) => write!(f, "unhealthy: {}", problem),
+        }
+    }
+}
+
+/// A problem with a stored device definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigProblem {
+    /// the definition cannot be loaded
+    Malformed(String),
+    /// the parent device is not present
+    ParentMissing,
+    /// the parent device does not support the mdev type
+    UnsupportedType(String),
+    /// the attribute does not exist in sysfs for the active device
+    UnknownAttribute(String),
+    /// the definition in the first file is ignored in favor of the definition of the same device
+    /// in the second file
+    Duplicate(String, String),
+}
+
+impl ConfigProblem {
+    /// a short identifier of the kind of problem for machine readable output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConfigProblem::Malformed(_) => "malformed",
+            ConfigProblem::ParentMissing => "parent-missing",
+            ConfigProblem::UnsupportedType(_) => "unsupported-type",
+            ConfigProblem::UnknownAttribute(_) => "unknown-attribute",
+            ConfigProblem::Duplicate(..) => "duplicate",
+        }
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigProblem::Malformed(e) => write!(f, "invalid definition: {}", e),
+            ConfigProblem::ParentMissing => write!(f, "parent device not present"),
+            ConfigProblem::UnsupportedType(t) => {
+                write!(f, "mdev type {} not supported by parent", t)
+            }
+            ConfigProblem::UnknownAttribute(a) => {
+                write!(f, "attribute {} does not exist in sysfs", a)
+            }
+            ConfigProblem::Duplicate(ignored, used) => {
+                write!(
+                    f,
+                    "definition {:?} is ignored in favor of {:?}",
+                    ignored, used
+                )
+            }
+        }
+    }
+}
+
+/// The reason why a device could not be started automatically
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum StartFailure {
+    /// the start attempt was abandoned after the timeout expired
+    #[serde(rename = "timeout")]
+    Timeout,
+    /// a callout script rejected the start of the device
+    #[serde(rename = "callout-veto")]
+    CalloutVeto,
+    /// the device could not be created or configured in sysfs
+    #[serde(rename = "sysfs-error")]
+    Sysfs,
+}
+
+impl StartFailure {
+    pub fn classify(e: &anyhow::Error) -> StartFailure {
+        match ErrorCategory::of(e) {
+            ErrorCategory::Timeout => StartFailure::Timeout,
+            ErrorCategory::CalloutRejected => StartFailure::CalloutVeto,
+            _ => StartFailure::Sysfs,
+        }
+    }
+}
+
+impl fmt::Display for StartFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartFailure::Timeout => write!(f, "timeout"),
+            StartFailure::CalloutVeto => write!(f, "callout-veto"),
+            StartFailure::Sysfs => write!(f, "sysfs-error"),
+        }
+    }
+}
+
+/// Check that a label key only consists of letters, digits and the characters '.', '_', '-'
+/// and '/'
+pub fn validate_label_key(key: &str) -> Result<()> {
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-/".contains(c))
+    {
+        return Err(MdevError::InvalidConfig(format!(
+            "invalid label key '{}': only letters, digits, '.', '_', '-' and '/' are allowed",
+            key
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn depends_on_from_json(json: &serde_json::Value) -> Result<Vec<Uuid>> {
+    if json.is_null() {
+        return Ok(Vec::new());
+    }
+    let invalid = || {
+        MdevError::InvalidConfig("invalid json: 'depends_on' is not a list of UUIDs".to_string())
+    };
+    json.as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|u| {
+            u.as_str()
+                .and_then(|u| Uuid::parse_str(u).ok())
+                .ok_or_else(|| invalid().into())
+        })
+        .collect()
+}
+
+fn labels_from_json(json: &serde_json::Value) -> Result<BTreeMap<String, String>> {
+    let mut labels = BTreeMap::new();
+    if json.is_null() {
+        return Ok(labels);
+    }
+    let obj = json
+        .as_object()
+        .ok_or_else(|| MdevError::InvalidConfig("labels field is not an object".to_string()))?;
+    for (key, val) in obj {
+        validate_label_key(key)?;
+        let val = val.as_str().ok_or_else(|| {
+            MdevError::InvalidConfig(format!("label '{}' must be of type str", key))
+        })?;
+        labels.insert(key.clone(), val.to_string());
+    }
+    Ok(labels)
+}
+
+/// Configuration of the labels that may only be assigned to a single defined device
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelPolicy {
+    /// label keys whose values must be unique among all defined devices
+    #[serde(default)]
+    pub unique: Vec<String>,
+}
+
+impl LabelPolicy {
+    /// Load the label configuration of the given environment. No label needs to be unique if the
+    /// configuration file does not exist.
+    pub fn load(env: &dyn Environment) -> Result<LabelPolicy> {
+        let path = env.labels_path();
+        let contents = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(LabelPolicy::default()),
+            Err(e) => return Err(e).with_context(|| format!("Unable to read {:?}", path)),
+        };
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid label configuration in {:?}", path))
+    }
+}
+
+/// Site-wide policies that change the default behavior of commands
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// start devices right after they were defined if their parent has capacity for them
+    #[serde(default)]
+    pub auto_start_on_define: bool,
+    /// record the attribute changes of the `modify`, `edit`, `commit` and `attr set` commands,
+    /// see the `log` command
+    #[serde(default)]
+    pub change_log: bool,
+    /// how notification scripts are run
+    #[serde(default)]
+    pub notifiers: NotifierMode,
+    /// the window in milliseconds in which repeated identical notifications of a device are
+    /// coalesced, 0 to send all of them
+    #[serde(default)]
+    pub notifier_dedup_ms: u64,
+    /// record device changes in the audit subsystem
+    #[serde(default)]
+    pub audit: bool,
+    /// how many devices of a parent are started concurrently when it is registered, 0 or 1 to
+    /// start them one after another
+    #[serde(default)]
+    pub autostart_jobs: usize,
+    /// how the attributes that call-out scripts provide for an active device are merged into
+    /// those of its definition, for `define` without `--attr-merge` and for `list`
+    #[serde(default, deserialize_with = "deserialize_attr_merge")]
+    pub attr_merge: Option<AttrMerge>,
+}
+
+fn deserialize_attr_merge<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<AttrMerge>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    AttrMerge::from_str(&s, false)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// How notification scripts are run after an action
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierMode {
+    /// all scripts run concurrently and the command waits for them to complete
+    #[default]
+    Parallel,
+    /// one script after the other, in the order of their names
+    Serial,
+    /// all scripts run concurrently and the command completes without waiting for them
+    Detached,
+}
+
+impl Policy {
+    /// Load the policy of the given environment. The default behavior applies if the policy file
+    /// does not exist.
+    pub fn load(env: &dyn Environment) -> Result<Policy> {
+        let path = env.policy_path();
+        let contents = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Policy::default()),
+            Err(e) => return Err(e).with_context(|| format!("Unable to read {:?}", path)),
+        };
+        serde_json::from_str(&contents).with_context(|| format!("Invalid policy in {:?}", path))
+    }
+}
+
 fn canonical_basename<P: AsRef<Path>>(path: P) -> Result<String> {
     let path = fs::canonicalize(path)?;
     let fname = path.file_name();
     if fname.is_none() {
-        return Err(anyhow!("Invalid path"));
+        return Err(MdevError::NotFound("Invalid path".to_string()).into());
     }
     let fname = fname.unwrap().to_str();
     match fname {
         Some(x) => Ok(x.to_string()),
-        None => Err(anyhow!("Invalid file name")),
+        None => Err(MdevError::NotFound("Invalid file name".to_string()).into()),
     }
 }
 
-fn write_attr(basepath: &Path, attr: &str, val: &str) -> Result<()> {
+/// Read the number of available instances of a type from its `available_instances` file
+pub fn read_available_instances(
+    path: &Path,
+    parent: &str,
+    mdev_type: &str,
+    deadline: Option<Instant>,
+) -> Result<i32> {
+    let raw = read_before_deadline(path, deadline)
+        .with_context(|| format!("Unable to read available instances from {:?}", path))?;
+    raw.trim().parse().with_context(|| {
+        format!(
+            "Invalid number of available instances {:?} for mdev type {} on parent {}",
+            raw.trim(),
+            mdev_type,
+            parent
+        )
+    })
+}
+
+fn write_attr(basepath: &Path, attr: &str, val: &str, deadline: Option<Instant>) -> Result<()> {
     debug!("Writing attribute '{}' -> '{}'", attr, val);
     let path = basepath.join(attr);
     if !path.exists() {
-        return Err(anyhow!("Invalid attribute '{}'", attr));
+        return Err(MdevError::NotFound(format!("Invalid attribute '{}'", attr)).into());
+    }
+    write_before_deadline(&path, val, deadline).with_context(|| {
+        MdevError::SysfsIo(format!("Failed to write {} to attribute {}", val, attr))
+    })
+}
+
+/// Whether the removal of a device failed because it is in use
+fn is_busy(res: &std::io::Result<()>) -> bool {
+    matches!(res, Err(e) if e.raw_os_error() == Some(libc::EBUSY))
+}
+
+/// Write the `remove` file of a device, retrying until `until` while the device is busy
+fn remove_until(env: &dyn Environment, path: &Path, until: Instant) -> std::io::Result<()> {
+    loop {
+        match env.write_sysfs(path, "1") {
+            res if is_busy(&res) && Instant::now() < until => {
+                debug!("Device is busy, retrying removal");
+                thread::sleep(REMOVE_RETRY_INTERVAL);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Write to a sysfs file. Drivers may block such writes for a long time, so mdevctl stops waiting
+/// for the write if it does not complete before the deadline. The write itself cannot be
+/// cancelled: the thread performing it remains blocked in the driver and the write may still
+/// take effect later. The error then carries a [`PendingWrite`] to find out whether it did.
+fn write_before_deadline(path: &Path, contents: &str, deadline: Option<Instant>) -> Result<()> {
+    let deadline = match deadline {
+        Some(d) => d,
+        None => return fs::write(path, contents).map_err(anyhow::Error::from),
+    };
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    if timeout.is_zero() {
+        return Err(DeadlineExceeded(format!("Writing {:?}", path)).into());
     }
-    fs::write(path, val).with_context(|| format!("Failed to write {} to attribute {}", val, attr))
+    let (tx, rx) = mpsc::channel();
+    let (p, c) = (path.to_path_buf(), contents.to_string());
+    thread::spawn(move || {
+        let _ = tx.send(fs::write(p, c));
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res.map_err(anyhow::Error::from),
+        Err(_) => Err(
+            anyhow::Error::new(DeadlineExceeded(format!("Writing {:?}", path))).context(
+                PendingWrite {
+                    path: path.to_path_buf(),
+                    result: Mutex::new((rx, None)),
+                },
+            ),
+        ),
+    }
+}
+
+/// A sysfs write that did not complete before its deadline and is still pending in the driver,
+/// see [`write_before_deadline`]
+#[derive(Debug)]
+pub struct PendingWrite {
+    path: PathBuf,
+    /// the result of the write once it completes, and whether it succeeded once received
+    result: Mutex<(mpsc::Receiver<std::io::Result<()>>, Option<bool>)>,
+}
+
+impl PendingWrite {
+    /// Wait at most `timeout` for the write to complete. Returns whether it succeeded, or `None`
+    /// if it is still pending.
+    pub fn wait(&self, timeout: Duration) -> Option<bool> {
+        let mut result = self.result.lock().unwrap_or_else(|e| e.into_inner());
+        if result.1.is_none() {
+            result.1 = match result.0.recv_timeout(timeout) {
+                Ok(res) => Some(res.is_ok()),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                // the writing thread panicked
+                Err(mpsc::RecvTimeoutError::Disconnected) => Some(false),
+            };
+        }
+        result.1
+    }
+}
+
+impl fmt::Display for PendingWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The write of {:?} is still pending", self.path)
+    }
+}
+
+/// Read a sysfs file. Like writes, reads of files provided by drivers may block, so mdevctl stops
+/// waiting for the read if it does not complete before the deadline. The thread performing the
+/// read remains blocked until the driver completes it, its result is discarded.
+pub fn read_before_deadline(path: &Path, deadline: Option<Instant>) -> Result<String> {
+    let deadline = match deadline {
+        Some(d) => d,
+        None => return fs::read_to_string(path).map_err(anyhow::Error::from),
+    };
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    if timeout.is_zero() {
+        return Err(DeadlineExceeded(format!("Reading {:?}", path)).into());
+    }
+    let (tx, rx) = mpsc::channel();
+    let p = path.to_path_buf();
+    thread::spawn(move || {
+        let _ = tx.send(fs::read_to_string(p));
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res.map_err(anyhow::Error::from),
+        Err(_) => Err(DeadlineExceeded(format!("Reading {:?}", path)).into()),
+    }
+}
+
+/// An exclusive lock on starting a device, which keeps concurrent starts of the same UUID from
+/// racing on the `create` file of the parent. It is released when dropped.
+#[derive(Debug)]
+pub struct StartLock {
+    _file: fs::File,
+}
+
+impl StartLock {
+    pub fn acquire(env: &dyn Environment, uuid: &Uuid) -> Result<StartLock> {
+        let dir = env.start_lock_base();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        // the lock files are kept, removing them would allow two processes to lock different
+        // files for the same device
+        let path = dir.join(uuid.hyphenated().to_string());
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {:?}", path))?;
+        // SAFETY: the file descriptor is valid as long as the file is open
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::WouldBlock {
+                return Err(MdevError::Other(format!(
+                    "Device {} is already being started",
+                    uuid.hyphenated()
+                ))
+                .into());
+            }
+            return Err(e).with_context(|| format!("Failed to lock {:?}", path));
+        }
+        debug!("Locked {:?}", path);
+        Ok(StartLock { _file: file })
+    }
+}
+
+/// Error for an operation that was abandoned because it did not complete before the deadline of
+/// the device
+#[derive(Debug)]
+pub struct DeadlineExceeded(pub String);
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} did not complete in time", self.0)
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Error for a host whose kernel does not provide the mediated device framework
+#[derive(Debug)]
+pub struct MdevUnavailable;
+
+impl fmt::Display for MdevUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Mediated device framework not available (is the kernel built with CONFIG_VFIO_MDEV?)"
+        )
+    }
+}
+
+impl std::error::Error for MdevUnavailable {}
+
+/// Host resources that must be present before a device can be started
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Requirements {
+    /// kernel modules that must be loaded
+    pub modules: Vec<String>,
+    /// minimum number of free hugepages of the default size
+    pub min_free_hugepages: Option<u64>,
+    /// files that must exist
+    pub files: Vec<PathBuf>,
+}
+
+impl Requirements {
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty() && self.min_free_hugepages.is_none() && self.files.is_empty()
+    }
+
+    pub fn from_json(json: &serde_json::Value) -> Result<Requirements> {
+        let mut req = Requirements::default();
+        if json.is_null() {
+            return Ok(req);
+        }
+        let obj = json.as_object().ok_or_else(|| {
+            MdevError::InvalidConfig("requires field is not an object".to_string())
+        })?;
+
+        let string_array = |key: &str| -> Result<Vec<String>> {
+            match obj.get(key) {
+                None => Ok(Vec::new()),
+                Some(val) => val
+                    .as_array()
+                    .ok_or_else(|| {
+                        MdevError::InvalidConfig(format!(
+                            "requires field '{}' is not an array",
+                            key
+                        ))
+                    })?
+                    .iter()
+                    .map(|v| {
+                        v.as_str().map(|s| s.to_string()).ok_or_else(|| {
+                            MdevError::InvalidConfig(format!(
+                                "requires field '{}' must only contain strings",
+                                key
+                            ))
+                            .into()
+                        })
+                    })
+                    .collect(),
+            }
+        };
+
+        for key in obj.keys() {
+            if !["modules", "min_free_hugepages", "files"].contains(&key.as_str()) {
+                return Err(
+                    MdevError::InvalidConfig(format!("unknown requires field '{}'", key)).into(),
+                );
+            }
+        }
+
+        req.modules = string_array("modules")?;
+        req.files = string_array("files")?
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+        if let Some(val) = obj.get("min_free_hugepages") {
+            req.min_free_hugepages = Some(val.as_u64().ok_or_else(|| {
+                MdevError::InvalidConfig(
+                    "requires field 'min_free_hugepages' is not a non-negative integer".to_string(),
+                )
+            })?);
+        }
+        Ok(req)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut obj = serde_json::Map::new();
+        if !self.modules.is_empty() {
+            obj.insert("modules".to_string(), self.modules.clone().into());
+        }
+        if let Some(n) = self.min_free_hugepages {
+            obj.insert("min_free_hugepages".to_string(), n.into());
+        }
+        if !self.files.is_empty() {
+            let files: Vec<_> = self
+                .files
+                .iter()
+                .map(|f| f.to_string_lossy().to_string())
+                .collect();
+            obj.insert("files".to_string(), files.into());
+        }
+        obj.into()
+    }
+
+    fn to_text(&self) -> String {
+        let mut output = String::new();
+        if self.is_empty() {
+            return output;
+        }
+        output.push_str("  Requires:\n");
+        for m in &self.modules {
+            output.push_str(&format!("    module: {}\n", m));
+        }
+        if let Some(n) = self.min_free_hugepages {
+            output.push_str(&format!("    free hugepages: {}\n", n));
+        }
+        for f in &self.files {
+            output.push_str(&format!("    file: {}\n", f.to_string_lossy()));
+        }
+        output
+    }
+
+    /// Return a description of every requirement that is not currently satisfied
+    pub fn unmet(&self, env: &dyn Environment) -> Vec<String> {
+        let mut problems = Vec::new();
+        for m in &self.modules {
+            if !module_loaded(env, m) {
+                problems.push(format!("kernel module '{}' is not loaded", m));
+            }
+        }
+        if let Some(min) = self.min_free_hugepages {
+            match free_hugepages(env) {
+                Ok(free) if free < min => problems.push(format!(
+                    "{} free hugepages required, but only {} available",
+                    min, free
+                )),
+                Ok(_) => (),
+                Err(e) => problems.push(format!("unable to determine free hugepages: {}", e)),
+            }
+        }
+        for f in &self.files {
+            let path = env.root().join(f.strip_prefix("/").unwrap_or(f));
+            if !path.exists() {
+                problems.push(format!("required file {:?} does not exist", f));
+            }
+        }
+        problems
+    }
+}
+
+/// A sysfs attribute of an active device and the value it has while the device is functional
+///
+/// Call-out scripts are not asked about the health of a device, only the attribute is checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// path of the attribute, relative to the directory of the device
+    pub attribute: String,
+    pub expected: String,
+}
+
+impl HealthCheck {
+    pub fn from_json(json: &serde_json::Value) -> Result<Option<HealthCheck>> {
+        if json.is_null() {
+            return Ok(None);
+        }
+        let obj = json.as_object().ok_or_else(|| {
+            MdevError::InvalidConfig("health_check field is not an object".to_string())
+        })?;
+        for key in obj.keys() {
+            if !["attribute", "expected"].contains(&key.as_str()) {
+                return Err(MdevError::InvalidConfig(format!(
+                    "unknown health_check field '{}'",
+                    key
+                ))
+                .into());
+            }
+        }
+        let string = |key: &str| -> Result<String> {
+            obj.get(key)
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string())
+                .ok_or_else(|| {
+                    MdevError::InvalidConfig(format!(
+                        "health_check field '{}' is missing or not a string",
+                        key
+                    ))
+                    .into()
+                })
+        };
+
+        let attribute = string("attribute")?;
+        let path = Path::new(&attribute);
+        if attribute.is_empty()
+            || path.is_absolute()
+            || path.components().any(|c| c == Component::ParentDir)
+        {
+            return Err(MdevError::InvalidConfig(format!(
+                "health_check attribute '{}' is not a path within the device",
+                attribute
+            ))
+            .into());
+        }
+        Ok(Some(HealthCheck {
+            attribute,
+            expected: string("expected")?,
+        }))
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "attribute": self.attribute,
+            "expected": self.expected,
+        })
+    }
+}
+
+/// Whether the kernel module `name` is loaded. Builtin modules are listed in sysfs as well, so
+/// this covers both cases. The kernel treats `-` and `_` in module names alike, so they are
+/// compared with both normalized to `_`.
+pub fn module_loaded(env: &dyn Environment, name: &str) -> bool {
+    let normalize = |name: &str| name.replace('-', "_");
+    let name = normalize(name);
+    fs::read_dir(env.module_base())
+        .map(|entries| {
+            entries
+                .flatten()
+                .any(|e| normalize(&e.file_name().to_string_lossy()) == name)
+        })
+        .unwrap_or(false)
+}
+
+fn free_hugepages(env: &dyn Environment) -> Result<u64> {
+    let path = env.meminfo_path();
+    let meminfo =
+        fs::read_to_string(&path).with_context(|| format!("Unable to read {:?}", path))?;
+    for line in meminfo.lines() {
+        if let Some(val) = line.strip_prefix("HugePages_Free:") {
+            return val
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid HugePages_Free value in {:?}", path));
+        }
+    }
+    Err(MdevError::Other(format!("No HugePages_Free entry in {:?}", path)).into())
 }
 
 /// Representation of a mediated device type
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct MDevType {
     pub parent: String,
     pub typename: String,
@@ -524,18 +2258,13 @@ pub struct MDevType {
     pub device_api: String,
     pub name: String,
     pub description: String,
+    /// the PCI IDs of the parent device, if they were resolved
+    pub parent_pci_id: Option<PciId>,
 }
 
 impl MDevType {
     pub fn new() -> MDevType {
-        MDevType {
-            parent: String::new(),
-            typename: String::new(),
-            available_instances: 0,
-            device_api: String::new(),
-            name: String::new(),
-            description: String::new(),
-        }
+        MDevType::default()
     }
 
     pub fn to_json(&self) -> Result<serde_json::Value> {
@@ -555,7 +2284,118 @@ impl MDevType {
                 serde_json::Value::String(self.description.clone()),
             );
         }
+        if let Some(id) = &self.parent_pci_id {
+            jsonobj
+                .as_object_mut()
+                .unwrap()
+                .insert("parent_pci_id".to_string(), serde_json::to_value(id)?);
+        }
 
         Ok(serde_json::json!({ &self.typename: jsonobj }))
     }
 }
+
+/// how often the sysfs entry of a started device is checked while waiting until it is usable
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// how often the removal of a busy device is retried
+const REMOVE_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// how long the removal of a device is retried after a signal was sent to the processes using it
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// how long to wait for the creation of a device to complete after its start timed out, so that
+/// the device can be removed again
+const PENDING_WRITE_GRACE: Duration = Duration::from_secs(5);
+
+/// drivers other than the VFIO drivers that devices may be bound to without keeping the other
+/// devices of their IOMMU group from being assigned, like the kernel allows
+const VFIO_VIABLE_DRIVERS: [&str; 2] = ["pci-stub", "pcieport"];
+
+/// Expand the expressions in `template`: `{index}` is replaced by `index`, to which an offset can
+/// be added as in `{index+4}`, and which is formatted in hexadecimal with `{index:x}`, and
+/// `{uuid}` is replaced by `uuid`. Literal braces are written as `{{` and `}}`.
+pub fn expand_template(template: &str, index: u32, uuid: &Uuid) -> Result<String> {
+    let expand = |expr: &str| -> Result<String> {
+        let (expr, hex) = match expr.strip_suffix(":x") {
+            Some(expr) => (expr, true),
+            None => (expr, false),
+        };
+        if expr == "uuid" && !hex {
+            return Ok(uuid.hyphenated().to_string());
+        }
+        let offset = match expr.strip_prefix("index") {
+            Some("") => Some(0),
+            Some(offset) => offset
+                .strip_prefix('+')
+                .and_then(|n| n.parse::<u64>().ok()),
+            None => None,
+        }
+        .ok_or_else(|| {
+            MdevError::InvalidConfig(format!(
+                "Unknown expression '{{{}}}', expected {{index}}, {{index+N}}, {{index:x}} or {{uuid}}",
+                expr
+            ))
+        })?;
+        let n = u64::from(index) + offset;
+        Ok(match hex {
+            true => format!("{:x}", n),
+            false => n.to_string(),
+        })
+    };
+
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(pos) = rest.find(['{', '}']) {
+        expanded.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            expanded.push_str(&rest[..1]);
+            rest = &rest[2..];
+        } else if rest.starts_with('}') {
+            return Err(
+                MdevError::InvalidConfig(format!("Unmatched '}}' in '{}'", template)).into(),
+            );
+        } else {
+            let end = rest.find('}').ok_or_else(|| {
+                MdevError::InvalidConfig(format!("Unterminated expression in '{}'", template))
+            })?;
+            expanded.push_str(&expand(&rest[1..end])?);
+            rest = &rest[end + 1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Match `text` against a shell-style `pattern`, where `*` matches any sequence of characters and
+/// `?` matches any single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last '*' in the pattern and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // let the last '*' consume one more character
+                Some((star, pos)) => {
+                    backtrack = Some((star, pos + 1));
+                    p = star + 1;
+                    t = pos + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
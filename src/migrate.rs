@@ -0,0 +1,229 @@
+//! Migration of legacy file locations for the `migrate-config` command
+//!
+//! Call-out and notification scripts used to be installed below `/etc/mdevctl.d/scripts.d`, and
+//! are still looked up there. Device definitions may be named by a UUID spelled in another way
+//! than the canonical lower case hyphenated form, e.g. by older tools writing upper case names.
+//! The migration moves the scripts to `/usr/lib/mdevctl/scripts.d` and renames the definitions,
+//! so that only the current layout remains.
+
+use crate::doctor::host_path;
+use crate::environment::{order_by_precedence, Environment};
+use anyhow::{Context, Result};
+use log::debug;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// A single step of the migration
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    /// move a file to its current location
+    Move { from: PathBuf, to: PathBuf },
+    /// remove a file that is identical to the file at its current location
+    RemoveDuplicate { path: PathBuf, of: PathBuf },
+    /// remove a legacy directory that is empty after the migration
+    RemoveDir { path: PathBuf },
+    /// a file that cannot be migrated without losing data
+    Skip { path: PathBuf, reason: String },
+}
+
+impl Step {
+    /// A description of the step as it appears on the host, before or after it was applied
+    pub fn describe(&self, env: &dyn Environment, applied: bool) -> String {
+        let path = |p: &PathBuf| host_path(env, p);
+        let (done, todo) = match self {
+            Step::Move { from, to } => (
+                format!("Moved {} to {}", path(from), path(to)),
+                format!("Would move {} to {}", path(from), path(to)),
+            ),
+            Step::RemoveDuplicate { path: p, of } => (
+                format!("Removed {}, identical to {}", path(p), path(of)),
+                format!("Would remove {}, identical to {}", path(p), path(of)),
+            ),
+            Step::RemoveDir { path: p } => (
+                format!("Removed empty directory {}", path(p)),
+                format!("Would remove empty directory {}", path(p)),
+            ),
+            Step::Skip { path: p, reason } => {
+                let skipped = format!("Skipped {}: {}", path(p), reason);
+                (skipped.clone(), skipped)
+            }
+        };
+        match applied {
+            true => done,
+            false => todo,
+        }
+    }
+
+    pub fn apply(&self) -> Result<()> {
+        debug!("Applying migration step {:?}", self);
+        match self {
+            Step::Move { from, to } => move_file(from, to),
+            Step::RemoveDuplicate { path, .. } => {
+                fs::remove_file(path).with_context(|| format!("Failed to remove {:?}", path))
+            }
+            Step::RemoveDir { path } => {
+                fs::remove_dir(path).with_context(|| format!("Failed to remove {:?}", path))
+            }
+            Step::Skip { .. } => Ok(()),
+        }
+    }
+}
+
+/// Move a file, copying it if it is moved to another filesystem, e.g. from `/etc` to `/usr`
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    }
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            // fs::copy() also copies the permissions, which keeps scripts executable
+            fs::copy(from, to)
+                .and_then(|_| fs::remove_file(from))
+                .with_context(|| format!("Failed to move {:?} to {:?}", from, to))
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to move {:?} to {:?}", from, to)),
+    }
+}
+
+/// the entries of a directory in sort order, empty if the directory does not exist
+fn sorted_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = match dir.read_dir() {
+        Ok(rd) => rd
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .with_context(|| format!("Unable to read {:?}", dir))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("Unable to read {:?}", dir)),
+    };
+    paths.sort();
+    Ok(paths)
+}
+
+fn same_contents(a: &Path, b: &Path) -> bool {
+    matches!((fs::read(a), fs::read(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// The step that moves the script `from` to `to`, unless another file already exists there
+fn move_step(env: &dyn Environment, from: PathBuf, to: PathBuf) -> Step {
+    if !to.exists() {
+        Step::Move { from, to }
+    } else if same_contents(&from, &to) {
+        Step::RemoveDuplicate { path: from, of: to }
+    } else {
+        let reason = format!("{} already exists and differs", host_path(env, &to));
+        Step::Skip { path: from, reason }
+    }
+}
+
+/// Steps that move the scripts of the legacy directory `old` to `new`, followed by the removal
+/// of the legacy directory if nothing is left in it. Returns whether it is removed.
+fn plan_scripts(
+    env: &dyn Environment,
+    old: &Path,
+    new: &Path,
+    steps: &mut Vec<Step>,
+) -> Result<bool> {
+    if !old.is_dir() {
+        return Ok(false);
+    }
+    let mut empty = true;
+    for path in sorted_entries(old)? {
+        if !path.is_file() {
+            let reason = "not a regular file".to_string();
+            steps.push(Step::Skip { path, reason });
+            empty = false;
+            continue;
+        }
+        let step = move_step(env, path.clone(), new.join(path.file_name().unwrap()));
+        empty &= !matches!(step, Step::Skip { .. });
+        steps.push(step);
+    }
+    if empty {
+        steps.push(Step::RemoveDir {
+            path: old.to_path_buf(),
+        });
+    }
+    Ok(empty)
+}
+
+/// Steps that rename the definitions of a parent that are not named by the canonical UUID
+fn plan_definitions(env: &dyn Environment, parentdir: &Path, steps: &mut Vec<Step>) -> Result<()> {
+    let mut definitions: BTreeMap<Uuid, Vec<PathBuf>> = BTreeMap::new();
+    for path in sorted_entries(parentdir)? {
+        let basename = path.file_name().unwrap().to_string_lossy().to_string();
+        if basename.starts_with('.') || !path.is_file() {
+            continue;
+        }
+        if let Ok(uuid) = Uuid::parse_str(&basename) {
+            definitions.entry(uuid).or_default().push(path);
+        }
+    }
+    for (uuid, mut paths) in definitions {
+        order_by_precedence(&uuid, &mut paths);
+        let canonical = parentdir.join(uuid.hyphenated().to_string());
+        let mut paths = paths.into_iter();
+        // the definition taking precedence is renamed, unless it is named canonically already
+        let current = match paths.next() {
+            Some(first) if first != canonical => {
+                steps.push(Step::Move {
+                    from: first.clone(),
+                    to: canonical.clone(),
+                });
+                first
+            }
+            _ => canonical.clone(),
+        };
+        for path in paths {
+            steps.push(match same_contents(&path, &current) {
+                true => Step::RemoveDuplicate {
+                    path,
+                    of: canonical.clone(),
+                },
+                false => Step::Skip {
+                    reason: format!(
+                        "conflicts with the definition in {}",
+                        host_path(env, &canonical)
+                    ),
+                    path,
+                },
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The steps that migrate all legacy file locations to the current layout
+pub fn plan(env: &dyn Environment) -> Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    let callouts = plan_scripts(env, &env.old_callout_dir(), &env.callout_dir(), &mut steps)?;
+    let notifiers = plan_scripts(
+        env,
+        &env.old_notification_dir(),
+        &env.notification_dir(),
+        &mut steps,
+    )?;
+    let scripts_base = env.config_scripts_base();
+    if scripts_base.is_dir() {
+        let others = sorted_entries(&scripts_base)?
+            .into_iter()
+            .filter(|p| {
+                !(callouts && *p == env.old_callout_dir()
+                    || notifiers && *p == env.old_notification_dir())
+            })
+            .count();
+        if others == 0 {
+            steps.push(Step::RemoveDir { path: scripts_base });
+        }
+    }
+
+    for parentdir in sorted_entries(&env.config_base())? {
+        if parentdir.is_dir() && parentdir != env.config_scripts_base() {
+            plan_definitions(env, &parentdir, &mut steps)?;
+        }
+    }
+    Ok(steps)
+}
@@ -0,0 +1,220 @@
+//! Monitoring of device definitions and active devices for changes
+
+use crate::environment::Environment;
+use anyhow::{Context, Result};
+use inotify::{Inotify, WatchMask};
+use log::debug;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Waits for changes to the devices of an [`Environment`].
+///
+/// Changes to device definitions are detected with inotify. sysfs does not generate inotify
+/// events when the kernel adds or removes devices, so callers should always pass a timeout to
+/// [`Monitor::wait`] and re-read the active devices periodically.
+pub struct Monitor {
+    inotify: Inotify,
+    dirs: Vec<PathBuf>,
+}
+
+impl Monitor {
+    pub fn new(env: &dyn Environment) -> Result<Monitor> {
+        let inotify = Inotify::init().with_context(|| "Failed to initialize inotify")?;
+        let mut monitor = Monitor {
+            inotify,
+            dirs: vec![env.config_base(), env.mdev_base(), env.parent_base()],
+        };
+        monitor.add_watches();
+        Ok(monitor)
+    }
+
+    /// watch the top-level directories and all per-parent definition directories below them
+    fn add_watches(&mut self) {
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO;
+        let subdirs = self
+            .dirs
+            .iter()
+            .filter_map(|d| d.read_dir().ok())
+            .flat_map(|rd| rd.filter_map(|e| e.ok().map(|e| e.path())))
+            .filter(|p| p.is_dir())
+            .collect::<Vec<_>>();
+        for dir in self.dirs.iter().chain(subdirs.iter()) {
+            // adding an existing watch again just updates its mask
+            if let Err(e) = self.inotify.watches().add(dir, mask) {
+                debug!("Unable to watch {:?}: {}", dir, e);
+            }
+        }
+    }
+
+    /// Block until a change is detected or the timeout expires. Returns whether a change was
+    /// detected.
+    pub fn wait(&mut self, timeout: Duration) -> Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        // SAFETY: fds points to a single valid pollfd for the duration of the call
+        let rc = unsafe { libc::poll(&mut fds, 1, timeout) };
+        if rc < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err).with_context(|| "Failed to wait for changes");
+        }
+        if rc == 0 {
+            return Ok(false);
+        }
+
+        let mut buffer = [0; 4096];
+        loop {
+            match self.inotify.read_events(&mut buffer) {
+                Ok(events) => {
+                    for event in events {
+                        debug!("Detected change: {:?} {:?}", event.mask, event.name);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).with_context(|| "Failed to read inotify events"),
+            }
+        }
+        // new parent directories may have been created
+        self.add_watches();
+        Ok(true)
+    }
+}
+
+/// A change of the active mediated devices or of the parent devices supporting them, as reported
+/// by the `monitor` command
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum LifecycleEvent {
+    MdevAdded {
+        uuid: String,
+        parent: String,
+        mdev_type: String,
+    },
+    MdevRemoved {
+        uuid: String,
+        parent: String,
+        mdev_type: String,
+    },
+    ParentAdded {
+        parent: String,
+    },
+    ParentRemoved {
+        parent: String,
+    },
+}
+
+impl fmt::Display for LifecycleEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LifecycleEvent::MdevAdded {
+                uuid,
+                parent,
+                mdev_type,
+            } => write!(f, "mdev-added {} {} {}", uuid, parent, mdev_type),
+            LifecycleEvent::MdevRemoved {
+                uuid,
+                parent,
+                mdev_type,
+            } => write!(f, "mdev-removed {} {} {}", uuid, parent, mdev_type),
+            LifecycleEvent::ParentAdded { parent } => write!(f, "parent-added {}", parent),
+            LifecycleEvent::ParentRemoved { parent } => write!(f, "parent-removed {}", parent),
+        }
+    }
+}
+
+fn canonical_name(path: &Path) -> Option<String> {
+    fs::canonicalize(path)
+        .ok()?
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+}
+
+/// The active mediated devices with their parent and type, and the parent devices that support
+/// mediated devices, as found in sysfs at one point in time
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    mdevs: BTreeMap<String, (String, String)>,
+    parents: BTreeSet<String>,
+}
+
+impl Snapshot {
+    /// Read the devices from sysfs. Devices that are removed while they are read are skipped.
+    pub fn read(env: &dyn Environment) -> Snapshot {
+        let entries = |dir: PathBuf| {
+            dir.read_dir()
+                .map(|rd| rd.filter_map(|e| e.ok().map(|e| e.path())).collect())
+                .unwrap_or_else(|_| Vec::new())
+        };
+        let mut snapshot = Snapshot::default();
+        for path in entries(env.mdev_base()) {
+            let uuid = path.file_name().unwrap().to_string_lossy().to_string();
+            let parent = fs::canonicalize(&path)
+                .ok()
+                .and_then(|p| p.parent().and_then(canonical_name));
+            let mdev_type = canonical_name(&path.join("mdev_type"));
+            if let (Some(parent), Some(mdev_type)) = (parent, mdev_type) {
+                snapshot.mdevs.insert(uuid, (parent, mdev_type));
+            }
+        }
+        for path in entries(env.parent_base()) {
+            if path.join("mdev_supported_types").is_dir() {
+                let parent = path.file_name().unwrap().to_string_lossy().to_string();
+                snapshot.parents.insert(parent);
+            }
+        }
+        snapshot
+    }
+
+    /// The events that lead from this snapshot to the `next` one. Parent devices are added before
+    /// and removed after their mediated devices.
+    pub fn changes(&self, next: &Snapshot) -> Vec<LifecycleEvent> {
+        let mut events = Vec::new();
+        for parent in next.parents.difference(&self.parents) {
+            events.push(LifecycleEvent::ParentAdded {
+                parent: parent.clone(),
+            });
+        }
+        for (uuid, dev @ (parent, mdev_type)) in self.mdevs.iter() {
+            if next.mdevs.get(uuid) != Some(dev) {
+                events.push(LifecycleEvent::MdevRemoved {
+                    uuid: uuid.clone(),
+                    parent: parent.clone(),
+                    mdev_type: mdev_type.clone(),
+                });
+            }
+        }
+        for (uuid, dev @ (parent, mdev_type)) in next.mdevs.iter() {
+            if self.mdevs.get(uuid) != Some(dev) {
+                events.push(LifecycleEvent::MdevAdded {
+                    uuid: uuid.clone(),
+                    parent: parent.clone(),
+                    mdev_type: mdev_type.clone(),
+                });
+            }
+        }
+        for parent in self.parents.difference(&next.parents) {
+            events.push(LifecycleEvent::ParentRemoved {
+                parent: parent.clone(),
+            });
+        }
+        events
+    }
+}
@@ -0,0 +1,151 @@
+//! Execution of scripts in a mount namespace of their own
+//!
+//! If enabled with `mount_namespace` in the callout limits, scripts run in a new mount namespace
+//! whose root directory is an empty tmpfs. Only the paths scripts need are bind mounted into it:
+//! the system directories with programs, libraries and their configuration, `/dev`, `/proc`,
+//! sysfs, the mdevctl configuration and the scripts themselves. Everything but sysfs, `/dev` and
+//! the additionally configured paths is read-only, so that scripts cannot touch unrelated state
+//! of the host.
+
+use crate::environment::Environment;
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+
+/// directories with the programs and libraries scripts use, and their configuration
+const SYSTEM_PATHS: [&str; 6] = ["/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc"];
+
+/// A path of the host that is bind mounted at the same path in the namespace
+struct Mount {
+    source: CString,
+    target: CString,
+    /// the directories to create before the mount, from the outermost to the target itself
+    dirs: Vec<CString>,
+    read_only: bool,
+}
+
+fn cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).with_context(|| format!("Invalid path {:?}", path))
+}
+
+impl Mount {
+    fn new(root: &Path, path: &Path, read_only: bool) -> Result<Mount> {
+        let target = root.join(path.strip_prefix("/").unwrap_or(path));
+        let mut dirs = target
+            .ancestors()
+            .take_while(|a| a.starts_with(root) && *a != root)
+            .map(cstring)
+            .collect::<Result<Vec<_>>>()?;
+        dirs.reverse();
+        Ok(Mount {
+            source: cstring(path)?,
+            target: cstring(&target)?,
+            dirs,
+            read_only,
+        })
+    }
+}
+
+/// The paths that are available in the namespace and whether they are read-only. Paths that do
+/// not exist on the host, e.g. `/lib64` on some architectures, are left out.
+fn mount_paths(env: &dyn Environment, extra: &[PathBuf]) -> Vec<(PathBuf, bool)> {
+    let mut paths: Vec<(PathBuf, bool)> = SYSTEM_PATHS
+        .iter()
+        .map(|p| (PathBuf::from(p), true))
+        .collect();
+    paths.push(("/dev".into(), false));
+    paths.push(("/proc".into(), false));
+    paths.push((env.root().join("sys"), false));
+    paths.push((env.config_base(), true));
+    for dir in env
+        .callout_dirs()
+        .into_iter()
+        .chain(env.notification_dirs())
+    {
+        paths.push((dir, true));
+    }
+    paths.extend(extra.iter().map(|p| (p.clone(), false)));
+    paths.retain(|(p, _)| p.exists());
+    // outer paths are mounted first, so that the mounts of inner paths are not hidden by them
+    paths.sort_by(|(a, _), (b, _)| (a.components().count(), a).cmp(&(b.components().count(), b)));
+    paths.dedup_by(|a, b| a.0 == b.0);
+    paths
+}
+
+/// Set up `cmd` to run in a new mount namespace with only the required paths of `env` and the
+/// `extra` paths available
+pub fn isolate(env: &dyn Environment, cmd: &mut Command, extra: &[PathBuf]) -> Result<()> {
+    let root = env.runtime_base().join("namespace");
+    fs::create_dir_all(&root).with_context(|| format!("Failed to create {:?}", root))?;
+    let mounts = mount_paths(env, extra)
+        .iter()
+        .map(|(p, ro)| Mount::new(&root, p, *ro))
+        .collect::<Result<Vec<_>>>()?;
+    let root = cstring(&root)?;
+    let slash = cstring(Path::new("/"))?;
+    let tmp = cstring(Path::new("/tmp"))?;
+    let tmpfs = CString::new("tmpfs")?;
+
+    // SAFETY: the closure runs in the forked child and only calls unshare(), mount(), mkdir(),
+    // chmod(), chroot() and chdir(), which are all async-signal-safe. The paths were converted
+    // before the fork, so nothing is allocated.
+    unsafe {
+        cmd.pre_exec(move || {
+            let check = |res: libc::c_int| match res {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error()),
+            };
+            let none = ptr::null();
+            check(libc::unshare(libc::CLONE_NEWNS))?;
+            // keep the mounts below from propagating to the host
+            check(libc::mount(
+                none,
+                slash.as_ptr(),
+                none,
+                libc::MS_REC | libc::MS_PRIVATE,
+                ptr::null(),
+            ))?;
+            check(libc::mount(
+                tmpfs.as_ptr(),
+                root.as_ptr(),
+                tmpfs.as_ptr(),
+                libc::MS_NOSUID,
+                ptr::null(),
+            ))?;
+            for mount in mounts.iter() {
+                for dir in mount.dirs.iter() {
+                    // directories within the mounts of outer paths already exist
+                    libc::mkdir(dir.as_ptr(), 0o755);
+                }
+                check(libc::mount(
+                    mount.source.as_ptr(),
+                    mount.target.as_ptr(),
+                    none,
+                    libc::MS_BIND | libc::MS_REC,
+                    ptr::null(),
+                ))?;
+                if mount.read_only {
+                    check(libc::mount(
+                        none,
+                        mount.target.as_ptr(),
+                        none,
+                        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+                        ptr::null(),
+                    ))?;
+                }
+            }
+            check(libc::chroot(root.as_ptr()))?;
+            check(libc::chdir(slash.as_ptr()))?;
+            // an empty scratch directory within the tmpfs
+            libc::mkdir(tmp.as_ptr(), 0o1777);
+            libc::chmod(tmp.as_ptr(), 0o1777);
+            Ok(())
+        });
+    }
+    Ok(())
+}
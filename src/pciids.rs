@@ -0,0 +1,112 @@
+//! PCI vendor and device IDs of parent devices, resolved to names with the `pci.ids` database
+//!
+//! The database is provided by the hwdata package on most distributions. The entries of a vendor
+//! are a line with the vendor ID and name, e.g. `10de  NVIDIA Corporation`, followed by lines
+//! indented with a tab with the IDs and names of its devices, e.g. `1b38  GP102GL [Tesla P40]`.
+
+use crate::environment::Environment;
+use log::debug;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+
+/// The PCI IDs of a parent device and their names, if known
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PciId {
+    #[serde(serialize_with = "serialize_id")]
+    pub vendor: u16,
+    #[serde(serialize_with = "serialize_id")]
+    pub device: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vendor_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+}
+
+fn serialize_id<S: serde::Serializer>(id: &u16, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&format!("0x{:04x}", id))
+}
+
+impl fmt::Display for PciId {
+    /// like `lspci -nn`, e.g. `NVIDIA Corporation GP102GL [Tesla P40] [10de:1b38]`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = [&self.vendor_name, &self.device_name]
+            .iter()
+            .filter_map(|n| n.as_deref())
+            .collect::<Vec<_>>();
+        if !names.is_empty() {
+            write!(f, "{} ", names.join(" "))?;
+        }
+        write!(f, "[{:04x}:{:04x}]", self.vendor, self.device)
+    }
+}
+
+impl PciId {
+    /// Read the IDs of a parent device from sysfs. Parent devices that are not PCI devices, e.g.
+    /// channel subsystem or AP devices, have none.
+    pub fn read(env: &dyn Environment, parent: &str) -> Option<PciId> {
+        let dir = env.parent_base().join(parent);
+        let read_id = |name: &str| {
+            let contents = fs::read_to_string(dir.join(name)).ok()?;
+            let id = contents.trim();
+            u16::from_str_radix(id.strip_prefix("0x").unwrap_or(id), 16).ok()
+        };
+        Some(PciId {
+            vendor: read_id("vendor")?,
+            device: read_id("device")?,
+            vendor_name: None,
+            device_name: None,
+        })
+    }
+}
+
+/// The contents of the `pci.ids` database
+#[derive(Debug)]
+pub struct PciIdDatabase {
+    contents: String,
+}
+
+impl PciIdDatabase {
+    /// Load the first database found, if any
+    pub fn load(env: &dyn Environment) -> Option<PciIdDatabase> {
+        for path in env.pci_ids_paths() {
+            match fs::read(&path) {
+                Ok(data) => {
+                    debug!("Using PCI ID database {:?}", path);
+                    return Some(PciIdDatabase {
+                        // the database is mostly ASCII, but not guaranteed to be UTF-8
+                        contents: String::from_utf8_lossy(&data).into_owned(),
+                    });
+                }
+                Err(e) => debug!("Unable to read PCI ID database {:?}: {}", path, e),
+            }
+        }
+        None
+    }
+
+    /// Fill in the names of the vendor and device of `id`
+    pub fn resolve(&self, id: &mut PciId) {
+        let vendor = format!("{:04x}  ", id.vendor);
+        let device = format!("\t{:04x}  ", id.device);
+        let mut in_vendor = false;
+        for line in self.contents.lines() {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            if !line.starts_with('\t') {
+                if in_vendor {
+                    break;
+                }
+                if let Some(name) = line.strip_prefix(&vendor) {
+                    in_vendor = true;
+                    id.vendor_name = Some(name.trim().to_string());
+                }
+            } else if in_vendor {
+                if let Some(name) = line.strip_prefix(&device) {
+                    id.device_name = Some(name.trim().to_string());
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! In-process hooks for device lifecycle events
+//!
+//! Programs built on the mdevctl library can add custom logic to every action, e.g. to track the
+//! resources assigned to devices, by implementing [`Plugin`] and registering it with [`register`]
+//! before acting on devices. Plugins receive the same pre and post events as callout scripts, but
+//! without having to patch the individual command functions.
+
+use crate::callouts::{Action, Event, State};
+use crate::mdev::MDev;
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::sync::{Arc, Mutex};
+
+/// A lifecycle event of a device
+pub struct LifecycleEvent<'a> {
+    /// either [`Event::Pre`] or [`Event::Post`]
+    pub event: Event,
+    pub action: Action,
+    /// the outcome of the action for post events, [`State::None`] for pre events
+    pub state: State,
+    pub dev: &'a MDev,
+}
+
+/// A hook that is invoked for the lifecycle events of all devices
+pub trait Plugin: Send + Sync {
+    /// a name identifying the plugin in log messages
+    fn name(&self) -> &str;
+
+    /// Handle an event. An error for a pre event aborts the action, unless it is forced, while
+    /// errors for post events are only logged.
+    fn handle(&self, event: &LifecycleEvent) -> Result<()>;
+}
+
+static PLUGINS: Mutex<Vec<Arc<dyn Plugin>>> = Mutex::new(Vec::new());
+
+/// Register a plugin for all further events. Plugins are invoked in the order of their
+/// registration.
+pub fn register(plugin: Arc<dyn Plugin>) {
+    debug!("Registering plugin {}", plugin.name());
+    PLUGINS.lock().unwrap().push(plugin);
+}
+
+/// Pass an event to all registered plugins, stopping at the first plugin that fails
+pub fn dispatch(event: Event, action: Action, state: State, dev: &MDev) -> Result<()> {
+    // plugins may register further plugins, so don't hold the lock while they are invoked
+    let plugins = PLUGINS.lock().unwrap().clone();
+    let event = LifecycleEvent {
+        event,
+        action,
+        state,
+        dev,
+    };
+    for plugin in plugins {
+        debug!(
+            "{}-{}: invoking plugin {}",
+            event.event,
+            event.action,
+            plugin.name()
+        );
+        let res = plugin.handle(&event).with_context(|| {
+            format!(
+                "Plugin {} failed for {}-{}",
+                plugin.name(),
+                event.event,
+                event.action
+            )
+        });
+        if let Err(e) = res {
+            if event.event == Event::Pre {
+                return Err(e);
+            }
+            warn!("{:#}", e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! Profiles of starting a device, for `start --capture-profile`
+//!
+//! Starting a device can fail in ways that depend on the host, e.g. when a vGPU driver rejects
+//! the creation with `EINVAL`. A profile records in a single JSON file what is needed to debug
+//! such a failure remotely: the command line and the device it started, the kernel, the result
+//! and every message logged while starting it with its timing, including the debug messages on
+//! the call-out scripts that were invoked and their decisions, the sysfs attributes that were
+//! read and written and the kernel messages of a failed creation.
+
+use crate::callouts::ActionResult;
+use crate::environment::Environment;
+use crate::error::ErrorCategory;
+use crate::logger::{self, Capture, Record};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+#[derive(Debug, Serialize)]
+pub struct StartProfile {
+    pub mdevctl: String,
+    /// the release of the running kernel, if known
+    pub kernel: Option<String>,
+    /// when the profile was captured, in seconds since the epoch
+    pub time: u64,
+    pub command: Vec<String>,
+    /// the configuration of the device that was started, if it could be determined
+    pub device: Option<serde_json::Value>,
+    pub result: ActionResult,
+    /// the error with all its causes, if the device could not be started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<ErrorCategory>,
+    /// the duration of the command in seconds
+    pub duration: f64,
+    pub log: Vec<Record>,
+}
+
+/// The capture of a profile, from [`ProfileCapture::begin`] until [`ProfileCapture::finish`]
+pub struct ProfileCapture {
+    begin: Instant,
+    time: SystemTime,
+    log: Capture,
+}
+
+impl ProfileCapture {
+    pub fn begin() -> ProfileCapture {
+        ProfileCapture {
+            begin: Instant::now(),
+            time: SystemTime::now(),
+            log: logger::capture(),
+        }
+    }
+
+    /// Stop capturing and return the profile of starting `device` with the result `res`
+    pub fn finish<T>(
+        self,
+        env: &dyn Environment,
+        device: Option<serde_json::Value>,
+        res: &Result<T>,
+    ) -> StartProfile {
+        let duration = self.begin.elapsed().as_secs_f64();
+        StartProfile {
+            mdevctl: env!("CARGO_PKG_VERSION").to_string(),
+            kernel: fs::read_to_string(env.osrelease_path())
+                .ok()
+                .map(|s| s.trim().to_string()),
+            time: self
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            command: std::env::args().collect(),
+            device,
+            result: ActionResult::of(res),
+            error: res.as_ref().err().map(|e| format!("{:#}", e)),
+            category: res.as_ref().err().map(ErrorCategory::of),
+            duration,
+            log: self.log.finish(),
+        }
+    }
+}
+
+impl StartProfile {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut contents =
+            serde_json::to_string_pretty(self).with_context(|| "Unable to serialize json")?;
+        contents.push('\n');
+        fs::write(path, contents).with_context(|| format!("Unable to write profile {:?}", path))
+    }
+}
@@ -0,0 +1,158 @@
+//! Archives of the state of mdevctl for the `state export` and `state import` commands
+//!
+//! A state archive is a gzip compressed tar archive with the following files:
+//!
+//! - `version.json`: the format of the archive and the version of mdevctl that created it
+//! - `definitions.json`: all defined devices in the format of `mdevctl export`
+//! - `active.json`: the devices that were running when the archive was created
+//! - `config/`: the configuration files of mdevctl, e.g. `labels.json`
+//! - `staged/`: the staged changes of devices, see the `stage` command
+
+use anyhow::{anyhow, ensure, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::debug;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// the format of the archives written by this version of mdevctl
+pub const FORMAT_VERSION: u64 = 1;
+
+/// Writes a state archive
+pub struct StateWriter<W: Write> {
+    archive: tar::Builder<GzEncoder<W>>,
+    mtime: u64,
+}
+
+impl<W: Write> StateWriter<W> {
+    pub fn new(output: W) -> StateWriter<W> {
+        StateWriter {
+            archive: tar::Builder::new(GzEncoder::new(output, Compression::default())),
+            mtime: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// add a file with the given contents to the archive
+    pub fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        debug!("Adding {} to state archive", name);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(self.mtime);
+        header.set_cksum();
+        self.archive
+            .append_data(&mut header, name, data)
+            .with_context(|| format!("Failed to add {} to state archive", name))
+    }
+
+    /// add all regular files below `dir`, keeping their relative paths below `prefix`
+    pub fn add_dir(&mut self, prefix: &str, dir: &Path) -> Result<()> {
+        let mut files = Vec::new();
+        collect_files(dir, &mut files)?;
+        files.sort();
+        for file in files {
+            let relpath = file.strip_prefix(dir).unwrap_or(&file);
+            let data = fs::read(&file).with_context(|| format!("Unable to read {:?}", file))?;
+            self.add(&format!("{}/{}", prefix, relpath.display()), &data)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.archive
+            .into_inner()
+            .and_then(|gz| gz.finish())
+            .map(|_| ())
+            .with_context(|| "Failed to write state archive")
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Unable to read {:?}", dir)),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The files of a state archive, by their path in the archive
+#[derive(Debug)]
+pub struct StateArchive {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl StateArchive {
+    /// Read a state archive and check that its format is supported
+    pub fn read<R: Read>(input: R) -> Result<StateArchive> {
+        let mut files = BTreeMap::new();
+        let mut archive = tar::Archive::new(GzDecoder::new(input));
+        for entry in archive.entries().with_context(|| "Invalid state archive")? {
+            let mut entry = entry.with_context(|| "Invalid state archive")?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?.to_path_buf();
+            // the files are restored below their directories, so they must not escape them
+            ensure!(
+                path.components().all(|c| matches!(c, Component::Normal(_))),
+                "Invalid path {:?} in state archive",
+                path
+            );
+            let mut data = Vec::new();
+            entry
+                .read_to_end(&mut data)
+                .with_context(|| format!("Unable to read {:?} from state archive", path))?;
+            files.insert(path, data);
+        }
+
+        let archive = StateArchive { files };
+        let version = archive.json("version.json")?;
+        let format = version["format"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("invalid json: 'format' of the state archive is missing"))?;
+        ensure!(
+            format <= FORMAT_VERSION,
+            "State archive format {} created by mdevctl {} is not supported",
+            format,
+            version["mdevctl"].as_str().unwrap_or("(unknown)")
+        );
+        Ok(archive)
+    }
+
+    /// the parsed contents of a JSON file in the archive
+    pub fn json(&self, name: &str) -> Result<serde_json::Value> {
+        let data = self
+            .files
+            .get(Path::new(name))
+            .ok_or_else(|| anyhow!("State archive does not contain {}", name))?;
+        serde_json::from_slice(data).with_context(|| format!("Invalid JSON document {}", name))
+    }
+
+    /// the files below the directory `prefix` in the archive, by their path relative to it
+    pub fn files_below<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a Path, &'a [u8])> {
+        self.files.iter().filter_map(move |(path, data)| {
+            path.strip_prefix(prefix)
+                .ok()
+                .map(|relpath| (relpath, data.as_slice()))
+        })
+    }
+}
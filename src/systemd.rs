@@ -0,0 +1,140 @@
+//! Generation of systemd units for defined devices, for the `generate-systemd` command
+//!
+//! The udev rules start all devices of a parent at once when the parent is registered, with no
+//! way to retry a device or to order it relative to other services. A unit per device that is
+//! set to start automatically lets systemd start it once the device unit of its parent is active,
+//! and lets administrators adjust restarts and ordering with drop-ins as for any other service.
+//! The udev rules still start the devices as well, so the units tolerate devices that are
+//! already active.
+
+use crate::environment::{Environment, MDEVCTL};
+use crate::mdev::MDev;
+use anyhow::Result;
+use log::warn;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Component, Path};
+use std::rc::Rc;
+
+/// the first line of generated units, which tells them apart from units written by hand
+pub const HEADER: &str = "# Generated by mdevctl generate-systemd\n";
+
+/// A generated unit file
+#[derive(Debug, PartialEq, Eq)]
+pub struct Unit {
+    /// the file name of the unit, e.g. `mdev@976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9.service`
+    pub name: String,
+    pub contents: String,
+}
+
+/// Escape `path` into a unit name like `systemd-escape --path` does, e.g.
+/// `/sys/devices/pci0000:00` becomes `sys-devices-pci0000:00`
+pub fn escape_path(path: &Path) -> String {
+    let components: Vec<_> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    let mut escaped = String::new();
+    for (i, c) in components.iter().enumerate() {
+        if i > 0 {
+            escaped.push('-');
+        }
+        for (j, b) in c.bytes().enumerate() {
+            match b {
+                b'.' if i == 0 && j == 0 => escaped.push_str("\\x2e"),
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => {
+                    escaped.push(b as char)
+                }
+                _ => {
+                    let _ = write!(escaped, "\\x{:02x}", b);
+                }
+            }
+        }
+    }
+    if escaped.is_empty() {
+        escaped.push('-');
+    }
+    escaped
+}
+
+/// The systemd device unit of the parent of `dev`, which is named by the path of the parent in
+/// sysfs. The parent must be present to resolve its path.
+fn parent_unit(dev: &MDev) -> Option<String> {
+    let parent = dev.parent.as_ref()?;
+    let path = fs::canonicalize(dev.env.parent_base().join(parent)).ok()?;
+    let path = path
+        .strip_prefix(fs::canonicalize(dev.env.root()).ok()?)
+        .ok()?;
+    Some(format!(
+        "{}.device",
+        escape_path(Path::new("/").join(path).as_path())
+    ))
+}
+
+/// The service unit that starts `dev` and stops it again
+pub fn device_unit(dev: &MDev) -> Result<Unit> {
+    let uuid = dev.uuid.hyphenated().to_string();
+    let parent = dev.parent()?;
+    let mut contents = format!(
+        "{}[Unit]\nDescription=Mediated device {} on {}\n",
+        HEADER, uuid, parent
+    );
+    match parent_unit(dev) {
+        Some(unit) => {
+            let _ = writeln!(contents, "BindsTo={}\nAfter={}", unit, unit);
+        }
+        None => warn!(
+            "Parent {} of device {} is not present, the unit is not ordered after it",
+            parent, uuid
+        ),
+    }
+    let _ = write!(
+        contents,
+        "\n\
+         [Service]\n\
+         Type=oneshot\n\
+         RemainAfterExit=yes\n\
+         ExecStart={mdevctl} start --uuid {uuid} --parent {parent} --skip-active\n\
+         ExecStop={mdevctl} stop --uuid {uuid}\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        mdevctl = MDEVCTL,
+        uuid = uuid,
+        parent = parent
+    );
+    Ok(Unit {
+        name: format!("mdev@{}.service", uuid),
+        contents,
+    })
+}
+
+/// The units of the devices that are set to start automatically, optionally only those of
+/// `parent`, ordered by parent and UUID. A device can only have one unit, so a UUID defined on
+/// several parents only gets a unit for the first of them.
+pub fn units(env: Rc<dyn Environment>, parent: Option<&String>) -> Result<Vec<Unit>> {
+    let mut devs = env.get_defined_devices(None, parent)?;
+    let mut generated = BTreeMap::new();
+    let mut units = Vec::new();
+    for (parent, children) in devs.iter_mut() {
+        children.sort_by_key(|d| d.uuid);
+        for dev in children.iter().filter(|d| d.autostart) {
+            if let Some(first) = generated.get(&dev.uuid) {
+                warn!(
+                    "Device {} is defined on more than one parent, only generating a unit for \
+                     parent {}",
+                    dev.uuid.hyphenated(),
+                    first
+                );
+                continue;
+            }
+            generated.insert(dev.uuid, parent.clone());
+            units.push(device_unit(dev)?);
+        }
+    }
+    Ok(units)
+}
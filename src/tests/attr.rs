@@ -0,0 +1,77 @@
+use super::*;
+use std::fs;
+use uuid::Uuid;
+
+const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+const PARENT: &str = "0000:00:02.0";
+
+#[cfg(feature = "callouts")]
+fn attr_set(test: &Rc<TestEnvironment>, name: &str, value: &str, expect: Expect) {
+    let res = crate::attr_set_command(
+        test.clone(),
+        Uuid::parse_str(UUID).unwrap(),
+        None,
+        name.to_string(),
+        value.to_string(),
+    );
+    let _ = test.assert_result(res, expect, None);
+}
+
+#[test]
+fn test_attr_get() {
+    init();
+
+    let test = TestEnvironment::new("attr", "get");
+    let get = |name: &str| {
+        let mut outbuf: Vec<u8> = Default::default();
+        crate::attr_get_command(
+            test.clone(),
+            Uuid::parse_str(UUID).unwrap(),
+            None,
+            name,
+            &mut outbuf,
+        )
+        .map(|_| String::from_utf8(outbuf).unwrap())
+    };
+
+    // the device must be running
+    assert!(get("matrix").is_err());
+
+    test.populate_active_device(UUID, PARENT, "vfio_ap-passthrough");
+    fs::write(test.mdev_base().join(UUID).join("matrix"), "05.00ab\n")
+        .expect("Unable to write attribute");
+    assert_eq!("05.00ab\n", get("matrix").unwrap());
+    assert!(get("missing").is_err());
+    assert!(get("../0000:00:02.0").is_err());
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_attr_set() {
+    init();
+
+    let test = TestEnvironment::new("attr", "set");
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    test.populate_active_device(UUID, PARENT, "vfio_ap-passthrough");
+    test.populate_callout_script("live-attr.sh");
+    let definition = fs::read_to_string(test.config_base().join(PARENT).join(UUID)).unwrap();
+
+    // the script rejects other domains
+    attr_set(&test, "assign_domain", "0x41", Expect::Fail(None));
+    // the attribute of the definition is replaced
+    attr_set(&test, "assign_domain", "0x42", Expect::Pass);
+    // ambiguous attributes cannot be replaced
+    attr_set(
+        &test,
+        "assign_control_domain",
+        "0x42",
+        Expect::Fail(Some(
+            "Attribute 'assign_control_domain' is set more than once, use 'modify --live' to change it",
+        )),
+    );
+    // the definition is unchanged
+    assert_eq!(
+        definition,
+        fs::read_to_string(test.config_base().join(PARENT).join(UUID)).unwrap()
+    );
+}
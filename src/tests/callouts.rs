@@ -1,4 +1,7 @@
 use super::*;
+use crate::cli::CalloutTestArgs;
+use std::thread;
+use std::time::Duration;
 
 fn test_invoke_callout<F>(
     testname: &str,
@@ -506,4 +509,852 @@ fn test_callouts() {
             test.populate_callout_script("ver-rc0.sh"); // versioning
         },
     );
+
+    // resource limits are applied to callout scripts before they are executed
+    test_invoke_callout(
+        "test_callout_limits_applied",
+        Expect::Pass,
+        Action::Test,
+        Uuid::parse_str(DEFAULT_UUID).unwrap(),
+        DEFAULT_PARENT,
+        DEFAULT_TYPE,
+        |test| {
+            test.populate_callout_script("limits.sh");
+            fs::write(
+                test.callout_limits_path(),
+                r#"{"cpu_time": 10, "open_files": 64, "memory": 1073741824}"#,
+            )
+            .expect("Unable to write callout limits");
+        },
+    );
+    test_invoke_callout(
+        "test_callout_limits_missing",
+        Expect::Fail(None),
+        Action::Test,
+        Uuid::parse_str(DEFAULT_UUID).unwrap(),
+        DEFAULT_PARENT,
+        DEFAULT_TYPE,
+        |test| {
+            test.populate_callout_script("limits.sh");
+        },
+    );
+    // invalid limits are ignored with a warning
+    test_invoke_callout(
+        "test_callout_limits_invalid",
+        Expect::Fail(None),
+        Action::Test,
+        Uuid::parse_str(DEFAULT_UUID).unwrap(),
+        DEFAULT_PARENT,
+        DEFAULT_TYPE,
+        |test| {
+            test.populate_callout_script("limits.sh");
+            fs::write(test.callout_limits_path(), r#"{"open_files": "64"}"#)
+                .expect("Unable to write callout limits");
+        },
+    );
+    // scripts of a user that does not exist are not run at all
+    test_invoke_callout(
+        "test_callout_user_missing",
+        Expect::Fail(None),
+        Action::Test,
+        Uuid::parse_str(DEFAULT_UUID).unwrap(),
+        DEFAULT_PARENT,
+        DEFAULT_TYPE,
+        |test| {
+            test.populate_callout_script("rc0.sh");
+            fs::write(
+                test.callout_limits_path(),
+                r#"{"script_users": {"rc0.sh": "mdevctl-no-such-user"}}"#,
+            )
+            .expect("Unable to write callout limits");
+        },
+    );
+    // scripts are killed once they exceed their timeout, which fails the action unless forced
+    test_invoke_callout(
+        "test_callout_timeout",
+        Expect::Fail(None),
+        Action::Start,
+        Uuid::parse_str(DEFAULT_UUID).unwrap(),
+        DEFAULT_PARENT,
+        DEFAULT_TYPE,
+        |test| {
+            test.populate_callout_script("hang.sh");
+            fs::write(test.callout_limits_path(), r#"{"timeout": 1}"#)
+                .expect("Unable to write callout limits");
+        },
+    );
+    // the timeout of an action overrides the global one
+    test_invoke_callout(
+        "test_callout_timeout_action",
+        Expect::Pass,
+        Action::Start,
+        Uuid::parse_str(DEFAULT_UUID).unwrap(),
+        DEFAULT_PARENT,
+        DEFAULT_TYPE,
+        |test| {
+            test.populate_callout_script("hang.sh");
+            fs::write(
+                test.callout_limits_path(),
+                r#"{"timeout": 1, "action_timeouts": {"start": 10}}"#,
+            )
+            .expect("Unable to write callout limits");
+        },
+    );
+}
+
+fn test_parse_capabilities_helper(testcase: &str, expect: Expect, versioned: bool) {
+    let test = TestEnvironment::new("capabilities", testcase);
+    let stdout = fs::read(test.datapath.join(format!("{}.json", testcase)))
+        .expect("Unable to read capabilities document");
+
+    let res = CalloutScriptCache::parse_script_capabilities(&stdout);
+    if let Ok(caps) = test.assert_result(res, expect, None) {
+        assert_eq!(versioned, caps.is_some());
+    }
+}
+
+#[test]
+fn test_parse_capabilities() {
+    init();
+
+    const SCHEMA_ERROR: Option<&str> = Some("Capabilities document does not match the schema");
+
+    test_parse_capabilities_helper("valid", Expect::Pass, true);
+    test_parse_capabilities_helper("valid-provides", Expect::Pass, true);
+    // unknown actions and events are accepted for forward compatibility
+    test_parse_capabilities_helper("valid-unknown-action", Expect::Pass, true);
+    test_parse_capabilities_helper("valid-manifest", Expect::Pass, true);
+    // no output at all means the script does not support versioning
+    test_parse_capabilities_helper("empty", Expect::Pass, false);
+    test_parse_capabilities_helper("not-json", Expect::Fail(SCHEMA_ERROR), false);
+    test_parse_capabilities_helper("not-object", Expect::Fail(SCHEMA_ERROR), false);
+    test_parse_capabilities_helper("missing-supports", Expect::Fail(SCHEMA_ERROR), false);
+    test_parse_capabilities_helper("unknown-field", Expect::Fail(SCHEMA_ERROR), false);
+    test_parse_capabilities_helper("unknown-toplevel", Expect::Fail(SCHEMA_ERROR), false);
+    test_parse_capabilities_helper("version-string", Expect::Fail(SCHEMA_ERROR), false);
+    test_parse_capabilities_helper("manifest-unknown-field", Expect::Fail(SCHEMA_ERROR), false);
+    test_parse_capabilities_helper(
+        "manifest-no-types",
+        Expect::Fail(Some(
+            "Capabilities document is invalid: 'mdev_types' must not be empty",
+        )),
+        false,
+    );
+    test_parse_capabilities_helper(
+        "version-zero",
+        Expect::Fail(Some(
+            "Capabilities document is invalid: 'version' must be 1 or greater",
+        )),
+        false,
+    );
+    test_parse_capabilities_helper(
+        "no-actions",
+        Expect::Fail(Some(
+            "Capabilities document is invalid: 'actions' must not be empty",
+        )),
+        false,
+    );
+    test_parse_capabilities_helper(
+        "no-events",
+        Expect::Fail(Some(
+            "Capabilities document is invalid: 'events' must not be empty",
+        )),
+        false,
+    );
+}
+
+#[test]
+fn test_callout_capabilities_memoized() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("callouts", "memoized");
+    test.populate_callout_script("count-capabilities.sh");
+    let invocations = || {
+        fs::read_to_string(test.root().join("invocations.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(|l| l.to_string())
+            .collect::<Vec<_>>()
+    };
+    let find_script = |uuid: &str, parent: &str| {
+        let mut dev = MDev::new(test.clone(), Uuid::parse_str(uuid).unwrap());
+        dev.parent = Some(parent.to_string());
+        dev.mdev_type = Some(MDEV_TYPE.to_string());
+        // bypass the per-environment cache of found scripts
+        CalloutScriptCache::new()
+            .find_versioned_script(&dev)
+            .expect("Callout script lookup failed")
+            .expect("No callout script found")
+    };
+
+    find_script(UUID[0], PARENT);
+    assert_eq!(vec!["get capabilities"], invocations());
+    // queries for the same type and parent reuse the output
+    find_script(UUID[0], PARENT);
+    find_script(UUID[1], PARENT);
+    assert_eq!(vec!["get capabilities"], invocations());
+    // queries for other parents execute the script
+    find_script(UUID[1], "0000:00:04.0");
+    assert_eq!(vec!["get capabilities"; 2], invocations());
+
+    // events with side effects are never reused
+    let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID[0]).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+    for _ in 0..2 {
+        callout(&mut dev)
+            .unwrap()
+            .invoke(Action::Define, false, |_| Ok(()))
+            .expect("Failed to invoke callout");
+    }
+    assert_eq!(
+        vec![
+            "get capabilities",
+            "get capabilities",
+            "pre define",
+            "post define",
+            "pre define",
+            "post define",
+        ],
+        invocations()
+    );
+}
+
+#[test]
+fn test_callout_manifest() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("callouts", "manifest");
+    test.populate_callout_script("manifest.sh");
+    let invocations = || {
+        fs::read_to_string(test.root().join("invocations.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(|l| l.to_string())
+            .collect::<Vec<_>>()
+    };
+    let device = |mdev_type: &str| {
+        let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+        dev.parent = Some(PARENT.to_string());
+        dev.mdev_type = Some(mdev_type.to_string());
+        dev
+    };
+
+    let dev = device("type_a");
+    assert!(CalloutScriptCache::new()
+        .find_versioned_script(&dev)
+        .expect("Callout script lookup failed")
+        .is_some());
+    assert_eq!(vec!["type_a get capabilities"], invocations());
+
+    // the manifest excludes other types, so the script is not invoked for them at all
+    let mut dev = device("type_b");
+    assert!(CalloutScriptCache::new()
+        .find_versioned_script(&dev)
+        .expect("Callout script lookup failed")
+        .is_none());
+    callout(&mut dev)
+        .unwrap()
+        .invoke(Action::Define, false, |_| Ok(()))
+        .expect("Failed to invoke callout");
+    assert_eq!(vec!["type_a get capabilities"], invocations());
+
+    // the manifest declares that the script does not implement get-attributes
+    let mut dev = device("type_a");
+    let attrs = callout(&mut dev)
+        .unwrap()
+        .get_attributes()
+        .expect("Failed to get attributes");
+    assert_eq!(serde_json::Value::Null, attrs);
+    assert_eq!(vec!["type_a get capabilities"], invocations());
+}
+
+#[test]
+fn test_callout_cache_persisted() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "6326d2b3-7b3c-4a5d-a3a1-0b0b2c3a6b19",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("callouts", "cache-persisted");
+    test.populate_callout_script("count-capabilities.sh");
+    let invocations = || {
+        fs::read_to_string(test.root().join("invocations.log"))
+            .unwrap_or_default()
+            .lines()
+            .count()
+    };
+    // every lookup is done by a new cache, as in a new invocation of mdevctl
+    let find_script = |uuid: &str| {
+        let mut dev = MDev::new(test.clone(), Uuid::parse_str(uuid).unwrap());
+        dev.parent = Some(PARENT.to_string());
+        dev.mdev_type = Some(MDEV_TYPE.to_string());
+        let mut cache = CalloutScriptCache::new();
+        cache.set_persist_path(Some(test.callout_cache_path()));
+        cache
+            .find_versioned_script(&dev)
+            .expect("Callout script lookup failed")
+            .expect("No callout script found")
+    };
+
+    find_script(UUID[0]);
+    assert_eq!(1, invocations());
+    assert!(test.callout_cache_path().exists());
+    // the script found for the type and parent before is used for other devices
+    find_script(UUID[1]);
+    assert_eq!(1, invocations());
+
+    // the cache is discarded once a script is modified
+    let script = test.callout_dir().join("count-capabilities.sh");
+    let mut contents = fs::read_to_string(&script).unwrap();
+    contents.push_str("# modified\n");
+    fs::write(&script, contents).unwrap();
+    find_script(UUID[2]);
+    assert_eq!(2, invocations());
+    find_script(UUID[0]);
+    assert_eq!(2, invocations());
+}
+
+#[test]
+fn test_callout_routing() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("callouts", "routing");
+    fs::create_dir_all(test.callout_dir().join("type_a")).unwrap();
+    test.populate_callout_script_full("routed.sh", Some("10-generic.sh"), true);
+    test.populate_callout_script_full("routed.sh", Some("type_a/20-a.sh"), true);
+    test.populate_callout_script_full("routed.sh", Some("type_b.sh"), true);
+    let invoked = |mdev_type: &str| {
+        let _ = fs::remove_file(test.root().join("invocations.log"));
+        let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+        dev.parent = Some(PARENT.to_string());
+        dev.mdev_type = Some(mdev_type.to_string());
+        callout(&mut dev)
+            .unwrap()
+            .invoke(Action::Define, false, |_| Ok(()))
+            .expect("Failed to invoke callout");
+        let mut scripts = fs::read_to_string(test.root().join("invocations.log"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| l.split(' ').next().map(|s| s.to_string()))
+            .collect::<Vec<_>>();
+        scripts.sort();
+        scripts.dedup();
+        scripts
+    };
+
+    // only the scripts installed for a type are executed for its devices
+    assert_eq!(vec!["20-a.sh"], invoked("type_a"));
+    assert_eq!(vec!["type_b.sh"], invoked("type_b"));
+    // all scripts are searched for other types
+    assert!(invoked("type_c").contains(&"10-generic.sh".to_string()));
+}
+
+#[test]
+fn test_callout_ambiguous() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("callouts", "ambiguous");
+    let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+
+    // a single script is never ambiguous
+    test.populate_callout_script_full("count-capabilities.sh", Some("20-second.sh"), true);
+    let mut cache = CalloutScriptCache::new();
+    cache.set_fail_on_ambiguous(true);
+    assert!(cache.find_versioned_script(&dev).unwrap().is_some());
+
+    // the first script in the search order is used, unless strict checking is requested
+    test.populate_callout_script_full("count-capabilities.sh", Some("10-first.sh"), true);
+    assert!(CalloutScriptCache::new()
+        .find_versioned_script(&dev)
+        .unwrap()
+        .is_some());
+    let mut cache = CalloutScriptCache::new();
+    cache.set_fail_on_ambiguous(true);
+    let first = test.callout_dir().join("10-first.sh");
+    let second = test.callout_dir().join("20-second.sh");
+    assert_eq!(
+        format!(
+            "Multiple callout scripts support mdev type {} on parent {}: {:?} (version 2), {:?} \
+             (version 2). {:?} is used because it is the first in the search order",
+            MDEV_TYPE, PARENT, first, second, first
+        ),
+        cache.find_versioned_script(&dev).unwrap_err().to_string()
+    );
+}
+
+#[test]
+#[ignore = "creating mount namespaces requires root, run with --ignored as root"]
+fn test_callout_namespace() {
+    init();
+
+    const DEFAULT_UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const DEFAULT_TYPE: &str = "test_type";
+    const DEFAULT_PARENT: &str = "test_parent";
+
+    test_invoke_callout(
+        "test_callout_namespace",
+        Expect::Pass,
+        Action::Test,
+        Uuid::parse_str(DEFAULT_UUID).unwrap(),
+        DEFAULT_PARENT,
+        DEFAULT_TYPE,
+        |test| {
+            test.populate_callout_script("namespace.sh");
+            fs::write(test.callout_limits_path(), r#"{"mount_namespace": true}"#)
+                .expect("Unable to write callout limits");
+        },
+    );
+    // without the namespace, the script finds the host
+    test_invoke_callout(
+        "test_callout_namespace_disabled",
+        Expect::Fail(None),
+        Action::Test,
+        Uuid::parse_str(DEFAULT_UUID).unwrap(),
+        DEFAULT_PARENT,
+        DEFAULT_TYPE,
+        |test| {
+            test.populate_callout_script("namespace.sh");
+        },
+    );
+}
+
+#[test]
+#[ignore = "changing the user requires root, run with --ignored as root"]
+fn test_callout_user() {
+    init();
+
+    const DEFAULT_UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const DEFAULT_TYPE: &str = "test_type";
+    const DEFAULT_PARENT: &str = "test_parent";
+
+    test_invoke_callout(
+        "test_callout_user",
+        Expect::Pass,
+        Action::Test,
+        Uuid::parse_str(DEFAULT_UUID).unwrap(),
+        DEFAULT_PARENT,
+        DEFAULT_TYPE,
+        |test| {
+            test.populate_callout_script("user.sh");
+            fs::write(test.callout_limits_path(), r#"{"user": "nobody"}"#)
+                .expect("Unable to write callout limits");
+        },
+    );
+    // the user of a script overrides the global one
+    test_invoke_callout(
+        "test_callout_user_script",
+        Expect::Fail(None),
+        Action::Test,
+        Uuid::parse_str(DEFAULT_UUID).unwrap(),
+        DEFAULT_PARENT,
+        DEFAULT_TYPE,
+        |test| {
+            test.populate_callout_script("user.sh");
+            fs::write(
+                test.callout_limits_path(),
+                r#"{"user": "nobody", "script_users": {"user.sh": "root"}}"#,
+            )
+            .expect("Unable to write callout limits");
+        },
+    );
+}
+
+#[test]
+fn test_callout_env() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("callouts", "env");
+    test.populate_callout_script("env.sh");
+    let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+
+    let attrs = callout(&mut dev).unwrap().get_attributes().unwrap();
+    assert_eq!(
+        serde_json::json!([
+            {"uuid": UUID},
+            {"parent": PARENT},
+            {"type": MDEV_TYPE},
+            {"event": "get"},
+            {"action": "attributes"},
+            {"state": "none"}
+        ]),
+        attrs
+    );
+}
+
+#[test]
+fn test_callout_stderr() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+
+    let test = TestEnvironment::new("callouts", "stderr");
+    test.populate_callout_script("stderr.sh");
+    let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some("0000:00:03.0".to_string());
+    dev.mdev_type = Some("arbitrary_type".to_string());
+
+    // what the script wrote to standard error is the cause of the failure
+    let e = callout(&mut dev)
+        .unwrap()
+        .invoke(Action::Start, false, |_| Ok(()))
+        .expect_err("Expected the callout script to reject the event");
+    assert!(e.chain().any(|c| c.is::<ScriptFailure>()));
+    assert_eq!(
+        Some("parent is in maintenance\ntry again later".to_string()),
+        e.chain().last().map(|c| c.to_string())
+    );
+}
+
+#[test]
+fn test_callout_report() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+
+    let reject = |mdev_type: &str| {
+        let test = TestEnvironment::new("callouts", "report");
+        test.populate_callout_script("ver-report.sh");
+        let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+        dev.parent = Some("0000:00:03.0".to_string());
+        dev.mdev_type = Some(mdev_type.to_string());
+        let e = callout(&mut dev)
+            .unwrap()
+            .invoke(Action::Start, false, |_| Ok(()))
+            .expect_err("Expected the callout script to reject the event");
+        assert!(e.chain().any(|c| c.is::<ScriptFailure>()));
+        e.chain().last().map(|c| c.to_string())
+    };
+
+    // the report on standard output is the cause of the failure since version 5
+    assert_eq!(
+        Some("parent is in maintenance (hint: try again later)".to_string()),
+        reject("report_v5")
+    );
+    // and ignored by earlier versions, which still find the standard error
+    assert_eq!(Some("no report".to_string()), reject("report_v4"));
+}
+
+#[test]
+fn test_notifier_filter() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+
+    let test = TestEnvironment::new("notifiers", "filter");
+    test.populate_notifier_script("notify-mark.sh", "mark.sh");
+    fs::write(
+        test.notification_dir().join("mark.sh.conf"),
+        r#"{"actions": ["stop"]}"#,
+    )
+    .expect("Unable to write notifier filter");
+    let marked = test.notification_dir().parent().unwrap().join("marked");
+
+    let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some("0000:00:03.0".to_string());
+    dev.mdev_type = Some("arbitrary_type".to_string());
+    // the filter itself is not invoked as a script, and the script only for the stop action
+    callout(&mut dev)
+        .unwrap()
+        .invoke(Action::Start, false, |_| Ok(()))
+        .unwrap();
+    assert!(!marked.exists());
+    callout(&mut dev)
+        .unwrap()
+        .invoke(Action::Stop, false, |_| Ok(()))
+        .unwrap();
+    assert!(marked.exists());
+
+    // only failed starts
+    fs::remove_file(&marked).expect("Unable to remove mark");
+    fs::write(
+        test.notification_dir().join("mark.sh.conf"),
+        r#"{"actions": ["start"], "results": ["failure"]}"#,
+    )
+    .expect("Unable to write notifier filter");
+    callout(&mut dev)
+        .unwrap()
+        .invoke(Action::Start, false, |_| Ok(()))
+        .unwrap();
+    assert!(!marked.exists());
+    callout(&mut dev)
+        .unwrap()
+        .invoke(Action::Start, false, |_| {
+            Err(anyhow::anyhow!("start failed"))
+        })
+        .unwrap_err();
+    assert!(marked.exists());
+}
+
+#[test]
+fn test_notifier_dedup() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+
+    let test = TestEnvironment::new("notifiers", "dedup");
+    let record = test.record_callouts();
+    fs::write(test.policy_path(), r#"{"notifier_dedup_ms": 60000}"#)
+        .expect("Unable to write policy");
+
+    let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some("0000:00:03.0".to_string());
+    dev.mdev_type = Some("arbitrary_type".to_string());
+    let mut start = |fail: bool| {
+        let _ = callout(&mut dev)
+            .unwrap()
+            .invoke(Action::Start, false, |_| match fail {
+                true => Err(anyhow::anyhow!("start failed")),
+                false => Ok(()),
+            });
+    };
+    let counts = || -> Vec<String> {
+        fs::read_to_string(&record)
+            .unwrap_or_default()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .filter(|v| v["event"] == "notify")
+            .map(|v| {
+                // the count is passed in the environment and on stdin
+                let count = v["vars"]["MDEVCTL_NOTIFY_COUNT"].as_str().unwrap();
+                assert_eq!(v["config"]["count"].to_string(), count);
+                count.to_string()
+            })
+            .collect()
+    };
+
+    // repeated failures within the window are coalesced, a success is a different event
+    start(true);
+    start(true);
+    start(true);
+    start(false);
+    assert_eq!(counts(), ["1", "1"]);
+
+    // once the window passed, the next notification includes the coalesced ones
+    let state = test
+        .notify_state_base()
+        .join(format!("{}-start-failure.json", UUID));
+    fs::write(&state, r#"{"notified": 1, "coalesced": 2}"#).expect("Unable to write state");
+    start(true);
+    assert_eq!(counts(), ["1", "1", "3"]);
+}
+
+#[test]
+fn test_notifiers() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let notify = |testname: &str, mode: Option<&str>| {
+        let test = TestEnvironment::new("notifiers", testname);
+        if let Some(mode) = mode {
+            fs::write(
+                test.policy_path(),
+                format!(r#"{{"notifiers": "{}"}}"#, mode),
+            )
+            .expect("Unable to write policy");
+        }
+        test.populate_notifier_script("notify-wait.sh", "01-wait.sh");
+        test.populate_notifier_script("notify-mark.sh", "02-mark.sh");
+
+        let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+        dev.parent = Some(PARENT.to_string());
+        dev.mdev_type = Some(MDEV_TYPE.to_string());
+        let mut c = callout(&mut dev).unwrap();
+        c.invoke(Action::Start, false, |_| Ok(())).unwrap();
+        let scripts_dir = test.notification_dir().parent().unwrap().to_path_buf();
+        (test, scripts_dir.join("marked"), scripts_dir.join("waited"))
+    };
+
+    // by default the scripts run at the same time and are waited for
+    let (_test, marked, waited) = notify("parallel", None);
+    assert!(waited.exists());
+    // the script got the notification on stdin
+    let notification: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(marked).expect("Unable to read marker")).unwrap();
+    assert_eq!(
+        notification,
+        serde_json::json!({"action": "start", "result": "success", "count": 1})
+    );
+
+    // one after the other, the first script gives up waiting for the second
+    let (_test, marked, waited) = notify("serial", Some("serial"));
+    assert!(marked.exists());
+    assert!(!waited.exists());
+
+    // detached scripts are still running when the action completes
+    let (_test, marked, waited) = notify("detached", Some("detached"));
+    assert!(!marked.exists());
+    let started = Instant::now();
+    while !waited.exists() && started.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(waited.exists());
+}
+
+#[test]
+fn test_callout_record() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const ACTIVE_UUID: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("callout-record", "start");
+    let record = test.record_callouts();
+    // scripts are not invoked, so a script rejecting every event does not fail the command
+    test.populate_callout_script("rc1.sh");
+    test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
+
+    let res = crate::start_command_helper(
+        test.clone(),
+        Some(Uuid::parse_str(UUID).unwrap()),
+        Some(PARENT.to_string()),
+        Some(MDEV_TYPE.to_string()),
+        None,
+        false,
+        false,
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    test.populate_active_device(ACTIVE_UUID, PARENT, MDEV_TYPE);
+    let res = crate::stop_command(
+        test.clone(),
+        Uuid::parse_str(ACTIVE_UUID).unwrap(),
+        false,
+        false,
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+
+    let events = fs::read_to_string(record).expect("Unable to read recorded events");
+    test.compare_to_file("events.jsonl", &events);
+}
+
+#[test]
+fn test_callout_test_command() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("callout-test", "default");
+    test.populate_callout_script("echo.sh");
+    let script = Some(test.callout_dir().join("echo.sh"));
+
+    let run =
+        |uuid: Option<&str>, script: Option<PathBuf>, event: &str, action: &str, expect: Expect| {
+            let mut outbuf: Vec<u8> = Default::default();
+            let args = CalloutTestArgs {
+                uuid: uuid.map(|u| Uuid::parse_str(u).unwrap()),
+                parent: Some(PARENT.to_string()),
+                mdev_type: Some(MDEV_TYPE.to_string()),
+                script,
+                event: event.to_string(),
+                action: action.to_string(),
+                state: "none".to_string(),
+                jsonfile: None,
+            };
+            let res = crate::callout_test_command(test.clone(), args, &mut outbuf);
+            let _ = test.assert_result(res, expect, Some(event));
+            String::from_utf8(outbuf).expect("invalid utf8 output")
+        };
+
+    // the device configuration is passed for actions on the device
+    test.compare_to_file(
+        "pre-start.text",
+        &run(Some(UUID), script.clone(), "pre", "start", Expect::Pass),
+    );
+    // the versioning JSON is passed for capabilities
+    test.compare_to_file(
+        "get-capabilities.text",
+        &run(
+            Some(UUID),
+            script.clone(),
+            "get",
+            "capabilities",
+            Expect::Pass,
+        ),
+    );
+    // the script does not support versioning, so none is found for the device
+    run(
+        Some(UUID),
+        None,
+        "pre",
+        "start",
+        Expect::Fail(Some(
+            "No callout script with version support found for device \
+             976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        )),
+    );
+}
+
+#[test]
+fn test_callouts_list_command() {
+    init();
+
+    let test = TestEnvironment::new("callouts-list", "default");
+    test.populate_parent_device("0000:00:02.0", "type_a", 1, "vfio-pci", "test device", None);
+    test.populate_parent_device("0000:00:03.0", "type_b", 1, "vfio-pci", "test device", None);
+    // supports type_a without versioning
+    test.populate_callout_script_full("type-a.sh", Some("10-type-a.sh"), true);
+    // supports all types with versioning
+    test.populate_callout_script_full("count-capabilities.sh", Some("20-versioned.sh"), true);
+    test.populate_callout_script_full("bad-json.sh", Some("30-bad-json.sh"), true);
+    fs::write(test.callout_dir().join("40-readme"), "not a script").expect("Unable to write file");
+    test.populate_notifier_script("notify-mark.sh", "10-notify.sh");
+    fs::write(
+        test.notification_dir().join("10-notify.sh.conf"),
+        r#"{"actions": ["start", "stop"], "results": ["failure"]}"#,
+    )
+    .expect("Unable to write notifier filter");
+    test.populate_notifier_script("notify-mark.sh", "20-notify.sh");
+    fs::write(
+        test.notification_dir().join("20-notify.sh.conf"),
+        r#"{"events": ["notify"]}"#,
+    )
+    .expect("Unable to write notifier filter");
+
+    for (filename, dumpjson) in [("list.text", false), ("list.json", true)] {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::callouts_list_command(test.clone(), dumpjson, &mut outbuf);
+        test.assert_result(res, Expect::Pass, Some(filename))
+            .unwrap();
+        test.compare_to_file(
+            filename,
+            &String::from_utf8(outbuf).expect("invalid utf8 output"),
+        );
+    }
 }
@@ -0,0 +1,189 @@
+use super::*;
+use crate::changelog::{attr_changes, ChangeEntry};
+use crate::cli::{DefinitionChanges, ModifyArgs};
+
+const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+const PARENT: &str = "0000:00:03.0";
+
+fn attrs(list: &[(&str, &str)]) -> Vec<(String, String)> {
+    list.iter()
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_attr_changes() {
+    let change = |name: &str, old: Option<&str>, new: Option<&str>| {
+        (
+            name.to_string(),
+            old.map(String::from),
+            new.map(String::from),
+        )
+    };
+    let old = attrs(&[
+        ("assign_adapter", "5"),
+        ("assign_adapter", "6"),
+        ("weight", "1"),
+    ]);
+    assert!(attr_changes(&old, &old).is_empty());
+    assert_eq!(
+        vec![
+            change("assign_adapter", Some("6"), Some("7")),
+            change("weight", Some("1"), None),
+            change("assign_domain", None, Some("4")),
+        ],
+        attr_changes(
+            &old,
+            &attrs(&[
+                ("assign_adapter", "5"),
+                ("assign_domain", "4"),
+                ("assign_adapter", "7"),
+            ])
+        )
+    );
+}
+
+fn modify(test: &Rc<TestEnvironment>, addattr: Option<&str>, delattr: bool, value: Option<&str>) {
+    let res = crate::modify_command(
+        test.clone(),
+        Uuid::parse_str(UUID).unwrap(),
+        ModifyArgs {
+            changes: DefinitionChanges {
+                addattr: addattr.map(String::from),
+                delattr,
+                value: value.map(String::from),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        &mut std::io::sink(),
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+}
+
+#[test]
+fn test_log_modify() {
+    init();
+
+    let test = TestEnvironment::new("changelog", "modify");
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+
+    // nothing is recorded unless enabled in the policy
+    modify(&test, Some("assign_domain"), false, Some("0x42"));
+    assert!(!test.changelog_base().exists());
+
+    fs::write(test.policy_path(), r#"{"change_log": true}"#).expect("Unable to write policy");
+    modify(&test, None, true, None);
+    modify(&test, Some("assign_adapter"), false, Some("7"));
+    let entries = crate::changelog::read(test.as_ref(), Uuid::parse_str(UUID).unwrap())
+        .expect("Failed to read change log")
+        .into_iter()
+        .map(|e| (e.command, e.parent, e.attribute, e.old, e.new))
+        .collect::<Vec<_>>();
+    let entry = |attribute: &str, old: Option<&str>, new: Option<&str>| {
+        (
+            "modify".to_string(),
+            PARENT.to_string(),
+            attribute.to_string(),
+            old.map(String::from),
+            new.map(String::from),
+        )
+    };
+    assert_eq!(
+        vec![
+            // the last attribute is deleted
+            entry("assign_domain", Some("0x42"), None),
+            entry("assign_adapter", None, Some("7")),
+        ],
+        entries
+    );
+}
+
+#[test]
+fn test_log_edit_commit() {
+    init();
+
+    let test = TestEnvironment::new("changelog", "edit-commit");
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    fs::write(test.policy_path(), r#"{"change_log": true}"#).expect("Unable to write policy");
+    let uuid = Uuid::parse_str(UUID).unwrap();
+
+    let res = crate::edit_command(test.clone(), uuid, None, "sed -i s/0xab/0xcd/", false);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let res = crate::stage_modify_command(
+        test.clone(),
+        uuid,
+        None,
+        DefinitionChanges {
+            addattr: Some("assign_adapter".to_string()),
+            value: Some("7".to_string()),
+            ..Default::default()
+        },
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    // staged changes are only recorded once they are committed
+    assert_eq!(
+        2,
+        crate::changelog::read(test.as_ref(), uuid).unwrap().len()
+    );
+    let res = crate::commit_command(test.clone(), false);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+
+    let entries = crate::changelog::read(test.as_ref(), uuid)
+        .expect("Failed to read change log")
+        .into_iter()
+        .map(|e| (e.command, e.attribute, e.old, e.new))
+        .collect::<Vec<_>>();
+    let entry = |command: &str, attribute: &str, old: Option<&str>, new: Option<&str>| {
+        (
+            command.to_string(),
+            attribute.to_string(),
+            old.map(String::from),
+            new.map(String::from),
+        )
+    };
+    assert_eq!(
+        vec![
+            entry("edit", "assign_domain", Some("0xab"), Some("0xcd")),
+            entry("edit", "assign_control_domain", Some("0xab"), Some("0xcd")),
+            entry("commit", "assign_adapter", None, Some("7")),
+        ],
+        entries
+    );
+}
+
+#[test]
+fn test_log() {
+    init();
+
+    let test = TestEnvironment::new("changelog", "default");
+    fs::create_dir_all(test.changelog_base()).expect("Unable to setup changelog dir");
+    fs::copy(
+        test.datapath.join("changes.jsonl"),
+        test.changelog_base().join(UUID),
+    )
+    .expect("Unable to setup change log");
+
+    for (subtest, parent, dumpjson) in [
+        ("all.text", None, false),
+        ("all.json", None, true),
+        ("parent.text", Some(PARENT.to_string()), false),
+    ] {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::log_command(
+            test.clone(),
+            Uuid::parse_str(UUID).unwrap(),
+            parent,
+            dumpjson,
+            &mut outbuf,
+        );
+        test.assert_result(res, Expect::Pass, Some(subtest))
+            .unwrap();
+        let actual = String::from_utf8(outbuf).expect("failed to convert log output from utf8");
+        test.compare_to_file(subtest, &actual);
+    }
+
+    // a device without changes has an empty log
+    let entries = crate::changelog::read(test.as_ref(), Uuid::new_v4()).unwrap();
+    assert_eq!(Vec::<ChangeEntry>::new(), entries);
+}
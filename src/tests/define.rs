@@ -1,7 +1,13 @@
 use super::*;
-use std::{fs, path::PathBuf};
+#[cfg(feature = "callouts")]
+use crate::cli::AttrMerge;
+use crate::cli::DefineArgs;
+use std::fs;
+#[cfg(feature = "callouts")]
+use std::path::PathBuf;
 use uuid::Uuid;
 
+#[cfg(feature = "callouts")]
 fn test_define_command_callout<F>(
     testname: &str,
     expect: Expect,
@@ -18,21 +24,23 @@ fn test_define_command_callout<F>(
     setupfn(&test);
 
     use crate::define_command;
-    let res = define_command(env, uuid, false, parent, mdev_type, None, force);
+    let res = define_command(
+        env,
+        &DefineArgs {
+            uuid,
+            parent,
+            mdev_type,
+            force,
+            ..Default::default()
+        },
+    );
 
     let _ = test.assert_result(res, expect, None);
 }
 
-fn test_define_helper<F>(
-    testname: &str,
-    expect: Expect,
-    uuid: Option<Uuid>,
-    auto: bool,
-    parent: Option<String>,
-    mdev_type: Option<String>,
-    jsonfile: Option<PathBuf>,
-    setupfn: F,
-) where
+#[cfg(feature = "callouts")]
+fn test_define_helper<F>(testname: &str, expect: Expect, args: DefineArgs, setupfn: F)
+where
     F: Fn(&TestEnvironment),
 {
     use crate::define_command_helper;
@@ -40,14 +48,18 @@ fn test_define_helper<F>(
     let env: Rc<dyn Environment> = test.clone();
 
     // load the jsonfile from the test path.
-    let jsonfile = match jsonfile {
-        Some(f) => Some(test.datapath.join(f)),
-        None => None,
-    };
+    let jsonfile = args.jsonfile.map(|f| test.datapath.join(f));
 
     setupfn(&test);
 
-    let res = define_command_helper(env, uuid, auto, parent, mdev_type, jsonfile);
+    let res = define_command_helper(
+        env,
+        args.uuid,
+        args.auto,
+        args.parent,
+        args.mdev_type,
+        jsonfile,
+    );
     if let Ok(def) = test.assert_result(res, expect, None) {
         let path = def.persist_path().unwrap();
         assert!(!path.exists());
@@ -60,6 +72,7 @@ fn test_define_helper<F>(
 }
 
 #[test]
+#[cfg(feature = "callouts")]
 fn test_define() {
     init();
 
@@ -68,77 +81,84 @@ fn test_define() {
     test_define_helper(
         "no-uuid-no-type",
         Expect::Fail(None),
-        None,
-        true,
-        Some(DEFAULT_PARENT.to_string()),
-        None,
-        None,
+        DefineArgs {
+            auto: true,
+            parent: Some(DEFAULT_PARENT.to_string()),
+            ..Default::default()
+        },
         |_| {},
     );
     // if no uuid is specified, one will be auto-generated
     test_define_helper(
         "no-uuid",
         Expect::Pass,
-        None,
-        true,
-        Some(DEFAULT_PARENT.to_string()),
-        Some("i915-GVTg_V5_4".to_string()),
-        None,
+        DefineArgs {
+            auto: true,
+            parent: Some(DEFAULT_PARENT.to_string()),
+            mdev_type: Some("i915-GVTg_V5_4".to_string()),
+            ..Default::default()
+        },
         |_| {},
     );
     // specify autostart
     test_define_helper(
         "uuid-auto",
         Expect::Pass,
-        Uuid::parse_str(DEFAULT_UUID).ok(),
-        true,
-        Some(DEFAULT_PARENT.to_string()),
-        Some("i915-GVTg_V5_4".to_string()),
-        None,
+        DefineArgs {
+            uuid: Uuid::parse_str(DEFAULT_UUID).ok(),
+            auto: true,
+            parent: Some(DEFAULT_PARENT.to_string()),
+            mdev_type: Some("i915-GVTg_V5_4".to_string()),
+            ..Default::default()
+        },
         |_| {},
     );
     // specify manual start
     test_define_helper(
         "uuid-manual",
         Expect::Pass,
-        Uuid::parse_str(DEFAULT_UUID).ok(),
-        false,
-        Some(DEFAULT_PARENT.to_string()),
-        Some("i915-GVTg_V5_4".to_string()),
-        None,
+        DefineArgs {
+            uuid: Uuid::parse_str(DEFAULT_UUID).ok(),
+            parent: Some(DEFAULT_PARENT.to_string()),
+            mdev_type: Some("i915-GVTg_V5_4".to_string()),
+            ..Default::default()
+        },
         |_| {},
     );
     // invalid to specify an separate mdev_type if defining via jsonfile
     test_define_helper(
         "jsonfile-type",
         Expect::Fail(None),
-        Uuid::parse_str(DEFAULT_UUID).ok(),
-        false,
-        Some(DEFAULT_PARENT.to_string()),
-        Some("i915-GVTg_V5_4".to_string()),
-        Some(PathBuf::from("defined.json")),
+        DefineArgs {
+            uuid: Uuid::parse_str(DEFAULT_UUID).ok(),
+            parent: Some(DEFAULT_PARENT.to_string()),
+            mdev_type: Some("i915-GVTg_V5_4".to_string()),
+            jsonfile: Some(PathBuf::from("defined.json")),
+            ..Default::default()
+        },
         |_| {},
     );
     // specifying via jsonfile properly
     test_define_helper(
         "jsonfile",
         Expect::Pass,
-        Uuid::parse_str(DEFAULT_UUID).ok(),
-        false,
-        Some(DEFAULT_PARENT.to_string()),
-        None,
-        Some(PathBuf::from("defined.json")),
+        DefineArgs {
+            uuid: Uuid::parse_str(DEFAULT_UUID).ok(),
+            parent: Some(DEFAULT_PARENT.to_string()),
+            jsonfile: Some(PathBuf::from("defined.json")),
+            ..Default::default()
+        },
         |_| {},
     );
     // If uuid is already active, specifying mdev_type will result in an error
     test_define_helper(
         "uuid-running-no-parent",
         Expect::Fail(None),
-        Uuid::parse_str(DEFAULT_UUID).ok(),
-        false,
-        None,
-        Some("i915-GVTg_V5_4".to_string()),
-        None,
+        DefineArgs {
+            uuid: Uuid::parse_str(DEFAULT_UUID).ok(),
+            mdev_type: Some("i915-GVTg_V5_4".to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_active_device(DEFAULT_UUID, DEFAULT_PARENT, "i915-GVTg_V5_4");
         },
@@ -147,11 +167,11 @@ fn test_define() {
     test_define_helper(
         "uuid-running-no-type",
         Expect::Pass,
-        Uuid::parse_str(DEFAULT_UUID).ok(),
-        false,
-        Some(DEFAULT_PARENT.to_string()),
-        None,
-        None,
+        DefineArgs {
+            uuid: Uuid::parse_str(DEFAULT_UUID).ok(),
+            parent: Some(DEFAULT_PARENT.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_active_device(DEFAULT_UUID, DEFAULT_PARENT, "i915-GVTg_V5_4");
         },
@@ -161,11 +181,12 @@ fn test_define() {
     test_define_helper(
         "uuid-running-diff-parent",
         Expect::Pass,
-        Uuid::parse_str(DEFAULT_UUID).ok(),
-        false,
-        Some(DEFAULT_PARENT.to_string()),
-        Some("i915-GVTg_V5_4".to_string()),
-        None,
+        DefineArgs {
+            uuid: Uuid::parse_str(DEFAULT_UUID).ok(),
+            parent: Some(DEFAULT_PARENT.to_string()),
+            mdev_type: Some("i915-GVTg_V5_4".to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_active_device(DEFAULT_UUID, "0000:00:02.0", "i915-GVTg_V5_4");
         },
@@ -175,11 +196,12 @@ fn test_define() {
     test_define_helper(
         "uuid-running-diff-type",
         Expect::Pass,
-        Uuid::parse_str(DEFAULT_UUID).ok(),
-        false,
-        Some(DEFAULT_PARENT.to_string()),
-        Some("i915-GVTg_V5_4".to_string()),
-        None,
+        DefineArgs {
+            uuid: Uuid::parse_str(DEFAULT_UUID).ok(),
+            parent: Some(DEFAULT_PARENT.to_string()),
+            mdev_type: Some("i915-GVTg_V5_4".to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_active_device(DEFAULT_UUID, DEFAULT_PARENT, "different_type");
         },
@@ -188,11 +210,12 @@ fn test_define() {
     test_define_helper(
         "uuid-already-defined",
         Expect::Fail(None),
-        Uuid::parse_str(DEFAULT_UUID).ok(),
-        false,
-        Some(DEFAULT_PARENT.to_string()),
-        Some("i915-GVTg_V5_4".to_string()),
-        None,
+        DefineArgs {
+            uuid: Uuid::parse_str(DEFAULT_UUID).ok(),
+            parent: Some(DEFAULT_PARENT.to_string()),
+            mdev_type: Some("i915-GVTg_V5_4".to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(DEFAULT_UUID, DEFAULT_PARENT, "defined.json");
         },
@@ -320,6 +343,7 @@ fn test_define() {
     );
 }
 
+#[cfg(feature = "callouts")]
 fn test_undefine_helper<F>(
     testname: &str,
     expect: Expect,
@@ -335,7 +359,7 @@ fn test_undefine_helper<F>(
     setupfn(&test);
     let uuid = Uuid::parse_str(uuid).unwrap();
 
-    let result = crate::undefine_command(env.clone(), uuid, parent.clone(), force);
+    let result = crate::undefine_command(env.clone(), Some(uuid), parent.clone(), false, force);
 
     if test.assert_result(result, expect, None).is_err() {
         return;
@@ -348,6 +372,7 @@ fn test_undefine_helper<F>(
 }
 
 #[test]
+#[cfg(feature = "callouts")]
 fn test_undefine() {
     init();
 
@@ -499,3 +524,640 @@ fn test_undefine() {
         },
     );
 }
+
+fn test_clone_helper<F>(
+    testname: &str,
+    expect: Expect,
+    parent: Option<String>,
+    new_uuid: Option<&str>,
+    new_parent: Option<String>,
+    setupfn: F,
+) where
+    F: Fn(&TestEnvironment),
+{
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+
+    let test = TestEnvironment::new("clone", testname);
+    let env: Rc<dyn Environment> = test.clone();
+    setupfn(&test);
+    let before = test
+        .clone()
+        .get_defined_devices(None, None)
+        .expect("failed to query defined devices");
+    let new_uuid = new_uuid.map(|u| Uuid::parse_str(u).unwrap());
+
+    let result = crate::clone_command(
+        env,
+        Uuid::parse_str(UUID).unwrap(),
+        parent,
+        new_uuid,
+        new_parent.clone(),
+        Vec::new(),
+        false,
+    );
+
+    let after = test
+        .clone()
+        .get_defined_devices(None, None)
+        .expect("failed to query defined devices");
+    if test.assert_result(result, expect, None).is_err() {
+        assert_eq!(
+            before.values().flatten().count(),
+            after.values().flatten().count()
+        );
+        return;
+    }
+
+    // exactly one device was added with the configuration of the source device
+    let source = after
+        .values()
+        .flatten()
+        .find(|d| d.uuid.to_string() == UUID)
+        .unwrap();
+    let copies = after
+        .values()
+        .flatten()
+        .filter(|d| {
+            !before
+                .values()
+                .flatten()
+                .any(|b| b.uuid == d.uuid && b.parent == d.parent)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(1, copies.len());
+    let copy = copies[0];
+    if let Some(u) = new_uuid {
+        assert_eq!(u, copy.uuid);
+    }
+    assert_ne!(source.uuid, copy.uuid);
+    if let Some(p) = new_parent {
+        assert_eq!(Some(p), copy.parent);
+    }
+    assert_eq!(source.to_json(false).unwrap(), copy.to_json(false).unwrap());
+}
+
+#[test]
+fn test_clone() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5a492";
+    const PARENT: &str = "0000:00:03.0";
+    const PARENT2: &str = "0000:00:02.0";
+
+    test_clone_helper("generated-uuid", Expect::Pass, None, None, None, |test| {
+        test.populate_defined_device(UUID, PARENT, "defined.json");
+    });
+    test_clone_helper("new-uuid", Expect::Pass, None, Some(UUID2), None, |test| {
+        test.populate_defined_device(UUID, PARENT, "defined.json");
+    });
+    test_clone_helper(
+        "new-parent",
+        Expect::Pass,
+        None,
+        Some(UUID2),
+        Some(PARENT2.to_string()),
+        |test| {
+            test.populate_defined_device(UUID, PARENT, "defined.json");
+        },
+    );
+    test_clone_helper("nonexistent", Expect::Fail(None), None, None, None, |_| {});
+    // the source is ambiguous without a parent
+    test_clone_helper(
+        "multiple-noparent",
+        Expect::Fail(Some(
+            "Multiple definitions found for 976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9, specify a parent",
+        )),
+        None,
+        None,
+        None,
+        |test| {
+            test.populate_defined_device(UUID, PARENT, "defined.json");
+            test.populate_defined_device(UUID, PARENT2, "defined.json");
+        },
+    );
+    test_clone_helper(
+        "multiple-parent",
+        Expect::Pass,
+        Some(PARENT2.to_string()),
+        None,
+        None,
+        |test| {
+            test.populate_defined_device(UUID, PARENT, "defined.json");
+            test.populate_defined_device(UUID, PARENT2, "defined.json");
+        },
+    );
+    test_clone_helper(
+        "exists",
+        Expect::Fail(Some(
+            "Device 59e8b599-afdd-4766-a59e-415ef4f5a492 on 0000:00:03.0 already defined",
+        )),
+        None,
+        Some(UUID2),
+        None,
+        |test| {
+            test.populate_defined_device(UUID, PARENT, "defined.json");
+            test.populate_defined_device(UUID2, PARENT, "defined.json");
+        },
+    );
+}
+
+#[cfg(feature = "callouts")]
+fn test_set_uuid_helper<F>(testname: &str, expect: Expect, parent: Option<String>, setupfn: F)
+where
+    F: Fn(&TestEnvironment),
+{
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const NEW_UUID: &str = "59e8b599-afdd-4766-a59e-415ef4f5a492";
+
+    let test = TestEnvironment::new("set-uuid", testname);
+    let env: Rc<dyn Environment> = test.clone();
+    setupfn(&test);
+    let uuid = Uuid::parse_str(UUID).unwrap();
+    let new_uuid = Uuid::parse_str(NEW_UUID).unwrap();
+    let before = test
+        .clone()
+        .get_defined_devices(Some(&uuid), parent.as_ref())
+        .expect("failed to query defined devices");
+
+    let result = crate::set_uuid_command(env, uuid, parent.clone(), new_uuid, false);
+
+    let after = test
+        .clone()
+        .get_defined_devices(Some(&new_uuid), parent.as_ref())
+        .expect("failed to query defined devices");
+    if test.assert_result(result, expect, None).is_err() {
+        // the definition is unchanged
+        let unchanged = test
+            .clone()
+            .get_defined_devices(Some(&uuid), parent.as_ref())
+            .expect("failed to query defined devices");
+        assert_eq!(before.len(), unchanged.len());
+        return;
+    }
+
+    // the definition was moved without changing it
+    let old = before.values().flatten().collect::<Vec<_>>();
+    let new = after.values().flatten().collect::<Vec<_>>();
+    assert_eq!(1, old.len());
+    assert_eq!(1, new.len());
+    assert_eq!(old[0].parent, new[0].parent);
+    assert_eq!(
+        old[0].to_json(false).unwrap(),
+        new[0].to_json(false).unwrap()
+    );
+    assert!(!old[0].persist_path().unwrap().exists());
+
+    let log = fs::read_to_string(test.root().join("set-uuid.log")).unwrap_or_default();
+    assert_eq!(
+        format!(
+            "pre modify {new} {old}\npost modify {new} {old}\n",
+            new = NEW_UUID,
+            old = UUID
+        ),
+        log
+    );
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_set_uuid() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const NEW_UUID: &str = "59e8b599-afdd-4766-a59e-415ef4f5a492";
+    const PARENT: &str = "0000:00:03.0";
+    const PARENT2: &str = "0000:00:02.0";
+
+    test_set_uuid_helper("single", Expect::Pass, None, |test| {
+        test.populate_defined_device(UUID, PARENT, "defined.json");
+        test.populate_callout_script("set-uuid.sh");
+    });
+    test_set_uuid_helper(
+        "multiple-parent",
+        Expect::Pass,
+        Some(PARENT2.to_string()),
+        |test| {
+            test.populate_defined_device(UUID, PARENT, "defined.json");
+            test.populate_defined_device(UUID, PARENT2, "defined.json");
+            test.populate_callout_script("set-uuid.sh");
+        },
+    );
+    test_set_uuid_helper("nonexistent", Expect::Fail(None), None, |_| {});
+    test_set_uuid_helper(
+        "active",
+        Expect::Fail(Some(
+            "Device 976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9 is active, stop it before changing its \
+             UUID",
+        )),
+        None,
+        |test| {
+            test.populate_defined_device(UUID, PARENT, "defined.json");
+            test.populate_active_device(UUID, PARENT, "type_a");
+        },
+    );
+    test_set_uuid_helper(
+        "exists",
+        Expect::Fail(Some(
+            "Device 59e8b599-afdd-4766-a59e-415ef4f5a492 on 0000:00:03.0 already defined",
+        )),
+        None,
+        |test| {
+            test.populate_defined_device(UUID, PARENT, "defined.json");
+            test.populate_defined_device(NEW_UUID, PARENT, "defined.json");
+        },
+    );
+    // callout script rejects the change
+    test_set_uuid_helper("callout-fail", Expect::Fail(None), None, |test| {
+        test.populate_defined_device(UUID, PARENT, "defined.json");
+        test.populate_callout_script("rc1.sh");
+    });
+}
+
+fn test_define_now_helper<F>(testname: &str, now: bool, instances: i32, started: bool, setupfn: F)
+where
+    F: Fn(&TestEnvironment),
+{
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("define-now", testname);
+    let env: Rc<dyn Environment> = test.clone();
+    let (_, parenttypedir) =
+        test.populate_parent_device(PARENT, MDEV_TYPE, instances, "vfio-pci", "", None);
+    setupfn(&test);
+
+    let res = crate::define_command(
+        env,
+        &DefineArgs {
+            uuid: Some(Uuid::parse_str(UUID).unwrap()),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            now,
+            ..Default::default()
+        },
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert!(test.config_base().join(PARENT).join(UUID).exists());
+    assert_eq!(started, parenttypedir.join("create").exists());
+}
+
+#[test]
+fn test_define_now() {
+    init();
+
+    test_define_now_helper("default", false, 1, false, |_| {});
+    test_define_now_helper("now", true, 1, true, |_| {});
+    // the device stays defined if the parent has no capacity for it
+    test_define_now_helper("no-capacity", true, 0, false, |_| {});
+    test_define_now_helper("policy", false, 1, true, |test| {
+        fs::write(test.policy_path(), r#"{"auto_start_on_define": true}"#)
+            .expect("Unable to write policy");
+    });
+    test_define_now_helper("policy-disabled", false, 1, false, |test| {
+        fs::write(test.policy_path(), r#"{"auto_start_on_define": false}"#)
+            .expect("Unable to write policy");
+    });
+}
+
+#[test]
+fn test_undefine_all() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+    const PARENT2: &str = "0000:00:02.0";
+
+    let test = TestEnvironment::new("undefine", "all");
+    let env: Rc<dyn Environment> = test.clone();
+    test.populate_defined_device(UUID[0], PARENT, "defined.json");
+    test.populate_defined_device(UUID[1], PARENT, "defined.json");
+    test.populate_defined_device(UUID[0], PARENT2, "defined.json");
+    let defined = |parent: &str| {
+        test.clone()
+            .get_defined_devices(None, Some(&parent.to_string()))
+            .expect("failed to query defined devices")
+            .values()
+            .flatten()
+            .count()
+    };
+
+    // neither a UUID nor --all
+    let res = crate::undefine_command(env.clone(), None, Some(PARENT.to_string()), false, false);
+    let _ = test.assert_result(
+        res,
+        Expect::Fail(Some("Either a UUID or --all is required")),
+        Some("no-uuid"),
+    );
+    let res = crate::undefine_command(env.clone(), None, None, true, false);
+    let _ = test.assert_result(
+        res,
+        Expect::Fail(Some("A parent is required to undefine all devices")),
+        Some("no-parent"),
+    );
+    assert_eq!(2, defined(PARENT));
+
+    let res = crate::undefine_command(env.clone(), None, Some(PARENT.to_string()), true, false);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert_eq!(0, defined(PARENT));
+    assert_eq!(1, defined(PARENT2));
+
+    // nothing left to undefine
+    let res = crate::undefine_command(env, None, Some(PARENT.to_string()), true, false);
+    test.assert_result(res, Expect::Pass, Some("empty"))
+        .unwrap();
+}
+
+#[test]
+fn test_expand_template() {
+    let uuid = Uuid::parse_str("976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9").unwrap();
+    for (template, expected) in [
+        ("plain", "plain"),
+        ("{index}", "3"),
+        ("{index+4}", "7"),
+        ("0x{index+12:x}", "0xf"),
+        ("{index:x}-{uuid}", "3-976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9"),
+        ("{{index}}", "{index}"),
+    ] {
+        assert_eq!(
+            expected,
+            crate::mdev::expand_template(template, 3, &uuid).unwrap()
+        );
+    }
+    for template in ["{", "}", "{unknown}", "{index+}", "{index-1}", "{uuid:x}"] {
+        assert!(crate::mdev::expand_template(template, 3, &uuid).is_err());
+    }
+}
+
+#[test]
+fn test_define_count() {
+    init();
+
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("define-count", "default");
+    let jsonfile = Some(test.datapath.join("template.json"));
+    let res = crate::define_command(
+        test.clone(),
+        &DefineArgs {
+            parent: Some(PARENT.to_string()),
+            jsonfile: jsonfile.clone(),
+            count: 3,
+            ..Default::default()
+        },
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+
+    // every device got its own values
+    let devices = test
+        .clone()
+        .get_defined_devices(None, None)
+        .expect("failed to query defined devices")
+        .into_values()
+        .flatten()
+        .collect::<Vec<_>>();
+    let mut domains = Vec::new();
+    for dev in devices.iter() {
+        assert_eq!(
+            (
+                "description".to_string(),
+                format!("{{{}}} #", dev.uuid.hyphenated())
+            ),
+            (
+                dev.attrs[2].0.clone(),
+                dev.attrs[2]
+                    .1
+                    .trim_end_matches(char::is_numeric)
+                    .to_string()
+            )
+        );
+        domains.push(dev.attrs[1].1.clone());
+    }
+    domains.sort();
+    assert_eq!(vec!["0xa", "0xb", "0xc"], domains);
+
+    // a UUID can only be assigned to a single device
+    let res = crate::define_command(
+        test.clone(),
+        &DefineArgs {
+            uuid: Some(Uuid::new_v4()),
+            parent: Some(PARENT.to_string()),
+            jsonfile: jsonfile.clone(),
+            count: 2,
+            ..Default::default()
+        },
+    );
+    let _ = test.assert_result(
+        res,
+        Expect::Fail(Some("A UUID cannot be assigned to more than one device")),
+        None,
+    );
+
+    // the templates of a single device are only expanded on request
+    for (expand_templates, domain) in [(false, "0x{index+10:x}"), (true, "0xa")] {
+        let uuid = Uuid::new_v4();
+        let res = crate::define_command(
+            test.clone(),
+            &DefineArgs {
+                uuid: Some(uuid),
+                parent: Some(PARENT.to_string()),
+                jsonfile: jsonfile.clone(),
+                expand_templates,
+                ..Default::default()
+            },
+        );
+        test.assert_result(res, Expect::Pass, None).unwrap();
+        let dev = test
+            .clone()
+            .get_defined_device(uuid, Some(&PARENT.to_string()))
+            .expect("failed to query defined device");
+        assert_eq!(domain, dev.attrs[1].1);
+    }
+}
+
+#[test]
+fn test_define_count_rollback() {
+    init();
+
+    const PARENT: &str = "0000:00:03.0";
+
+    // the 'vm' label is unique, so only the first of the devices can be defined
+    let test = TestEnvironment::new("define-count", "rollback");
+    fs::write(test.labels_path(), r#"{"unique": ["vm"]}"#).expect("Unable to write labels");
+    let res = crate::define_command(
+        test.clone(),
+        &DefineArgs {
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some("i915-GVTg_V5_4".to_string()),
+            label: vec![("vm".to_string(), "guest1".to_string())],
+            count: 3,
+            ..Default::default()
+        },
+    );
+    let _ = test.assert_result(
+        res,
+        Expect::Fail(Some(
+            "Failed to define device 2 of 3, the devices defined before were undefined again",
+        )),
+        None,
+    );
+    let devices = test
+        .clone()
+        .get_defined_devices(None, None)
+        .expect("failed to query defined devices");
+    assert!(devices.values().all(|d| d.is_empty()));
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_define_attr_merge() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let format = |dev: &MDev| {
+        dev.attrs
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+    };
+    let setup = |policy: Option<&str>| {
+        let test = TestEnvironment::new("define-attr-merge", "default");
+        test.populate_active_device(UUID, PARENT, "i915-GVTg_V5_4");
+        // provides attribute0=VALUE
+        test.populate_callout_script("good-json.sh");
+        if let Some(policy) = policy {
+            fs::write(test.policy_path(), policy).expect("Unable to write policy");
+        }
+        test
+    };
+    let define = |attr_merge: Option<AttrMerge>, policy: Option<&str>| {
+        let test = setup(policy);
+        let res = crate::define_command(
+            test.clone(),
+            &DefineArgs {
+                uuid: Some(Uuid::parse_str(UUID).unwrap()),
+                parent: Some(PARENT.to_string()),
+                jsonfile: Some(test.datapath.join("attrs.json")),
+                attr_merge,
+                ..Default::default()
+            },
+        );
+        test.assert_result(res, Expect::Pass, None).unwrap();
+        let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+        dev.parent = Some(PARENT.to_string());
+        dev.load_definition().unwrap();
+        format(&dev)
+    };
+    let attrs = |attr_merge: Option<AttrMerge>| define(attr_merge, None);
+    let list = |policy: Option<&str>| {
+        let test = setup(policy);
+        test.populate_defined_device(UUID, PARENT, "attrs.json");
+        let devs = test
+            .clone()
+            .get_active_devices(Some(&Uuid::parse_str(UUID).unwrap()), None)
+            .expect("failed to query active devices");
+        format(&devs[PARENT][0])
+    };
+
+    // a definition from a file is complete unless a policy is given
+    assert_eq!(vec!["attribute0=DEFINED", "attribute1=1"], attrs(None));
+    assert_eq!(
+        vec!["attribute0=DEFINED", "attribute1=1", "attribute0=VALUE"],
+        attrs(Some(AttrMerge::Append))
+    );
+    assert_eq!(
+        vec!["attribute0=VALUE", "attribute1=1"],
+        attrs(Some(AttrMerge::Replace))
+    );
+    assert_eq!(
+        vec!["attribute0=DEFINED", "attribute1=1"],
+        attrs(Some(AttrMerge::IgnoreDuplicates))
+    );
+    // the policy applies if no policy is given with the command
+    let replace = Some(r#"{"attr_merge": "replace"}"#);
+    assert_eq!(
+        vec!["attribute0=VALUE", "attribute1=1"],
+        define(None, replace)
+    );
+    assert_eq!(
+        vec!["attribute0=DEFINED", "attribute1=1", "attribute0=VALUE"],
+        define(Some(AttrMerge::Append), replace)
+    );
+
+    // without a policy, only the provided attributes of an active device are listed, otherwise
+    // they are merged into the defined ones
+    assert_eq!(vec!["attribute0=VALUE"], list(None));
+    assert_eq!(vec!["attribute0=VALUE", "attribute1=1"], list(replace));
+    assert_eq!(
+        vec!["attribute0=DEFINED", "attribute1=1"],
+        list(Some(r#"{"attr_merge": "ignore-duplicates"}"#))
+    );
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_define_print_only() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("define-print-only", "default");
+    let jsonfile = Some(test.datapath.join("template.json"));
+    let print_only = |uuid: Option<Uuid>, count: u32, force: bool| {
+        let mut outbuf: Vec<u8> = Default::default();
+        crate::define_print_only_command(
+            test.clone(),
+            &DefineArgs {
+                uuid,
+                parent: Some(PARENT.to_string()),
+                jsonfile: jsonfile.clone(),
+                label: vec![("vm".to_string(), "guest1".to_string())],
+                count,
+                force,
+                ..Default::default()
+            },
+            &mut outbuf,
+        )
+        .map(|_| String::from_utf8(outbuf).expect("invalid utf8 output"))
+    };
+
+    // the scripts are told that it is a dry run
+    test.populate_callout_script("dry-run.sh");
+    let res = print_only(Some(Uuid::parse_str(UUID).unwrap()), 1, false);
+    let output = test.assert_result(res, Expect::Pass, None).unwrap();
+    test.compare_to_file("uuid.json", &output);
+
+    // generated devices are printed along with their UUIDs
+    let res = print_only(None, 2, false);
+    let output = test.assert_result(res, Expect::Pass, None).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    let devs = json[0][PARENT].as_array().unwrap();
+    assert_eq!(2, devs.len());
+    assert_ne!(
+        devs[0].as_object().unwrap().keys().next(),
+        devs[1].as_object().unwrap().keys().next()
+    );
+
+    // nothing is saved
+    assert!(test
+        .clone()
+        .get_defined_devices(None, None)
+        .unwrap()
+        .is_empty());
+
+    // devices rejected by a script are not printed unless forced
+    fs::remove_file(test.callout_dir().join("dry-run.sh")).unwrap();
+    test.populate_callout_script("rc1.sh");
+    let res = print_only(Some(Uuid::parse_str(UUID).unwrap()), 1, false);
+    let _ = test.assert_result(res, Expect::Fail(None), None);
+    let res = print_only(Some(Uuid::parse_str(UUID).unwrap()), 1, true);
+    let output = test.assert_result(res, Expect::Pass, None).unwrap();
+    test.compare_to_file("uuid.json", &output);
+}
@@ -0,0 +1,71 @@
+use super::*;
+use std::fs;
+
+fn test_doctor_helper(subtest: &str, expect: Expect, setup: impl Fn(&Rc<TestEnvironment>)) {
+    let test = TestEnvironment::new("doctor", subtest);
+    setup(&test);
+
+    for (dumpjson, ext) in [(false, "text"), (true, "json")] {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::doctor_command(test.clone(), dumpjson, &mut outbuf);
+        let _ = test.assert_result(res, expect, Some(ext));
+        let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+        test.compare_to_file(&format!("{}.{}", subtest, ext), &actual);
+    }
+}
+
+#[test]
+fn test_doctor() {
+    init();
+
+    // a bare environment only lacks parent devices
+    test_doctor_helper("empty", Expect::Pass, |_| {});
+
+    test_doctor_helper("healthy", Expect::Pass, |test| {
+        test.populate_parent_device(
+            "0000:00:02.0",
+            "vfio_ap-passthrough",
+            1,
+            "vfio-ap",
+            "",
+            None,
+        );
+        test.populate_defined_device(
+            "11111111-1111-0000-0000-000000000000",
+            "0000:00:02.0",
+            "defined.json",
+        );
+        test.populate_callout_script("ver-rc0.sh");
+        test.populate_callout_script_full("rc0.sh", None, false);
+        fs::write(
+            test.callout_limits_path(),
+            r#"{"script_users": {"vendor.sh": "root"}}"#,
+        )
+        .expect("Unable to write callout limits");
+    });
+
+    test_doctor_helper(
+        "broken",
+        Expect::Fail(Some("3 of 8 checks failed")),
+        |test| {
+            fs::remove_dir(test.notification_dir()).expect("Unable to remove notification dir");
+            // a script that responds with an invalid capabilities document
+            test.populate_defined_device(
+                "11111111-1111-0000-0000-ffffffffffff",
+                "0000:00:02.0",
+                "defined.json",
+            );
+            test.populate_callout_script("ver-rc0.sh");
+            fs::write(
+                test.old_callout_dir().join("not-executable.sh"),
+                "#!/bin/sh\n",
+            )
+            .expect("Unable to write callout script");
+            fs::write(
+                test.callout_limits_path(),
+                r#"{"script_users": {"vendor.sh": "mdevctl-no-such-user"}}"#,
+            )
+            .expect("Unable to write callout limits");
+        },
+    );
+}
@@ -0,0 +1,77 @@
+use super::*;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use uuid::Uuid;
+
+const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+const PARENT: &str = "0000:00:02.0";
+
+fn edit_helper(test: &Rc<TestEnvironment>, editor: &str, expect: Expect) -> Option<String> {
+    let editor = match editor {
+        "true" => editor.to_string(),
+        _ => format!("{}/edit/{}", TEST_DATA_DIR, editor),
+    };
+    let res = crate::edit_command(
+        test.clone(),
+        Uuid::parse_str(UUID).unwrap(),
+        None,
+        &editor,
+        false,
+    );
+    test.assert_result(res, expect, None)
+        .err()
+        .map(|e| format!("{:#}", e))
+}
+
+fn read_definition(test: &Rc<TestEnvironment>) -> serde_json::Value {
+    let path = test.config_base().join(PARENT).join(UUID);
+    serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+}
+
+#[test]
+fn test_edit() {
+    init();
+
+    let test = TestEnvironment::new("edit", "default");
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    test.populate_callout_script("reject-type-b.sh");
+    let original = read_definition(&test);
+
+    // nothing happens if the definition isn't changed
+    edit_helper(&test, "true", Expect::Pass);
+    assert_eq!(original, read_definition(&test));
+
+    // changes are discarded if the editor fails
+    edit_helper(
+        &test,
+        "abort.sh",
+        Expect::Fail(Some(
+            "Failed to edit the definition, no changes were applied",
+        )),
+    );
+    assert_eq!(original, read_definition(&test));
+
+    // invalid definitions are not applied, but kept in a file only root can access
+    let e = edit_helper(&test, "invalid.sh", Expect::Fail(None)).unwrap();
+    assert_eq!(original, read_definition(&test));
+    let kept = PathBuf::from(e.split('"').nth(1).expect("kept file not reported"));
+    let mode = fs::metadata(&kept).unwrap().permissions().mode();
+    assert_eq!(0o600, mode & 0o777);
+    let dir = kept.parent().unwrap();
+    assert_eq!(
+        0o700,
+        fs::metadata(dir).unwrap().permissions().mode() & 0o777
+    );
+    fs::remove_dir_all(dir).unwrap();
+
+    // changes rejected by a callout script are not applied
+    let e = edit_helper(&test, "set-type-b.sh", Expect::Fail(None)).unwrap();
+    assert_eq!(original, read_definition(&test));
+    let kept = PathBuf::from(e.split('"').nth(1).expect("kept file not reported"));
+    fs::remove_dir_all(kept.parent().unwrap()).unwrap();
+
+    edit_helper(&test, "set-auto.sh", Expect::Pass);
+    let mut expected = original;
+    expected["start"] = "auto".into();
+    assert_eq!(expected, read_definition(&test));
+}
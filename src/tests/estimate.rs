@@ -0,0 +1,111 @@
+use super::*;
+
+fn test_estimate_helper(
+    test: &Rc<TestEnvironment>,
+    subtest: &str,
+    expect: Expect,
+    parent: &str,
+    mdev_type: &str,
+    count: u32,
+) {
+    for (dumpjson, ext) in [(false, "text"), (true, "json")] {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::estimate_command(
+            test.clone(),
+            parent.to_string(),
+            mdev_type.to_string(),
+            count,
+            dumpjson,
+            &mut outbuf,
+        );
+        if test.assert_result(res, expect, Some(ext)).is_ok() {
+            let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+            test.compare_to_file(&format!("{}.{}", subtest, ext), &actual);
+        }
+    }
+}
+
+#[test]
+fn test_estimate() {
+    init();
+
+    let test = TestEnvironment::new("estimate", "default");
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    // the active device of the type doesn't take more of the available instances
+    test.populate_active_device(UUID1, "0000:00:03.0", "nvidia-35");
+    test.populate_defined_device(UUID1, "0000:00:03.0", "defined.json");
+    test.populate_defined_device(UUID2, "0000:00:03.0", "defined.json");
+    test.populate_parent_device(
+        "0000:00:03.0",
+        "nvidia-35",
+        6,
+        "vfio-pci",
+        "GRID P40-2B",
+        Some(
+            "num_heads=4, frl_config=45, framebuffer=2048M, max_resolution=5120x2880, \
+             max_instance=12",
+        ),
+    );
+    test.populate_parent_device(
+        "0000:00:03.0",
+        "nvidia-36",
+        16,
+        "vfio-pci",
+        "GRID P40-1Q",
+        None,
+    );
+    test.populate_parent_device(
+        "0000:00:02.0",
+        "i915-GVTg_V5_4",
+        2,
+        "vfio-pci",
+        "",
+        Some(
+            "low_gm_size: 128MB\nhigh_gm_size: 384MB\nfence: 4\nresolution: 1920x1200\nweight: 4\n",
+        ),
+    );
+
+    test_estimate_helper(
+        &test,
+        "nvidia",
+        Expect::Pass,
+        "0000:00:03.0",
+        "nvidia-35",
+        4,
+    );
+    test_estimate_helper(
+        &test,
+        "gvt",
+        Expect::Pass,
+        "0000:00:02.0",
+        "i915-GVTg_V5_4",
+        3,
+    );
+    test_estimate_helper(
+        &test,
+        "no-description",
+        Expect::Pass,
+        "0000:00:03.0",
+        "nvidia-36",
+        1,
+    );
+    test_estimate_helper(
+        &test,
+        "unsupported-type",
+        Expect::Fail(Some(
+            "Parent 0000:00:03.0 does not support mdev type i915-GVTg_V5_4",
+        )),
+        "0000:00:03.0",
+        "i915-GVTg_V5_4",
+        1,
+    );
+    test_estimate_helper(
+        &test,
+        "missing-parent",
+        Expect::Fail(Some("Parent device 0000:00:04.0 does not exist")),
+        "0000:00:04.0",
+        "nvidia-35",
+        1,
+    );
+}
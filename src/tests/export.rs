@@ -0,0 +1,89 @@
+use super::*;
+use crate::bulk::OnFailure;
+
+const UUID: &[&str] = &[
+    "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+    "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    "4a0a190f-dcf3-4def-9342-c48768f0c940",
+];
+const PARENT: &[&str] = &["0000:00:02.0", "0000:00:03.0"];
+
+fn setup(test: &Rc<TestEnvironment>) {
+    test.populate_defined_device(UUID[0], PARENT[0], "auto.json");
+    test.populate_defined_device(UUID[1], PARENT[0], "manual.json");
+    test.populate_defined_device(UUID[2], PARENT[1], "manual.json");
+}
+
+fn export(test: &Rc<TestEnvironment>) -> String {
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::export_command(test.clone(), &mut outbuf);
+    test.assert_result(res, Expect::Pass, Some("export"))
+        .unwrap();
+    String::from_utf8(outbuf).expect("invalid utf8 output")
+}
+
+fn test_import_helper(testname: &str, expect: Expect, file: &str, force: bool) {
+    let test = TestEnvironment::new("export", testname);
+    setup(&test);
+    let before = export(&test);
+
+    let res = crate::import_command(
+        test.clone(),
+        test.datapath.join(file),
+        force,
+        OnFailure::Cancel,
+    );
+    match test.assert_result(res, expect, None) {
+        Ok(_) => test.compare_to_file(&format!("{}.json", testname), &export(&test)),
+        // nothing is imported if any device fails
+        Err(_) => assert_eq!(before, export(&test)),
+    }
+}
+
+#[test]
+fn test_export_import() {
+    init();
+
+    let test = TestEnvironment::new("export", "export");
+    assert_eq!("[]\n", export(&test));
+    setup(&test);
+    let exported = export(&test);
+    test.compare_to_file("export.json", &exported);
+
+    // the exported devices are defined identically on another host
+    let other = TestEnvironment::new("export", "import");
+    let res = crate::import_command(
+        other.clone(),
+        test.datapath.join("export.json"),
+        false,
+        OnFailure::Cancel,
+    );
+    other.assert_result(res, Expect::Pass, None).unwrap();
+    assert_eq!(exported, export(&other));
+    for (uuid, parent) in [
+        (UUID[0], PARENT[0]),
+        (UUID[1], PARENT[0]),
+        (UUID[2], PARENT[1]),
+    ] {
+        assert!(other.config_base().join(parent).join(uuid).exists());
+    }
+
+    test_import_helper(
+        "collision",
+        Expect::Fail(Some(
+            "Device 976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9 on 0000:00:02.0 already defined, use \
+             --force to overwrite it",
+        )),
+        "import-changed.json",
+        false,
+    );
+    test_import_helper("collision-force", Expect::Pass, "import-changed.json", true);
+    test_import_helper(
+        "invalid",
+        Expect::Fail(Some(
+            "Invalid definition of 0000:00:02.0/976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        )),
+        "invalid.json",
+        true,
+    );
+}
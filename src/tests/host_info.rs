@@ -0,0 +1,43 @@
+use super::*;
+
+fn test_host_info_helper(subtest: &str, setup: impl Fn(&Rc<TestEnvironment>)) {
+    let test = TestEnvironment::new("host-info", subtest);
+    setup(&test);
+
+    for (dumpjson, ext) in [(false, "text"), (true, "json")] {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::host_info_command(test.clone(), dumpjson, &mut outbuf);
+        test.assert_result(res, Expect::Pass, Some(ext)).unwrap();
+        let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+        test.compare_to_file(&format!("{}.{}", subtest, ext), &actual);
+    }
+}
+
+#[test]
+fn test_host_info() {
+    init();
+
+    // nothing is known about the host
+    test_host_info_helper("empty", |_| {});
+
+    test_host_info_helper("ready", |test| {
+        let osrelease = test.osrelease_path();
+        fs::create_dir_all(osrelease.parent().unwrap()).expect("Unable to setup proc dir");
+        fs::write(osrelease, "6.1.0-test\n").expect("Unable to write osrelease");
+        fs::write(
+            test.cmdline_path(),
+            "BOOT_IMAGE=/vmlinuz root=/dev/vda1 intel_iommu=on iommu=pt quiet",
+        )
+        .expect("Unable to write cmdline");
+        for module in ["mdev", "vfio", "vfio_iommu_type1", "vfio-pci"] {
+            fs::create_dir_all(test.module_base().join(module))
+                .expect("Unable to setup module dir");
+        }
+        for group in ["0", "1", "2"] {
+            fs::create_dir_all(test.iommu_groups_base().join(group))
+                .expect("Unable to setup iommu group");
+        }
+        test.populate_parent_device("0000:00:02.0", "type_a", 1, "vfio-pci", "", None);
+        test.populate_parent_device("0000:00:03.0", "type_a", 1, "vfio-pci", "", None);
+    });
+}
@@ -0,0 +1,125 @@
+use super::*;
+use proptest::prelude::*;
+
+const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+const PARENT: &str = "0000:00:02.0";
+
+// arbitrary JSON values, including deeply nested arrays and objects
+fn arb_json() -> impl Strategy<Value = serde_json::Value> {
+    let leaf = prop_oneof![
+        Just(serde_json::Value::Null),
+        any::<bool>().prop_map(serde_json::Value::from),
+        any::<i64>().prop_map(serde_json::Value::from),
+        any::<f64>().prop_map(serde_json::Value::from),
+        ".*".prop_map(serde_json::Value::from),
+    ];
+    leaf.prop_recursive(4, 32, 8, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..8).prop_map(serde_json::Value::from),
+            prop::collection::btree_map(".*", inner, 0..8)
+                .prop_map(|m| serde_json::Value::Object(m.into_iter().collect())),
+        ]
+    })
+}
+
+// device configurations with the required fields and arbitrary values for all other fields
+fn arb_config() -> impl Strategy<Value = serde_json::Value> {
+    (
+        ".*",
+        prop_oneof![Just("auto".to_string()), Just("manual".to_string())],
+        prop::collection::vec(arb_json(), 0..8),
+    )
+        .prop_map(|(mdev_type, start, attrs)| {
+            serde_json::json!({"mdev_type": mdev_type, "start": start, "attrs": attrs})
+        })
+}
+
+fn new_device() -> MDev {
+    let test = TestEnvironment::new("json", "proptest");
+    MDev::new(test, Uuid::parse_str(UUID).unwrap())
+}
+
+proptest! {
+    #[test]
+    fn load_from_json_arbitrary(json in prop_oneof![arb_json(), arb_config()]) {
+        // must never panic, only succeed or fail with an error
+        let mut dev = new_device();
+        if dev.load_from_json(PARENT.to_string(), &json).is_err() {
+            prop_assert!(dev.attrs.is_empty());
+        }
+    }
+
+    #[test]
+    fn add_attributes_arbitrary(attrs in arb_json()) {
+        let mut dev = new_device();
+        match dev.add_attributes(&attrs) {
+            Ok(_) => prop_assert_eq!(attrs.as_array().map_or(0, |a| a.len()), dev.attrs.len()),
+            // attributes are either all added or none
+            Err(_) => prop_assert!(dev.attrs.is_empty()),
+        }
+    }
+
+    #[test]
+    fn attributes_roundtrip(
+        mdev_type in "[a-z0-9_-]{1,16}",
+        attrs in prop::collection::vec((".*", ".*"), 0..16),
+    ) {
+        let json = serde_json::json!({
+            "mdev_type": mdev_type,
+            "start": "manual",
+            "attrs": attrs
+                .iter()
+                .map(|(k, v)| serde_json::json!({ k: v }))
+                .collect::<Vec<_>>(),
+        });
+        let mut dev = new_device();
+        dev.load_from_json(PARENT.to_string(), &json).unwrap();
+        prop_assert_eq!(&attrs, &dev.attrs);
+
+        let mut reloaded = new_device();
+        reloaded
+            .load_from_json(PARENT.to_string(), &dev.to_json(false).unwrap())
+            .unwrap();
+        prop_assert_eq!(&attrs, &reloaded.attrs);
+    }
+
+    #[test]
+    fn parse_capabilities_arbitrary(stdout in prop::collection::vec(any::<u8>(), 0..256)) {
+        let _ = CalloutScriptCache::parse_script_capabilities(&stdout);
+    }
+
+    #[test]
+    fn parse_capabilities_arbitrary_json(json in arb_json()) {
+        let _ = CalloutScriptCache::parse_script_capabilities(json.to_string().as_bytes());
+    }
+}
+
+// every malformed configuration in testdata/load-json-invalid is declared by a `<case>.json`
+// file and the error message it is expected to produce in `<case>.err`
+#[test]
+fn test_load_json_invalid() {
+    init();
+
+    let test = TestEnvironment::new("load-json-invalid", "default");
+    let mut cases = test
+        .datapath
+        .read_dir()
+        .expect("Unable to read test data")
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "json"))
+        .collect::<Vec<_>>();
+    cases.sort();
+    assert!(!cases.is_empty());
+
+    for case in cases {
+        let casename = case.file_stem().unwrap().to_str().unwrap().to_string();
+        let contents = fs::read_to_string(&case).expect("Unable to read test case");
+        let json: serde_json::Value = serde_json::from_str(&contents).expect("Invalid test case");
+        let mut dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+        let res = dev.load_from_json(PARENT.to_string(), &json);
+        let expected = fs::read_to_string(case.with_extension("err"))
+            .unwrap_or_else(|_| panic!("Missing expected error for {}", casename));
+        let e = res.expect_err(&format!("Expected {} to fail", casename));
+        assert_eq!(expected.trim_end(), format!("{:#}", e), "{}", casename);
+    }
+}
@@ -0,0 +1,248 @@
+use super::*;
+use crate::cli::{DefineArgs, DefinitionChanges, ModifyArgs};
+use uuid::Uuid;
+
+const UUID: &[&str] = &[
+    "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+    "59e8b599-afdd-4766-a59e-415ef4f5a492",
+];
+const PARENT: &[&str] = &["0000:00:02.0", "0000:00:03.0"];
+const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+fn label(key: &str, value: &str) -> (String, String) {
+    (key.to_string(), value.to_string())
+}
+
+// set up an environment where the 'vm' label must be unique and a device with the label
+// vm=guest1 is defined
+fn setup_labels(testcase: &str) -> Rc<TestEnvironment> {
+    let test = TestEnvironment::new("labels", testcase);
+    fs::copy(test.datapath.join("labels.json"), test.labels_path())
+        .expect("Unable to copy label configuration");
+    test.populate_defined_device(UUID[0], PARENT[0], "labeled.json");
+    test
+}
+
+fn test_define_labels_helper(
+    testcase: &str,
+    expect: Expect,
+    uuid: &str,
+    parent: &str,
+    labels: Vec<(String, String)>,
+) {
+    let test = setup_labels(testcase);
+    let uuid = Uuid::parse_str(uuid).unwrap();
+    let res = crate::define_command(
+        test.clone(),
+        &DefineArgs {
+            uuid: Some(uuid),
+            parent: Some(parent.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            label: labels,
+            ..Default::default()
+        },
+    );
+    if test.assert_result(res, expect, None).is_err() {
+        return;
+    }
+    let def = test
+        .clone()
+        .get_defined_device(uuid, Some(&parent.to_string()))
+        .expect("Couldn't find defined device");
+    let contents = fs::read_to_string(def.persist_path().unwrap()).unwrap();
+    test.compare_to_file(&format!("{}.expected", testcase), &contents);
+}
+
+#[test]
+fn test_define_labels() {
+    init();
+
+    test_define_labels_helper(
+        "define",
+        Expect::Pass,
+        UUID[1],
+        PARENT[0],
+        vec![label("vm", "guest2"), label("tier", "gold")],
+    );
+    test_define_labels_helper(
+        "define-duplicate",
+        Expect::Fail(Some(
+            "Label vm=guest1 is already assigned to device \
+             976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9 on parent 0000:00:02.0",
+        )),
+        UUID[1],
+        PARENT[0],
+        vec![label("vm", "guest1")],
+    );
+    // only the 'vm' label is unique
+    test_define_labels_helper(
+        "define-not-unique",
+        Expect::Pass,
+        UUID[1],
+        PARENT[0],
+        vec![label("tier", "gold")],
+    );
+    // definitions of the same device on other parents may share unique labels
+    test_define_labels_helper(
+        "define-same-uuid",
+        Expect::Pass,
+        UUID[0],
+        PARENT[1],
+        vec![label("vm", "guest1")],
+    );
+    test_define_labels_helper(
+        "define-invalid-key",
+        Expect::Fail(Some(
+            "invalid label key 'my vm': only letters, digits, '.', '_', '-' and '/' are allowed",
+        )),
+        UUID[1],
+        PARENT[0],
+        vec![label("my vm", "guest2")],
+    );
+}
+
+fn test_modify_labels_helper(
+    testcase: &str,
+    expect: Expect,
+    labels: Vec<(String, String)>,
+    remove_labels: Vec<&str>,
+) {
+    let test = setup_labels(testcase);
+    test.populate_defined_device(UUID[1], PARENT[0], "labeled.json");
+    let uuid = Uuid::parse_str(UUID[1]).unwrap();
+    let res = crate::modify_command(
+        test.clone(),
+        uuid,
+        ModifyArgs {
+            changes: DefinitionChanges {
+                label: labels,
+                remove_label: remove_labels.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        &mut std::io::sink(),
+    );
+    if test.assert_result(res, expect, None).is_err() {
+        return;
+    }
+    let def = test
+        .clone()
+        .get_defined_device(uuid, None)
+        .expect("Couldn't find defined device");
+    let contents = fs::read_to_string(def.persist_path().unwrap()).unwrap();
+    test.compare_to_file(&format!("{}.expected", testcase), &contents);
+}
+
+#[test]
+fn test_modify_labels() {
+    init();
+
+    // the device copied from the same definition as UUID[0] has a conflicting label until it is
+    // changed
+    test_modify_labels_helper(
+        "modify-unchanged",
+        Expect::Fail(Some(
+            "Label vm=guest1 is already assigned to device \
+             976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9 on parent 0000:00:02.0",
+        )),
+        vec![label("tier", "silver")],
+        vec![],
+    );
+    test_modify_labels_helper(
+        "modify",
+        Expect::Pass,
+        vec![label("vm", "guest2")],
+        vec!["tier"],
+    );
+    test_modify_labels_helper("modify-remove", Expect::Pass, vec![], vec!["tier", "vm"]);
+    test_modify_labels_helper(
+        "modify-remove-missing",
+        Expect::Fail(Some(
+            "Device 59e8b599-afdd-4766-a59e-415ef4f5a492 has no label 'owner'",
+        )),
+        vec![label("vm", "guest2")],
+        vec!["owner"],
+    );
+}
+
+fn test_clone_labels_helper(testcase: &str, expect: Expect, labels: Vec<(String, String)>) {
+    let test = setup_labels(testcase);
+    let uuid = Uuid::parse_str(UUID[1]).unwrap();
+    let res = crate::clone_command(
+        test.clone(),
+        Uuid::parse_str(UUID[0]).unwrap(),
+        None,
+        Some(uuid),
+        None,
+        labels,
+        false,
+    );
+    if test.assert_result(res, expect, None).is_err() {
+        return;
+    }
+    let def = test
+        .clone()
+        .get_defined_device(uuid, None)
+        .expect("Couldn't find defined device");
+    let contents = fs::read_to_string(def.persist_path().unwrap()).unwrap();
+    test.compare_to_file(&format!("{}.expected", testcase), &contents);
+}
+
+#[test]
+fn test_clone_labels() {
+    init();
+
+    // the unique 'vm' label is not copied
+    test_clone_labels_helper("clone", Expect::Pass, vec![]);
+    test_clone_labels_helper("clone-label", Expect::Pass, vec![label("vm", "guest2")]);
+    test_clone_labels_helper(
+        "clone-duplicate",
+        Expect::Fail(Some(
+            "Label vm=guest1 is already assigned to device \
+             976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9 on parent 0000:00:02.0",
+        )),
+        vec![label("vm", "guest1")],
+    );
+}
+
+#[test]
+fn test_list_selector() {
+    init();
+
+    let test = TestEnvironment::new("labels", "selector");
+    test.populate_defined_device(UUID[0], PARENT[0], "labeled.json");
+    test.populate_defined_device(UUID[1], PARENT[1], "device.json");
+    test.populate_active_device(UUID[0], PARENT[0], MDEV_TYPE);
+
+    for (subtest, defined, selector) in [
+        ("selector-defined", true, vec![label("tier", "gold")]),
+        (
+            "selector-defined-all",
+            true,
+            vec![label("tier", "gold"), label("vm", "guest1")],
+        ),
+        (
+            "selector-defined-none",
+            true,
+            vec![label("tier", "gold"), label("vm", "guest2")],
+        ),
+        ("selector-active", false, vec![label("vm", "guest1")]),
+    ] {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::list_command(
+            test.clone(),
+            &LsmdevOptions {
+                defined,
+                verbose: true,
+                selector,
+                ..Default::default()
+            },
+            &mut outbuf,
+        );
+        test.assert_result(res, Expect::Pass, Some(subtest))
+            .unwrap();
+        let actual = String::from_utf8(outbuf).expect("failed to convert list output from utf8");
+        test.compare_to_file(&format!("{}.text", subtest), &actual);
+    }
+}
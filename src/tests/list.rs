@@ -1,4 +1,8 @@
 use super::*;
+use crate::cli::ListColumn;
+#[cfg(feature = "rich-output")]
+use crate::cli::{ListOutput, ListSort, OutputFormat};
+#[cfg(feature = "rich-output")]
 use uuid::Uuid;
 
 #[test]
@@ -13,28 +17,37 @@ fn test_invalid_files() {
     let test = TestEnvironment::new("invalid-files", "invalid-active");
     let env: Rc<dyn Environment> = test.clone();
     test.populate_active_device("invalid-uuid-value", PARENT, MDEV_TYPE);
-    let result = crate::list_command(env.clone(), false, false, false, None, None, &mut outbuf);
+    let result = crate::list_command(env.clone(), &LsmdevOptions::default(), &mut outbuf);
     assert!(result.is_ok());
 
     let test = TestEnvironment::new("invalid-files", "invalid-defined");
     test.populate_defined_device("invalid-uuid-value", PARENT, "device.json");
-    let result = crate::list_command(env.clone(), true, false, false, None, None, &mut outbuf);
+    let result = crate::list_command(
+        env.clone(),
+        &LsmdevOptions {
+            defined: true,
+            ..Default::default()
+        },
+        &mut outbuf,
+    );
     assert!(result.is_ok());
 }
 
+#[cfg(feature = "rich-output")]
 fn test_list_helper<F>(
     subtest: &str,
     expect: Expect,
     defined: bool,
     verbose: bool,
     uuid: Option<String>,
-    parent: Option<String>,
+    parents: &[&str],
     setupfn: F,
 ) where
     F: Fn(&Rc<TestEnvironment>),
 {
     use crate::list_command;
     let uuid = uuid.map(|s| Uuid::parse_str(s.as_ref()).unwrap());
+    let parents = parents.iter().map(|p| p.to_string()).collect::<Vec<_>>();
     let test = TestEnvironment::new("list", "default");
     let env: Rc<dyn Environment> = test.clone();
 
@@ -43,14 +56,16 @@ fn test_list_helper<F>(
     let mut outbuf: Vec<u8> = Default::default();
     let res = list_command(
         env.clone(),
-        defined,
-        false,
-        verbose,
-        uuid,
-        parent.clone(),
+        &LsmdevOptions {
+            defined,
+            verbose,
+            uuid,
+            parent: parents.clone(),
+            ..Default::default()
+        },
         &mut outbuf,
     );
-    if let Ok(_) = test.assert_result(res, expect, Some("json")) {
+    if test.assert_result(res, expect, Some("json")).is_ok() {
         let actual = String::from_utf8(outbuf).expect("failed to convert list output from utf8");
         test.compare_to_file(&format!("{}.text", subtest), &actual);
     }
@@ -58,20 +73,48 @@ fn test_list_helper<F>(
     let mut outbuf: Vec<u8> = Default::default();
     let res = list_command(
         env.clone(),
-        defined,
-        true,
-        verbose,
-        uuid,
-        parent.clone(),
+        &LsmdevOptions {
+            defined,
+            output: Some(ListOutput::Format(OutputFormat::Json)),
+            verbose,
+            uuid,
+            parent: parents.clone(),
+            ..Default::default()
+        },
         &mut outbuf,
     );
-    if let Ok(_) = test.assert_result(res, expect, Some("text")) {
+    if test.assert_result(res, expect, Some("text")).is_ok() {
         let actual = String::from_utf8(outbuf).expect("failed to convert list output from utf8");
         test.compare_to_file(&format!("{}.json", subtest), &actual);
     }
 }
 
+#[cfg(all(feature = "callouts", feature = "rich-output"))]
+fn test_list_yaml_helper<F>(subtest: &str, defined: bool, uuid: Option<&str>, setupfn: F)
+where
+    F: Fn(&Rc<TestEnvironment>),
+{
+    let test = TestEnvironment::new("list", "default");
+    setupfn(&test);
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::list_command(
+        test.clone(),
+        &LsmdevOptions {
+            defined,
+            output: Some(ListOutput::Format(OutputFormat::Yaml)),
+            uuid: uuid.map(|u| Uuid::parse_str(u).unwrap()),
+            ..Default::default()
+        },
+        &mut outbuf,
+    );
+    test.assert_result(res, Expect::Pass, Some("yaml")).unwrap();
+    let actual = String::from_utf8(outbuf).expect("failed to convert list output from utf8");
+    test.compare_to_file(&format!("{}.yaml", subtest), &actual);
+}
+
 #[test]
+#[cfg(all(feature = "callouts", feature = "rich-output"))]
 fn test_list() {
     init();
 
@@ -86,24 +129,8 @@ fn test_list() {
     const MDEV_TYPE: &[&str] = &["arbitrary_type1", "arbitrary_type2"];
 
     // first test with an empty environment -- nothing defined, nothing active
-    test_list_helper(
-        "active-none",
-        Expect::Pass,
-        false,
-        false,
-        None,
-        None,
-        |_| {},
-    );
-    test_list_helper(
-        "defined-none",
-        Expect::Pass,
-        true,
-        false,
-        None,
-        None,
-        |_| {},
-    );
+    test_list_helper("active-none", Expect::Pass, false, false, None, &[], |_| {});
+    test_list_helper("defined-none", Expect::Pass, true, false, None, &[], |_| {});
 
     // now setup test environment with some active devices and some defined devices. Include
     // multiple parents, multiple types, some parents with multiple devices, some with same UUID on
@@ -116,14 +143,14 @@ fn test_list() {
         test.populate_defined_device(UUID[3], PARENT[0], "device1.json");
     };
 
-    test_list_helper("active", Expect::Pass, false, false, None, None, setup);
+    test_list_helper("active", Expect::Pass, false, false, None, &[], setup);
     test_list_helper(
         "active-verbose",
         Expect::Pass,
         false,
         true,
         None,
-        None,
+        &[],
         setup,
     );
     test_list_helper(
@@ -132,7 +159,7 @@ fn test_list() {
         false,
         false,
         None,
-        Some(PARENT[0].to_string()),
+        &[PARENT[0]],
         setup,
     );
     test_list_helper(
@@ -141,7 +168,7 @@ fn test_list() {
         false,
         true,
         None,
-        Some(PARENT[0].to_string()),
+        &[PARENT[0]],
         setup,
     );
     test_list_helper(
@@ -150,7 +177,7 @@ fn test_list() {
         false,
         false,
         Some(UUID[0].to_string()),
-        None,
+        &[],
         setup,
     );
     test_list_helper(
@@ -159,7 +186,7 @@ fn test_list() {
         false,
         true,
         Some(UUID[0].to_string()),
-        None,
+        &[],
         setup,
     );
     test_list_helper(
@@ -168,7 +195,7 @@ fn test_list() {
         false,
         false,
         Some(UUID[0].to_string()),
-        Some(PARENT[0].to_string()),
+        &[PARENT[0]],
         setup,
     );
     test_list_helper(
@@ -177,17 +204,21 @@ fn test_list() {
         false,
         true,
         Some(UUID[0].to_string()),
-        Some(PARENT[0].to_string()),
+        &[PARENT[0]],
         setup,
     );
-    test_list_helper("defined", Expect::Pass, true, false, None, None, setup);
+    test_list_helper("defined", Expect::Pass, true, false, None, &[], setup);
+    test_list_yaml_helper("active", false, None, setup);
+    test_list_yaml_helper("defined", true, None, setup);
+    test_list_yaml_helper("defined-uuid", true, Some(UUID[2]), setup);
+    test_list_yaml_helper("defined-none", true, None, |_| {});
     test_list_helper(
         "defined-verbose",
         Expect::Pass,
         true,
         true,
         None,
-        None,
+        &[],
         setup,
     );
     test_list_helper(
@@ -196,7 +227,7 @@ fn test_list() {
         true,
         false,
         None,
-        Some(PARENT[0].to_string()),
+        &[PARENT[0]],
         setup,
     );
     test_list_helper(
@@ -205,7 +236,7 @@ fn test_list() {
         true,
         true,
         None,
-        Some(PARENT[0].to_string()),
+        &[PARENT[0]],
         setup,
     );
     test_list_helper(
@@ -214,7 +245,7 @@ fn test_list() {
         true,
         false,
         Some(UUID[3].to_string()),
-        None,
+        &[],
         setup,
     );
     test_list_helper(
@@ -223,7 +254,7 @@ fn test_list() {
         true,
         true,
         Some(UUID[3].to_string()),
-        None,
+        &[],
         setup,
     );
     test_list_helper(
@@ -232,7 +263,7 @@ fn test_list() {
         true,
         false,
         Some(UUID[3].to_string()),
-        Some(PARENT[0].to_string()),
+        &[PARENT[0]],
         setup,
     );
     test_list_helper(
@@ -241,7 +272,7 @@ fn test_list() {
         true,
         true,
         Some(UUID[3].to_string()),
-        Some(PARENT[0].to_string()),
+        &[PARENT[0]],
         setup,
     );
     test_list_helper(
@@ -250,7 +281,7 @@ fn test_list() {
         true,
         true,
         Some("466983a3-1240-4543-8d02-01c29a08fb0c".to_string()),
-        None,
+        &[],
         setup,
     );
     test_list_helper(
@@ -259,10 +290,52 @@ fn test_list() {
         true,
         true,
         None,
-        Some("nonexistent".to_string()),
+        &["nonexistent"],
+        setup,
+    );
+    test_list_helper(
+        "active-parent-multiple",
+        Expect::Pass,
+        false,
+        false,
+        None,
+        &[PARENT[0], PARENT[1]],
+        setup,
+    );
+    test_list_helper(
+        "defined-parent-multiple",
+        Expect::Pass,
+        true,
+        false,
+        None,
+        &[PARENT[1], "nonexistent", PARENT[0]],
         setup,
     );
 
+    // definitions whose parent device is not present are marked as such
+    let setup_missing = |test: &Rc<TestEnvironment>| {
+        setup(test);
+        test.populate_defined_device(UUID[4], "0000:00:04.0", "device1.json");
+    };
+    test_list_helper(
+        "defined-parent-missing",
+        Expect::Pass,
+        true,
+        false,
+        None,
+        &[],
+        setup_missing,
+    );
+    test_list_helper(
+        "defined-parent-missing-uuid",
+        Expect::Pass,
+        true,
+        false,
+        Some(UUID[4].to_string()),
+        &[],
+        setup_missing,
+    );
+
     // test list with the Get Attributes callout
     test_list_helper(
         "active-callout",
@@ -270,7 +343,7 @@ fn test_list() {
         false,
         false,
         None,
-        None,
+        &[],
         |test| {
             setup(test);
             test.populate_callout_script("good-json.sh");
@@ -283,10 +356,508 @@ fn test_list() {
         false,
         false,
         None,
-        None,
+        &[],
         |test| {
             setup(test);
             test.populate_callout_script("bad-json.sh");
         },
     );
 }
+
+#[cfg(feature = "monitor")]
+#[test]
+fn test_list_watch() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    ];
+    const PARENT: &str = "0000:00:02.0";
+    const CLEAR: &str = "\x1b[2J\x1b[H";
+
+    let test = TestEnvironment::new("list", "watch");
+    let env: Rc<dyn Environment> = test.clone();
+    test.populate_defined_device(UUID[0], PARENT, "device1.json");
+
+    // define a second device while the listing is being watched
+    let jsonfile = test.datapath.join("device2.json");
+    let deffile = test.config_base().join(PARENT).join(UUID[1]);
+    let definer = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        fs::copy(jsonfile, deffile).expect("Unable to copy device def");
+    });
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::list_watch_command(
+        env,
+        &LsmdevOptions {
+            defined: true,
+            ..Default::default()
+        },
+        &mut outbuf,
+        std::time::Duration::from_millis(50),
+        Some(2),
+    );
+    definer.join().unwrap();
+    test.assert_result(res, Expect::Pass, None).unwrap();
+
+    let actual = String::from_utf8(outbuf).expect("failed to convert list output from utf8");
+    let redraws: Vec<&str> = actual.split(CLEAR).skip(1).collect();
+    assert_eq!(redraws.len(), 2);
+    assert!(redraws[0].contains(UUID[0]) && !redraws[0].contains(UUID[1]));
+    assert!(redraws[1].contains(UUID[0]) && redraws[1].contains(UUID[1]));
+}
+
+#[test]
+fn test_list_attr() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    ];
+    const PARENT: &str = "0000:00:02.0";
+
+    let test = TestEnvironment::new("list", "attr");
+    test.populate_defined_device(UUID[0], PARENT, "device1.json");
+    test.populate_defined_device(UUID[1], PARENT, "device2.json");
+
+    let attr = |name: &str, value: &str| (name.to_string(), value.to_string());
+    for (subtest, attrs) in [
+        ("attr-exact", vec![attr("assign_domain", "0xab")]),
+        ("attr-glob", vec![attr("assign_adapter", "?")]),
+        (
+            "attr-all",
+            vec![
+                attr("assign_domain", "0x*"),
+                attr("assign_control_domain", "4"),
+            ],
+        ),
+        ("attr-none", vec![attr("assign_adapter", "*7*")]),
+    ] {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::list_command(
+            test.clone(),
+            &LsmdevOptions {
+                defined: true,
+                attr: attrs,
+                ..Default::default()
+            },
+            &mut outbuf,
+        );
+        test.assert_result(res, Expect::Pass, Some(subtest))
+            .unwrap();
+        let actual = String::from_utf8(outbuf).expect("failed to convert list output from utf8");
+        test.compare_to_file(&format!("{}.text", subtest), &actual);
+    }
+}
+
+fn test_list_columns_helper(
+    test: &Rc<TestEnvironment>,
+    subtest: &str,
+    defined: bool,
+    columns: &[ListColumn],
+    headings: bool,
+) {
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::list_columns_command(
+        test.clone(),
+        &LsmdevOptions {
+            defined,
+            noheadings: !headings,
+            ..Default::default()
+        },
+        columns,
+        &mut outbuf,
+    );
+    test.assert_result(res, Expect::Pass, Some(subtest))
+        .unwrap();
+    let actual = String::from_utf8(outbuf).expect("failed to convert list output from utf8");
+    test.compare_to_file(&format!("{}.text", subtest), &actual);
+}
+
+#[test]
+fn test_list_columns() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+    ];
+    const PARENT: &[&str] = &["0000:00:02.0", "0000:00:03.0"];
+
+    let test = TestEnvironment::new("list", "columns");
+    test.populate_active_device(UUID[0], PARENT[0], "i915-GVTg_V5_8");
+    test.populate_defined_device(UUID[0], PARENT[0], "device2.json");
+    test.populate_active_device(UUID[1], PARENT[0], "arbitrary_type");
+    test.populate_defined_device(UUID[2], PARENT[1], "device1.json");
+
+    let all = &[
+        ListColumn::Uuid,
+        ListColumn::Parent,
+        ListColumn::Type,
+        ListColumn::Start,
+        ListColumn::State,
+        ListColumn::Defined,
+        ListColumn::Attrs,
+    ];
+    test_list_columns_helper(&test, "columns-active", false, all, true);
+    test_list_columns_helper(&test, "columns-defined", true, all, true);
+    test_list_columns_helper(
+        &test,
+        "columns-noheadings",
+        true,
+        &[ListColumn::State, ListColumn::Uuid],
+        false,
+    );
+    test_list_columns_helper(&test, "table", false, crate::mdev::TABLE_COLUMNS, true);
+    test_list_columns_helper(
+        &test,
+        "table-defined",
+        true,
+        crate::mdev::TABLE_COLUMNS,
+        true,
+    );
+}
+
+#[test]
+#[cfg(feature = "rich-output")]
+fn test_list_sort() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+    ];
+    const PARENT: &[&str] = &["0000:00:02.0", "0000:00:03.0"];
+
+    let test = TestEnvironment::new("list", "sort");
+    test.populate_active_device(UUID[0], PARENT[0], "i915-GVTg_V5_8");
+    test.populate_defined_device(UUID[0], PARENT[0], "device2.json");
+    test.populate_defined_device(UUID[1], PARENT[1], "device2.json");
+    test.populate_defined_device(UUID[2], PARENT[0], "device1.json");
+    test.populate_defined_device(UUID[2], PARENT[1], "device1.json");
+
+    for (subtest, sort) in [
+        ("sort-uuid", ListSort::Uuid),
+        ("sort-type", ListSort::Type),
+        ("sort-state", ListSort::State),
+    ] {
+        for (ext, format) in [("text", None), ("json", Some(OutputFormat::Json))] {
+            let mut outbuf: Vec<u8> = Default::default();
+            let res = crate::list_command(
+                test.clone(),
+                &LsmdevOptions {
+                    defined: true,
+                    output: format.map(ListOutput::Format),
+                    sort,
+                    ..Default::default()
+                },
+                &mut outbuf,
+            );
+            test.assert_result(res, Expect::Pass, Some(ext)).unwrap();
+            let actual =
+                String::from_utf8(outbuf).expect("failed to convert list output from utf8");
+            test.compare_to_file(&format!("{}.{}", subtest, ext), &actual);
+        }
+    }
+}
+
+#[test]
+fn test_list_count() {
+    init();
+
+    // the number of listed devices decides whether 'list --fail-if-none' fails
+    let test = TestEnvironment::new("list", "count");
+    test.populate_defined_device(
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "0000:00:02.0",
+        "device1.json",
+    );
+    test.populate_defined_device(
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "0000:00:03.0",
+        "device2.json",
+    );
+
+    for (defined, parents, expected) in [
+        (true, vec![], 2),
+        (true, vec!["0000:00:03.0".to_string()], 1),
+        (true, vec!["missing".to_string()], 0),
+        (false, vec![], 0),
+    ] {
+        let opts = LsmdevOptions {
+            defined,
+            parent: parents,
+            ..Default::default()
+        };
+        let res = crate::list_command(test.clone(), &opts, &mut std::io::sink());
+        assert_eq!(expected, res.expect("Failed to list devices"));
+
+        let res = crate::list_columns_command(
+            test.clone(),
+            &opts,
+            crate::mdev::TABLE_COLUMNS,
+            &mut std::io::sink(),
+        );
+        assert_eq!(expected, res.expect("Failed to list devices"));
+
+        let opts = LsmdevOptions {
+            fail_if_none: true,
+            ..opts
+        };
+        let res = crate::list(test.clone(), opts, &mut std::io::sink());
+        match expected {
+            0 => assert_eq!(
+                "No matching devices found",
+                res.expect_err("Empty listing did not fail").to_string()
+            ),
+            _ => res.expect("Failed to list devices"),
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "rich-output")]
+fn test_list_paths() {
+    init();
+
+    let test = TestEnvironment::new("list", "paths");
+    test.populate_defined_device(
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "0000:00:02.0",
+        "device1.json",
+    );
+    test.populate_active_device(
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "0000:00:02.0",
+        "i915-GVTg_V5_4",
+    );
+    test.populate_defined_device(
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "0000:00:03.0",
+        "device2.json",
+    );
+    test.populate_active_device(
+        "b6f7e33f-ea28-4f9d-8c42-797ff0ec2888",
+        "0000:00:03.0",
+        "nvidia-155",
+    );
+    // show the paths relative to the root of the test environment
+    let root = test.root().display().to_string();
+
+    for (subtest, defined, format) in [
+        ("paths-defined.text", true, None),
+        ("paths-defined.json", true, Some(OutputFormat::Json)),
+        ("paths-active.text", false, None),
+        ("paths-active.json", false, Some(OutputFormat::Json)),
+    ] {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::list_command(
+            test.clone(),
+            &LsmdevOptions {
+                defined,
+                output: format.map(ListOutput::Format),
+                paths: true,
+                ..Default::default()
+            },
+            &mut outbuf,
+        );
+        assert!(res.is_ok());
+        let actual = String::from_utf8(outbuf).expect("failed to convert list output from utf8");
+        test.compare_to_file(subtest, &actual.replace(&root, ""));
+    }
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_list_timeout() {
+    use std::time::{Duration, Instant};
+
+    init();
+
+    let test = TestEnvironment::new("list", "timeout");
+    test.populate_active_device(
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "0000:00:02.0",
+        "i915-GVTg_V5_4",
+    );
+    test.populate_active_device(
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "0000:00:02.0",
+        "i915-GVTg_V5_4",
+    );
+    test.populate_callout_script("slow-attributes.sh");
+
+    let list = || {
+        crate::list_command(
+            test.clone(),
+            &LsmdevOptions::default(),
+            &mut std::io::sink(),
+        )
+        .expect("Failed to list devices")
+    };
+
+    // the callout script blocks until the deadline, after which the listing ends with the device
+    // it was called for
+    test.set_deadline(Some(Instant::now() + Duration::from_secs(1)));
+    let begin = Instant::now();
+    assert_eq!(1, list());
+    assert!(begin.elapsed() < Duration::from_secs(10));
+
+    // nothing is listed once the deadline has passed
+    test.set_deadline(Some(Instant::now()));
+    assert_eq!(0, list());
+    let types = test
+        .clone()
+        .get_supported_types(None)
+        .expect("Failed to list types");
+    assert!(types.is_empty());
+}
+
+#[test]
+#[cfg(feature = "rich-output")]
+fn test_list_type_name() {
+    init();
+
+    // the name of the type is read from the parent device, if it provides one
+    test_list_helper(
+        "active-type-name",
+        Expect::Pass,
+        false,
+        true,
+        None,
+        &[],
+        |test| {
+            test.populate_active_device(
+                "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+                "0000:3b:00.0",
+                "nvidia-155",
+            );
+            test.populate_parent_device(
+                "0000:3b:00.0",
+                "nvidia-155",
+                1,
+                "vfio-pci",
+                "GRID P40-2B",
+                None,
+            );
+            test.populate_active_device(
+                "59e8b599-afdd-4766-a59e-415ef4f5a492",
+                "0000:00:02.0",
+                "i915-GVTg_V5_4",
+            );
+        },
+    );
+    test_list_helper(
+        "defined-type-name",
+        Expect::Pass,
+        true,
+        true,
+        None,
+        &[],
+        |test| {
+            test.populate_defined_device(
+                "59e8b599-afdd-4766-a59e-415ef4f5a492",
+                "0000:00:02.0",
+                "device2.json",
+            );
+            test.populate_parent_device(
+                "0000:00:02.0",
+                "i915-GVTg_V5_8",
+                2,
+                "vfio-pci",
+                "GVTg_SKL_8",
+                None,
+            );
+        },
+    );
+}
+
+/// Put the active device `uuid` on `parent` into the IOMMU group `group`, along with PCI devices
+/// bound to the given drivers
+#[cfg(feature = "rich-output")]
+fn populate_iommu_group(
+    test: &TestEnvironment,
+    uuid: &str,
+    parent: &str,
+    group: &str,
+    others: &[(&str, Option<&str>)],
+) {
+    use std::os::unix::fs::symlink;
+
+    let devices = test.iommu_groups_base().join(group).join("devices");
+    fs::create_dir_all(&devices).expect("Unable to setup iommu group");
+    let devdir = test.parent_base().join(parent).join(uuid);
+    symlink(
+        test.iommu_groups_base().join(group),
+        devdir.join("iommu_group"),
+    )
+    .expect("Unable to setup iommu group link");
+    symlink(&devdir, devices.join(uuid)).expect("Unable to setup iommu group member");
+    symlink(
+        test.root().join("sys/bus/mdev/drivers/nvidia-vgpu-vfio"),
+        devdir.join("driver"),
+    )
+    .expect("Unable to setup driver link");
+
+    for (name, driver) in others {
+        let dir = test.root().join("sys/devices/pci0000:00").join(name);
+        fs::create_dir_all(&dir).expect("Unable to setup pci device");
+        if let Some(driver) = driver {
+            symlink(
+                test.root().join("sys/bus/pci/drivers").join(driver),
+                dir.join("driver"),
+            )
+            .expect("Unable to setup driver link");
+        }
+        symlink(&dir, devices.join(name)).expect("Unable to setup iommu group member");
+    }
+}
+
+#[test]
+#[cfg(feature = "rich-output")]
+fn test_list_assignable() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "b6f7e33f-ea28-4f9d-8c42-797ff0ec2888",
+    ];
+    const PARENT: &str = "0000:3b:00.0";
+
+    // only active vfio-pci devices in an IOMMU group are marked
+    test_list_helper(
+        "assignable",
+        Expect::Pass,
+        false,
+        false,
+        None,
+        &[],
+        |test| {
+            for uuid in UUID {
+                test.populate_active_device(uuid, PARENT, "nvidia-155");
+            }
+            test.populate_parent_device(PARENT, "nvidia-155", 1, "vfio-pci", "GRID P40-2B", None);
+            populate_iommu_group(
+                test,
+                UUID[0],
+                PARENT,
+                "10",
+                &[("0000:3b:00.1", Some("vfio-pci")), ("0000:3b:00.2", None)],
+            );
+            // another device of the group is still used by the host
+            populate_iommu_group(
+                test,
+                UUID[1],
+                PARENT,
+                "11",
+                &[("0000:3b:00.3", Some("snd_hda_intel"))],
+            );
+        },
+    );
+}
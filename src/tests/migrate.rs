@@ -0,0 +1,85 @@
+use super::*;
+use std::fs;
+
+const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+const PARENT: &str = "0000:00:03.0";
+
+fn migrate(test: &Rc<TestEnvironment>, dry_run: bool, expect: Expect, subtest: &str) {
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::migrate_config_command(test.clone(), dry_run, &mut outbuf);
+    let _ = test.assert_result(res, expect, Some(subtest));
+    let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+    test.compare_to_file(subtest, &actual);
+}
+
+#[test]
+fn test_migrate_config() {
+    init();
+
+    let test = TestEnvironment::new("migrate", "default");
+    // a script only in the legacy directory, one that was installed in both and a notifier
+    test.populate_callout_script_full("rc0.sh", None, false);
+    test.populate_callout_script_full("rc1.sh", None, false);
+    test.populate_callout_script_full("rc1.sh", None, true);
+    fs::create_dir_all(test.old_notification_dir()).unwrap();
+    fs::copy(
+        test.datapath.join("defined.json"),
+        test.old_notification_dir().join("notify.sh"),
+    )
+    .unwrap();
+    // a definition named by the upper case UUID
+    test.populate_defined_device(&UUID.to_uppercase(), PARENT, "defined.json");
+
+    migrate(&test, true, Expect::Pass, "dry-run.text");
+    assert!(test.old_callout_dir().join("rc0.sh").exists());
+    assert!(!test.callout_dir().join("rc0.sh").exists());
+
+    migrate(&test, false, Expect::Pass, "migrate.text");
+    assert!(!test.config_scripts_base().exists());
+    assert!(test.callout_dir().join("rc0.sh").exists());
+    assert!(test.notification_dir().join("notify.sh").exists());
+    assert!(test.config_base().join(PARENT).join(UUID).exists());
+    assert!(!test
+        .config_base()
+        .join(PARENT)
+        .join(UUID.to_uppercase())
+        .exists());
+
+    // the legacy directories are gone
+    migrate(&test, false, Expect::Pass, "nothing.text");
+}
+
+#[test]
+fn test_migrate_config_conflicts() {
+    init();
+
+    let test = TestEnvironment::new("migrate", "conflicts");
+    // scripts that differ in both directories are left alone
+    test.populate_callout_script_full("rc0.sh", None, false);
+    test.populate_callout_script_full("rc1.sh", Some("rc0.sh"), true);
+    // as are definitions that differ from the one taking precedence
+    test.populate_defined_device(&UUID.to_uppercase(), PARENT, "defined.json");
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    fs::write(
+        test.config_base()
+            .join(PARENT)
+            .join(format!("{{{}}}", UUID)),
+        "{}",
+    )
+    .unwrap();
+
+    migrate(
+        &test,
+        false,
+        Expect::Fail(Some(
+            "2 files could not be migrated and need to be resolved manually",
+        )),
+        "conflicts.text",
+    );
+    assert!(test.old_callout_dir().join("rc0.sh").exists());
+    assert!(test
+        .config_base()
+        .join(PARENT)
+        .join(format!("{{{}}}", UUID))
+        .exists());
+}
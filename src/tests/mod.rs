@@ -2,33 +2,59 @@ use anyhow::{anyhow, Result};
 use log::info;
 use nix::sys::wait::waitpid;
 use nix::unistd::{fork, ForkResult};
+use std::cell::{Cell, RefCell};
+#[cfg(feature = "archives")]
 use std::collections::BTreeMap;
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tempfile::Builder;
 use tempfile::TempDir;
 use uuid::Uuid;
 
 use crate::callouts::*;
-use crate::environment::Environment;
-use crate::logger::logger;
-use crate::mdev::MDev;
+use crate::cli::LsmdevOptions;
+use crate::environment::{Environment, WorkerEnvironment};
+use crate::mdev::{DeviceSpec, MDev};
 
 // additional tests
+mod attr;
+#[cfg(feature = "callouts")]
 mod callouts;
+mod changelog;
 mod define;
+mod doctor;
+#[cfg(feature = "callouts")]
+mod edit;
+mod estimate;
+mod export;
+mod host_info;
+mod json;
+mod labels;
 mod list;
+mod migrate;
 mod modify;
+#[cfg(feature = "monitor")]
+mod monitor;
+mod plugins;
+mod reconcile;
+mod stage;
 mod startstop;
+#[cfg(feature = "archives")]
+mod state;
+mod systemd;
 mod types;
+mod udev;
+mod validate;
+mod version;
 
 const TEST_DATA_DIR: &str = "testdata";
 
 fn init() {
-    let _ = logger().is_test(true).try_init();
+    let _ = crate::logger::init(true);
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -40,10 +66,12 @@ enum Expect<'a> {
 #[derive(Debug)]
 struct TestEnvironment {
     datapath: PathBuf,
-    scratch: TempDir,
+    scratch: Arc<TempDir>,
     name: String,
     case: String,
     callout_scripts: Mutex<CalloutScriptCache>,
+    deadline: Cell<Option<Instant>>,
+    callout_record: RefCell<Option<PathBuf>>,
 }
 
 impl Environment for TestEnvironment {
@@ -51,7 +79,7 @@ impl Environment for TestEnvironment {
         self.scratch.path()
     }
 
-    fn find_script(&self, dev: &MDev) -> Option<CalloutScriptInfo> {
+    fn find_script(&self, dev: &MDev) -> Result<Option<CalloutScriptInfo>> {
         return self
             .callout_scripts
             .lock()
@@ -62,6 +90,94 @@ impl Environment for TestEnvironment {
     fn as_env(self: Rc<Self>) -> Rc<dyn Environment> {
         self.clone()
     }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline.get()
+    }
+
+    fn callout_record_path(&self) -> Option<PathBuf> {
+        self.callout_record.borrow().clone()
+    }
+
+    fn worker_environment(&self) -> Option<WorkerEnvironment> {
+        let datapath = self.datapath.clone();
+        let scratch = self.scratch.clone();
+        let (name, case) = (self.name.clone(), self.case.clone());
+        let deadline = self.deadline.get();
+        let callout_record = self.callout_record.borrow().clone();
+        Some(Arc::new(move || {
+            Rc::new(TestEnvironment {
+                datapath: datapath.clone(),
+                scratch: scratch.clone(),
+                name: name.clone(),
+                case: case.clone(),
+                callout_scripts: Mutex::new(CalloutScriptCache::new()),
+                deadline: Cell::new(deadline),
+                callout_record: RefCell::new(callout_record.clone()),
+            })
+        }))
+    }
+
+    // never send test devices to the audit subsystem of the host
+    fn audit(&self, message: &str) -> Result<()> {
+        use std::io::Write;
+
+        let mut line = message.to_string();
+        line.push('\n');
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.audit_log_path())?
+            .write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    // never kill processes of the host. The simulated process exits on the signal unless its
+    // `ignore` file names the signal.
+    fn kill(&self, pid: i32, signal: libc::c_int) -> Result<()> {
+        use std::io::Write;
+
+        let name = match signal {
+            libc::SIGTERM => "TERM",
+            libc::SIGKILL => "KILL",
+            _ => "other",
+        };
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.killed_path())?
+            .write_all(format!("{} {}\n", pid, name).as_bytes())?;
+        let process = self.proc_base().join(pid.to_string());
+        let ignored = fs::read_to_string(process.join("ignore")).unwrap_or_default();
+        if !ignored.split_whitespace().any(|s| s == name) {
+            let _ = fs::remove_dir_all(process);
+        }
+        Ok(())
+    }
+
+    // the removal of a device fails with EBUSY while the `busy` file next to its `remove` file
+    // holds a number of attempts to refuse, or the pid of a running process as `pid:PID`
+    fn write_sysfs(&self, path: &Path, contents: &str) -> std::io::Result<()> {
+        let busy = path.with_file_name("busy");
+        if path.file_name().is_some_and(|n| n == "remove") {
+            if let Ok(holder) = fs::read_to_string(&busy) {
+                let is_busy = match holder.trim().strip_prefix("pid:") {
+                    Some(pid) => self.proc_base().join(pid).exists(),
+                    None => {
+                        let attempts: u32 = holder.trim().parse().unwrap_or(0);
+                        if attempts > 0 {
+                            fs::write(&busy, (attempts - 1).to_string())?;
+                        }
+                        attempts > 0
+                    }
+                };
+                if is_busy {
+                    return Err(std::io::Error::from_raw_os_error(libc::EBUSY));
+                }
+            }
+        }
+        fs::write(path, contents)
+    }
 }
 
 impl TestEnvironment {
@@ -70,10 +186,12 @@ impl TestEnvironment {
         let scratchdir = Builder::new().prefix("mdevctl-test").tempdir().unwrap();
         let test = TestEnvironment {
             datapath: path,
-            scratch: scratchdir,
+            scratch: Arc::new(scratchdir),
             name: testname.to_owned(),
             case: testcase.to_owned(),
             callout_scripts: Mutex::new(CalloutScriptCache::new()),
+            deadline: Cell::new(None),
+            callout_record: RefCell::new(None),
         };
         // populate the basic directories in the environment
         fs::create_dir_all(test.mdev_base()).expect("Unable to create mdev_base");
@@ -81,11 +199,11 @@ impl TestEnvironment {
         fs::create_dir_all(test.parent_base()).expect("Unable to create parent_base");
         for dir in test.callout_dirs() {
             fs::create_dir_all(&dir)
-                .expect(format!("Unable to create callout_dir {:?}", &dir).as_str());
+                .unwrap_or_else(|_| panic!("Unable to create callout_dir {:?}", &dir));
         }
         for dir in test.notification_dirs() {
             fs::create_dir_all(&dir)
-                .expect(format!("Unable to create notification_dir '{:?}'", &dir).as_str());
+                .unwrap_or_else(|_| panic!("Unable to create notification_dir '{:?}'", &dir));
         }
         info!("---- Running test '{}/{}' ----", testname, testcase);
         Rc::new(test)
@@ -113,13 +231,38 @@ impl TestEnvironment {
         fs::create_dir_all(&parentdevdir).expect("Unable to setup parent device dir");
 
         let devdir = self.mdev_base().join(uuid);
-        fs::create_dir_all(&devdir.parent().unwrap()).expect("Unable to setup mdev dir");
+        fs::create_dir_all(devdir.parent().unwrap()).expect("Unable to setup mdev dir");
         symlink(&parentdevdir, &devdir).expect("Unable to setup mdev dir");
 
         let typefile = devdir.join("mdev_type");
         symlink(&parenttypedir, &typefile).expect("Unable to setup mdev type");
     }
 
+    // the messages the commands sent to the audit subsystem, one per line
+    fn audit_log_path(&self) -> PathBuf {
+        self.root().join("audit.log")
+    }
+
+    // the processes that the commands killed, one per line
+    fn killed_path(&self) -> PathBuf {
+        self.root().join("killed")
+    }
+
+    // bound the commands in the test environment as if given with --timeout
+    #[cfg(feature = "callouts")]
+    fn set_deadline(&self, deadline: Option<Instant>) {
+        self.deadline.set(deadline);
+    }
+
+    // record the call-out and notification events in the returned file instead of invoking
+    // scripts, as if MDEVCTL_RECORD_CALLOUTS was set
+    #[cfg(feature = "callouts")]
+    fn record_callouts(&self) -> PathBuf {
+        let path = self.root().join("callouts.jsonl");
+        self.callout_record.replace(Some(path.clone()));
+        path
+    }
+
     // set up a script in the test environment to simulate a callout
     fn populate_callout_script(&self, filename: &str) {
         self.populate_callout_script_full(filename, None, true)
@@ -132,13 +275,23 @@ impl TestEnvironment {
         destname: Option<&str>,
         default_dir: bool,
     ) {
-        let calloutscriptdir: PathBuf = [TEST_DATA_DIR, "callouts"].iter().collect();
-        let calloutscript = calloutscriptdir.join(filename);
         let dest = match default_dir {
             true => self.callout_dir(),
             false => self.old_callout_dir(),
         }
         .join(destname.unwrap_or(filename));
+        self.copy_script(filename, &dest);
+    }
+
+    // set up a script in the test environment to simulate a notifier
+    #[cfg(feature = "callouts")]
+    fn populate_notifier_script(&self, filename: &str, destname: &str) {
+        self.copy_script(filename, &self.notification_dir().join(destname));
+    }
+
+    fn copy_script(&self, filename: &str, dest: &Path) {
+        let calloutscriptdir: PathBuf = [TEST_DATA_DIR, "callouts"].iter().collect();
+        let calloutscript = calloutscriptdir.join(filename);
         assert!(calloutscript.exists());
 
         /* Because the test suite is multi-threaded, we end up having the same flaky failures
@@ -155,7 +308,7 @@ impl TestEnvironment {
                 waitpid(child, None).expect("Failed to wait for child");
             }
             ForkResult::Child => {
-                fs::copy(calloutscript, &dest).expect("Unable to copy callout script");
+                fs::copy(calloutscript, dest).expect("Unable to copy callout script");
                 unsafe {
                     libc::_exit(0);
                 }
@@ -183,19 +336,56 @@ impl TestEnvironment {
             .expect("Unable to write available_instances");
 
         let apifile = parenttypedir.join("device_api");
-        fs::write(apifile, format!("{}", device_api)).expect("Unable to write device_api");
+        fs::write(apifile, device_api).expect("Unable to write device_api");
 
         let namefile = parenttypedir.join("name");
-        fs::write(namefile, format!("{}", name)).expect("Unable to write name");
+        fs::write(namefile, name).expect("Unable to write name");
 
         if let Some(desc) = description {
             let descfile = parenttypedir.join("description");
-            fs::write(descfile, format!("{}", desc)).expect("Unable to write description");
+            fs::write(descfile, desc).expect("Unable to write description");
         }
 
         (parentdir, parenttypedir)
     }
 
+    // write a kernel log in /dev/kmsg record format to simulate messages logged by drivers
+    #[cfg(feature = "callouts")]
+    fn populate_kernel_log(&self, messages: &[&str]) {
+        let kmsg = self.kmsg_path();
+        fs::create_dir_all(kmsg.parent().unwrap()).expect("Unable to setup dev dir");
+        let records: String = messages
+            .iter()
+            .enumerate()
+            .map(|(seq, m)| format!("3,{},{},-;{}\n", seq, seq * 1000, m))
+            .collect();
+        fs::write(kmsg, records).expect("Unable to write kernel log");
+    }
+
+    // set up loaded kernel modules, the hugepage count and arbitrary files in the test
+    // environment to simulate host resources that devices may require
+    #[cfg(feature = "callouts")]
+    fn populate_host_resources(&self, modules: &[&str], free_hugepages: u64, files: &[&str]) {
+        for m in modules {
+            fs::create_dir_all(self.module_base().join(m)).expect("Unable to setup module dir");
+        }
+        let meminfo = self.meminfo_path();
+        fs::create_dir_all(meminfo.parent().unwrap()).expect("Unable to setup proc dir");
+        fs::write(
+            meminfo,
+            format!(
+                "HugePages_Total:    {}\nHugePages_Free:     {}\n",
+                free_hugepages, free_hugepages
+            ),
+        )
+        .expect("Unable to write meminfo");
+        for f in files {
+            let path = self.root().join(f.trim_start_matches('/'));
+            fs::create_dir_all(path.parent().unwrap()).expect("Unable to setup file dir");
+            fs::write(path, "").expect("Unable to write file");
+        }
+    }
+
     fn compare_to_file(&self, filename: &str, actual: &str) {
         let path = self.datapath.join(filename);
         if get_flag(REGEN_FLAG) {
@@ -247,16 +437,15 @@ impl TestEnvironment {
                 }
                 Err(anyhow!(e))
             }
-            Expect::Pass => Ok(res.expect(format!("Expected {} to pass", testname).as_str())),
+            Expect::Pass => {
+                Ok(res.unwrap_or_else(|e| panic!("Expected {} to pass: {:?}", testname, e)))
+            }
         }
     }
 }
 
 fn get_flag(varname: &str) -> bool {
-    env::var(varname).map_or(false, |s| match s.trim().parse::<i32>() {
-        Ok(n) if n > 0 => true,
-        _ => false,
-    })
+    env::var(varname).is_ok_and(|s| matches!(s.trim().parse::<i32>(), Ok(n) if n > 0))
 }
 
 fn regen(filename: &PathBuf, data: &str) -> Result<()> {
@@ -264,10 +453,7 @@ fn regen(filename: &PathBuf, data: &str) -> Result<()> {
     fs::create_dir_all(parentdir)?;
 
     fs::write(filename, data.as_bytes())
-        .and_then(|_| {
-            println!("Regenerated expected data file {:?}", filename);
-            Ok(())
-        })
+        .map(|_| println!("Regenerated expected data file {:?}", filename))
         .map_err(|err| err.into())
 }
 
@@ -311,6 +497,12 @@ fn test_load_json() {
         "0000:00:03.0",
         Expect::Pass,
     );
+    // json file with host requirements
+    test_load_json_helper(
+        "0d5c8a3d-6f8a-4d6e-9d3e-3ba6a3f7c5a1",
+        "matrix",
+        Expect::Pass,
+    );
     // json file has malformed attributes - an array of one object with multiple fields
     test_load_json_helper(
         "b6f7e33f-ea28-4f9d-8c42-797ff0ec2888",
@@ -330,3 +522,239 @@ fn test_load_json() {
         Expect::Fail(None),
     );
 }
+
+fn test_sysfs_path_helper(
+    test: &Rc<TestEnvironment>,
+    path: &str,
+    expect: Expect,
+    expected: DeviceSpec,
+) {
+    let res = DeviceSpec::from_sysfs_path(test.as_ref(), Path::new(path));
+    if let Ok(spec) = test.assert_result(res, expect, Some(path)) {
+        assert_eq!(expected, spec);
+    }
+}
+
+#[test]
+fn test_sysfs_path() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("sysfs-path", "default");
+    test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+
+    let device = DeviceSpec {
+        uuid: Some(Uuid::parse_str(UUID).unwrap()),
+        parent: Some(PARENT.to_string()),
+        mdev_type: Some(MDEV_TYPE.to_string()),
+    };
+    test_sysfs_path_helper(
+        &test,
+        &format!("/sys/bus/mdev/devices/{}", UUID),
+        Expect::Pass,
+        device.clone(),
+    );
+    test_sysfs_path_helper(
+        &test,
+        &format!("/sys/class/mdev_bus/{}/{}/", PARENT, UUID),
+        Expect::Pass,
+        device,
+    );
+    test_sysfs_path_helper(
+        &test,
+        &format!(
+            "/sys/class/mdev_bus/{}/mdev_supported_types/{}",
+            PARENT, MDEV_TYPE
+        ),
+        Expect::Pass,
+        DeviceSpec {
+            uuid: None,
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+        },
+    );
+    test_sysfs_path_helper(
+        &test,
+        &format!("/sys/class/mdev_bus/{}", PARENT),
+        Expect::Fail(Some(
+            format!(
+                "Path \"/sys/class/mdev_bus/{}\" is neither a mediated device nor a mediated \
+                 device type",
+                PARENT
+            )
+            .as_str(),
+        )),
+        DeviceSpec::default(),
+    );
+    test_sysfs_path_helper(
+        &test,
+        "/sys/bus/mdev/devices/missing",
+        Expect::Fail(Some(
+            "Unable to resolve path \"/sys/bus/mdev/devices/missing\"",
+        )),
+        DeviceSpec::default(),
+    );
+}
+
+#[test]
+#[cfg(feature = "archives")]
+fn test_support_bundle() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("support-bundle", "default");
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+    test.populate_callout_script("rc0.sh");
+    for (path, val) in [
+        ("proc/sys/kernel/hostname", "testhost\n"),
+        ("etc/machine-id", "0123456789abcdef0123456789abcdef\n"),
+    ] {
+        let path = test.root().join(path);
+        fs::create_dir_all(path.parent().unwrap()).expect("Unable to setup dir");
+        fs::write(path, val).expect("Unable to write file");
+    }
+    // the machine id shows up in device attributes, e.g. when used as a label
+    fs::write(
+        test.parent_base()
+            .join(PARENT)
+            .join("mdev_supported_types")
+            .join(MDEV_TYPE)
+            .join("description"),
+        "vgpu for 0123456789abcdef0123456789abcdef on testhost_1 of testhosts",
+    )
+    .expect("Unable to write description");
+
+    let output = test.scratch.path().join("bundle.tar.gz");
+    crate::support_bundle_command(test.clone(), output.clone()).expect("Failed to create bundle");
+
+    let mut archive = tar::Archive::new(GzDecoder::new(
+        fs::File::open(&output).expect("Unable to open bundle"),
+    ));
+    let mut files = BTreeMap::new();
+    for entry in archive.entries().expect("Unable to read bundle") {
+        let mut entry = entry.expect("Unable to read bundle entry");
+        let name = entry.path().unwrap().to_string_lossy().to_string();
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        files.insert(name, contents);
+    }
+    assert_eq!(
+        vec![
+            format!("definitions/{}/{}", PARENT, UUID),
+            "journal.txt".to_string(),
+            "list-active.json".to_string(),
+            "list-defined.json".to_string(),
+            "scripts.txt".to_string(),
+            "sysfs.txt".to_string(),
+            "types.json".to_string(),
+            "version.txt".to_string(),
+        ],
+        files.keys().cloned().collect::<Vec<_>>()
+    );
+    for (name, contents) in files.iter() {
+        // only whole words are redacted
+        let contents = contents.replace("testhost_1", "").replace("testhosts", "");
+        assert!(!contents.contains("testhost"), "{} is not redacted", name);
+        assert!(
+            !contents.contains("0123456789abcdef0123456789abcdef"),
+            "{} is not redacted",
+            name
+        );
+    }
+    assert!(files[&format!("definitions/{}/{}", PARENT, UUID)].contains("vgpu-<hostname>"));
+    assert!(files["list-active.json"].contains(UUID));
+    assert!(files["list-defined.json"].contains(UUID));
+    assert!(files["types.json"].contains("vgpu for <machine-id> on testhost_1 of testhosts"));
+    assert!(files["scripts.txt"].contains("rc0.sh"));
+    assert!(files["sysfs.txt"].contains(&format!(
+        "{} -> /sys/class/mdev_bus/{}/{}",
+        UUID, PARENT, UUID
+    )));
+    assert!(files["sysfs.txt"].contains(&format!(
+        "mdev_type: /sys/class/mdev_bus/{}/mdev_supported_types/{}",
+        PARENT, MDEV_TYPE
+    )));
+}
+
+#[test]
+fn test_self_check_recovery() {
+    use std::os::unix::fs::symlink;
+
+    init();
+
+    let test = TestEnvironment::new("self-check", "default");
+    test.self_check().expect("self check failed");
+    assert_eq!(None, test.recovery_target());
+
+    // missing directories are fatal during normal operation
+    fs::remove_dir_all(test.callout_dir()).unwrap();
+    fs::remove_dir_all(test.config_base()).unwrap();
+    assert!(test.self_check().is_err());
+
+    let write_cmdline = |cmdline: &str| {
+        let path = test.cmdline_path();
+        fs::create_dir_all(path.parent().unwrap()).expect("Unable to setup proc dir");
+        fs::write(path, cmdline).expect("Unable to write cmdline");
+    };
+    write_cmdline("BOOT_IMAGE=/vmlinuz root=/dev/vda1 ro quiet");
+    assert!(test.self_check().is_err());
+    // only whole fields select a recovery target
+    for cmdline in [
+        "root=/dev/vda1 ro dyndbg=\"file mdev.c +p 1\"",
+        "root=/dev/vda1 systemd.unit=multi-user.target rescue.target",
+        "root=/dev/vda1 console=single",
+    ] {
+        write_cmdline(cmdline);
+        assert_eq!(None, test.recovery_target(), "{}", cmdline);
+        assert!(test.self_check().is_err());
+    }
+
+    // but only reported while recovering the system
+    for (cmdline, target) in [
+        ("root=/dev/vda1 ro single", "rescue.target"),
+        ("root=/dev/vda1 ro 1", "rescue.target"),
+        (
+            "root=/dev/vda1 systemd.unit=emergency.target",
+            "emergency.target",
+        ),
+        ("root=/dev/vda1 emergency", "emergency.target"),
+    ] {
+        write_cmdline(cmdline);
+        assert_eq!(Some(target), test.recovery_target(), "{}", cmdline);
+        test.self_check()
+            .expect("self check failed in recovery mode");
+    }
+
+    // e.g. after 'systemctl rescue'
+    write_cmdline("root=/dev/vda1 ro quiet");
+    let units = test.systemd_units_base();
+    fs::create_dir_all(&units).expect("Unable to setup systemd units dir");
+    symlink("0123456789abcdef", units.join("invocation:rescue.target"))
+        .expect("Unable to setup invocation id");
+    assert_eq!(Some("rescue.target"), test.recovery_target());
+    test.self_check()
+        .expect("self check failed in recovery mode");
+
+    // listing devices still works without the configuration directory
+    let mut outbuf: Vec<u8> = Default::default();
+    crate::list_command(
+        test.clone(),
+        &LsmdevOptions {
+            defined: true,
+            ..Default::default()
+        },
+        &mut outbuf,
+    )
+    .expect("Failed to list defined devices");
+    assert!(outbuf.is_empty());
+}
@@ -1,23 +1,16 @@
 use super::*;
-use std::{fs, path::PathBuf};
+use crate::cli::{DefinitionChanges, ModifyArgs};
+use std::fs;
+#[cfg(feature = "callouts")]
+use std::path::PathBuf;
 use uuid::Uuid;
 
+#[cfg(feature = "callouts")]
 fn test_modify_helper<F>(
     testname: &str,
     expect: Expect,
     uuid: &str,
-    parent: Option<String>,
-    mdev_type: Option<String>,
-    addattr: Option<String>,
-    delattr: bool,
-    index: Option<u32>,
-    value: Option<String>,
-    auto: bool,
-    manual: bool,
-    live: bool,
-    defined: bool,
-    jsonfile: Option<PathBuf>,
-    force: bool,
+    mut args: ModifyArgs,
     setupfn: F,
 ) where
     F: Fn(Rc<TestEnvironment>),
@@ -27,30 +20,13 @@ fn test_modify_helper<F>(
     let env: Rc<dyn Environment> = test.clone();
 
     // load the jsonfile from the test path.
-    let jsonfile = match jsonfile {
-        Some(f) => Some(test.datapath.join(f)),
-        None => None,
-    };
+    args.changes.jsonfile = args.changes.jsonfile.map(|f| test.datapath.join(f));
+    let parent = args.parent.clone();
 
     setupfn(test.clone());
 
     let uuid = Uuid::parse_str(uuid).unwrap();
-    let result = modify_command(
-        env.clone(),
-        uuid,
-        parent.clone(),
-        mdev_type,
-        addattr,
-        delattr,
-        index,
-        value,
-        auto,
-        manual,
-        live,
-        defined,
-        jsonfile,
-        force,
-    );
+    let result = modify_command(env.clone(), uuid, args, &mut std::io::sink());
 
     if test.assert_result(result, expect, None).is_err() {
         return;
@@ -68,22 +44,12 @@ fn test_modify_helper<F>(
         .compare_to_file(&format!("{}.expected", testname), &filecontents);
 }
 
+#[cfg(feature = "callouts")]
 fn test_modify_defined_active_helper<F>(
     testname: &str,
     expect: Expect,
     uuid: &str,
-    parent: Option<String>,
-    mdev_type: Option<String>,
-    addattr: Option<String>,
-    delattr: bool,
-    index: Option<u32>,
-    value: Option<String>,
-    auto: bool,
-    manual: bool,
-    live: bool,
-    defined: bool,
-    jsonfile: Option<PathBuf>,
-    force: bool,
+    mut args: ModifyArgs,
     setupfn: F,
 ) where
     F: Fn(Rc<TestEnvironment>),
@@ -93,30 +59,13 @@ fn test_modify_defined_active_helper<F>(
     let env: Rc<dyn Environment> = test.clone();
 
     // load the jsonfile from the test path.
-    let jsonfile = match jsonfile {
-        Some(f) => Some(test.datapath.join(f)),
-        None => None,
-    };
+    args.changes.jsonfile = args.changes.jsonfile.map(|f| test.datapath.join(f));
+    let parent = args.parent.clone();
 
     setupfn(test.clone());
 
     let uuid = Uuid::parse_str(uuid).unwrap();
-    let result = modify_command(
-        env.clone(),
-        uuid,
-        parent.clone(),
-        mdev_type,
-        addattr,
-        delattr,
-        index,
-        value,
-        auto,
-        manual,
-        live,
-        defined,
-        jsonfile,
-        force,
-    );
+    let result = modify_command(env.clone(), uuid, args, &mut std::io::sink());
     if test
         .assert_result(result, expect, Some("modify command"))
         .is_err()
@@ -151,6 +100,7 @@ fn test_modify_defined_active_helper<F>(
 }
 
 #[test]
+#[cfg(feature = "callouts")]
 fn test_modify() {
     init();
 
@@ -160,36 +110,21 @@ fn test_modify() {
         "device-not-defined",
         Expect::Fail(None),
         UUID,
-        None,
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs::default(),
         |_| {},
     );
     test_modify_helper(
         "auto",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        true,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                auto: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -198,18 +133,14 @@ fn test_modify() {
         "manual",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        true,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                manual: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -218,18 +149,15 @@ fn test_modify() {
         "delattr",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        true,
-        Some(2),
-        None,
-        false,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                delattr: true,
+                index: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -238,18 +166,14 @@ fn test_modify() {
         "delattr-noindex",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        true,
-        None,
-        None,
-        false,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                delattr: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -258,18 +182,16 @@ fn test_modify() {
         "addattr",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        Some("added-attr".to_string()),
-        false,
-        Some(3),
-        Some("added-attr-value".to_string()),
-        false,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                addattr: Some("added-attr".to_string()),
+                index: Some(3),
+                value: Some("added-attr-value".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -278,18 +200,15 @@ fn test_modify() {
         "addattr-noindex",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        Some("added-attr".to_string()),
-        false,
-        None,
-        Some("added-attr-value".to_string()),
-        false,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                addattr: Some("added-attr".to_string()),
+                value: Some("added-attr-value".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -298,18 +217,14 @@ fn test_modify() {
         "mdev_type",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        Some("changed-mdev-type".to_string()),
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                mdev_type: Some("changed-mdev-type".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -318,18 +233,13 @@ fn test_modify() {
         "multiple-noparent",
         Expect::Fail(None),
         UUID,
-        None,
-        None,
-        None,
-        false,
-        None,
-        None,
-        true,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            changes: DefinitionChanges {
+                auto: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
             test.populate_defined_device(UUID, "0000:00:02.0", "defined.json");
@@ -339,18 +249,14 @@ fn test_modify() {
         "multiple-parent",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        true,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                auto: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
             test.populate_defined_device(UUID, "0000:00:02.0", "defined.json");
@@ -360,18 +266,15 @@ fn test_modify() {
         "auto-manual",
         Expect::Fail(None),
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        true,
-        true,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                auto: true,
+                manual: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -381,18 +284,14 @@ fn test_modify() {
         "jsonfile",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        false,
-        false,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -402,18 +301,14 @@ fn test_modify() {
         "callout-pass",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        true,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                auto: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
             test.populate_callout_script("rc0.sh");
@@ -424,18 +319,14 @@ fn test_modify() {
         "callout-fail",
         Expect::Fail(None),
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        true,
-        false,
-        false,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                auto: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
             test.populate_callout_script("rc1.sh");
@@ -446,18 +337,15 @@ fn test_modify() {
         "callout-fail-force",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        true,
-        false,
-        false,
-        false,
-        None,
-        true,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                auto: true,
+                ..Default::default()
+            },
+            force: true,
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
             test.populate_callout_script("rc1.sh");
@@ -471,18 +359,14 @@ fn test_modify() {
         "modify-jsonfile-with-version-callout-all-pass",
         Expect::Pass,
         UUID_VER,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        false,
-        false,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_VER, PARENT, "defined.json");
             test.populate_callout_script("ver-rc0.sh"); // versioning
@@ -492,18 +376,14 @@ fn test_modify() {
         "modify-jsonfile-with-version-callout-all-fail",
         Expect::Fail(None),
         UUID_VER,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        false,
-        false,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_VER, PARENT, "defined.json");
             test.populate_callout_script("ver-rc1.sh"); // versioning error
@@ -513,18 +393,14 @@ fn test_modify() {
         "modify-jsonfile-with-version-callout-multiple-with-version-pass",
         Expect::Pass,
         UUID_VER,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        false,
-        false,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_VER, PARENT, "defined.json");
             test.populate_callout_script("rc0.sh"); // no versioning
@@ -535,18 +411,14 @@ fn test_modify() {
         "modify-jsonfile-with-version-callout-multiple-with-version-pass2",
         Expect::Pass,
         UUID_VER,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        false,
-        false,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_VER, PARENT, "defined.json");
             test.populate_callout_script("rc1.sh"); // no versioning error
@@ -557,18 +429,14 @@ fn test_modify() {
         "modify-jsonfile-with-version-callout-multiple-with-version-fail",
         Expect::Fail(None),
         UUID_VER,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        false,
-        false,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_VER, PARENT, "defined.json");
             test.populate_callout_script("rc0.sh"); // no versioning
@@ -584,18 +452,15 @@ fn test_modify() {
         "live-event-supported",
         Expect::Pass,
         UUID_LIVE,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        true,
-        false,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            live: true,
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_LIVE, PARENT, "defined.json");
             test.populate_active_device(UUID_LIVE, PARENT, "vfio_ap-passthrough");
@@ -606,18 +471,15 @@ fn test_modify() {
         "live-event-unsupported-by-callout",
         Expect::Fail(None),
         UUID_NO_LIVE,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        true,
-        false,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            live: true,
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_NO_LIVE, PARENT, "defined.json");
             test.populate_active_device(UUID_NO_LIVE, PARENT, "vfio_ap-passthrough");
@@ -626,22 +488,13 @@ fn test_modify() {
     );
     test_modify_helper(
         "live-unsupported-script-without-version-support",
-        Expect::Fail(Some(
-            format!("'live' option must be used with 'jsonfile' option").as_str(),
-        )),
+        Expect::Fail(Some("'live' option must be used with 'jsonfile' option")),
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        true,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            live: true,
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
             test.populate_active_device(UUID, PARENT, "vfio_ap-passthrough");
@@ -652,18 +505,15 @@ fn test_modify() {
         "live-supported-but-fails",
         Expect::Fail(None),
         UUID_LIVE,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        true,
-        false,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            live: true,
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_LIVE, PARENT, "defined.json");
             test.populate_active_device(UUID_LIVE, PARENT, "vfio_ap-passthrough");
@@ -672,22 +522,13 @@ fn test_modify() {
     );
     test_modify_helper(
         "live-fail-without-jsonfile",
-        Expect::Fail(Some(
-            format!("'live' option must be used with 'jsonfile' option").as_str(),
-        )),
+        Expect::Fail(Some("'live' option must be used with 'jsonfile' option")),
         UUID_LIVE,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        true,
-        false,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            live: true,
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_LIVE, PARENT, "defined.json");
             test.populate_active_device(UUID_LIVE, PARENT, "vfio_ap-passthrough");
@@ -699,18 +540,16 @@ fn test_modify() {
         "live-defined-supported",
         Expect::Pass,
         UUID_LIVE,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        true,
-        true,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            live: true,
+            defined: true,
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_LIVE, PARENT, "defined.json");
             test.populate_active_device(UUID_LIVE, PARENT, "vfio_ap-passthrough");
@@ -721,18 +560,16 @@ fn test_modify() {
         "live-defined-live-event-unsupported",
         Expect::Fail(None),
         UUID_NO_LIVE,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
-        None,
-        None,
-        false,
-        false,
-        true,
-        true,
-        Some(PathBuf::from("modified.json")),
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                jsonfile: Some(PathBuf::from("modified.json")),
+                ..Default::default()
+            },
+            live: true,
+            defined: true,
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID_NO_LIVE, PARENT, "defined.json");
             test.populate_active_device(UUID_NO_LIVE, PARENT, "vfio_ap-passthrough");
@@ -743,18 +580,16 @@ fn test_modify() {
         "defined-only",
         Expect::Pass,
         UUID,
-        Some(PARENT.to_string()),
-        None,
-        Some("added-attr".to_string()),
-        false,
-        None,
-        Some("added-attr-value".to_string()),
-        false,
-        false,
-        false,
-        true,
-        None,
-        false,
+        ModifyArgs {
+            parent: Some(PARENT.to_string()),
+            changes: DefinitionChanges {
+                addattr: Some("added-attr".to_string()),
+                value: Some("added-attr-value".to_string()),
+                ..Default::default()
+            },
+            defined: true,
+            ..Default::default()
+        },
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
             test.populate_active_device(UUID, PARENT, "vfio_ap-passthrough");
@@ -762,3 +597,140 @@ fn test_modify() {
         },
     );
 }
+
+#[test]
+fn test_modify_clear_attrs() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    // all attributes are deleted before new attributes are added
+    for (testname, addattr, value) in [
+        ("clear-attrs", None, None),
+        (
+            "clear-attrs-add",
+            Some("assign_adapter".to_string()),
+            Some("7".to_string()),
+        ),
+    ] {
+        let test = TestEnvironment::new("modify", testname);
+        test.populate_defined_device(UUID, PARENT, "defined.json");
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let result = crate::modify_command(
+            test.clone(),
+            uuid,
+            ModifyArgs {
+                changes: DefinitionChanges {
+                    addattr,
+                    clear_attrs: true,
+                    value,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            &mut std::io::sink(),
+        );
+        test.assert_result(result, Expect::Pass, None).unwrap();
+
+        let def = test
+            .clone()
+            .get_defined_device(uuid, None)
+            .expect("Couldn't find defined device");
+        let filecontents = fs::read_to_string(def.persist_path().unwrap()).unwrap();
+        test.compare_to_file(&format!("{}.expected", testname), &filecontents);
+    }
+}
+
+#[test]
+fn test_modify_only_if() {
+    use crate::cli::ModifyCondition;
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    // the device is defined but not active, only the matching conditions modify it
+    for (testname, condition, modified) in [
+        ("only-if-active", ModifyCondition::Active, false),
+        ("only-if-inactive", ModifyCondition::Inactive, true),
+        ("only-if-defined", ModifyCondition::Defined, true),
+    ] {
+        let test = TestEnvironment::new("modify", testname);
+        test.populate_defined_device(UUID, PARENT, "defined.json");
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let result = crate::modify_command(
+            test.clone(),
+            uuid,
+            ModifyArgs {
+                changes: DefinitionChanges {
+                    auto: true,
+                    ..Default::default()
+                },
+                only_if: Some(condition),
+                ..Default::default()
+            },
+            &mut std::io::sink(),
+        );
+        test.assert_result(result, Expect::Pass, None).unwrap();
+
+        let def = test
+            .clone()
+            .get_defined_device(uuid, None)
+            .expect("Couldn't find defined device");
+        assert_eq!(def.autostart, modified, "{}", testname);
+    }
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_modify_regenerate_attrs() {
+    use crate::mdev::FormatType;
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    for (testname, active, expect) in [
+        ("regenerate-attrs", true, Expect::Pass),
+        (
+            "regenerate-attrs-inactive",
+            false,
+            Expect::Fail(Some(
+                "Device 976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9 must be active to regenerate its \
+                 attributes",
+            )),
+        ),
+    ] {
+        let test = TestEnvironment::new("modify", testname);
+        test.populate_defined_device(UUID, PARENT, "callout-attrs.json");
+        if active {
+            test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+        }
+        test.populate_callout_script("good-json.sh");
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let result = crate::modify_command(
+            test.clone(),
+            uuid,
+            ModifyArgs {
+                regenerate_attrs: true,
+                ..Default::default()
+            },
+            &mut std::io::sink(),
+        );
+        if test.assert_result(result, expect, None).is_err() {
+            continue;
+        }
+
+        // only the attributes provided by the script are replaced
+        let def = test
+            .clone()
+            .get_defined_device(uuid, None)
+            .expect("Couldn't find defined device");
+        let filecontents = fs::read_to_string(def.persist_path().unwrap()).unwrap();
+        test.compare_to_file(&format!("{}.expected", testname), &filecontents);
+        let text = def.to_text(FormatType::Defined, true).unwrap();
+        test.compare_to_file(&format!("{}.text", testname), &text);
+    }
+}
@@ -0,0 +1,77 @@
+use super::*;
+use std::os::unix::fs::symlink;
+use std::time::Duration;
+
+const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+const PARENT: &str = "0000:00:02.0";
+const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+/// run the monitor command for `max_events` while `change` is applied in the background
+fn monitor<F>(test: &Rc<TestEnvironment>, dumpjson: bool, max_events: usize, change: F) -> String
+where
+    F: FnOnce() + Send + 'static,
+{
+    let changer = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        change();
+    });
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::monitor_command(
+        test.clone(),
+        dumpjson,
+        &mut outbuf,
+        Duration::from_millis(50),
+        Some(max_events),
+    );
+    changer.join().unwrap();
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    String::from_utf8(outbuf).expect("failed to convert monitor output from utf8")
+}
+
+#[test]
+fn test_monitor() {
+    init();
+
+    let test = TestEnvironment::new("monitor", "default");
+    let parentdir = test.parent_base().join(PARENT);
+    let typedir = parentdir.join("mdev_supported_types").join(MDEV_TYPE);
+    let mdevdir = test.mdev_base().join(UUID);
+
+    // a parent device appears and a device is created on it
+    let (p, t, m) = (parentdir.clone(), typedir.clone(), mdevdir.clone());
+    let actual = monitor(&test, false, 2, move || {
+        fs::create_dir_all(&t).expect("Unable to setup parent device");
+        fs::create_dir_all(p.join(UUID)).expect("Unable to setup parent device dir");
+        symlink(t, p.join(UUID).join("mdev_type")).expect("Unable to setup mdev type");
+        symlink(p.join(UUID), m).expect("Unable to setup mdev dir");
+    });
+    assert_eq!(
+        format!(
+            "parent-added {}\nmdev-added {} {} {}\n",
+            PARENT, UUID, PARENT, MDEV_TYPE
+        ),
+        actual
+    );
+
+    // the device is removed, then the parent device disappears
+    let actual = monitor(&test, true, 2, move || {
+        fs::remove_file(mdevdir).expect("Unable to remove mdev dir");
+        fs::remove_dir_all(parentdir).expect("Unable to remove parent device");
+    });
+    let events = actual
+        .lines()
+        .map(|l| serde_json::from_str(l).expect("invalid json event"))
+        .collect::<Vec<serde_json::Value>>();
+    assert_eq!(
+        vec![
+            serde_json::json!({
+                "event": "mdev-removed",
+                "uuid": UUID,
+                "parent": PARENT,
+                "mdev_type": MDEV_TYPE,
+            }),
+            serde_json::json!({"event": "parent-removed", "parent": PARENT}),
+        ],
+        events
+    );
+}
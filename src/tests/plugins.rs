@@ -0,0 +1,116 @@
+use super::*;
+use crate::cli::{DefineArgs, StartArgs};
+use mdevctl::plugins::{self, LifecycleEvent, Plugin};
+use std::sync::Arc;
+
+const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+const PARENT: &str = "0000:00:03.0";
+const MDEV_TYPE: &str = "arbitrary_type";
+
+// plugins are registered for the whole test process, so they only act on the devices of the
+// test environment that registered them
+struct RecordingPlugin {
+    root: PathBuf,
+    veto: Option<Action>,
+    events: Mutex<Vec<String>>,
+}
+
+impl Plugin for RecordingPlugin {
+    fn name(&self) -> &str {
+        "recording"
+    }
+
+    fn handle(&self, event: &LifecycleEvent) -> Result<()> {
+        if event.dev.env.root() != self.root {
+            return Ok(());
+        }
+        self.events.lock().unwrap().push(format!(
+            "{} {} {} {}",
+            event.event,
+            event.action,
+            event.state,
+            event.dev.uuid.hyphenated()
+        ));
+        match self.veto {
+            Some(a) if a == event.action => Err(anyhow!("vetoed")),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn register(test: &TestEnvironment, veto: Option<Action>) -> Arc<RecordingPlugin> {
+    let plugin = Arc::new(RecordingPlugin {
+        root: test.root().to_path_buf(),
+        veto,
+        events: Mutex::new(Vec::new()),
+    });
+    plugins::register(plugin.clone());
+    plugin
+}
+
+#[test]
+fn test_plugins() {
+    init();
+
+    let test = TestEnvironment::new("plugins", "events");
+    let env: Rc<dyn Environment> = test.clone();
+    test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "", None);
+    let plugin = register(&test, None);
+
+    let res = crate::start_command(
+        env.clone(),
+        StartArgs {
+            uuid: Some(Uuid::parse_str(UUID).unwrap()),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert_eq!(
+        vec![
+            format!("pre start none {}", UUID),
+            format!("post start success {}", UUID),
+        ],
+        *plugin.events.lock().unwrap()
+    );
+
+    // a plugin failing the pre event aborts the action
+    let test = TestEnvironment::new("plugins", "veto");
+    let env: Rc<dyn Environment> = test.clone();
+    let plugin = register(&test, Some(Action::Define));
+    let res = crate::define_command(
+        env.clone(),
+        &DefineArgs {
+            uuid: Some(Uuid::parse_str(UUID).unwrap()),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
+    );
+    test.assert_result(
+        res,
+        Expect::Fail(Some("Plugin recording failed for pre-define")),
+        None,
+    )
+    .unwrap_err();
+    assert_eq!(
+        vec![format!("pre define none {}", UUID)],
+        *plugin.events.lock().unwrap()
+    );
+    assert!(!test.config_base().join(PARENT).join(UUID).exists());
+
+    // unless the action is forced
+    let res = crate::define_command(
+        env,
+        &DefineArgs {
+            uuid: Some(Uuid::parse_str(UUID).unwrap()),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            force: true,
+            ..Default::default()
+        },
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert!(test.config_base().join(PARENT).join(UUID).exists());
+}
@@ -0,0 +1,145 @@
+use super::*;
+
+const UUID: &[&str] = &[
+    "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+    "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    "4a0a190f-dcf3-4def-9342-c48768f0c940",
+    "9f579710-6ffc-4201-987a-4ffa0fb1f3a5",
+    "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+];
+const PARENT: &[&str] = &["0000:00:02.0", "0000:00:03.0"];
+const MDEV_TYPE: &[&str] = &["type_a", "type_b"];
+
+// set up defined devices in every possible reconciliation state
+fn setup(test: &Rc<TestEnvironment>) {
+    // running as desired
+    test.populate_active_device(UUID[0], PARENT[0], MDEV_TYPE[0]);
+    test.populate_defined_device(UUID[0], PARENT[0], "auto.json");
+    // not running, but parent present
+    test.populate_defined_device(UUID[1], PARENT[0], "auto.json");
+    // parent not present
+    test.populate_defined_device(UUID[2], PARENT[1], "auto.json");
+    // manually started
+    test.populate_defined_device(UUID[3], PARENT[0], "manual.json");
+    // running with a different type than defined
+    test.populate_active_device(UUID[4], PARENT[0], MDEV_TYPE[1]);
+    test.populate_defined_device(UUID[4], PARENT[0], "auto.json");
+}
+
+fn test_reconcile_report_helper(subtest: &str, parent: Option<String>) {
+    let test = TestEnvironment::new("reconcile", subtest);
+    let env: Rc<dyn Environment> = test.clone();
+    setup(&test);
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res =
+        crate::reconcile_command(env.clone(), parent.clone(), true, false, false, &mut outbuf);
+    if test.assert_result(res, Expect::Pass, Some("text")).is_ok() {
+        let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+        test.compare_to_file(&format!("{}.text", subtest), &actual);
+    }
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::reconcile_command(env, parent, true, true, false, &mut outbuf);
+    if test.assert_result(res, Expect::Pass, Some("json")).is_ok() {
+        let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+        test.compare_to_file(&format!("{}.json", subtest), &actual);
+    }
+}
+
+#[test]
+fn test_reconcile() {
+    init();
+
+    test_reconcile_report_helper("report", None);
+    test_reconcile_report_helper("report-parent", Some(PARENT[1].to_string()));
+
+    // only devices that are not running although their parent is present get started
+    let test = TestEnvironment::new("reconcile", "start");
+    let env: Rc<dyn Environment> = test.clone();
+    setup(&test);
+    let res = crate::reconcile_command(env, None, false, false, false, &mut std::io::sink());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+
+    let typedir = test
+        .parent_base()
+        .join(PARENT[0])
+        .join("mdev_supported_types");
+    let contents = fs::read_to_string(typedir.join(MDEV_TYPE[0]).join("create"))
+        .expect("Unable to read 'create' file");
+    assert_eq!(UUID[1], contents);
+    assert!(!typedir.join(MDEV_TYPE[1]).join("create").exists());
+}
+
+#[test]
+fn test_reconcile_health() {
+    init();
+
+    let test = TestEnvironment::new("reconcile", "health-report");
+    let env: Rc<dyn Environment> = test.clone();
+    // healthy, failed and unreadable health check
+    for (uuid, status) in [
+        (UUID[0], Some("ok\n")),
+        (UUID[1], Some("error\n")),
+        (UUID[3], None),
+    ] {
+        test.populate_active_device(uuid, PARENT[0], MDEV_TYPE[0]);
+        test.populate_defined_device(uuid, PARENT[0], "health.json");
+        if let Some(status) = status {
+            fs::write(test.mdev_base().join(uuid).join("status"), status)
+                .expect("Unable to write status attribute");
+        }
+    }
+    // inactive devices are not checked
+    test.populate_defined_device(UUID[2], PARENT[0], "health.json");
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::reconcile_command(env.clone(), None, true, false, false, &mut outbuf);
+    if test.assert_result(res, Expect::Pass, Some("text")).is_ok() {
+        let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+        test.compare_to_file("health-report.text", &actual);
+    }
+
+    // unhealthy devices are reported, but left running
+    let res = crate::reconcile_command(env, None, false, false, false, &mut std::io::sink());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert!(test.mdev_base().join(UUID[1]).exists());
+}
+
+#[test]
+fn test_reconcile_changed_only() {
+    init();
+
+    let test = TestEnvironment::new("reconcile", "changed-only");
+    let env: Rc<dyn Environment> = test.clone();
+    setup(&test);
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::reconcile_command(env.clone(), None, true, false, true, &mut outbuf);
+    test.assert_result(res, Expect::Pass, Some("report"))
+        .unwrap();
+    test.compare_to_file(
+        "changed-only-report.text",
+        &String::from_utf8(outbuf).expect("invalid utf8 output"),
+    );
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::reconcile_command(env.clone(), None, false, false, true, &mut outbuf);
+    test.assert_result(res, Expect::Pass, Some("reconcile"))
+        .unwrap();
+    test.compare_to_file(
+        "changed-only.text",
+        &String::from_utf8(outbuf).expect("invalid utf8 output"),
+    );
+
+    // nothing is printed if nothing needs to be changed
+    let test = TestEnvironment::new("reconcile", "changed-only-in-sync");
+    test.populate_active_device(UUID[0], PARENT[0], MDEV_TYPE[0]);
+    test.populate_defined_device(UUID[0], PARENT[0], "auto.json");
+    for report in [true, false] {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::reconcile_command(test.clone(), None, report, false, true, &mut outbuf);
+        test.assert_result(res, Expect::Pass, None).unwrap();
+        assert!(outbuf.is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+use super::*;
+use crate::cli::DefinitionChanges;
+use std::fs;
+use uuid::Uuid;
+
+const UUID: &[&str] = &[
+    "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+    "59e8b599-afdd-4766-a59e-415ef4f5a492",
+];
+const PARENT: &[&str] = &["0000:00:02.0", "0000:00:03.0"];
+
+fn stage_helper(
+    test: &Rc<TestEnvironment>,
+    uuid: &str,
+    mdev_type: Option<&str>,
+    addattr: Option<(&str, &str)>,
+    auto: bool,
+    labels: Vec<(String, String)>,
+) -> Result<()> {
+    crate::stage_modify_command(
+        test.clone(),
+        Uuid::parse_str(uuid).unwrap(),
+        None,
+        DefinitionChanges {
+            mdev_type: mdev_type.map(|t| t.to_string()),
+            addattr: addattr.map(|(attr, _)| attr.to_string()),
+            value: addattr.map(|(_, value)| value.to_string()),
+            auto,
+            label: labels,
+            ..Default::default()
+        },
+    )
+}
+
+fn read_definition(test: &Rc<TestEnvironment>, uuid: &str) -> serde_json::Value {
+    let dev = test
+        .clone()
+        .get_defined_device(Uuid::parse_str(uuid).unwrap(), None)
+        .expect("Couldn't find defined device");
+    serde_json::from_str(&fs::read_to_string(dev.persist_path().unwrap()).unwrap()).unwrap()
+}
+
+fn stage_list(test: &Rc<TestEnvironment>, subtest: &str) {
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::stage_list_command(test.clone(), false, &mut outbuf);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    test.compare_to_file(
+        &format!("{}.text", subtest),
+        &String::from_utf8(outbuf).unwrap(),
+    );
+}
+
+#[test]
+fn test_stage_commit() {
+    init();
+
+    let test = TestEnvironment::new("stage", "commit");
+    test.populate_defined_device(UUID[0], PARENT[0], "defined.json");
+    test.populate_defined_device(UUID[1], PARENT[1], "defined.json");
+    let original = read_definition(&test, UUID[0]);
+
+    // changes are accumulated in the staging area
+    let res = stage_helper(&test, UUID[0], None, None, true, Vec::new());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let res = stage_helper(
+        &test,
+        UUID[0],
+        None,
+        Some(("assign_domain", "5")),
+        false,
+        vec![("owner".to_string(), "vm1".to_string())],
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let res = stage_helper(&test, UUID[1], Some("type_a"), None, false, Vec::new());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    stage_list(&test, "staged");
+
+    // the definitions only change once the staged changes are committed
+    assert_eq!(original, read_definition(&test, UUID[0]));
+    assert_eq!(original, read_definition(&test, UUID[1]));
+
+    let res = crate::commit_command(test.clone(), false);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    for (i, uuid) in UUID.iter().enumerate() {
+        let dev = test
+            .clone()
+            .get_defined_device(Uuid::parse_str(uuid).unwrap(), None)
+            .unwrap();
+        test.compare_to_file(
+            &format!("committed-{}.expected", i),
+            &fs::read_to_string(dev.persist_path().unwrap()).unwrap(),
+        );
+    }
+    assert!(test
+        .clone()
+        .get_staged_devices(None, None)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_stage_commit_rollback() {
+    init();
+
+    let test = TestEnvironment::new("stage", "rollback");
+    test.populate_defined_device(UUID[0], PARENT[0], "defined.json");
+    test.populate_defined_device(UUID[1], PARENT[1], "defined.json");
+    test.populate_callout_script("reject-type-b.sh");
+    let original = read_definition(&test, UUID[0]);
+
+    let res = stage_helper(&test, UUID[0], None, None, true, Vec::new());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let res = stage_helper(&test, UUID[1], Some("type_b"), None, false, Vec::new());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+
+    // the changes of the first device are reverted when the second device can't be changed
+    let res = crate::commit_command(test.clone(), false);
+    let _ = test.assert_result(
+        res,
+        Expect::Fail(Some(
+            "Failed to commit the staged changes of device 59e8b599-afdd-4766-a59e-415ef4f5a492, no changes were applied",
+        )),
+        None,
+    );
+    assert_eq!(original, read_definition(&test, UUID[0]));
+    assert_eq!(original, read_definition(&test, UUID[1]));
+    assert_eq!(
+        2,
+        test.clone()
+            .get_staged_devices(None, None)
+            .unwrap()
+            .values()
+            .flatten()
+            .count()
+    );
+
+    // discarded changes are no longer committed
+    let res =
+        crate::stage_discard_command(test.clone(), Some(Uuid::parse_str(UUID[1]).unwrap()), None);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let res = crate::commit_command(test.clone(), false);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert_ne!(original, read_definition(&test, UUID[0]));
+    assert_eq!(original, read_definition(&test, UUID[1]));
+}
+
+#[test]
+fn test_stage_commit_changed_base() {
+    init();
+
+    let test = TestEnvironment::new("stage", "changed-base");
+    test.populate_defined_device(UUID[0], PARENT[0], "defined.json");
+    test.populate_defined_device(UUID[1], PARENT[1], "defined.json");
+
+    let res = stage_helper(&test, UUID[0], None, None, true, Vec::new());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let res = stage_helper(&test, UUID[1], Some("type_a"), None, false, Vec::new());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+
+    // the definition is changed after the changes were staged
+    let mut dev = test
+        .clone()
+        .get_defined_device(Uuid::parse_str(UUID[1]).unwrap(), None)
+        .unwrap();
+    dev.add_attribute("assign_adapter".to_string(), "5".to_string(), None)
+        .unwrap();
+    dev.write_config().unwrap();
+    let changed = read_definition(&test, UUID[1]);
+    let original = read_definition(&test, UUID[0]);
+
+    let res = crate::commit_command(test.clone(), false);
+    let _ = test.assert_result(
+        res,
+        Expect::Fail(Some(
+            "The definition of device 59e8b599-afdd-4766-a59e-415ef4f5a492 changed since its \
+             changes were staged, discard and stage them again",
+        )),
+        None,
+    );
+    assert_eq!(original, read_definition(&test, UUID[0]));
+    assert_eq!(changed, read_definition(&test, UUID[1]));
+
+    // changes staged on top of the current definition are committed
+    let res =
+        crate::stage_discard_command(test.clone(), Some(Uuid::parse_str(UUID[1]).unwrap()), None);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let res = stage_helper(&test, UUID[1], Some("type_a"), None, false, Vec::new());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let res = crate::commit_command(test.clone(), false);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert_ne!(original, read_definition(&test, UUID[0]));
+    assert_eq!("type_a", read_definition(&test, UUID[1])["mdev_type"]);
+}
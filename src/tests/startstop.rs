@@ -1,25 +1,33 @@
 use super::*;
-use std::{fs, path::PathBuf};
+use crate::bulk::OnFailure;
+use crate::cli::{AutostartArgs, StartArgs};
+#[cfg(feature = "callouts")]
+use crate::cli::{DefineArgs, DefinitionChanges, ModifyArgs};
+use std::fs;
+#[cfg(feature = "callouts")]
+use std::path::PathBuf;
+use std::time::Duration;
 use uuid::Uuid;
 
-fn test_start_helper<F>(
-    testname: &str,
-    expect: Expect,
-    uuid: Option<String>,
-    parent: Option<String>,
-    mdev_type: Option<String>,
-    jsonfile: Option<PathBuf>,
-    force: bool,
-    setupfn: F,
-) where
+#[cfg(feature = "callouts")]
+fn test_start_helper<F>(testname: &str, expect: Expect, args: StartArgs, setupfn: F)
+where
     F: Fn(Rc<TestEnvironment>),
 {
     let test = TestEnvironment::new("start", testname);
     let env: Rc<dyn Environment> = test.clone();
     setupfn(test.clone());
-    let uuid = uuid.map(|s| Uuid::parse_str(s.as_ref()).unwrap());
+    let uuid = args.uuid;
 
-    let result = crate::start_command_helper(env, uuid, parent, mdev_type, jsonfile, force);
+    let result = crate::start_command_helper(
+        env,
+        uuid,
+        args.parent,
+        args.mdev_type,
+        args.jsonfile,
+        args.force,
+        false,
+    );
 
     if let Ok(dev) = test.assert_result(result, expect, None) {
         let create_path = test
@@ -29,8 +37,8 @@ fn test_start_helper<F>(
             .join(dev.mdev_type.unwrap())
             .join("create");
         assert!(create_path.exists());
-        if uuid.is_some() {
-            assert_eq!(uuid.unwrap(), dev.uuid);
+        if let Some(uuid) = uuid {
+            assert_eq!(uuid, dev.uuid);
         }
         let contents = fs::read_to_string(create_path).expect("Unable to read 'create' file");
         assert_eq!(dev.uuid.hyphenated().to_string(), contents);
@@ -38,6 +46,7 @@ fn test_start_helper<F>(
 }
 
 #[test]
+#[cfg(feature = "callouts")]
 fn test_start() {
     init();
 
@@ -50,11 +59,12 @@ fn test_start() {
     test_start_helper(
         "uuid-type-parent",
         Expect::Pass,
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
         },
@@ -62,11 +72,11 @@ fn test_start() {
     test_start_helper(
         "no-uuid",
         Expect::Pass,
-        None,
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
         },
@@ -74,11 +84,10 @@ fn test_start() {
     test_start_helper(
         "no-uuid-no-parent",
         Expect::Fail(None),
-        None,
-        None,
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
         },
@@ -86,11 +95,10 @@ fn test_start() {
     test_start_helper(
         "no-uuid-no-type",
         Expect::Fail(None),
-        None,
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
+        StartArgs {
+            parent: Some(PARENT.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
         },
@@ -98,33 +106,33 @@ fn test_start() {
     test_start_helper(
         "no-parent",
         Expect::Fail(None),
-        Some(UUID.to_string()),
-        None,
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |_| {},
     );
     // should fail if there is no defined device with the given uuid
     test_start_helper(
         "no-type",
         Expect::Fail(None),
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            ..Default::default()
+        },
         |_| {},
     );
     // should pass if there is a defined device with the given uuid
     test_start_helper(
         "no-type-defined",
         Expect::Pass,
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID, PARENT, "defined.json");
@@ -133,11 +141,10 @@ fn test_start() {
     test_start_helper(
         "no-type-parent-defined",
         Expect::Pass,
-        Some(UUID.to_string()),
-        None,
-        None,
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID, PARENT, "defined.json");
@@ -146,11 +153,12 @@ fn test_start() {
     test_start_helper(
         "defined-with-type",
         Expect::Pass,
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID, PARENT, "defined.json");
@@ -162,11 +170,10 @@ fn test_start() {
     test_start_helper(
         "defined-multiple-underspecified",
         Expect::Fail(None),
-        Some(UUID.to_string()),
-        None,
-        None,
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID, PARENT, "defined.json");
@@ -177,11 +184,11 @@ fn test_start() {
     test_start_helper(
         "defined-multiple",
         Expect::Pass,
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        None,
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID, PARENT, "defined.json");
@@ -194,11 +201,12 @@ fn test_start() {
     test_start_helper(
         "defined-diff-type",
         Expect::Fail(None),
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        Some("wrong-type".to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some("wrong-type".to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID, PARENT, "defined.json");
@@ -207,11 +215,12 @@ fn test_start() {
     test_start_helper(
         "already-running",
         Expect::Fail(None),
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_active_device(UUID, PARENT, MDEV_TYPE);
@@ -220,11 +229,12 @@ fn test_start() {
     test_start_helper(
         "no-instances",
         Expect::Fail(None),
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 0, "vfio-pci", "testdev", None);
         },
@@ -233,11 +243,12 @@ fn test_start() {
     test_start_helper(
         "uuid-type-parent",
         Expect::Pass,
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
         },
@@ -246,11 +257,12 @@ fn test_start() {
     test_start_helper(
         "defined-multiple-callout-success",
         Expect::Pass,
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID, PARENT, "defined.json");
@@ -262,11 +274,12 @@ fn test_start() {
     test_start_helper(
         "defined-multiple-callout-fail",
         Expect::Fail(None),
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID, PARENT, "defined.json");
@@ -278,11 +291,13 @@ fn test_start() {
     test_start_helper(
         "defined-multiple-callout-fail-force",
         Expect::Pass,
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        true,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            force: true,
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID, PARENT, "defined.json");
@@ -296,11 +311,12 @@ fn test_start() {
         Expect::Fail(Some(
             format!("Unable to find parent device '{}'", PARENT).as_str(),
         )),
-        Some(UUID.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |_| {},
     );
     test_start_helper(
@@ -309,20 +325,117 @@ fn test_start() {
             format!(
                 "Unable to find parent device '{}'. Did you mean '{}'?",
                 PARENT3.to_string().to_uppercase(),
-                PARENT3.to_string()
+                PARENT3
             )
             .as_str(),
         )),
-        Some(UUID.to_string()),
-        Some(PARENT3.to_string().to_uppercase()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT3.to_string().to_uppercase()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT3, MDEV_TYPE, 1, "vfio-pci", "test device", None);
         },
     );
 
+    // devices with host requirements may only be started if all requirements are met
+    test_start_helper(
+        "requires-met",
+        Expect::Pass,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            ..Default::default()
+        },
+        |test| {
+            test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
+            test.populate_defined_device(UUID, PARENT, "requires.json");
+            // module names match regardless of '-' or '_' on either side
+            test.populate_host_resources(&["vfio_mdev", "vfio-ap"], 512, &["/dev/vfio/vfio"]);
+        },
+    );
+    test_start_helper(
+        "requires-unmet",
+        Expect::Fail(Some(
+            format!(
+                "Requirements for device {} are not met:\n  \
+                 - kernel module 'vfio_ap' is not loaded\n  \
+                 - 512 free hugepages required, but only 128 available\n  \
+                 - required file \"/dev/vfio/vfio\" does not exist",
+                UUID
+            )
+            .as_str(),
+        )),
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            ..Default::default()
+        },
+        |test| {
+            test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
+            test.populate_defined_device(UUID, PARENT, "requires.json");
+            test.populate_host_resources(&["vfio_mdev"], 128, &[]);
+        },
+    );
+
+    test_start_helper(
+        "invalid-instances",
+        Expect::Fail(Some(
+            format!(
+                "Invalid number of available instances \"\" for mdev type {} on parent {}",
+                MDEV_TYPE, PARENT
+            )
+            .as_str(),
+        )),
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
+        |test| {
+            let (_, typedir) =
+                test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
+            fs::write(typedir.join("available_instances"), "")
+                .expect("Unable to write available_instances");
+        },
+    );
+
+    // driver messages logged while creating the device are included in the error
+    test_start_helper(
+        "create-fail-kmsg",
+        Expect::Fail(Some(
+            format!(
+                "Failed to create mdev {}, type {} on {}\n\
+                 Kernel messages:\n  \
+                 vfio-pci {}: not enough resources for {}\n  \
+                 vfio-pci {}: mdev create failed",
+                UUID, MDEV_TYPE, PARENT, PARENT, UUID, PARENT
+            )
+            .as_str(),
+        )),
+        StartArgs {
+            uuid: Uuid::parse_str(UUID).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
+        |test| {
+            let (_, typedir) =
+                test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
+            // writing to a directory fails, just like a driver rejecting the request
+            fs::create_dir(typedir.join("create")).expect("Unable to setup create dir");
+            test.populate_kernel_log(&[
+                "usb 1-1: new high-speed USB device number 2",
+                &format!("vfio-pci {}: not enough resources for {}", PARENT, UUID),
+                " SUBSYSTEM=pci",
+                &format!("vfio-pci {}: mdev create failed", PARENT),
+            ]);
+        },
+    );
+
     // TODO: test attributes -- difficult because executing the 'start' command by writing to
     // the 'create' file in sysfs does not automatically create the device file structure in
     // the temporary test environment, so writing the sysfs attribute files fails.
@@ -333,11 +446,12 @@ fn test_start() {
     test_start_helper(
         "start-single-with-version-callout-pass",
         Expect::Pass,
-        Some(UUID_VER.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID_VER).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID_VER, PARENT, "defined.json");
@@ -347,11 +461,12 @@ fn test_start() {
     test_start_helper(
         "start-single-with-version-callout-fail",
         Expect::Fail(None),
-        Some(UUID_VER.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID_VER).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID_VER, PARENT, "defined.json");
@@ -361,11 +476,12 @@ fn test_start() {
     test_start_helper(
         "start-with-version-callout-multiple-with-version-pass",
         Expect::Pass,
-        Some(UUID_VER.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID_VER).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID_VER, PARENT, "defined.json");
@@ -376,11 +492,12 @@ fn test_start() {
     test_start_helper(
         "start-with-version-callout-multiple-with-version-pass2",
         Expect::Pass,
-        Some(UUID_VER.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID_VER).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID_VER, PARENT, "defined.json");
@@ -391,11 +508,12 @@ fn test_start() {
     test_start_helper(
         "start-with-version-callout-multiple-with-version-fail",
         Expect::Fail(None),
-        Some(UUID_VER.to_string()),
-        Some(PARENT.to_string()),
-        Some(MDEV_TYPE.to_string()),
-        None,
-        false,
+        StartArgs {
+            uuid: Uuid::parse_str(UUID_VER).ok(),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
         |test| {
             test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
             test.populate_defined_device(UUID_VER, PARENT, "defined.json");
@@ -405,6 +523,7 @@ fn test_start() {
     );
 }
 
+#[cfg(feature = "callouts")]
 fn test_stop_helper<F>(testname: &str, expect: Expect, uuid: &str, force: bool, setupfn: F)
 where
     F: Fn(Rc<TestEnvironment>),
@@ -413,9 +532,9 @@ where
     let env: Rc<dyn Environment> = test.clone();
     setupfn(test.clone());
 
-    let res = crate::stop_command(env, Uuid::parse_str(uuid).unwrap(), force);
+    let res = crate::stop_command(env, Uuid::parse_str(uuid).unwrap(), force, false);
 
-    if let Ok(_) = test.assert_result(res, expect, None) {
+    if test.assert_result(res, expect, None).is_ok() {
         let remove_path = test.mdev_base().join(uuid).join("remove");
         assert!(remove_path.exists());
         let contents = fs::read_to_string(remove_path).expect("Unable to read 'remove' file");
@@ -424,6 +543,7 @@ where
 }
 
 #[test]
+#[cfg(feature = "callouts")]
 fn test_stop() {
     init();
 
@@ -493,3 +613,1066 @@ fn test_stop() {
         },
     );
 }
+
+#[test]
+fn test_start_timeout() {
+    init();
+
+    use crate::error::ErrorCategory;
+    use nix::sys::stat::Mode;
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("start", "start-timeout");
+    test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
+    test.populate_defined_device(UUID, PARENT, "start-timeout.json");
+    // writing the uuid blocks until it is read, like a driver waiting for busy firmware
+    let create = test
+        .parent_base()
+        .join(PARENT)
+        .join("mdev_supported_types")
+        .join(MDEV_TYPE)
+        .join("create");
+    nix::unistd::mkfifo(&create, Mode::S_IRWXU).expect("Unable to setup create");
+
+    // the driver completes the write after the timeout, without creating the device
+    let driver = {
+        let create = create.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(1500));
+            fs::read_to_string(&create).unwrap()
+        })
+    };
+
+    let res = crate::start_command_helper(
+        test.clone(),
+        Some(Uuid::parse_str(UUID).unwrap()),
+        None,
+        None,
+        None,
+        false,
+        false,
+    );
+    let e = res.expect_err("start did not time out");
+    assert_eq!(ErrorCategory::Timeout, ErrorCategory::of(&e));
+    assert_eq!(UUID, driver.join().unwrap());
+    assert!(!test.mdev_base().join(UUID).exists());
+}
+
+#[test]
+fn test_start_timeout_remove_late_device() {
+    init();
+
+    use crate::error::ErrorCategory;
+    use nix::sys::stat::Mode;
+    use std::os::unix::fs::symlink;
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("start", "start-timeout");
+    let (parentdir, _) =
+        test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
+    test.populate_defined_device(UUID, PARENT, "start-timeout.json");
+    let create = parentdir
+        .join("mdev_supported_types")
+        .join(MDEV_TYPE)
+        .join("create");
+    nix::unistd::mkfifo(&create, Mode::S_IRWXU).expect("Unable to setup create");
+
+    // the driver creates the device only after the start timed out
+    let devdir = test.mdev_base().join(UUID);
+    let driver = {
+        let (create, devdir) = (create.clone(), devdir.clone());
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(1500));
+            let parentdevdir = parentdir.join(UUID);
+            fs::create_dir_all(&parentdevdir).unwrap();
+            fs::create_dir_all(devdir.parent().unwrap()).unwrap();
+            symlink(&parentdevdir, &devdir).unwrap();
+            fs::read_to_string(&create).unwrap()
+        })
+    };
+
+    let res = crate::start_command_helper(
+        test.clone(),
+        Some(Uuid::parse_str(UUID).unwrap()),
+        None,
+        None,
+        None,
+        false,
+        false,
+    );
+    let e = res.expect_err("start did not time out");
+    assert_eq!(ErrorCategory::Timeout, ErrorCategory::of(&e));
+    assert_eq!(UUID, driver.join().unwrap());
+    // the device was stopped once it appeared
+    assert_eq!("1", fs::read_to_string(devdir.join("remove")).unwrap());
+}
+
+#[test]
+fn test_stop_kill_users() {
+    init();
+
+    use std::os::unix::fs::symlink;
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    // the device is busy while a VM uses it, it is removed once the VM exited unless the remove
+    // file is broken
+    for (kill_users, ignore, broken, expect_killed) in [
+        (false, "", false, ""),
+        (true, "", false, "1234 TERM\n"),
+        (true, "TERM", false, "1234 TERM\n1234 KILL\n"),
+        (true, "", true, ""),
+    ] {
+        let test = TestEnvironment::new("stop", "kill-users");
+        test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+        test.populate_defined_device(UUID, PARENT, "stop-timeout.json");
+        let devdir = test.mdev_base().join(UUID);
+        match broken {
+            // writing the remove file fails with EISDIR, which is no reason to kill anything
+            true => fs::create_dir_all(devdir.join("remove")).expect("Unable to setup remove"),
+            false => fs::write(devdir.join("busy"), "pid:1234").expect("Unable to setup remove"),
+        }
+        let group = test.iommu_groups_base().join("12");
+        fs::create_dir_all(&group).expect("Unable to setup IOMMU group");
+        symlink(&group, devdir.join("iommu_group")).expect("Unable to setup IOMMU group");
+        // a VM using the device and an unrelated process
+        for (pid, node) in [("1234", "/dev/vfio/12"), ("99", "/dev/vfio/13")] {
+            let fds = test.proc_base().join(pid).join("fd");
+            fs::create_dir_all(&fds).expect("Unable to setup process");
+            symlink(node, fds.join("3")).expect("Unable to setup process");
+        }
+        fs::write(test.proc_base().join("1234/ignore"), ignore).expect("Unable to setup process");
+
+        let dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+        assert_eq!(vec![1234], dev.users());
+
+        let res = crate::stop_command(
+            test.clone(),
+            Uuid::parse_str(UUID).unwrap(),
+            false,
+            kill_users,
+        );
+        assert_eq!(kill_users && !broken, res.is_ok(), "{:?}", res);
+        let killed = fs::read_to_string(test.killed_path()).unwrap_or_default();
+        assert_eq!(expect_killed, killed);
+        assert!(test.proc_base().join("99").exists());
+    }
+}
+
+#[test]
+fn test_stop_busy() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    // the removal is retried while the device is busy, until its stop_timeout expires
+    for (attempts, expect) in [("3", Expect::Pass), ("1000", Expect::Fail(None))] {
+        let test = TestEnvironment::new("stop", "busy");
+        test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+        test.populate_defined_device(UUID, PARENT, "stop-timeout-1s.json");
+        let busy = test.mdev_base().join(UUID).join("busy");
+        fs::write(&busy, attempts).expect("Unable to setup remove");
+
+        let res = crate::stop_command(test.clone(), Uuid::parse_str(UUID).unwrap(), false, false);
+        let _ = test.assert_result(res, expect, Some(attempts));
+        if let Expect::Pass = expect {
+            assert_eq!("0", fs::read_to_string(&busy).unwrap());
+            assert_eq!(
+                "1",
+                fs::read_to_string(test.mdev_base().join(UUID).join("remove")).unwrap()
+            );
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_recorded_callout() {
+    init();
+
+    const UUID_VER: &str = "11111111-1111-0000-0000-000000000000";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+    let uuid = Uuid::parse_str(UUID_VER).unwrap();
+
+    // the script negotiated on start is recorded in the runtime state
+    let test = TestEnvironment::new("start", "record-callout");
+    let env: Rc<dyn Environment> = test.clone();
+    test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
+    test.populate_defined_device(UUID_VER, PARENT, "defined.json");
+    test.populate_callout_script("ver-rc0.sh");
+    let res = crate::start_command_helper(env, Some(uuid), None, None, None, false, false);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+
+    let state = test.callout_state_base().join(UUID_VER);
+    let recorded: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(state).expect("callout state not recorded"))
+            .unwrap();
+    assert_eq!(
+        test.callout_dir().join("ver-rc0.sh").to_str(),
+        recorded["path"].as_str()
+    );
+    assert_eq!(Some(2), recorded["supports"]["version"].as_u64());
+
+    // the recorded script is used to stop the device, even though a different script would be
+    // negotiated now
+    let test = TestEnvironment::new("stop", "recorded-callout");
+    let env: Rc<dyn Environment> = test.clone();
+    test.populate_active_device(UUID_VER, PARENT, MDEV_TYPE);
+    test.populate_callout_script("ver-rc1.sh");
+    test.populate_callout_script_full("ver-rc0.sh", Some("recorded.sh"), false);
+    let state = test.callout_state_base().join(UUID_VER);
+    fs::create_dir_all(test.callout_state_base()).unwrap();
+    fs::write(
+        &state,
+        serde_json::json!({
+            "path": test.old_callout_dir().join("recorded.sh"),
+            "parent": PARENT,
+            "mdev_type": MDEV_TYPE,
+            "supports": {
+                "version": 2,
+                "actions": ["start", "stop", "define", "undefine", "modify", "attributes"],
+                "events": ["pre", "post", "notify", "get"]
+            }
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let res = crate::stop_command(env, uuid, false, false);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert!(!state.exists());
+}
+
+#[test]
+fn test_start_wait() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("start", "wait");
+    test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+    let dev = MDev::new(test.clone(), Uuid::parse_str(UUID).unwrap());
+
+    // the device never joins an IOMMU group
+    let res = dev.wait_until_ready(std::time::Duration::from_secs(1));
+    let _ = test.assert_result(
+        res,
+        Expect::Fail(Some(
+            "Device 976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9 did not become ready within 1 seconds",
+        )),
+        None,
+    );
+
+    // the device becomes usable while waiting
+    let group = dev.path().join("iommu_group");
+    let creator = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        fs::create_dir(group).expect("Unable to create iommu group");
+    });
+    let res = dev.wait_until_ready(std::time::Duration::from_secs(5));
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    creator.join().unwrap();
+}
+
+#[test]
+fn test_start_concurrent() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let uuid = Uuid::parse_str(UUID).unwrap();
+    let start = |test: &Rc<TestEnvironment>| {
+        crate::start_command_helper(
+            test.clone(),
+            Some(uuid),
+            Some(PARENT.to_string()),
+            Some(MDEV_TYPE.to_string()),
+            None,
+            false,
+            false,
+        )
+    };
+    let test = TestEnvironment::new("start", "concurrent");
+    test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
+
+    // another start of the same device is in progress
+    let lock = crate::mdev::StartLock::acquire(test.as_ref(), &uuid).unwrap();
+    let _ = test.assert_result(
+        start(&test),
+        Expect::Fail(Some(
+            "Device 976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9 is already being started",
+        )),
+        None,
+    );
+    assert!(!test
+        .parent_base()
+        .join(PARENT)
+        .join("mdev_supported_types")
+        .join(MDEV_TYPE)
+        .join("create")
+        .exists());
+
+    drop(lock);
+    test.assert_result(start(&test), Expect::Pass, None)
+        .unwrap();
+}
+
+#[test]
+fn test_start_no_mdev_support() {
+    init();
+
+    let test = TestEnvironment::new("start", "no-mdev-support");
+    fs::remove_dir(test.parent_base()).expect("Unable to remove parent_base");
+    let res = crate::start_command_helper(
+        test.clone(),
+        Some(Uuid::parse_str("976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9").unwrap()),
+        Some("0000:00:03.0".to_string()),
+        Some("arbitrary_type".to_string()),
+        None,
+        false,
+        false,
+    );
+    let _ = test.assert_result(
+        res,
+        Expect::Fail(Some(
+            "Mediated device framework not available (is the kernel built with CONFIG_VFIO_MDEV?)",
+        )),
+        None,
+    );
+}
+
+#[test]
+fn test_start_strict_parent_check() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let start = |testname: &str, max: Option<&str>, strict: bool, expect: Expect| {
+        let test = TestEnvironment::new("start", testname);
+        test.populate_active_device("11111111-1111-0000-0000-000000000000", PARENT, MDEV_TYPE);
+        test.populate_active_device("22222222-2222-0000-0000-000000000000", PARENT, MDEV_TYPE);
+        // a device of another type does not count
+        test.populate_active_device("33333333-3333-0000-0000-000000000000", PARENT, "other");
+        // the driver claims capacity regardless of its maximum
+        let (_, typedir) =
+            test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "test device", None);
+        if let Some(max) = max {
+            fs::write(typedir.join("max_instances"), max).expect("Unable to write max_instances");
+        }
+        let res = crate::start_command_helper(
+            test.clone(),
+            Some(Uuid::parse_str(UUID).unwrap()),
+            Some(PARENT.to_string()),
+            Some(MDEV_TYPE.to_string()),
+            None,
+            false,
+            strict,
+        );
+        let _ = test.assert_result(res, expect, None);
+    };
+
+    start(
+        "strict-exceeded",
+        Some("2\n"),
+        true,
+        Expect::Fail(Some(
+            "Capacity of arbitrary_type on 0000:00:03.0 exceeded: 2 of 2 instances are active",
+        )),
+    );
+    start("strict-capacity", Some("3\n"), true, Expect::Pass);
+    start("strict-no-max", None, true, Expect::Pass);
+    start("not-strict", Some("2\n"), false, Expect::Pass);
+}
+
+#[cfg(feature = "callouts")]
+fn test_restart_helper<F>(testname: &str, expect: Expect, uuid: &str, force: bool, setupfn: F)
+where
+    F: Fn(Rc<TestEnvironment>),
+{
+    let test = TestEnvironment::new("restart", testname);
+    let env: Rc<dyn Environment> = test.clone();
+    setupfn(test.clone());
+
+    let res = crate::restart_command(env, Uuid::parse_str(uuid).unwrap(), force);
+
+    if test.assert_result(res, expect, None).is_ok() {
+        let log = fs::read_to_string(test.root().join("restart.log")).unwrap_or_default();
+        test.compare_to_file(&format!("{}.log", testname), &log);
+    }
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_restart() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let create_path = |test: &TestEnvironment, mdev_type: &str| {
+        test.parent_base()
+            .join(PARENT)
+            .join("mdev_supported_types")
+            .join(mdev_type)
+            .join("create")
+    };
+
+    test_restart_helper("not-active", Expect::Fail(None), UUID, false, |_| {});
+    // a transient device is started again with the same type
+    test_restart_helper("transient", Expect::Pass, UUID, false, |t| {
+        t.populate_active_device(UUID, PARENT, MDEV_TYPE);
+        t.populate_callout_script("restart.sh");
+    });
+    // a defined device is started from its definition, including a change of its type since it
+    // was started
+    test_restart_helper("defined", Expect::Pass, UUID, false, |t| {
+        t.populate_active_device(UUID, PARENT, MDEV_TYPE);
+        t.populate_parent_device(PARENT, "i915-GVTg_V5_4", 1, "vfio-pci", "", None);
+        t.populate_defined_device(UUID, PARENT, "defined.json");
+        t.populate_callout_script("restart.sh");
+    });
+    // the device is not started again if it could not be stopped
+    test_restart_helper("stop-fail", Expect::Fail(None), UUID, false, |t| {
+        t.populate_active_device(UUID, PARENT, MDEV_TYPE);
+        t.populate_callout_script("rc1.sh");
+    });
+
+    let test = TestEnvironment::new("restart", "defined-created");
+    test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+    test.populate_parent_device(PARENT, "i915-GVTg_V5_4", 1, "vfio-pci", "", None);
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    test.populate_callout_script("restart.sh");
+    crate::restart_command(test.clone(), Uuid::parse_str(UUID).unwrap(), false)
+        .expect("Failed to restart device");
+    assert_eq!(
+        UUID,
+        fs::read_to_string(create_path(&test, "i915-GVTg_V5_4")).unwrap()
+    );
+    assert!(!create_path(&test, MDEV_TYPE).exists());
+    assert_eq!(
+        "1",
+        fs::read_to_string(test.parent_base().join(PARENT).join(UUID).join("remove")).unwrap()
+    );
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_autostart() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "4a0a190f-dcf3-4def-9342-c48768f0c940",
+        "9f579710-6ffc-4201-987a-4ffa0fb1f3a5",
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("autostart", "default");
+    let env: Rc<dyn Environment> = test.clone();
+    test.populate_parent_device(PARENT, "type_a", 1, "vfio-pci", "", None);
+    // no instances of this type are available
+    test.populate_parent_device(PARENT, "type_b", 0, "vfio-pci", "", None);
+    test.populate_callout_script("autostart.sh");
+    // started successfully
+    test.populate_defined_device(UUID[0], PARENT, "type-a.json");
+    // rejected by the callout script
+    test.populate_defined_device(UUID[1], PARENT, "type-a.json");
+    // the callout script does not exit before the timeout
+    test.populate_defined_device(UUID[2], PARENT, "type-a.json");
+    // rejected by sysfs
+    test.populate_defined_device(UUID[3], PARENT, "type-b.json");
+    // not started automatically
+    test.populate_defined_device(UUID[4], PARENT, "manual.json");
+
+    // a failure marker of an earlier attempt is removed once the device is started
+    let markers = test.autostart_state_base();
+    fs::create_dir_all(&markers).expect("Unable to setup autostart state dir");
+    fs::write(markers.join(UUID[0]), "{}").expect("Unable to write autostart failure");
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::start_parent_mdevs_command(
+        env,
+        PARENT.to_string(),
+        &AutostartArgs {
+            timeout: 1,
+            ..Default::default()
+        },
+        &mut outbuf,
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert_eq!(
+        "Started 1 of 4 devices on parent 0000:00:03.0, 1 callout-veto, 1 sysfs-error, 1 timeout\n",
+        String::from_utf8(outbuf).expect("invalid utf8 output")
+    );
+
+    let failure = |uuid: &str| -> Option<String> {
+        let contents = fs::read_to_string(markers.join(uuid)).ok()?;
+        let json: serde_json::Value =
+            serde_json::from_str(&contents).expect("Invalid autostart failure");
+        assert_eq!(PARENT, json["parent"]);
+        Some(json["failure"].as_str().unwrap().to_string())
+    };
+    assert_eq!(None, failure(UUID[0]));
+    assert_eq!(Some("callout-veto".to_string()), failure(UUID[1]));
+    assert_eq!(Some("timeout".to_string()), failure(UUID[2]));
+    assert_eq!(Some("sysfs-error".to_string()), failure(UUID[3]));
+    assert_eq!(None, failure(UUID[4]));
+}
+
+#[test]
+fn test_autostart_json() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "9f579710-6ffc-4201-987a-4ffa0fb1f3a5",
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("autostart", "json");
+    test.populate_parent_device(PARENT, "type_a", 1, "vfio-pci", "", None);
+    test.populate_parent_device(PARENT, "type_b", 0, "vfio-pci", "", None);
+    test.populate_defined_device(UUID[0], PARENT, "type-a.json");
+    test.populate_defined_device(UUID[1], PARENT, "type-b.json");
+    test.populate_defined_device(UUID[2], PARENT, "manual.json");
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::start_parent_mdevs_command(
+        test.clone(),
+        PARENT.to_string(),
+        &AutostartArgs {
+            timeout: 1,
+            dumpjson: true,
+            ..Default::default()
+        },
+        &mut outbuf,
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    test.compare_to_file(
+        "result.json",
+        &String::from_utf8(outbuf).expect("invalid utf8 output"),
+    );
+}
+
+#[test]
+fn test_autostart_jobs() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "9f579710-6ffc-4201-987a-4ffa0fb1f3a5",
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+
+    for (subtest, jobs) in [("jobs-policy", None), ("jobs", Some(2))] {
+        let test = TestEnvironment::new("autostart", subtest);
+        test.populate_parent_device(PARENT, "type_a", 1, "vfio-pci", "", None);
+        test.populate_parent_device(PARENT, "type_b", 0, "vfio-pci", "", None);
+        test.populate_defined_device(UUID[0], PARENT, "type-a.json");
+        test.populate_defined_device(UUID[1], PARENT, "type-b.json");
+        test.populate_defined_device(UUID[2], PARENT, "manual.json");
+        fs::write(test.policy_path(), r#"{"autostart_jobs": 4}"#).expect("Unable to write policy");
+
+        // the devices are started in worker threads, with the same result as one after another
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::start_parent_mdevs_command(
+            test.clone(),
+            PARENT.to_string(),
+            &AutostartArgs {
+                timeout: 1,
+                jobs,
+                dumpjson: true,
+                ..Default::default()
+            },
+            &mut outbuf,
+        );
+        test.assert_result(res, Expect::Pass, None).unwrap();
+        test.compare_to_file(
+            "result.json",
+            &String::from_utf8(outbuf).expect("invalid utf8 output"),
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_autostart_dry_run() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "9f579710-6ffc-4201-987a-4ffa0fb1f3a5",
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+
+    for (subtest, dumpjson) in [("dry-run.text", false), ("dry-run.json", true)] {
+        let test = TestEnvironment::new("autostart", subtest);
+        test.populate_parent_device(PARENT, "type_a", 2, "vfio-pci", "", None);
+        test.populate_callout_script("autostart.sh");
+        test.populate_defined_device(UUID[0], PARENT, "type-a.json");
+        // rejected by the callout script
+        test.populate_defined_device(UUID[1], PARENT, "type-a.json");
+        test.populate_active_device(UUID[2], PARENT, "type_a");
+        test.populate_defined_device(UUID[2], PARENT, "type-a.json");
+        test.populate_defined_device(UUID[3], PARENT, "manual.json");
+
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::preview_parent_mdevs_command(
+            test.clone(),
+            PARENT.to_string(),
+            dumpjson,
+            &mut outbuf,
+        );
+        test.assert_result(res, Expect::Pass, None).unwrap();
+        // show the script path relative to the root of the test environment
+        let root = test.root().display().to_string();
+        let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+        test.compare_to_file(subtest, &actual.replace(&root, ""));
+
+        // nothing was written to sysfs
+        let create = test
+            .parent_base()
+            .join(PARENT)
+            .join("mdev_supported_types")
+            .join("type_a")
+            .join("create");
+        assert!(!create.exists());
+    }
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_start_all_parents() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "9f579710-6ffc-4201-987a-4ffa0fb1f3a5",
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+        "4a0a190f-dcf3-4def-9342-c48768f0c940",
+    ];
+    const PARENT: &[&str] = &[
+        "0000:00:02.0",
+        "0000:00:03.0",
+        "0000:00:04.0",
+        "0000:00:05.0",
+    ];
+
+    let test = TestEnvironment::new("autostart", "all-parents");
+    test.populate_parent_device(PARENT[0], "type_a", 2, "vfio-pci", "", None);
+    test.populate_parent_device(PARENT[1], "type_a", 1, "vfio-pci", "", None);
+    test.populate_parent_device(PARENT[3], "type_a", 1, "vfio-pci", "", None);
+    test.populate_defined_device(UUID[0], PARENT[0], "type-a.json");
+    test.populate_defined_device(UUID[1], PARENT[0], "type-a.json");
+    test.populate_defined_device(UUID[2], PARENT[1], "type-a.json");
+    // the parent is not present
+    test.populate_defined_device(UUID[3], PARENT[2], "type-a.json");
+    // no devices start automatically
+    test.populate_defined_device(UUID[4], PARENT[3], "manual.json");
+    let record = test.record_callouts();
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::start_all_parents_command(
+        test.clone(),
+        &AutostartArgs {
+            timeout: 1,
+            ..Default::default()
+        },
+        &mut outbuf,
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    test.compare_to_file(
+        "all-parents.text",
+        &String::from_utf8(outbuf).expect("invalid utf8 output"),
+    );
+
+    let started: Vec<String> = fs::read_to_string(record)
+        .expect("Unable to read call-out record")
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|v| v["event"] == "pre" && v["action"] == "start")
+        .map(|v| v["uuid"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(vec![UUID[1], UUID[0], UUID[2]], started);
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_autostart_active() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("autostart", "active");
+    test.populate_parent_device(PARENT, "type_a", 1, "vfio-pci", "", None);
+    // already started by an earlier trigger of the parent
+    test.populate_active_device(UUID[0], PARENT, "type_a");
+    test.populate_defined_device(UUID[0], PARENT, "type-a.json");
+    test.populate_defined_device(UUID[1], PARENT, "type-a.json");
+    let record = test.record_callouts();
+    test.populate_callout_script("rc0.sh");
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::start_parent_mdevs_command(
+        test.clone(),
+        PARENT.to_string(),
+        &AutostartArgs {
+            timeout: 1,
+            ..Default::default()
+        },
+        &mut outbuf,
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert_eq!(
+        "Started 1 of 1 devices on parent 0000:00:03.0\n",
+        String::from_utf8(outbuf).expect("invalid utf8 output")
+    );
+
+    // no call-outs for the device that is already active
+    let events = fs::read_to_string(record).expect("Unable to read call-out record");
+    assert!(!events.is_empty());
+    assert!(!events.contains(UUID[0]));
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_autostart_order() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "4a0a190f-dcf3-4def-9342-c48768f0c940",
+        "9f579710-6ffc-4201-987a-4ffa0fb1f3a5",
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+    ];
+    const ACTIVE: &str = "11111111-1111-0000-0000-000000000000";
+    const PARENT: &[&str] = &["0000:00:03.0", "0000:00:02.0"];
+
+    let test = TestEnvironment::new("autostart", "order");
+    test.populate_parent_device(PARENT[0], "type_a", 3, "vfio-pci", "", None);
+    test.populate_parent_device(PARENT[1], "type_a", 3, "vfio-pci", "", None);
+    test.populate_active_device(ACTIVE, PARENT[0], "type_a");
+    test.populate_defined_device(UUID[0], PARENT[0], "type-a.json");
+    test.populate_defined_device(UUID[1], PARENT[0], "priority.json");
+    // depends on the first device, which is not created in the test environment
+    test.populate_defined_device(UUID[2], PARENT[0], "depends.json");
+    // devices on another parent, which are started once the first device was started
+    test.populate_defined_device(UUID[3], PARENT[1], "depends.json");
+    test.populate_defined_device(UUID[4], PARENT[1], "depends-active.json");
+    let record = test.record_callouts();
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::start_parent_mdevs_command(
+        test.clone(),
+        PARENT[0].to_string(),
+        &AutostartArgs {
+            timeout: 1,
+            dumpjson: true,
+            ..Default::default()
+        },
+        &mut outbuf,
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    test.compare_to_file(
+        "order.json",
+        &String::from_utf8(outbuf).expect("invalid utf8 output"),
+    );
+
+    let started: Vec<String> = fs::read_to_string(record)
+        .expect("Unable to read call-out record")
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|v| v["event"] == "pre" && v["action"] == "start")
+        .map(|v| v["uuid"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(vec![UUID[1], UUID[0], UUID[2], UUID[4], UUID[3]], started);
+}
+
+#[test]
+fn test_start_all_defined() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+        "4a0a190f-dcf3-4def-9342-c48768f0c940",
+        "9f579710-6ffc-4201-987a-4ffa0fb1f3a5",
+    ];
+    const PARENT: &[&str] = &["0000:00:02.0", "0000:00:03.0"];
+
+    for (subtest, auto_only, on_failure, expect) in [
+        (
+            "all-defined",
+            false,
+            OnFailure::Continue,
+            Expect::Fail(Some("Failed to start 1 of 3 devices")),
+        ),
+        (
+            "all-defined-auto",
+            true,
+            OnFailure::Continue,
+            Expect::Fail(Some("Failed to start 1 of 2 devices")),
+        ),
+        // the failing device comes first, so the others are not started
+        (
+            "all-defined-fail-fast",
+            false,
+            OnFailure::Cancel,
+            Expect::Fail(Some("Failed to start 1 of 3 devices, 2 cancelled")),
+        ),
+    ] {
+        let test = TestEnvironment::new("autostart", subtest);
+        test.populate_parent_device(PARENT[0], "type_a", 1, "vfio-pci", "", None);
+        // no instances of this type are available
+        test.populate_parent_device(PARENT[0], "type_b", 0, "vfio-pci", "", None);
+        test.populate_defined_device(UUID[0], PARENT[0], "type-a.json");
+        test.populate_defined_device(UUID[1], PARENT[0], "manual.json");
+        test.populate_defined_device(UUID[2], PARENT[0], "type-b.json");
+        // already running
+        test.populate_active_device(UUID[3], PARENT[1], "type_a");
+        test.populate_defined_device(UUID[3], PARENT[1], "type-a.json");
+
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::start_all_defined_command(
+            test.clone(),
+            &StartArgs {
+                all_defined: true,
+                auto_only,
+                fail_fast: on_failure == OnFailure::Cancel,
+                ..Default::default()
+            },
+            &mut outbuf,
+        );
+        let _ = test.assert_result(res, expect, None);
+        let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+        test.compare_to_file(&format!("{}.text", subtest), &actual);
+    }
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_audit() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("startstop", "audit");
+    test.populate_parent_device(PARENT, MDEV_TYPE, 2, "vfio-pci", "test device", None);
+    let start = |uuid: &str| {
+        crate::start_command_helper(
+            test.clone(),
+            Some(Uuid::parse_str(uuid).unwrap()),
+            Some(PARENT.to_string()),
+            Some(MDEV_TYPE.to_string()),
+            None,
+            false,
+            false,
+        )
+    };
+    let audited = || -> Vec<String> {
+        fs::read_to_string(test.audit_log_path())
+            .unwrap_or_default()
+            .lines()
+            // the executable is the test binary
+            .map(|l| {
+                l.split(" exe=").next().unwrap().to_string() + " " + l.rsplit(' ').next().unwrap()
+            })
+            .collect()
+    };
+
+    // nothing is audited unless the policy asks for it
+    test.assert_result(start(UUID[0]), Expect::Pass, None)
+        .unwrap();
+    assert!(audited().is_empty());
+
+    fs::write(test.policy_path(), r#"{"audit": true}"#).expect("Unable to write policy");
+    test.assert_result(start(UUID[0]), Expect::Pass, None)
+        .unwrap();
+    test.populate_active_device(UUID[0], PARENT, MDEV_TYPE);
+    let res = crate::stop_command(
+        test.clone(),
+        Uuid::parse_str(UUID[0]).unwrap(),
+        false,
+        false,
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    // changes of definitions are audited as modifications
+    let uuid = Uuid::parse_str(UUID[0]).unwrap();
+    let res = crate::define_command(
+        test.clone(),
+        &DefineArgs {
+            uuid: Some(uuid),
+            parent: Some(PARENT.to_string()),
+            mdev_type: Some(MDEV_TYPE.to_string()),
+            ..Default::default()
+        },
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let res = crate::modify_command(
+        test.clone(),
+        uuid,
+        ModifyArgs {
+            changes: DefinitionChanges {
+                auto: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        &mut std::io::sink(),
+    );
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    // failures are audited as well
+    test.populate_callout_script("rc1.sh");
+    assert!(start(UUID[1]).is_err());
+
+    let device = |uuid: &str| {
+        format!(
+            "uuid=\"{}\" parent=\"{}\" type=\"{}\"",
+            uuid, PARENT, MDEV_TYPE
+        )
+    };
+    assert_eq!(
+        audited(),
+        [
+            format!("op=start {} res=success", device(UUID[0])),
+            format!("op=stop {} res=success", device(UUID[0])),
+            format!("op=define {} res=success", device(UUID[0])),
+            format!("op=modify {} res=success", device(UUID[0])),
+            format!("op=start {} res=failed", device(UUID[1])),
+        ]
+    );
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_start_profile() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let profile = |instances: i32, expect: Expect| -> serde_json::Value {
+        let test = TestEnvironment::new("startstop", "profile");
+        test.populate_parent_device(PARENT, MDEV_TYPE, instances, "vfio-pci", "", None);
+        test.populate_callout_script("rc0.sh");
+        let path = test.root().join("profile.json");
+        let res = crate::start_command(
+            test.clone(),
+            StartArgs {
+                uuid: Some(Uuid::parse_str(UUID).unwrap()),
+                parent: Some(PARENT.to_string()),
+                mdev_type: Some(MDEV_TYPE.to_string()),
+                capture_profile: Some(path.clone()),
+                ..Default::default()
+            },
+        );
+        let _ = test.assert_result(res, expect, None);
+        let contents = fs::read_to_string(path).expect("Unable to read profile");
+        serde_json::from_str(&contents).expect("Invalid profile")
+    };
+    let logged = |profile: &serde_json::Value, message: &str| {
+        profile["log"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|r| r["message"].as_str().unwrap().contains(message))
+    };
+
+    // the debug messages are captured although they are not logged
+    let started = profile(1, Expect::Pass);
+    assert_eq!(started["result"], "success");
+    assert_eq!(started["device"]["mdev_type"], MDEV_TYPE);
+    assert!(started.get("error").is_none());
+    assert!(logged(&started, "pre-start: executing"));
+    assert!(logged(&started, "Creating mediated device"));
+
+    let failed = profile(
+        0,
+        Expect::Fail(Some(
+            "No available instances of arbitrary_type on 0000:00:03.0",
+        )),
+    );
+    assert_eq!(failed["result"], "failure");
+    assert!(failed["error"]
+        .as_str()
+        .unwrap()
+        .contains("No available instances"));
+    assert!(logged(&failed, "Available instances: 0"));
+}
+
+#[test]
+#[cfg(feature = "callouts")]
+fn test_error_category() {
+    use crate::error::ErrorCategory;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("startstop", "error-category");
+    let start = |parent: &str, jsonfile: Option<PathBuf>| -> ErrorCategory {
+        let res = crate::start_command_helper(
+            test.clone(),
+            Some(Uuid::parse_str(UUID).unwrap()),
+            Some(parent.to_string()),
+            jsonfile.is_none().then(|| MDEV_TYPE.to_string()),
+            jsonfile,
+            false,
+            false,
+        );
+        ErrorCategory::of(&res.unwrap_err())
+    };
+
+    test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "", None);
+    assert_eq!(start("0000:00:02.0", None), ErrorCategory::NotFound);
+
+    let jsonfile = test.root().join("invalid.json");
+    fs::write(&jsonfile, r#"{"mdev_type": "arbitrary_type"}"#).expect("Unable to write file");
+    assert_eq!(start(PARENT, Some(jsonfile)), ErrorCategory::InvalidConfig);
+
+    test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+    assert_eq!(start(PARENT, None), ErrorCategory::AlreadyExists);
+
+    test.populate_callout_script("rc1.sh");
+    let err = crate::stop_command(test.clone(), Uuid::parse_str(UUID).unwrap(), false, false);
+    let category = ErrorCategory::of(&err.unwrap_err());
+    assert_eq!(category, ErrorCategory::CalloutRejected);
+    assert_eq!(category.exit_code(), 6);
+}
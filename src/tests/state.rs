@@ -0,0 +1,141 @@
+use super::*;
+use crate::state::StateWriter;
+
+const UUID: &[&str] = &[
+    "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+    "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    "4a0a190f-dcf3-4def-9342-c48768f0c940",
+];
+const PARENT: &str = "0000:00:02.0";
+
+fn export(test: &Rc<TestEnvironment>) -> String {
+    let mut outbuf: Vec<u8> = Default::default();
+    crate::export_command(test.clone(), &mut outbuf).expect("Failed to export devices");
+    String::from_utf8(outbuf).expect("invalid utf8 output")
+}
+
+/// an environment with the parent device of all devices, and their types
+fn target(testcase: &str) -> Rc<TestEnvironment> {
+    let test = TestEnvironment::new("state", testcase);
+    test.populate_parent_device(PARENT, "type_a", 1, "vfio-pci", "", None);
+    test.populate_parent_device(PARENT, "type_b", 1, "vfio-pci", "", None);
+    test
+}
+
+#[test]
+fn test_state_export_import() {
+    init();
+
+    // an automatically started device, a running device, a running device that is not defined,
+    // a configuration file and staged changes
+    let source = TestEnvironment::new("state", "source");
+    source.populate_defined_device(UUID[0], PARENT, "auto.json");
+    source.populate_defined_device(UUID[1], PARENT, "manual.json");
+    source.populate_active_device(UUID[1], PARENT, "type_b");
+    source.populate_active_device(UUID[2], PARENT, "type_b");
+    fs::copy(source.datapath.join("labels.json"), source.labels_path())
+        .expect("Unable to setup labels.json");
+    let staged = source.staging_base().join(PARENT).join(UUID[1]);
+    fs::create_dir_all(staged.parent().unwrap()).expect("Unable to setup staging dir");
+    fs::copy(source.datapath.join("auto.json"), &staged).expect("Unable to stage changes");
+
+    let archive = source.root().join("state.tar.gz");
+    let res = crate::state_export_command(source.clone(), archive.clone());
+    source
+        .assert_result(res, Expect::Pass, Some("export"))
+        .unwrap();
+
+    // everything is restored, and the devices are started as requested
+    for start in [false, true] {
+        let test = target("import");
+        let res = crate::state_import_command(test.clone(), archive.clone(), start, false, false);
+        test.assert_result(res, Expect::Pass, None).unwrap();
+        assert_eq!(export(&source), export(&test));
+        assert_eq!(
+            fs::read(source.labels_path()).unwrap(),
+            fs::read(test.labels_path()).unwrap()
+        );
+        assert_eq!(
+            fs::read(&staged).unwrap(),
+            fs::read(test.staging_base().join(PARENT).join(UUID[1])).unwrap()
+        );
+        let typedir = test.parent_base().join(PARENT).join("mdev_supported_types");
+        assert_eq!(start, typedir.join("type_a").join("create").exists());
+        assert_eq!(start, typedir.join("type_b").join("create").exists());
+
+        // existing definitions are only overwritten with force
+        let res = crate::state_import_command(test.clone(), archive.clone(), false, false, false);
+        let _ = test.assert_result(
+            res,
+            Expect::Fail(Some(&format!(
+                "Device {} on {} already defined, use --force to overwrite it",
+                UUID[1], PARENT
+            ))),
+            None,
+        );
+        let res = crate::state_import_command(test.clone(), archive.clone(), false, true, false);
+        test.assert_result(res, Expect::Pass, Some("force"))
+            .unwrap();
+    }
+
+    // nothing is restored if a file exists with different contents
+    let test = target("conflict");
+    fs::write(test.labels_path(), "{}").expect("Unable to setup labels.json");
+    let res = crate::state_import_command(test.clone(), archive.clone(), false, false, false);
+    let _ = test.assert_result(
+        res,
+        Expect::Fail(Some(&format!(
+            "{:?} already exists, use --force to overwrite it",
+            test.labels_path()
+        ))),
+        None,
+    );
+    assert_eq!("[]\n", export(&test));
+    assert!(!test.staging_base().join(PARENT).exists());
+
+    // a device that fails to start does not keep the others from being started, unless the
+    // call-out scripts are overridden
+    for force_callouts in [false, true] {
+        let test = target("start-failure");
+        test.populate_callout_script("reject-start-type-a.sh");
+        let res =
+            crate::state_import_command(test.clone(), archive.clone(), true, false, force_callouts);
+        let expect = match force_callouts {
+            false => Expect::Fail(Some("Failed to start 1 of 2 devices")),
+            true => Expect::Pass,
+        };
+        let _ = test.assert_result(res, expect, None);
+        assert_eq!(export(&source), export(&test));
+        let typedir = test.parent_base().join(PARENT).join("mdev_supported_types");
+        assert_eq!(
+            force_callouts,
+            typedir.join("type_a").join("create").exists()
+        );
+        assert!(typedir.join("type_b").join("create").exists());
+    }
+}
+
+#[test]
+fn test_state_import_format() {
+    init();
+
+    // archives of a newer format are refused
+    let test = target("format");
+    let archive = test.root().join("state.tar.gz");
+    let mut writer = StateWriter::new(fs::File::create(&archive).unwrap());
+    writer
+        .add("version.json", br#"{"format": 2, "mdevctl": "99.0.0"}"#)
+        .unwrap();
+    writer.add("definitions.json", b"[]").unwrap();
+    writer.add("active.json", b"[]").unwrap();
+    writer.finish().unwrap();
+
+    let res = crate::state_import_command(test.clone(), archive, false, false, false);
+    let e = test
+        .assert_result(res, Expect::Fail(None), None)
+        .unwrap_err();
+    assert_eq!(
+        "State archive format 2 created by mdevctl 99.0.0 is not supported",
+        e.root_cause().to_string()
+    );
+}
@@ -0,0 +1,78 @@
+use super::*;
+
+const UUID: &[&str] = &[
+    "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+    "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    "4a0a190f-dcf3-4def-9342-c48768f0c940",
+];
+const PARENT: &[&str] = &["0000:00:02.0", "0000:00:03.0"];
+
+#[test]
+fn test_generate_systemd() {
+    init();
+
+    use std::os::unix::fs::symlink;
+
+    let test = TestEnvironment::new("systemd", "default");
+    // the parent device is registered, the parent of the other device is not
+    let devdir = test.root().join("sys/devices/pci0000:00").join(PARENT[0]);
+    fs::create_dir_all(&devdir).expect("Unable to setup parent device");
+    symlink(&devdir, test.parent_base().join(PARENT[0])).expect("Unable to setup parent device");
+    test.populate_defined_device(UUID[0], PARENT[0], "auto.json");
+    test.populate_defined_device(UUID[1], PARENT[0], "manual.json");
+    test.populate_defined_device(UUID[2], PARENT[1], "auto.json");
+
+    let dir = test.root().join("units");
+    fs::create_dir_all(&dir).expect("Unable to setup unit dir");
+    // a unit generated for a device that no longer starts automatically, and one that was not
+    // generated by mdevctl
+    let stale = format!("mdev@{}.service", UUID[1]);
+    fs::write(dir.join(&stale), crate::systemd::HEADER).expect("Unable to write unit");
+    fs::write(dir.join("mdev@custom.service"), "[Unit]\n").expect("Unable to write unit");
+
+    let res = crate::generate_systemd_command(test.clone(), dir.clone(), None);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let unit = |uuid: &str| fs::read_to_string(dir.join(format!("mdev@{}.service", uuid)));
+    test.compare_to_file("registered.service", &unit(UUID[0]).unwrap());
+    test.compare_to_file("missing.service", &unit(UUID[2]).unwrap());
+    assert!(!dir.join(&stale).exists());
+    assert!(dir.join("mdev@custom.service").exists());
+}
+
+#[test]
+fn test_escape_path() {
+    for (path, expected) in [
+        ("/", "-"),
+        (
+            "/sys/devices/pci0000:00/0000:00:02.0",
+            "sys-devices-pci0000:00-0000:00:02.0",
+        ),
+        (
+            "/sys/devices/platform/my-device",
+            "sys-devices-platform-my\\x2ddevice",
+        ),
+        ("/.hidden/a b", "\\x2ehidden-a\\x20b"),
+    ] {
+        assert_eq!(expected, crate::systemd::escape_path(Path::new(path)));
+    }
+}
+
+#[test]
+fn test_skip_active() {
+    init();
+
+    let test = TestEnvironment::new("systemd", "skip-active");
+    test.populate_active_device(UUID[0], PARENT[0], "arbitrary_type");
+    let env: Rc<dyn Environment> = test.clone();
+    let uuid = Uuid::parse_str(UUID[0]).unwrap();
+    // the device was started on its parent, e.g. by the udev rules
+    assert!(crate::is_active(&env, uuid, Some(&PARENT[0].to_string())));
+    assert!(crate::is_active(&env, uuid, None));
+    // starting the device on another parent must still fail
+    assert!(!crate::is_active(&env, uuid, Some(&PARENT[1].to_string())));
+    assert!(!crate::is_active(
+        &env,
+        Uuid::parse_str(UUID[1]).unwrap(),
+        None
+    ));
+}
@@ -1,18 +1,40 @@
 use super::*;
+use crate::cli::{OutputFormat, TypesGroupBy};
+#[cfg(feature = "rich-output")]
+use std::collections::BTreeMap;
 
-fn test_types_helper(
+fn test_types_helper(test: &Rc<TestEnvironment>, subtest: &str, expect: Expect, parents: &[&str]) {
+    test_types_grouped_helper(test, subtest, expect, parents, None, TypesGroupBy::Parent)
+}
+
+fn test_types_grouped_helper(
     test: &Rc<TestEnvironment>,
     subtest: &str,
     expect: Expect,
-    parent: Option<String>,
+    parents: &[&str],
+    device_api: Option<&str>,
+    group_by: TypesGroupBy,
 ) {
     use crate::types_command;
     let env: Rc<dyn Environment> = test.clone();
+    let parents = parents.iter().map(|p| p.to_string()).collect::<Vec<_>>();
 
     // test text output
     let mut outbuf: Vec<u8> = Default::default();
-    let res = types_command(env.clone(), parent.clone(), false, &mut outbuf);
-    if let Ok(_) = test.clone().assert_result(res, expect, Some("text")) {
+    let res = types_command(
+        env.clone(),
+        &parents,
+        device_api,
+        None,
+        group_by,
+        false,
+        &mut outbuf,
+    );
+    if test
+        .clone()
+        .assert_result(res, expect, Some("text"))
+        .is_ok()
+    {
         test.compare_to_file(
             &format!("{}.text", subtest),
             &String::from_utf8(outbuf).expect("invalid utf8 output"),
@@ -21,8 +43,20 @@ fn test_types_helper(
 
     // test JSON output
     let mut outbuf: Vec<u8> = Default::default();
-    let res = types_command(env.clone(), parent.clone(), true, &mut outbuf);
-    if let Ok(_) = test.clone().assert_result(res, expect, Some("json")) {
+    let res = types_command(
+        env.clone(),
+        &parents,
+        device_api,
+        Some(OutputFormat::Json),
+        group_by,
+        false,
+        &mut outbuf,
+    );
+    if test
+        .clone()
+        .assert_result(res, expect, Some("json"))
+        .is_ok()
+    {
         test.compare_to_file(
             &format!("{}.json", subtest),
             &String::from_utf8(outbuf).expect("invalid utf8 output"),
@@ -31,13 +65,14 @@ fn test_types_helper(
 }
 
 #[test]
+#[cfg(feature = "rich-output")]
 fn test_types() {
     init();
 
     let test = TestEnvironment::new("types", "default");
 
     // test an empty environment without any devices that suppport mdevs
-    test_types_helper(&test, "empty", Expect::Pass, None);
+    test_types_helper(&test, "empty", Expect::Pass, &[]);
 
     // populate test environment with a variety of parent devices that support certain mdev types
     let mut parents = BTreeMap::new();
@@ -67,29 +102,176 @@ fn test_types() {
         }
     }
 
-    test_types_helper(&test, "full", Expect::Pass, None);
-    test_types_helper(
+    test_types_helper(&test, "full", Expect::Pass, &[]);
+    for (subtest, group_by) in [("full", TypesGroupBy::Parent), ("api", TypesGroupBy::Api)] {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res = crate::types_command(
+            test.clone(),
+            &[],
+            None,
+            Some(OutputFormat::Yaml),
+            group_by,
+            false,
+            &mut outbuf,
+        );
+        test.assert_result(res, Expect::Pass, Some("yaml")).unwrap();
+        test.compare_to_file(
+            &format!("{}.yaml", subtest),
+            &String::from_utf8(outbuf).expect("invalid utf8 output"),
+        );
+    }
+    test_types_grouped_helper(&test, "api", Expect::Pass, &[], None, TypesGroupBy::Api);
+    test_types_grouped_helper(
         &test,
-        "parent-match-1",
+        "api-parent-match",
         Expect::Pass,
-        Some("0000:00:02.0".to_string()),
+        &["0000:00:03.0"],
+        None,
+        TypesGroupBy::Api,
     );
-    test_types_helper(
+    test_types_grouped_helper(
         &test,
-        "parent-match-2",
+        "device-api",
         Expect::Pass,
-        Some("0000:00:03.0".to_string()),
+        &[],
+        Some("vfio-pci"),
+        TypesGroupBy::Parent,
     );
-    test_types_helper(
+    test_types_grouped_helper(
         &test,
-        "parent-match-3",
+        "device-api-parent-match",
         Expect::Pass,
-        Some("0.0.26ab".to_string()),
+        &["0000:00:03.0", "0.0.26ab"],
+        Some("vfio-pci"),
+        TypesGroupBy::Parent,
     );
+    test_types_grouped_helper(
+        &test,
+        "device-api-no-match",
+        Expect::Pass,
+        &[],
+        Some("vfio-ap"),
+        TypesGroupBy::Parent,
+    );
+    test_types_helper(&test, "parent-match-1", Expect::Pass, &["0000:00:02.0"]);
+    test_types_helper(&test, "parent-match-2", Expect::Pass, &["0000:00:03.0"]);
+    test_types_helper(&test, "parent-match-3", Expect::Pass, &["0.0.26ab"]);
+    test_types_helper(&test, "parent-no-match", Expect::Pass, &["missing"]);
     test_types_helper(
         &test,
-        "parent-no-match",
+        "parent-match-multiple",
         Expect::Pass,
-        Some("missing".to_string()),
+        &["0.0.26ab", "0000:00:03.0"],
+    );
+}
+
+#[test]
+fn test_types_invalid_instances() {
+    init();
+
+    // a type with an unparsable number of available instances is listed with 0 instances
+    let test = TestEnvironment::new("types", "invalid-instances");
+    let (_, typedir) =
+        test.populate_parent_device("0000:00:02.0", "mdev_type1", 5, "vfio-pci", "name1", None);
+    fs::write(typedir.join("available_instances"), "junk\n")
+        .expect("Unable to write available_instances");
+    test.populate_parent_device("0000:00:02.0", "mdev_type2", 16, "vfio-pci", "name2", None);
+    test_types_helper(&test, "invalid-instances", Expect::Pass, &[]);
+}
+
+#[test]
+fn test_types_parent_vanished() {
+    init();
+
+    // parents that disappear while their types are read are skipped
+    let test = TestEnvironment::new("types", "parent-vanished");
+    test.populate_parent_device("0000:00:02.0", "mdev_type1", 5, "vfio-pci", "name1", None);
+    let (parentdir, _) =
+        test.populate_parent_device("0000:00:03.0", "nvidia-155", 4, "vfio-pci", "GRID", None);
+    fs::remove_dir_all(parentdir.join("mdev_supported_types"))
+        .expect("Unable to remove mdev_supported_types");
+    let (_, typedir) =
+        test.populate_parent_device("0.0.26ab", "vfio_ccw-io", 4, "vfio_mdev", "name", None);
+    fs::remove_file(typedir.join("device_api")).expect("Unable to remove device_api");
+    test_types_helper(&test, "parent-vanished", Expect::Pass, &[]);
+}
+
+#[test]
+fn test_types_pci_ids() {
+    init();
+
+    let test = TestEnvironment::new("types", "pci-ids");
+    for (parent, vendor, device) in [
+        ("0000:00:02.0", "0x8086", "0x3e92"),
+        ("0000:00:03.0", "0x10de", "0x1b38"),
+    ] {
+        let (parentdir, _) =
+            test.populate_parent_device(parent, "mdev_type1", 2, "vfio-pci", "name", None);
+        fs::write(parentdir.join("vendor"), format!("{}\n", vendor)).unwrap();
+        fs::write(parentdir.join("device"), format!("{}\n", device)).unwrap();
+    }
+    // not a PCI device
+    test.populate_parent_device("0.0.26ab", "vfio_ccw-io", 4, "vfio_mdev", "name", None);
+
+    let types = |format: Option<OutputFormat>, group_by: TypesGroupBy| {
+        let mut outbuf: Vec<u8> = Default::default();
+        let res =
+            crate::types_command(test.clone(), &[], None, format, group_by, true, &mut outbuf);
+        test.assert_result(res, Expect::Pass, None).unwrap();
+        String::from_utf8(outbuf).expect("invalid utf8 output")
+    };
+
+    // without a database only the IDs are known
+    test.compare_to_file("ids.text", &types(None, TypesGroupBy::Parent));
+
+    let database = test.root().join("usr/share/hwdata/pci.ids");
+    fs::create_dir_all(database.parent().unwrap()).unwrap();
+    fs::copy(test.datapath.join("pci.ids"), database).unwrap();
+    test.compare_to_file("names.text", &types(None, TypesGroupBy::Parent));
+    test.compare_to_file(
+        "names.json",
+        &types(Some(OutputFormat::Json), TypesGroupBy::Parent),
+    );
+    test.compare_to_file("names-api.text", &types(None, TypesGroupBy::Api));
+}
+
+#[test]
+fn test_types_no_mdev_support() {
+    init();
+
+    const ERROR: &str =
+        "Mediated device framework not available (is the kernel built with CONFIG_VFIO_MDEV?)";
+
+    let test = TestEnvironment::new("types", "no-mdev-support");
+    fs::remove_dir(test.parent_base()).expect("Unable to remove parent_base");
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::types_command(
+        test.clone(),
+        &[],
+        None,
+        None,
+        TypesGroupBy::Parent,
+        false,
+        &mut outbuf,
+    );
+    let _ = test.assert_result(res, Expect::Fail(Some(ERROR)), Some("text"));
+    assert!(outbuf.is_empty());
+
+    // JSON output explains the missing types
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::types_command(
+        test.clone(),
+        &[],
+        None,
+        Some(OutputFormat::Json),
+        TypesGroupBy::Parent,
+        false,
+        &mut outbuf,
+    );
+    let _ = test.assert_result(res, Expect::Fail(Some(ERROR)), Some("json"));
+    test.compare_to_file(
+        "no-mdev-support.json",
+        &String::from_utf8(outbuf).expect("invalid utf8 output"),
     );
 }
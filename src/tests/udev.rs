@@ -0,0 +1,41 @@
+use super::*;
+
+const UUID: &[&str] = &[
+    "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+    "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    "4a0a190f-dcf3-4def-9342-c48768f0c940",
+];
+const PARENT: &[&str] = &["0000:00:02.0", "0000:00:03.0", "0.0.0123"];
+
+#[test]
+fn test_generate_udev_rules() {
+    init();
+
+    let test = TestEnvironment::new("udev", "default");
+    test.populate_defined_device(UUID[0], PARENT[0], "auto.json");
+    // no devices start automatically
+    test.populate_defined_device(UUID[1], PARENT[1], "manual.json");
+    test.populate_defined_device(UUID[2], PARENT[2], "auto.json");
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let dir = test.root().join("rules.d");
+    let res = crate::generate_udev_rules_command(test.clone(), dir.clone(), true, &mut outbuf);
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    let rules = String::from_utf8(outbuf).expect("invalid utf8 output");
+    test.compare_to_file("60-mdevctl.rules", &rules);
+    assert!(!dir.exists());
+
+    // generated rules are replaced, others are left alone
+    let path = dir.join(crate::udev::RULES);
+    let res = crate::generate_udev_rules_command(test.clone(), dir.clone(), false, &mut Vec::new());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    assert_eq!(rules, fs::read_to_string(&path).unwrap());
+    let res = crate::generate_udev_rules_command(test.clone(), dir.clone(), false, &mut Vec::new());
+    test.assert_result(res, Expect::Pass, None).unwrap();
+    // the rules are written to a temporary file first, which does not remain
+    assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+    fs::write(&path, "# local rules\n").expect("Unable to write rules");
+    let res = crate::generate_udev_rules_command(test.clone(), dir, false, &mut Vec::new());
+    let _ = test.assert_result(res, Expect::Fail(None), None);
+    assert_eq!("# local rules\n", fs::read_to_string(&path).unwrap());
+}
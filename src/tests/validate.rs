@@ -0,0 +1,97 @@
+use super::*;
+
+const UUID: &[&str] = &[
+    "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+    "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    "4a0a190f-dcf3-4def-9342-c48768f0c940",
+    "9f579710-6ffc-4201-987a-4ffa0fb1f3a5",
+    "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+    "ae1aa2a4-4f80-42b0-a7c4-1a3c5ca6f0e8",
+];
+const PARENT: &[&str] = &["0000:00:02.0", "0000:00:03.0"];
+const MDEV_TYPE: &str = "type_a";
+
+fn test_validate_helper(subtest: &str, expect: Expect, setup: impl Fn(&Rc<TestEnvironment>)) {
+    let test = TestEnvironment::new("validate", subtest);
+    let env: Rc<dyn Environment> = test.clone();
+    test.populate_parent_device(PARENT[0], MDEV_TYPE, 1, "", "", None);
+    setup(&test);
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::validate_command(env.clone(), false, &mut outbuf);
+    let _ = test.assert_result(res, expect, Some("text"));
+    let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+    test.compare_to_file(&format!("{}.text", subtest), &actual);
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::validate_command(env, true, &mut outbuf);
+    let _ = test.assert_result(res, expect, Some("json"));
+    let actual = String::from_utf8(outbuf).expect("invalid utf8 output");
+    test.compare_to_file(&format!("{}.json", subtest), &actual);
+}
+
+#[test]
+fn test_validate() {
+    init();
+
+    test_validate_helper("valid", Expect::Pass, |test| {
+        test.populate_defined_device(UUID[0], PARENT[0], "type-a.json");
+        // attributes of inactive devices are not checked
+        test.populate_defined_device(UUID[1], PARENT[0], "attrs.json");
+    });
+    test_validate_helper(
+        "problems",
+        Expect::Fail(Some("Found 5 problem(s) in device definitions")),
+        |test| {
+            test.populate_defined_device(UUID[0], PARENT[0], "type-a.json");
+            test.populate_defined_device(UUID[1], PARENT[1], "type-a.json");
+            test.populate_defined_device(UUID[2], PARENT[0], "type-b.json");
+            test.populate_defined_device(UUID[3], PARENT[0], "malformed.json");
+            test.populate_defined_device(UUID[4], PARENT[0], "missing-type.json");
+            // only one of the attributes exists for the active device
+            test.populate_active_device(UUID[5], PARENT[0], MDEV_TYPE);
+            test.populate_defined_device(UUID[5], PARENT[0], "attrs.json");
+            fs::write(test.mdev_base().join(UUID[5]).join("weight"), "10")
+                .expect("Unable to write attribute");
+        },
+    );
+}
+
+#[test]
+fn test_duplicate_definitions() {
+    init();
+
+    const UPPER: &str = "976D8CC2-4BFC-43B9-B9F9-F4AF2DE91AB9";
+    const SIMPLE: &str = "976d8cc24bfc43b9b9f9f4af2de91ab9";
+
+    let defined_type = |test: &Rc<TestEnvironment>| {
+        let devs = test
+            .clone()
+            .get_defined_devices(None, None)
+            .expect("failed to query defined devices");
+        let devs = devs.values().flatten().collect::<Vec<_>>();
+        assert_eq!(1, devs.len());
+        assert_eq!(UUID[0], devs[0].uuid.hyphenated().to_string());
+        devs[0].mdev_type.clone().unwrap()
+    };
+
+    // without a canonical file name, the first file name in sort order is used
+    let test = TestEnvironment::new("validate", "duplicates");
+    test.populate_defined_device(SIMPLE, PARENT[0], "type-a.json");
+    test.populate_defined_device(UPPER, PARENT[0], "type-b.json");
+    assert_eq!("type_b", defined_type(&test));
+
+    // a file named by the canonical UUID always takes precedence
+    test.populate_defined_device(UUID[0], PARENT[0], "type-a.json");
+    assert_eq!("type_a", defined_type(&test));
+
+    test_validate_helper(
+        "duplicates",
+        Expect::Fail(Some("Found 2 problem(s) in device definitions")),
+        |test| {
+            test.populate_defined_device(SIMPLE, PARENT[0], "type-b.json");
+            test.populate_defined_device(UPPER, PARENT[0], "type-b.json");
+            test.populate_defined_device(UUID[0], PARENT[0], "type-a.json");
+        },
+    );
+}
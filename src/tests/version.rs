@@ -0,0 +1,54 @@
+use super::*;
+
+#[test]
+fn test_version() {
+    init();
+
+    let test = TestEnvironment::new("version", "default");
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::version_command(test.clone(), true, &mut outbuf);
+    test.assert_result(res, Expect::Pass, Some("json")).unwrap();
+    let version: serde_json::Value =
+        serde_json::from_slice(&outbuf).expect("version output is not valid json");
+    assert_eq!(env!("CARGO_PKG_VERSION"), version["mdevctl"]);
+    let callout_versions = version["callout_versions"].as_array().unwrap();
+    assert_eq!(
+        vec![1, 2, 3, 4, 5],
+        callout_versions
+            .iter()
+            .map(|v| v["version"].as_u64().unwrap())
+            .collect::<Vec<_>>()
+    );
+    assert!(callout_versions[1]["events"]
+        .as_array()
+        .unwrap()
+        .contains(&"live".into()));
+    assert_eq!(
+        cfg!(feature = "callouts"),
+        version["features"]
+            .as_array()
+            .unwrap()
+            .contains(&"callouts".into())
+    );
+    // nested commands are listed, hidden commands are not
+    let commands = version["commands"].as_array().unwrap();
+    for cmd in ["list", "stage modify", "version"] {
+        assert!(commands.contains(&cmd.into()), "missing command {}", cmd);
+    }
+    assert!(!commands.contains(&"start-parent-mdevs".into()));
+    // paths are shown as on the host, not within the test environment
+    assert_eq!("/etc/mdevctl.d", version["paths"]["config"]);
+    assert_eq!(
+        "/usr/lib/mdevctl/scripts.d/callouts",
+        version["paths"]["callouts"]
+    );
+
+    let mut outbuf: Vec<u8> = Default::default();
+    let res = crate::version_command(test.clone(), false, &mut outbuf);
+    test.assert_result(res, Expect::Pass, Some("text")).unwrap();
+    let text = String::from_utf8(outbuf).expect("invalid utf8 output");
+    assert!(text.starts_with(&format!("mdevctl {}\n", env!("CARGO_PKG_VERSION"))));
+    assert!(text.contains("Call-out protocol versions: 1, 2, 3, 4, 5\n"));
+    assert!(text.contains("Configuration directory: /etc/mdevctl.d\n"));
+}
@@ -0,0 +1,59 @@
+//! Generation of udev rules for the parents of defined devices, for `generate-udev-rules`
+//!
+//! The rules shipped with mdevctl run `start-parent-mdevs` for every device that is registered
+//! with the mediated device framework, and leave it to the command to find out whether the parent
+//! has any definitions. The generated rules only match the parents that have devices set to
+//! start automatically. They are written with the name of the shipped rules, so that rules in
+//! /etc/udev/rules.d replace them.
+
+use crate::environment::{Environment, MDEVCTL};
+use anyhow::Result;
+use log::warn;
+use std::fmt::Write;
+use std::rc::Rc;
+
+/// the file name of the rules, which is the name of the rules shipped with mdevctl
+pub const RULES: &str = "60-mdevctl.rules";
+
+/// the first line of generated rules, which tells them apart from rules written by hand
+pub const HEADER: &str = "# Generated by mdevctl generate-udev-rules\n";
+
+/// Characters that udev interprets in the values of matches
+const PATTERN_CHARS: &[char] = &['*', '?', '[', ']', '|', '"', '\\'];
+
+/// The rules that start the devices of the parents that have devices set to start automatically,
+/// ordered by parent. Parents whose names udev would interpret as a pattern are left out.
+pub fn rules(env: Rc<dyn Environment>) -> Result<String> {
+    let run = format!(
+        "RUN+=\"/bin/sh -c '{{ {} start-parent-mdevs %k 2>&3 | logger -t mdevctl; }} 3>&1 1>&2 \
+         | logger -t mdevctl -p 2'\"",
+        MDEVCTL
+    );
+    let mut rules = format!(
+        "{}# When one of the parents is registered with mdev, start its devices that are set \
+         to start\n# automatically. Older kernels do not trigger the change uevent, so the \
+         devices are also\n# started when the parent is added.\n",
+        HEADER
+    );
+    let devs = env.get_defined_devices(None, None)?;
+    for (parent, children) in devs.iter() {
+        if !children.iter().any(|d| d.autostart) {
+            continue;
+        }
+        if parent.contains(PATTERN_CHARS) {
+            warn!(
+                "Not generating rules for parent {}, which udev would interpret as a pattern",
+                parent
+            );
+            continue;
+        }
+        let _ = write!(
+            rules,
+            "\nACTION==\"change\", ENV{{MDEV_STATE}}==\"registered\", KERNEL==\"{parent}\", {run}\n\
+             ACTION==\"add\", KERNEL==\"{parent}\", {run}\n",
+            parent = parent,
+            run = run
+        );
+    }
+    Ok(rules)
+}
@@ -0,0 +1,23 @@
+#!/bin/sh
+# rejects the start of one device and hangs when another device is started
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+cat > /dev/null
+shift 3
+event=$1
+shift 2
+action=$1
+shift 4
+uuid=$1
+
+if [ "$event" = "pre" ] && [ "$action" = "start" ]; then
+    case "$uuid" in
+        59e8b599-afdd-4766-a59e-415ef4f5a492)
+            exit 1
+        ;;
+        4a0a190f-dcf3-4def-9342-c48768f0c940)
+            sleep 5
+        ;;
+    esac
+fi
+exit 0
@@ -0,0 +1,20 @@
+#!/bin/sh
+# supports all events and counts how often it is executed in invocations.log in the test root
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+root="$(dirname "$0")/../../../../.."
+json=$(cat)
+shift 3
+event=$1
+shift 2
+action=$1
+
+echo "$event $action" >> "$root/invocations.log"
+if [ "$event" = "get" ] && [ "$action" = "capabilities" ]; then
+    echo "{\"supports\":{"
+    echo "\"version\":2,"
+    echo "\"actions\":[\"start\",\"stop\",\"define\",\"undefine\",\"modify\",\"attributes\",\"capabilities\"],"
+    echo "\"events\":[\"pre\",\"post\",\"notify\",\"get\"]"
+    echo "}}"
+fi
+exit 0
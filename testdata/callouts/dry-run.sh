@@ -0,0 +1,8 @@
+#!/bin/sh
+# Supports all devices, but only accepts them in a dry run
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+if [ "$MDEVCTL_DRY_RUN" = "1" ]; then
+    exit 0
+fi
+exit 1
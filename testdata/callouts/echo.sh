@@ -0,0 +1,6 @@
+#!/bin/sh
+# Echoes standard input and fails, to show what a script was given
+
+cat
+echo "failing on purpose" >&2
+exit 3
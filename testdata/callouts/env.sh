@@ -0,0 +1,6 @@
+#!/bin/sh
+# Returns the device and the event passed in the environment as attributes
+
+printf '[{"uuid": "%s"}, {"parent": "%s"}, {"type": "%s"}, {"event": "%s"}, {"action": "%s"}, {"state": "%s"}]' \
+    "$MDEVCTL_UUID" "$MDEVCTL_PARENT" "$MDEVCTL_TYPE" "$MDEVCTL_EVENT" "$MDEVCTL_ACTION" \
+    "$MDEVCTL_STATE"
@@ -0,0 +1,11 @@
+#!/bin/sh
+# Supports all devices, but takes two seconds for pre events like a slow vendor script
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+shift 3
+event=$1
+
+if [ "$event" = "pre" ]; then
+    exec sleep 2
+fi
+exit 0
@@ -0,0 +1,9 @@
+#!/bin/sh
+# succeeds only if the limits from callout-limits.json were applied
+if [ "$(ulimit -n)" != "64" ]; then
+    exit 1
+fi
+if [ "$(ulimit -t)" != "10" ]; then
+    exit 1
+fi
+exit 0
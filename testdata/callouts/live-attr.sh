@@ -0,0 +1,28 @@
+#!/bin/sh
+# Supports live changes, which only succeed if the device is to have the AP domain 0x42
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+shift 3
+event=$1
+shift 2
+action=$1
+json=$(cat)
+
+case "$event" in
+    get)
+        if [ "$action" = "capabilities" ]; then
+            echo "{\"supports\":{"
+            echo "\"version\":3,"
+            echo "\"actions\":[\"start\",\"stop\",\"define\",\"undefine\",\"modify\",\"attributes\",\"capabilities\"],"
+            echo "\"events\":[\"pre\",\"post\",\"notify\",\"get\",\"live\"]"
+            echo "}}"
+        fi
+        exit 0
+    ;;
+    live)
+        echo "$json" | grep -q '"assign_adapter":"5"' || exit 1
+        echo "$json" | grep -q '"assign_domain":"0x42"' || exit 1
+        exit 0
+    ;;
+esac
+exit 0
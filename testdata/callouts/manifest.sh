@@ -0,0 +1,29 @@
+#!/bin/sh
+# handles only type_a according to its manifest and counts how often it is executed in
+# invocations.log in the test root
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+root="$(dirname "$0")/../../../../.."
+json=$(cat)
+shift
+type=$1
+shift 2
+event=$1
+shift 2
+action=$1
+
+echo "$type $event $action" >> "$root/invocations.log"
+if [ "$type" != "type_a" ]; then
+    exit 2
+fi
+if [ "$event" = "get" ] && [ "$action" = "capabilities" ]; then
+    echo "{\"supports\":{"
+    echo "\"version\":4,"
+    echo "\"actions\":[\"start\",\"stop\",\"define\",\"undefine\",\"modify\",\"attributes\",\"capabilities\"],"
+    echo "\"events\":[\"pre\",\"post\",\"notify\",\"get\",\"live\"]"
+    echo "},\"manifest\":{"
+    echo "\"mdev_types\":[\"type_a\"],"
+    echo "\"get_attributes\":false"
+    echo "}}"
+fi
+exit 0
@@ -0,0 +1,14 @@
+#!/bin/sh
+# succeeds only if it runs in a mount namespace without unrelated paths of the host
+
+# host state that scripts do not need is not available
+if [ -e /var ]; then
+    exit 1
+fi
+# scripts are read-only
+if touch "$(dirname "$0")/written" 2>/dev/null; then
+    exit 1
+fi
+# but sysfs and a scratch directory are writable
+touch /tmp/scratch || exit 1
+exit 0
@@ -0,0 +1,5 @@
+#!/bin/sh
+# Notifier that leaves a marker for notify-wait.sh after a while, with the notification it got
+
+sleep 0.5
+cat > "$(dirname "$0")/../marked"
@@ -0,0 +1,12 @@
+#!/bin/sh
+# Notifier that only completes if notify-mark.sh runs at the same time
+
+dir=$(dirname "$0")/..
+for i in $(seq 20); do
+    if [ -e "$dir/marked" ]; then
+        touch "$dir/waited"
+        exit 0
+    fi
+    sleep 0.1
+done
+exit 1
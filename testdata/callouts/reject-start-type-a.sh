@@ -0,0 +1,17 @@
+#!/bin/sh
+# Rejects starting a device of type_a
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+shift
+type=$1
+shift 2
+event=$1
+shift 2
+action=$1
+json=$(cat)
+
+if [ "$event" = "pre" ] && [ "$action" = "start" ] && [ "$type" = "type_a" ]; then
+	exit 1
+fi
+
+exit 0
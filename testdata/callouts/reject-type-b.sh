@@ -0,0 +1,15 @@
+#!/bin/sh
+# Rejects any change to a device of type_b
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+shift
+type=$1
+shift 2
+event=$1
+json=$(cat)
+
+if [ "$event" = "pre" ] && [ "$type" = "type_b" ]; then
+	exit 1
+fi
+
+exit 0
@@ -0,0 +1,25 @@
+#!/bin/sh
+# logs the events of a restart to restart.log in the test root and removes the device from sysfs
+# once it has been stopped, like the kernel would
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+root="$(dirname "$0")/../../../../.."
+json=$(cat)
+shift 3
+event=$1
+shift 2
+action=$1
+shift 2
+state=$1
+shift 2
+uuid=$1
+
+case "$event" in
+    pre|post)
+        echo "$event $action $state" >> "$root/restart.log"
+    ;;
+esac
+if [ "$event" = "post" ] && [ "$action" = "stop" ]; then
+    rm -f "$root/sys/bus/mdev/devices/$uuid"
+fi
+exit 0
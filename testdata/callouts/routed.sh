@@ -0,0 +1,14 @@
+#!/bin/sh
+# accepts every device without versioning and logs its invocations along with its file name in
+# invocations.log in the test root, wherever it is installed
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+root="${0%%/usr/lib/mdevctl/*}"
+json=$(cat)
+shift 3
+event=$1
+shift 2
+action=$1
+
+echo "$(basename "$0") $event $action" >> "$root/invocations.log"
+exit 0
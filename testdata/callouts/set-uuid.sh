@@ -0,0 +1,19 @@
+#!/bin/sh
+# logs the events of a UUID change to set-uuid.log in the test root
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+root="$(dirname "$0")/../../../../.."
+cat > /dev/null
+shift 3
+event=$1
+shift 2
+action=$1
+shift 4
+uuid=$1
+
+case "$event" in
+    pre|post)
+        echo "$event $action $uuid $MDEVCTL_OLD_UUID" >> "$root/set-uuid.log"
+    ;;
+esac
+exit 0
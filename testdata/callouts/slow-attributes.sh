@@ -0,0 +1,24 @@
+#!/bin/sh
+# Supports all devices, but blocks when asked for their attributes like a wedged driver
+
+#stdin | -t type -e event -a action -s state -u uuid -p parent
+shift 3
+event=$1
+shift 2
+action=$1
+
+if [ "$event" = "get" ]; then
+    case "$action" in
+        capabilities)
+            echo "{\"supports\":{"
+            echo "\"version\":2,"
+            echo "\"actions\":[\"start\",\"stop\",\"define\",\"undefine\",\"modify\",\"attributes\",\"capabilities\"],"
+            echo "\"events\":[\"pre\",\"post\",\"notify\",\"get\"]"
+            echo "}}"
+        ;;
+        attributes)
+            exec sleep 60
+        ;;
+    esac
+fi
+exit 0
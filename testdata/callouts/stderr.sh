@@ -0,0 +1,7 @@
+#!/bin/sh
+# Rejects any event and explains why on standard error
+
+json=$(cat)
+echo "parent is in maintenance" >&2
+echo "try again later" >&2
+exit 1
@@ -0,0 +1,9 @@
+#!/bin/sh
+# succeeds only if it runs without the privileges of root
+if [ "$(id -u)" = "0" ] || [ "$(id -g)" = "0" ]; then
+    exit 1
+fi
+if id -G | grep -qw 0; then
+    exit 1
+fi
+exit 0
@@ -0,0 +1,27 @@
+#!/bin/sh
+# Supports versioning and rejects the start of a device with an error report on standard
+# output. The report is only parsed if the version the script supports is 5 or later, which
+# is chosen by the type of the device.
+
+json=$(cat)
+case "$MDEVCTL_TYPE" in
+    report_v5) version=5 ;;
+    *) version=4 ;;
+esac
+
+case "$MDEVCTL_EVENT-$MDEVCTL_ACTION" in
+    get-capabilities)
+        echo "{\"supports\":{"
+        echo "\"version\":$version,"
+        echo "\"actions\":[\"start\",\"stop\",\"define\",\"undefine\",\"modify\",\"attributes\",\"capabilities\"],"
+        echo "\"events\":[\"pre\",\"post\",\"notify\",\"get\"]"
+        echo "}}"
+        exit 0
+    ;;
+    pre-start)
+        echo "{\"error\": \"parent is in maintenance\", \"hint\": \"try again later\"}"
+        echo "no report" >&2
+        exit 1
+    ;;
+esac
+exit 0
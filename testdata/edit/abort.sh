@@ -0,0 +1,4 @@
+#!/bin/sh
+# Changes the definition, but exits with an error
+sed -i 's/"manual"/"auto"/' "$1"
+exit 1
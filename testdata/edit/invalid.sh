@@ -0,0 +1,3 @@
+#!/bin/sh
+# Leaves a definition that is not valid JSON
+echo '{ "mdev_type": ' > "$1"
@@ -0,0 +1,3 @@
+#!/bin/sh
+# Changes the device to be started automatically
+sed -i 's/"manual"/"auto"/' "$1"
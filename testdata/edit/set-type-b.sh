@@ -0,0 +1,3 @@
+#!/bin/sh
+# Changes the type of the device to type_b
+sed -i 's/"vfio_ap-passthrough"/"type_b"/' "$1"